@@ -1,16 +1,180 @@
-use clap::{arg, command, value_parser, Parser};
+use std::path::PathBuf;
 
-#[derive(Debug, Default, Parser)]
+use clap::{arg, command, value_parser, ArgAction, Parser, ValueEnum};
+use log::LevelFilter;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{
+    formatting::TimeFormat,
+    model::Bounds,
+    simplification::metres_to_epsilon,
+    stage::{AthleteProfile, HrZones},
+};
+
+pub use crate::{join::JoinStrategy, simplification::SimplifyMethod};
+
+#[derive(Debug, Default, Clone, Parser)]
 #[command(version, about, long_about = None)]
 pub struct Args {
     #[arg(
         short = 'm',
         long,
         help = "Simplify by using Ramer-Douglas-Peucker with METRES accuracy",
-        value_parser = value_parser!(u16).range(1..=1000)
+        value_parser = value_parser!(u16).range(1..=1000),
+        group = "accuracy"
     )]
     pub metres: Option<u16>,
 
+    #[arg(
+        long,
+        help = "Simplify by using Ramer-Douglas-Peucker with the raw EPSILON, in degrees, rather than converting from --metres",
+        value_parser = parse_positive_f64,
+        group = "accuracy"
+    )]
+    pub epsilon: Option<f64>,
+
+    #[arg(
+        short,
+        long,
+        value_enum,
+        default_value_t = SimplifyMethod::Rdp,
+        help = "The algorithm to use when simplifying with --metres",
+        requires = "metres"
+    )]
+    pub algorithm: SimplifyMethod,
+
+    #[arg(
+        long,
+        help = "When simplifying with Ramer-Douglas-Peucker, also keep points that are significant in the elevation profile",
+        requires = "metres"
+    )]
+    pub preserve_elevation: bool,
+
+    #[arg(
+        long,
+        help = "When simplifying with Ramer-Douglas-Peucker, vary the accuracy along the track according to how closely spaced the points are: dense sections are simplified more and sparse sections less",
+        requires = "metres",
+        conflicts_with = "preserve_elevation"
+    )]
+    pub adaptive: bool,
+
+    #[arg(
+        long,
+        value_name = "METRES",
+        help = "Before simplifying, replace each run of points within METRES of each other (such as when stopped) with a single point"
+    )]
+    pub min_metres: Option<f64>,
+
+    #[arg(
+        long,
+        help = "When simplifying with --metres or --epsilon, use Ramer-Douglas-Peucker on each track segment separately and keep the segments in the simplified file, rather than joining them into one",
+        requires = "accuracy",
+        conflicts_with_all = ["algorithm", "preserve_elevation", "adaptive", "min_metres", "max_hdop", "despike", "fill_elevation", "smooth_elevation", "trim_ends", "resample", "join"]
+    )]
+    pub keep_segments: bool,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "With --keep-segments, start a new segment wherever there are more than SECONDS between two points, e.g. where the device was paused",
+        requires = "keep_segments"
+    )]
+    pub split_on_gap: Option<i64>,
+
+    #[arg(
+        long,
+        help = "Simplify by using Ramer-Douglas-Peucker, keeping at most MAX_POINTS trackpoints (plus the stage boundaries, with --detect-stages)",
+        value_parser = value_parser!(u32).range(2..),
+        conflicts_with = "accuracy"
+    )]
+    pub max_points: Option<u32>,
+
+    #[arg(
+        long,
+        default_value = "6",
+        help = "The number of decimal places to write for latitudes and longitudes in the simplified file. 6 is about 11cm",
+        value_parser = value_parser!(u8).range(1..=10)
+    )]
+    pub coord_decimals: u8,
+
+    #[arg(
+        long,
+        default_value = "1",
+        help = "The number of decimal places to write for elevations in the simplified file",
+        value_parser = value_parser!(u8).range(0..=6)
+    )]
+    pub ele_decimals: u8,
+
+    #[arg(
+        long,
+        help = "The number of decimal places to write for the seconds of times in the simplified file, e.g. 3 for milliseconds. By default as many as are needed are written, without trailing zeros",
+        value_parser = value_parser!(u8).range(0..=9)
+    )]
+    pub time_decimals: Option<u8>,
+
+    #[arg(
+        long,
+        help = "Write the simplified file without indentation or line breaks, to make it smaller"
+    )]
+    pub compact: bool,
+
+    #[arg(
+        long,
+        help = "Keep any elements and extension elements that are not understood and write them to the simplified file unchanged"
+    )]
+    pub preserve_unknown: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "preserve_unknown",
+        help = "Drop all extensions, such as heart rate, cadence and temperature, from the files that are written, for privacy or to make them smaller. The summary still uses them"
+    )]
+    pub strip_extensions: bool,
+
+    #[arg(
+        long,
+        value_name = "KMH",
+        help = "Remove GPS spikes: points which could only be reached from the previous point by travelling faster than KMH are moved back onto the track"
+    )]
+    pub despike: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "HDOP",
+        help = "Remove the points whose horizontal dilution of precision is greater than HDOP, since their positions are probably wrong. Points without an <hdop> are kept",
+        value_parser = parse_positive_f64
+    )]
+    pub max_hdop: Option<f64>,
+
+    #[arg(
+        long,
+        help = "Fill in missing elevations by interpolating between the nearest points that have one"
+    )]
+    pub fill_elevation: bool,
+
+    #[arg(
+        long,
+        value_name = "WINDOW",
+        help = "Smooth the elevations by averaging over WINDOW points, to stop noise inflating the total ascent",
+        value_parser = value_parser!(u32).range(2..)
+    )]
+    pub smooth_elevation: Option<u32>,
+
+    #[arg(
+        long,
+        default_value = "0",
+        value_name = "METRES",
+        help = "Ignore changes in elevation smaller than METRES when calculating ascent and descent"
+    )]
+    pub ascent_threshold: f64,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Calculate a smoothed speed by averaging over SECONDS, and use it to find the maximum speed so that a single GPS glitch is not reported"
+    )]
+    pub speed_smoothing_window: Option<f64>,
+
     #[arg(
         short,
         long,
@@ -18,6 +182,28 @@ pub struct Args {
     )]
     pub join: bool,
 
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = JoinStrategy::ByTime,
+        help = "The order in which to join the input files",
+        requires = "join"
+    )]
+    pub join_order: JoinStrategy,
+
+    #[arg(
+        long,
+        help = "When joining, fail if the input files overlap in time rather than just warning",
+        requires = "join"
+    )]
+    pub strict: bool,
+
+    #[arg(
+        long,
+        help = "When joining, also write a summary workbook for each input file. The outputs for the joined track are then named after the first file with a '.joined' suffix so that they do not overwrite its summary",
+        requires_all = ["join", "detect_stages"]
+    )]
+    pub analyse_individually: bool,
 
     #[arg(
         short,
@@ -27,6 +213,20 @@ pub struct Args {
     )]
     pub detect_stages: bool,
 
+    #[arg(
+        long,
+        help = "Detect the categorized climbs in the track and add a 'Climbs' tab to the summary workbook",
+        requires = "detect_stages"
+    )]
+    pub detect_climbs: bool,
+
+    #[arg(
+        long,
+        help = "Add a 'Map' tab to the summary workbook with a chart of the route, drawn on a flat projection centred on the track",
+        requires = "detect_stages"
+    )]
+    pub map: bool,
+
     #[arg(
         long,
         default_value = "0.15",
@@ -35,6 +235,14 @@ pub struct Args {
     )]
     pub stopped_speed: f64,
 
+    #[arg(
+        long,
+        value_name = "RPM",
+        help = "Only think you are stopped if your cadence is also at or below RPM, like Garmin's auto-pause. Points without a cadence are judged on speed alone",
+        requires = "detect_stages"
+    )]
+    pub stopped_cadence: Option<u16>,
+
     #[arg(
         long,
         default_value = "5.0",
@@ -43,6 +251,66 @@ pub struct Args {
     )]
     pub min_stop_time: f64,
 
+    #[arg(
+        long,
+        help = "After detection, merge any stage shorter than this many minutes into its neighbours",
+        requires = "detect_stages"
+    )]
+    pub min_stage_time: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "KM",
+        help = "Split the track every KM kilometres (like a watch's auto-lap) and write the splits to the summary sheet",
+        requires = "detect_stages",
+        value_parser = parse_positive_f64
+    )]
+    pub split_km: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "METRES",
+        help = "Detect the laps of a closed-loop course, starting a new lap each time the track comes back within METRES of the start, and write them to a 'Laps' tab in the summary workbook",
+        requires = "detect_stages",
+        value_parser = parse_positive_f64
+    )]
+    pub lap_proximity: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "METRES",
+        default_value_t = 200.0,
+        help = "The minimum length of a lap, so that the points just after passing the start are not counted as laps",
+        requires = "lap_proximity",
+        value_parser = parse_positive_f64
+    )]
+    pub min_lap_metres: f64,
+
+    #[arg(
+        long,
+        value_name = "KM",
+        value_delimiter = ',',
+        help = "Find the fastest part of the track covering each of these distances, e.g. '1,5,10', and write them to the summary sheet",
+        requires = "detect_stages",
+        value_parser = parse_positive_f64
+    )]
+    pub fastest_km: Vec<f64>,
+
+    #[arg(
+        long,
+        help = "Write each Moving stage (e.g. each leg between controls) to its own file, named after the stage number, e.g. 'ride.stage03.gpx'. The points are not simplified",
+        requires = "detect_stages"
+    )]
+    pub split_stages: bool,
+
+    #[arg(
+        long,
+        help = "When the file has more than one track, such as a multi-day tour, also detect the stages of each track separately and write them to a tab per track in the summary workbook. The Stages tab is still an overview of the whole file",
+        requires = "detect_stages",
+        conflicts_with_all = ["join", "max_hdop", "trim_ends", "resample"]
+    )]
+    pub per_track: bool,
+
     #[arg(
         long,
         default_value = "100.0",
@@ -53,19 +321,476 @@ pub struct Args {
 
     #[arg(
         long,
-        help = "Whether to include a Google Maps hyperlink when writing TrackPoints to the summary sheet. WARNING: This can slow down the opening of the .xlsx in LibreOffice a lot",
-        requires = "write_trackpoints, detect_stages"
+        default_value = "5.0",
+        help = "If your average speed, in km/h, over the first few minutes of the track is below this then the first stage is considered to be a stop",
+        requires = "detect_stages"
+    )]
+    pub initial_stage_speed: f64,
+
+    #[arg(
+        long,
+        help = "Write a '.geojson' file containing the track, and the stages if --detect-stages is specified"
+    )]
+    pub geojson: bool,
+
+    #[arg(
+        long,
+        help = "Write a '.kml' file containing the track and any waypoints, for opening in Google Earth"
+    )]
+    pub kml: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = AltitudeMode::ClampToGround,
+        value_name = "MODE",
+        help = "How Google Earth should use the elevations in the KML file",
+        requires = "kml"
+    )]
+    pub kml_altitude_mode: AltitudeMode,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        allow_negative_numbers = true,
+        help = "Shift all the times in the input by this many seconds (which can be negative), to correct for a device with the wrong clock"
+    )]
+    pub time_shift: Option<i64>,
+
+    #[arg(
+        long,
+        help = "Repair files whose points are out of time order, by sorting the points of each segment by time and removing points with the same time as the previous one. The tracks and segments are not changed"
+    )]
+    pub repair: bool,
+
+    #[arg(
+        long,
+        help = "Reverse the direction of the track, e.g. to make the return leg of an out-and-back route. The times are rebuilt so that the reversed track starts at the original start time"
+    )]
+    pub reverse: bool,
+
+    #[arg(
+        long,
+        value_name = "MINLAT,MINLON,MAXLAT,MAXLON",
+        help = "Remove the points outside the box, e.g. to share only part of a ride. Segments are split where the track leaves the box; use --keep-segments to keep those gaps in the simplified file",
+        value_parser = parse_bounds
+    )]
+    pub crop: Option<Bounds>,
+
+    #[arg(
+        long,
+        value_name = "TIME",
+        help = "Remove the points before TIME, an RFC 3339 time such as '2024-06-01T09:30:00Z', e.g. to extract part of a long recording. Applied after --time-shift",
+        value_parser = parse_rfc3339
+    )]
+    pub since: Option<OffsetDateTime>,
+
+    #[arg(
+        long,
+        value_name = "TIME",
+        help = "Remove the points after TIME, an RFC 3339 time such as '2024-06-01T11:00:00+01:00'. Applied after --time-shift",
+        value_parser = parse_rfc3339
+    )]
+    pub until: Option<OffsetDateTime>,
+
+    #[arg(
+        long,
+        value_name = "METRES",
+        help = "Remove the points within METRES of the start and end of the track, so that a ride can be shared without revealing where you live. Points near the start or end in the middle of the ride are removed too, splitting the segment. Applied after --crop, --since and --until",
+        value_parser = parse_positive_f64
+    )]
+    pub privacy_radius: Option<f64>,
+
+    #[arg(
+        long,
+        help = "Remove any stopped time at the start and end of the track, such as when you forgot to stop recording"
+    )]
+    pub trim_ends: bool,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Resample the track so that there is a point every SECONDS, for tools which expect a fixed recording rate",
+        value_parser = parse_positive_f64
+    )]
+    pub resample: Option<f64>,
+
+    #[arg(
+        long,
+        default_value = "60",
+        value_name = "SECONDS",
+        help = "When resampling, gaps between points longer than SECONDS (e.g. when stopped) are not filled in",
+        requires = "resample"
+    )]
+    pub resample_max_gap: f64,
+
+    #[arg(
+        long,
+        help = "Print the overall distance, duration, ascent, descent, speeds and heart rate of the track"
+    )]
+    pub summary: bool,
+
+    #[arg(
+        long,
+        value_name = "LAT,LON",
+        allow_hyphen_values = true,
+        help = "Print the trackpoint nearest to this location, e.g. to match up a photo with the track. Can be specified more than once",
+        value_parser = parse_lat_lon
+    )]
+    pub nearest_point: Vec<(f64, f64)>,
+
+    #[arg(
+        long,
+        value_name = "KM",
+        help = "Print the location and elevation at KM kilometres along the track, e.g. to compare two rides of the same route. Can be specified more than once"
+    )]
+    pub at_km: Vec<f64>,
+
+    #[arg(
+        long,
+        help = "Write a '.analysis.json' file containing the same stage analysis as the summary workbook",
+        requires = "detect_stages"
+    )]
+    pub json: bool,
+
+    #[arg(
+        long,
+        help = "Add a waypoint at each stop (Control stage) to the simplified GPX file",
+        requires = "detect_stages"
+    )]
+    pub emit_stop_waypoints: bool,
+
+    #[arg(
+        long,
+        help = "A file of 'name,lat,lon' lines used to fill in the location descriptions in the summary sheet",
+        requires = "detect_stages"
+    )]
+    pub geocode: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Replace the recorded elevations with heights from a DEM, an ESRI ASCII grid (.asc) in WGS84 lat-lon. Points outside the grid keep their recorded elevation"
+    )]
+    pub dem: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Show progress bars while enriching and simplifying, which can take a while for tracks with millions of points"
+    )]
+    pub progress: bool,
+
+    #[arg(
+        long,
+        value_name = "KG",
+        help = "Your weight in kg. If this and --athlete-max-hr are given, the calories used are estimated from your heart rate",
+        requires = "athlete_max_hr"
+    )]
+    pub athlete_weight: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "BPM",
+        help = "Your maximum heart rate",
+        requires = "athlete_weight",
+        value_parser = value_parser!(u16).range(100..=250)
+    )]
+    pub athlete_max_hr: Option<u16>,
+
+    #[arg(
+        long,
+        value_name = "BPM",
+        default_value = "60",
+        help = "Your resting heart rate. Time spent at or below this does not count towards the calories",
+        value_parser = value_parser!(u16).range(30..=120)
+    )]
+    pub athlete_resting_hr: u16,
+
+    #[arg(long, value_enum, default_value_t = Sex::Male, help = "Your sex, for estimating calories")]
+    pub athlete_sex: Sex,
+
+    #[arg(
+        long,
+        value_name = "WATTS",
+        help = "Your Functional Threshold Power. If given, the Intensity Factor and Training Stress Score are written to the summary sheet for tracks with power data",
+        value_parser = parse_positive_f64
+    )]
+    pub athlete_ftp: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "Z1,Z2,Z3,Z4",
+        help = "The maximum heart rate of zones 1 to 4, used to write an 'HR Zones' tab. Defaults to 60/70/80/90% of --athlete-max-hr if that is given",
+        value_parser = parse_hr_zones,
+        requires = "detect_stages"
+    )]
+    pub hr_zones: Option<HrZones>,
+
+    #[arg(
+        long,
+        help = "Whether to include a map hyperlink when writing TrackPoints to the summary sheet. WARNING: This can slow down the opening of the .xlsx in LibreOffice a lot",
+        requires = "detect_stages"
     )]
     pub write_trackpoint_hyperlinks: bool,
-}
 
+    #[arg(
+        long,
+        value_name = "METRES",
+        default_value_t = 0.0,
+        help = "On the Track Points sheet, only link highlighted trackpoints (such as the max speed) to the map if they are at least METRES along the track from a more significant highlighted point",
+        conflicts_with = "write_trackpoint_hyperlinks"
+    )]
+    pub min_highlight_metres: f64,
+
+    #[arg(
+        long,
+        default_value = "google",
+        value_name = "PROVIDER",
+        help = "The map that hyperlinks in the summary workbook open: 'google', 'osm', 'bing', or a URL template containing {lat} and {lon}",
+        value_parser = parse_map_provider
+    )]
+    pub map_provider: MapLinkProvider,
+
+    #[arg(
+        long,
+        default_value = "rfc3339",
+        value_name = "FORMAT",
+        help = "How times are written in the JSON and GeoJSON files and the text output: 'rfc3339', 'rfc3339-millis', 'epoch' (seconds since 1970), or a format description such as '[day]/[month]/[year] [hour]:[minute]:[second]'. GPX files always use RFC 3339",
+        value_parser = parse_time_format
+    )]
+    pub time_format: TimeFormat,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Units::Metric,
+        help = "The units to use for distances, elevations, speeds and temperatures in the summary sheet",
+        requires = "detect_stages"
+    )]
+    pub units: Units,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = PaceMode::SpeedKmh,
+        value_name = "MODE",
+        help = "Show the average and max speeds on the Stages and Splits sheets as a speed, or as a pace (minutes per km or mile) which runners generally prefer",
+        requires = "detect_stages"
+    )]
+    pub pace: PaceMode,
+
+    #[arg(
+        long,
+        help = "Check that the input files conform to the GPX 1.1 schema and print any problems, without writing anything. The exit code is 1 if any file has problems"
+    )]
+    pub validate: bool,
+
+    #[arg(
+        long,
+        help = "Check the input files for problems such as times that go backwards, duplicate points and GPS glitches, and print them, without writing anything"
+    )]
+    pub diagnose: bool,
+
+    #[arg(
+        long,
+        help = "Report the files that would be written, without writing anything. The input files are only read if they are being joined, to find the name of the joined file"
+    )]
+    pub dry_run: bool,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Write the output files to DIR, which is created if necessary, rather than next to the input files"
+    )]
+    pub output_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["join", "validate", "diagnose", "dry_run", "summary", "nearest_point", "at_km"],
+        help = "Read a single GPX file from stdin, rather than the files in the program's directory, and write the simplified file to stdout, e.g. for use in a pipeline. Any other outputs are named 'stdin'"
+    )]
+    pub stdin: bool,
+
+    #[arg(
+        short,
+        long,
+        help = "Only log errors. Overrides the RUST_LOG environment variable",
+        conflicts_with = "verbose"
+    )]
+    pub quiet: bool,
+
+    #[arg(
+        short,
+        long,
+        action = ArgAction::Count,
+        help = "Log more detail: -v for info, -vv for debug and -vvv for trace. Overrides the RUST_LOG environment variable"
+    )]
+    pub verbose: u8,
+}
 
 pub fn parse_args() -> Args {
     Args::parse()
 }
 
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, ValueEnum)]
+/// Used when estimating calories.
+pub enum Sex {
+    #[default]
+    Male,
+    Female,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, ValueEnum)]
+/// How the elevations in a KML file are interpreted.
+pub enum AltitudeMode {
+    /// Ignore the elevations and draw the track on the ground.
+    #[default]
+    ClampToGround,
+    /// Draw the track at the elevations, relative to sea level.
+    Absolute,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, ValueEnum)]
+/// The units used when writing the summary sheet. The model
+/// is always metric, this only affects what is displayed.
+pub enum Units {
+    /// Kilometres, metres and °C.
+    #[default]
+    Metric,
+    /// Miles, feet and °F.
+    Imperial,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, ValueEnum)]
+/// How speeds are displayed in the summary sheet.
+pub enum PaceMode {
+    /// A speed, in km/h or mph depending on the units.
+    #[default]
+    SpeedKmh,
+    /// A pace, in minutes per kilometre, e.g. 5:00 for 12 km/h.
+    PaceMinPerKm,
+    /// A pace, in minutes per mile.
+    PaceMinPerMile,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+/// The map that hyperlinks in the summary workbook point to.
+pub enum MapLinkProvider {
+    #[default]
+    GoogleMaps,
+    OpenStreetMap,
+    Bing,
+    /// A URL template, with {lat} and {lon} placeholders.
+    Custom(String),
+}
+
+fn parse_hr_zones(s: &str) -> Result<HrZones, String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<u16>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    match values[..] {
+        [z1_max, z2_max, z3_max, z4_max]
+            if z1_max < z2_max && z2_max < z3_max && z3_max < z4_max =>
+        {
+            Ok(HrZones {
+                z1_max,
+                z2_max,
+                z3_max,
+                z4_max,
+            })
+        }
+        _ => Err("expected 4 increasing heart rates, e.g. '120,140,155,170'".to_string()),
+    }
+}
+
+fn parse_lat_lon(s: &str) -> Result<(f64, f64), String> {
+    let (lat, lon) = s
+        .split_once(',')
+        .ok_or_else(|| "expected a location of the form 'lat,lon'".to_string())?;
+    let lat: f64 = lat
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid latitude '{lat}'"))?;
+    let lon: f64 = lon
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid longitude '{lon}'"))?;
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return Err(format!("'{s}' is not a valid location"));
+    }
+    Ok((lat, lon))
+}
+
+fn parse_bounds(s: &str) -> Result<Bounds, String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    match values[..] {
+        [min_lat, min_lon, max_lat, max_lon]
+            if (-90.0..=90.0).contains(&min_lat)
+                && (-90.0..=90.0).contains(&max_lat)
+                && (-180.0..=180.0).contains(&min_lon)
+                && (-180.0..=180.0).contains(&max_lon)
+                && min_lat < max_lat
+                && min_lon < max_lon =>
+        {
+            Ok(Bounds {
+                min_lat,
+                min_lon,
+                max_lat,
+                max_lon,
+            })
+        }
+        _ => Err("expected 'minlat,minlon,maxlat,maxlon', e.g. '53.0,-2.1,53.2,-1.9'".to_string()),
+    }
+}
+
+fn parse_map_provider(s: &str) -> Result<MapLinkProvider, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "google" => Ok(MapLinkProvider::GoogleMaps),
+        "osm" | "openstreetmap" => Ok(MapLinkProvider::OpenStreetMap),
+        "bing" => Ok(MapLinkProvider::Bing),
+        _ if s.contains("{lat}") && s.contains("{lon}") => {
+            Ok(MapLinkProvider::Custom(s.to_string()))
+        }
+        _ => {
+            Err("expected 'google', 'osm', 'bing' or a URL containing {lat} and {lon}".to_string())
+        }
+    }
+}
+
+fn parse_time_format(s: &str) -> Result<TimeFormat, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "rfc3339" => Ok(TimeFormat::Rfc3339),
+        "rfc3339-millis" => Ok(TimeFormat::Rfc3339Millis),
+        "epoch" => Ok(TimeFormat::Epoch),
+        // Without a component the description would just be literal text,
+        // which is more likely to be a misspelling of one of the above.
+        _ if s.contains('[') => TimeFormat::custom(s),
+        _ => Err("expected 'rfc3339', 'rfc3339-millis', 'epoch' or a format description such as '[year]-[month]-[day] [hour]:[minute]'".to_string()),
+    }
+}
+
+fn parse_rfc3339(s: &str) -> Result<OffsetDateTime, String> {
+    OffsetDateTime::parse(s, &Rfc3339).map_err(|err| err.to_string())
+}
+
+fn parse_positive_f64(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v > 0.0 => Ok(v),
+        Ok(_) => Err("must be greater than 0".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-/// Whether to include Google maps hyperlinks
+/// Whether to include map hyperlinks
 /// when writing the trackpoints.
 pub enum Hyperlink {
     Yes,
@@ -73,6 +798,17 @@ pub enum Hyperlink {
 }
 
 impl Args {
+    /// Returns true if a simplified file is to be written.
+    pub fn is_simplifying(&self) -> bool {
+        self.metres.is_some() || self.epsilon.is_some() || self.max_points.is_some()
+    }
+
+    /// Returns the epsilon to use for Ramer-Douglas-Peucker, either as
+    /// given by --epsilon or converted from --metres.
+    pub fn rdp_epsilon(&self) -> Option<f64> {
+        self.epsilon.or(self.metres.map(metres_to_epsilon))
+    }
+
     /// We always write the list of trackpoints, but adding
     /// hyperlinks is optional.
     pub fn trackpoint_hyperlinks(&self) -> Hyperlink {
@@ -82,4 +818,152 @@ impl Args {
             Hyperlink::No
         }
     }
+
+    /// Returns the heart rate zones, either as specified or
+    /// derived from the athlete's max heart rate.
+    pub fn hr_zones(&self) -> Option<HrZones> {
+        self.hr_zones
+            .or_else(|| self.athlete_max_hr.map(HrZones::from_max_hr))
+    }
+
+    /// Returns the athlete's profile, if enough has been specified
+    /// to estimate calories.
+    pub fn athlete_profile(&self) -> Option<AthleteProfile> {
+        Some(AthleteProfile {
+            weight_kg: self.athlete_weight?,
+            max_hr: self.athlete_max_hr?,
+            resting_hr: self.athlete_resting_hr,
+            sex: self.athlete_sex,
+        })
+    }
+
+    /// Returns the log level specified by --quiet or --verbose, or
+    /// None if neither was given, in which case RUST_LOG is used.
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        if self.quiet {
+            return Some(LevelFilter::Error);
+        }
+
+        match self.verbose {
+            0 => None,
+            1 => Some(LevelFilter::Info),
+            2 => Some(LevelFilter::Debug),
+            _ => Some(LevelFilter::Trace),
+        }
+    }
+
+    /// Returns the args to use when analysing each input file individually
+    /// before joining them. These are the same as 'self' except that only
+    /// the summary workbook is written; the other outputs are only wanted
+    /// for the joined track.
+    pub fn individual_analysis_args(&self) -> Args {
+        Args {
+            metres: None,
+            epsilon: None,
+            max_points: None,
+            keep_segments: false,
+            geojson: false,
+            kml: false,
+            json: false,
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn the_arguments_are_consistent() {
+        // Clap only checks this in debug builds, when parsing the arguments.
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn a_custom_map_url_needs_both_placeholders() {
+        assert_eq!(
+            parse_map_provider("OSM"),
+            Ok(MapLinkProvider::OpenStreetMap)
+        );
+        assert!(parse_map_provider("https://maps.example.com/{lat}").is_err());
+    }
+
+    #[test]
+    fn quiet_and_verbose_map_to_log_levels() {
+        let log_level = |args: &[&str]| {
+            Args::try_parse_from(["gapix"].iter().chain(args))
+                .unwrap()
+                .log_level()
+        };
+
+        assert_eq!(log_level(&[]), None);
+        assert_eq!(log_level(&["-q"]), Some(LevelFilter::Error));
+        assert_eq!(log_level(&["-v"]), Some(LevelFilter::Info));
+        assert_eq!(log_level(&["-vv"]), Some(LevelFilter::Debug));
+        assert_eq!(log_level(&["-vvv"]), Some(LevelFilter::Trace));
+    }
+
+    #[test]
+    fn epsilon_and_metres_are_mutually_exclusive() {
+        let epsilon = metres_to_epsilon(10);
+        let by_metres = Args::try_parse_from(["gapix", "-m", "10"]).unwrap();
+        let by_epsilon =
+            Args::try_parse_from(["gapix", "--epsilon", &epsilon.to_string()]).unwrap();
+
+        assert_eq!(by_metres.rdp_epsilon(), Some(epsilon));
+        assert_eq!(by_epsilon.rdp_epsilon(), Some(epsilon));
+        assert!(Args::try_parse_from(["gapix", "-m", "10", "--epsilon", "0.0001"]).is_err());
+    }
+
+    #[test]
+    fn a_known_time_is_written_in_each_format() {
+        // 2024-09-01T05:10:44Z.
+        let date = OffsetDateTime::from_unix_timestamp(1_725_167_444).unwrap();
+        let format = |s: &str| parse_time_format(s).unwrap().format(&date);
+
+        assert_eq!(format("rfc3339"), "2024-09-01T05:10:44Z");
+        assert_eq!(format("rfc3339-millis"), "2024-09-01T05:10:44.000Z");
+        assert_eq!(format("epoch"), "1725167444");
+        assert_eq!(
+            format("[day]/[month]/[year] [hour]:[minute]:[second]"),
+            "01/09/2024 05:10:44"
+        );
+
+        // Local times keep their offset.
+        let local = date.to_offset(time::UtcOffset::from_hms(1, 0, 0).unwrap());
+        assert_eq!(
+            TimeFormat::Rfc3339Millis.format(&local),
+            "2024-09-01T06:10:44.000+01:00"
+        );
+        assert_eq!(TimeFormat::Epoch.format(&local), "1725167444");
+    }
+
+    #[test]
+    fn time_format_parses_the_keywords_and_checks_descriptions() {
+        let parse = |s: &str| Args::try_parse_from(["gapix", "--time-format", s]);
+
+        assert_eq!(parse("epoch").unwrap().time_format, TimeFormat::Epoch);
+        assert_eq!(
+            parse("RFC3339-Millis").unwrap().time_format,
+            TimeFormat::Rfc3339Millis
+        );
+        assert_eq!(
+            Args::try_parse_from(["gapix"]).unwrap().time_format,
+            TimeFormat::Rfc3339
+        );
+        assert!(parse("[year]-[mnth]").is_err());
+        assert!(parse("epoc").is_err());
+    }
+
+    #[test]
+    fn split_km_must_be_positive() {
+        let parse = |km: &str| Args::try_parse_from(["gapix", "-d", "--split-km", km]);
+
+        assert_eq!(parse("2.5").unwrap().split_km, Some(2.5));
+        assert!(parse("0").is_err());
+        assert!(parse("-5").is_err());
+    }
 }
@@ -0,0 +1,269 @@
+//! Contains the functionality relating to Climbs. A climb is a sustained
+//! section of ascent, categorized in the same way that cycling races
+//! (and Strava) categorize hills: 4 (easiest) to 1, then HC.
+
+use core::fmt;
+
+use log::info;
+use logging_timer::time;
+use time::Duration;
+
+use crate::model::{EnrichedGpx, EnrichedTrackPoint};
+
+/// The parameters used to control climb detection.
+#[derive(Debug)]
+pub struct ClimbParameters {
+    /// Climbs shorter than this are ignored.
+    pub min_length_metres: f64,
+
+    /// Climbs with an average gradient less than this are ignored.
+    pub min_avg_grade_percent: f64,
+
+    /// A climb is allowed to dip by this much (a short descent) without
+    /// it being considered the end of the climb.
+    pub max_dip_metres: f64,
+
+    /// A climb is allowed to flatten out for this distance without
+    /// it being considered the end of the climb.
+    pub max_plateau_metres: f64,
+}
+
+impl Default for ClimbParameters {
+    fn default() -> Self {
+        Self {
+            min_length_metres: 500.0,
+            min_avg_grade_percent: 3.0,
+            max_dip_metres: 10.0,
+            max_plateau_metres: 300.0,
+        }
+    }
+}
+
+/// The category of a climb, from the score (elevation gain in metres
+/// multiplied by the average gradient as a percentage).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClimbCategory {
+    Cat4,
+    Cat3,
+    Cat2,
+    Cat1,
+    HorsCategorie,
+}
+
+impl fmt::Display for ClimbCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClimbCategory::Cat4 => write!(f, "4"),
+            ClimbCategory::Cat3 => write!(f, "3"),
+            ClimbCategory::Cat2 => write!(f, "2"),
+            ClimbCategory::Cat1 => write!(f, "1"),
+            ClimbCategory::HorsCategorie => write!(f, "HC"),
+        }
+    }
+}
+
+impl ClimbCategory {
+    /// Returns the category for a score, or None if the
+    /// score is too low for the climb to be categorized.
+    /// For example, 120m at 5% (score 600) is a Cat 4 and
+    /// 1100m at 8% (score 8800) is HC.
+    fn from_score(score: f64) -> Option<Self> {
+        if score >= 8000.0 {
+            Some(ClimbCategory::HorsCategorie)
+        } else if score >= 5000.0 {
+            Some(ClimbCategory::Cat1)
+        } else if score >= 2500.0 {
+            Some(ClimbCategory::Cat2)
+        } else if score >= 1000.0 {
+            Some(ClimbCategory::Cat3)
+        } else if score >= 400.0 {
+            Some(ClimbCategory::Cat4)
+        } else {
+            None
+        }
+    }
+}
+
+/// A categorized climb. The climb starts at the low point
+/// and ends at the high point.
+#[derive(Debug)]
+pub struct Climb {
+    pub start: EnrichedTrackPoint,
+    pub end: EnrichedTrackPoint,
+    /// The total ascent, which can be more than the elevation gain
+    /// if the climb has dips in it.
+    pub ascent_metres: f64,
+    pub category: ClimbCategory,
+}
+
+impl Climb {
+    /// Returns the length of the climb, in metres.
+    pub fn length_metres(&self) -> f64 {
+        self.end.running_metres - self.start.running_metres
+    }
+
+    /// Returns the difference in elevation between the bottom
+    /// and the top of the climb.
+    pub fn elevation_gain_metres(&self) -> f64 {
+        self.end.ele.unwrap_or_default() - self.start.ele.unwrap_or_default()
+    }
+
+    /// Returns the average gradient of the climb, as a percentage.
+    pub fn avg_grade(&self) -> f64 {
+        self.elevation_gain_metres() / self.length_metres() * 100.0
+    }
+
+    /// Returns how long it took to get up the climb.
+    pub fn duration(&self) -> Option<Duration> {
+        match (self.end.time, self.start.time) {
+            (Some(t1), Some(t2)) => Some(t1 - t2),
+            _ => None,
+        }
+    }
+}
+
+/// Detects the categorized climbs in the track. A climb starts at a low point
+/// and continues until the track either dips more than 'max_dip_metres' below
+/// the highest point reached, or goes more than 'max_plateau_metres' without
+/// reaching a new high point. The climb then ends at the highest point. Short
+/// or shallow climbs, and those which are too small to be categorized, are
+/// discarded.
+#[time]
+pub fn detect_climbs(gpx: &EnrichedGpx, params: ClimbParameters) -> Vec<Climb> {
+    let mut climbs = Vec::new();
+
+    if gpx.points.len() < 2 || gpx.points.iter().any(|p| p.ele.is_none()) {
+        return climbs;
+    }
+
+    let ele = |idx: usize| gpx.points[idx].ele.unwrap();
+    let mut start_idx = 0;
+    let mut peak_idx = 0;
+
+    for idx in 1..gpx.points.len() {
+        if peak_idx == start_idx && ele(idx) <= ele(start_idx) {
+            // Still going down (or along), so move the start of
+            // any climb forward to the lowest point.
+            start_idx = idx;
+            peak_idx = idx;
+        } else if ele(idx) > ele(peak_idx) {
+            peak_idx = idx;
+        } else if ele(peak_idx) - ele(idx) > params.max_dip_metres
+            || gpx.points[idx].running_metres - gpx.points[peak_idx].running_metres
+                > params.max_plateau_metres
+        {
+            climbs.extend(make_climb(gpx, start_idx, peak_idx, &params));
+            start_idx = idx;
+            peak_idx = idx;
+        }
+    }
+
+    climbs.extend(make_climb(gpx, start_idx, peak_idx, &params));
+
+    info!("Found {} climbs in {:?}", climbs.len(), gpx.filename);
+    climbs
+}
+
+/// Makes a climb between two points, or returns None if it is
+/// not significant enough to be a categorized climb.
+fn make_climb(
+    gpx: &EnrichedGpx,
+    start_idx: usize,
+    end_idx: usize,
+    params: &ClimbParameters,
+) -> Option<Climb> {
+    let start = &gpx.points[start_idx];
+    let end = &gpx.points[end_idx];
+
+    let length_metres = end.running_metres - start.running_metres;
+    if length_metres < params.min_length_metres {
+        return None;
+    }
+
+    let gain_metres = end.ele? - start.ele?;
+    let avg_grade = gain_metres / length_metres * 100.0;
+    if avg_grade < params.min_avg_grade_percent {
+        return None;
+    }
+
+    let category = ClimbCategory::from_score(gain_metres * avg_grade)?;
+
+    let ascent_metres = gpx.points[start_idx + 1..=end_idx]
+        .iter()
+        .filter_map(|p| p.ele_delta_metres)
+        .filter(|&d| d > 0.0)
+        .sum();
+
+    Some(Climb {
+        start: start.clone(),
+        end: end.clone(),
+        ascent_metres,
+        category,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::TrackPoint,
+        test_helpers::{enriched, xy_point},
+    };
+
+    /// Makes an enriched track from a list of (length in metres, grade in
+    /// percent) legs, with a point every 50 metres.
+    fn profile(legs: &[(f64, f64)]) -> EnrichedGpx {
+        const STEP: f64 = 50.0;
+        let mut points = vec![TrackPoint {
+            ele: Some(100.0),
+            ..xy_point(0.0, 0.0)
+        }];
+        let (mut x, mut ele) = (0.0, 100.0);
+
+        for &(length, grade) in legs {
+            for _ in 0..(length / STEP) as usize {
+                x += STEP;
+                ele += STEP * grade / 100.0;
+                points.push(TrackPoint {
+                    ele: Some(ele),
+                    ..xy_point(x, 0.0)
+                });
+            }
+        }
+
+        enriched(points)
+    }
+
+    #[test]
+    fn finds_a_long_climb_and_rejects_a_bump() {
+        let gpx = profile(&[
+            (1000.0, 0.0),
+            // 240m of climbing, interrupted by a short plateau.
+            (2000.0, 6.0),
+            (200.0, 0.0),
+            (2000.0, 6.0),
+            (4000.0, -6.0),
+            // A bump which is too short and too small to be a climb.
+            (300.0, 5.0),
+            (300.0, -5.0),
+            (1000.0, 0.0),
+        ]);
+
+        let climbs = detect_climbs(&gpx, ClimbParameters::default());
+        assert_eq!(climbs.len(), 1);
+
+        let climb = &climbs[0];
+        assert_eq!(climb.category, ClimbCategory::Cat3);
+        assert!((climb.elevation_gain_metres() - 240.0).abs() < 0.1);
+        assert!((climb.ascent_metres - 240.0).abs() < 0.1);
+        assert!((climb.length_metres() - 4200.0).abs() < 20.0);
+        assert!((climb.start.running_metres - 1000.0).abs() < 5.0);
+    }
+
+    #[test]
+    fn a_long_plateau_splits_a_climb() {
+        let gpx = profile(&[(2000.0, 6.0), (1000.0, 0.0), (2000.0, 6.0)]);
+        let climbs = detect_climbs(&gpx, ClimbParameters::default());
+        assert_eq!(climbs.len(), 2);
+    }
+}
@@ -0,0 +1,221 @@
+//! Contains the functionality relating to correcting elevations from a
+//! Digital Elevation Model (DEM). GPS elevations are often out by tens of
+//! metres, and barometric ones drift with the weather, whereas a DEM gives
+//! the height of the ground itself.
+//!
+//! The DEM is read from an ESRI ASCII grid (.asc) file in WGS84 lat-lon,
+//! which most GIS tools can export, e.g. from the SRTM or Copernicus data.
+
+use std::{error::Error, fs::read_to_string, path::Path};
+
+use log::info;
+use logging_timer::time;
+
+use crate::model::Gpx;
+
+/// A grid of heights, one per cell, loaded from a DEM file.
+#[derive(Debug)]
+pub struct DemProvider {
+    ncols: usize,
+    nrows: usize,
+    /// The lon of the centre of the bottom-left cell.
+    x_centre: f64,
+    /// The lat of the centre of the bottom-left cell.
+    y_centre: f64,
+    /// The size of each cell, in degrees.
+    cellsize: f64,
+    /// Cells with this value have no height.
+    nodata: Option<f64>,
+    /// The heights in metres, in rows from north to south, as in the file.
+    heights: Vec<f64>,
+}
+
+impl DemProvider {
+    /// Reads an ESRI ASCII grid. The header must contain 'ncols', 'nrows',
+    /// 'cellsize' and either 'xllcorner' and 'yllcorner' or 'xllcenter' and
+    /// 'yllcenter'. 'nodata_value' is optional.
+    #[time]
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        info!("Reading DEM {:?}", path);
+
+        let contents = read_to_string(path)?;
+        let mut tokens = contents.split_whitespace().peekable();
+
+        let mut ncols: Option<usize> = None;
+        let mut nrows: Option<usize> = None;
+        let mut xll: Option<f64> = None;
+        let mut yll: Option<f64> = None;
+        let mut is_corner = true;
+        let mut cellsize: Option<f64> = None;
+        let mut nodata: Option<f64> = None;
+
+        // The header is a set of 'key value' pairs, then the heights start.
+        while let Some(key) = tokens.next_if(|t| t.starts_with(|c: char| c.is_ascii_alphabetic())) {
+            let value = tokens
+                .next()
+                .ok_or_else(|| format!("The DEM header in {:?} has no value for {key}", path))?;
+
+            match key.to_ascii_lowercase().as_str() {
+                "ncols" => ncols = Some(value.parse()?),
+                "nrows" => nrows = Some(value.parse()?),
+                "xllcorner" => xll = Some(value.parse()?),
+                "yllcorner" => yll = Some(value.parse()?),
+                "xllcenter" => {
+                    xll = Some(value.parse()?);
+                    is_corner = false;
+                }
+                "yllcenter" => yll = Some(value.parse()?),
+                "cellsize" => cellsize = Some(value.parse()?),
+                "nodata_value" => nodata = Some(value.parse()?),
+                _ => Err(format!(
+                    "Unknown key '{key}' in the DEM header in {:?}",
+                    path
+                ))?,
+            }
+        }
+
+        let (Some(ncols), Some(nrows), Some(xll), Some(yll), Some(cellsize)) =
+            (ncols, nrows, xll, yll, cellsize)
+        else {
+            return Err(format!("The DEM header in {:?} is incomplete", path))?;
+        };
+        if ncols == 0 || nrows == 0 || cellsize <= 0.0 {
+            return Err(format!("The DEM in {:?} has no cells", path))?;
+        }
+
+        let heights = tokens.map(str::parse).collect::<Result<Vec<f64>, _>>()?;
+        if heights.len() != ncols * nrows {
+            return Err(format!(
+                "The DEM in {:?} has {} heights, expected {ncols} x {nrows}",
+                path,
+                heights.len()
+            ))?;
+        }
+
+        let offset = if is_corner { cellsize / 2.0 } else { 0.0 };
+        info!("Read a {ncols} x {nrows} DEM from {:?}", path);
+
+        Ok(Self {
+            ncols,
+            nrows,
+            x_centre: xll + offset,
+            y_centre: yll + offset,
+            cellsize,
+            nodata,
+            heights,
+        })
+    }
+
+    /// Returns the height at the lat-lon by bilinear interpolation between
+    /// the centres of the four surrounding cells. Returns None if the point
+    /// is outside the grid or any of the four cells has no height. Within
+    /// half a cell of the edge of the grid the edge cells are used.
+    pub fn elevation_at(&self, lat: f64, lon: f64) -> Option<f64> {
+        let max_col = (self.ncols - 1) as f64;
+        let max_row = (self.nrows - 1) as f64;
+
+        // Positions in cells, measured from the bottom-left cell centre.
+        let fx = (lon - self.x_centre) / self.cellsize;
+        let fy = (lat - self.y_centre) / self.cellsize;
+        if !(-0.5..=max_col + 0.5).contains(&fx) || !(-0.5..=max_row + 0.5).contains(&fy) {
+            return None;
+        }
+        let fx = fx.clamp(0.0, max_col);
+        let fy = fy.clamp(0.0, max_row);
+
+        let col0 = fx.floor() as usize;
+        let row0 = fy.floor() as usize;
+        let col1 = (col0 + 1).min(self.ncols - 1);
+        let row1 = (row0 + 1).min(self.nrows - 1);
+        let tx = fx - col0 as f64;
+        let ty = fy - row0 as f64;
+
+        let h00 = self.height(col0, row0)?;
+        let h10 = self.height(col1, row0)?;
+        let h01 = self.height(col0, row1)?;
+        let h11 = self.height(col1, row1)?;
+
+        let bottom = h00 + (h10 - h00) * tx;
+        let top = h01 + (h11 - h01) * tx;
+        Some(bottom + (top - bottom) * ty)
+    }
+
+    /// Returns the height of a cell, where 'row' is counted from the
+    /// bottom (south) of the grid, or None if the cell has no height.
+    fn height(&self, col: usize, row: usize) -> Option<f64> {
+        let h = self.heights[(self.nrows - 1 - row) * self.ncols + col];
+        (Some(h) != self.nodata).then_some(h)
+    }
+}
+
+/// Replaces the elevation of every point with the height from the DEM.
+/// Points outside the DEM, or where it has no height, keep their recorded
+/// elevation. This should be done before the points are enriched, so that
+/// the ascent and descent are calculated from the corrected heights.
+/// Returns the number of points that were corrected.
+#[time]
+pub fn correct_elevation_from_dem(gpx: &mut Gpx, dem: &DemProvider) -> usize {
+    let mut num_corrected = 0;
+
+    for p in gpx
+        .tracks
+        .iter_mut()
+        .flat_map(|t| &mut t.segments)
+        .flat_map(|s| &mut s.points)
+    {
+        if let Some(ele) = dem.elevation_at(p.lat, p.lon) {
+            p.ele = Some(ele);
+            num_corrected += 1;
+        }
+    }
+
+    num_corrected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{make_gpx, temp_file, track_point};
+
+    #[test]
+    fn elevations_are_snapped_to_a_flat_dem() {
+        let path = temp_file(
+            "flat.asc",
+            "ncols 3\nnrows 3\nxllcorner -1.0\nyllcorner 51.0\ncellsize 1.0\n\
+             75 75 75\n75 75 75\n75 75 75\n",
+        );
+        let dem = DemProvider::from_file(&path).unwrap();
+        let mut gpx = make_gpx(vec![
+            track_point(51.5, -0.5, Some(120.0)),
+            track_point(52.2, 0.4, None),
+            // Outside the DEM.
+            track_point(55.0, 0.0, Some(300.0)),
+        ]);
+
+        assert_eq!(correct_elevation_from_dem(&mut gpx, &dem), 2);
+
+        let elevations: Vec<_> = gpx.tracks[0].segments[0]
+            .points
+            .iter()
+            .map(|p| p.ele)
+            .collect();
+        assert_eq!(elevations, [Some(75.0), Some(75.0), Some(300.0)]);
+    }
+
+    #[test]
+    fn heights_are_interpolated_between_the_cell_centres() {
+        // The cell centres are at lon 0 and 1, lat 51 and 52.
+        let path = temp_file(
+            "slope.asc",
+            "ncols 2\nnrows 2\nxllcenter 0.0\nyllcenter 51.0\ncellsize 1.0\n\
+             NODATA_value -9999\n200 300\n0 100\n",
+        );
+        let dem = DemProvider::from_file(&path).unwrap();
+
+        assert_eq!(dem.elevation_at(51.0, 0.0), Some(0.0));
+        assert_eq!(dem.elevation_at(52.0, 1.0), Some(300.0));
+        assert_eq!(dem.elevation_at(51.5, 0.5), Some(150.0));
+        assert_eq!(dem.elevation_at(51.25, 0.0), Some(50.0));
+        assert_eq!(dem.elevation_at(53.0, 0.0), None);
+    }
+}
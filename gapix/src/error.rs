@@ -0,0 +1,66 @@
+//! Contains the error type returned when reading input files, writing
+//! output files and enriching the trackpoints. Having specific variants
+//! means that callers can tell the difference between, say, a file that is
+//! not XML at all and one that is valid XML but is missing a mandatory
+//! attribute.
+
+use std::{io, path::PathBuf, str::Utf8Error};
+
+use quick_xml::events::attributes::AttrError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum GapixError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// The file is not well-formed XML.
+    #[error("Invalid XML at position {position}: {source}")]
+    Xml {
+        position: u64,
+        source: quick_xml::Error,
+    },
+
+    /// An attribute could not be parsed, e.g. it has no value.
+    #[error("Invalid attribute: {0}")]
+    Attribute(#[from] AttrError),
+
+    /// The file contains text that is not valid UTF-8.
+    #[error("Invalid UTF-8: {0}")]
+    Encoding(#[from] Utf8Error),
+
+    /// An element does not have an attribute which the schema requires.
+    #[error("Mandatory attribute '{attribute}' was missing on the '{element}' element")]
+    MissingAttribute { element: String, attribute: String },
+
+    /// The file does not contain an element which the schema requires.
+    #[error("Did not find the '{0}' element")]
+    MissingElement(&'static str),
+
+    /// We found an element (or other XML node) in a place where the
+    /// schema does not allow it.
+    #[error("Unexpected element {element} at position {position}")]
+    UnexpectedElement { element: String, position: u64 },
+
+    /// The file ended before the closing tag of the element.
+    #[error("Reached EOF unexpectedly (before the closing '{0}' tag). File is probably corrupt")]
+    UnexpectedEof(&'static str),
+
+    /// The text of an element or attribute could not be converted
+    /// to the right type, e.g. a latitude that is not a number.
+    #[error("Invalid value '{value}': {message}")]
+    InvalidValue { value: String, message: String },
+
+    /// The time of a trackpoint is not later than the time it is compared
+    /// to, 'earlier', so the speeds and durations cannot be calculated.
+    #[error("The time of trackpoint {index} is not later than {earlier} in {filename:?}. Use --diagnose to find all the problems in the file, or --repair to sort the points by time")]
+    TimeNotIncreasing {
+        index: usize,
+        earlier: &'static str,
+        filename: PathBuf,
+    },
+
+    /// The file is valid but there is nothing in it that we can use.
+    #[error("Did not find any trackpoints with a position")]
+    NoTrackPoints,
+}
@@ -2,16 +2,19 @@ use std::{collections::HashSet, error::Error, path::Path};
 
 use logging_timer::time;
 use rust_xlsxwriter::{
-    Color, ExcelDateTime, Format, FormatAlign, FormatBorder, FormatPattern, Url, Workbook,
-    Worksheet,
+    Chart, ChartType, Color, ExcelDateTime, Format, FormatAlign, FormatBorder, FormatPattern, Url,
+    Workbook, Worksheet,
 };
 use time::{Duration, OffsetDateTime};
+use time_tz::Tz;
 
 use crate::{
-    args::Hyperlink,
+    args::{Hyperlink, MapLinkProvider, PaceMode, Units},
+    climbs::Climb,
     formatting::to_local_date,
     model::{EnrichedGpx, EnrichedTrackPoint},
-    stage::{StageList, StageType},
+    stage::{speed_kmh_from_duration, AthleteProfile, HrZones, StageList, StageType, TrackStages},
+    timezone::timezone_for_point,
 };
 
 const DATE_COLUMN_WIDTH: f64 = 18.0;
@@ -29,44 +32,192 @@ const SPEED_COLUMN_WIDTH: f64 = 8.0;
 const HEART_RATE_WIDTH_WITH_UNITS: f64 = 17.5;
 const TEMPERATURE_COLUMN_WIDTH_WITH_UNITS: f64 = 12.0;
 const CADENCE_COLUMN_WIDTH_WITH_UNITS: f64 = 15.5;
+const POWER_COLUMN_WIDTH_WITH_UNITS: f64 = 11.0;
+const METRES_PER_FOOT: f64 = 0.3048;
+const KILOMETRES_PER_MILE: f64 = 1.609344;
+const TRACK_POINTS_SHEET_NAME: &str = "Track Points";
+const MAP_SHEET_NAME: &str = "Map";
 
 /// Builds the Workbook that is used for the summary.
 #[time]
+#[allow(clippy::too_many_arguments)]
 pub fn create_summary_xlsx(
     trackpoint_hyperlinks: Hyperlink,
+    units: Units,
+    pace: PaceMode,
+    min_highlight_metres: f64,
+    map_provider: &MapLinkProvider,
+    athlete: Option<&AthleteProfile>,
+    ftp: Option<f64>,
+    hr_zones: Option<&HrZones>,
+    map_chart: bool,
     gpx: &EnrichedGpx,
     stages: &StageList,
+    track_stages: &[TrackStages],
+    climbs: &[Climb],
+    splits: &[(usize, usize)],
+    laps: &[(usize, usize)],
+    fastest: &[(f64, usize, usize, Duration)],
 ) -> Result<Workbook, Box<dyn Error>> {
+    // All the local times are shown in the time zone where the ride started.
+    let timezone = gpx.points.first().and_then(timezone_for_point);
     let mut workbook = Workbook::new();
 
     // This will appear as the first sheet in the workbook.
     let stages_ws = workbook.add_worksheet();
     stages_ws.set_name("Stages")?;
-    write_stages(stages_ws, gpx, stages)?;
+    write_stages(
+        stages_ws,
+        units,
+        pace,
+        map_provider,
+        timezone,
+        athlete,
+        ftp,
+        gpx,
+        stages,
+        0,
+    )?;
+
+    // Followed by a Stages tab for each track when there are several. The
+    // track point numbers on them still refer to the 'Track Points' sheet,
+    // which has the whole file.
+    for track in track_stages {
+        let track_ws = workbook.add_worksheet();
+        track_ws.set_name(track_sheet_name(track))?;
+        write_stages(
+            track_ws,
+            units,
+            pace,
+            map_provider,
+            track.gpx.points.first().and_then(timezone_for_point),
+            athlete,
+            ftp,
+            &track.gpx,
+            &track.stages,
+            track.first_index,
+        )?;
+    }
 
-    // This will appear as the second sheet in the workbook.
+    // Then the track points.
     let tp_ws = workbook.add_worksheet();
-    tp_ws.set_name("Track Points")?;
-    write_trackpoints(
+    tp_ws.set_name(TRACK_POINTS_SHEET_NAME)?;
+    let tp_columns = write_trackpoints(
         tp_ws,
+        units,
+        map_provider,
+        timezone,
         &gpx.points,
         trackpoint_hyperlinks,
-        &stages.highlighted_trackpoints(),
+        &stages.highlighted_trackpoints_spaced(min_highlight_metres),
     )?;
 
+    // Then the elevation profile. There is no point in
+    // a profile if we have no elevation data.
+    if gpx.points.iter().any(|p| p.ele.is_some()) {
+        let profile_ws = workbook.add_worksheet();
+        profile_ws.set_name("Profile")?;
+        write_profile_chart(profile_ws, units, &gpx.points, &tp_columns)?;
+    }
+
+    if map_chart && gpx.points.len() >= 2 {
+        let map_ws = workbook.add_worksheet();
+        map_ws.set_name(MAP_SHEET_NAME)?;
+        write_map_chart(map_ws, units, gpx)?;
+    }
+
+    if !climbs.is_empty() {
+        let climbs_ws = workbook.add_worksheet();
+        climbs_ws.set_name("Climbs")?;
+        write_climbs(climbs_ws, units, map_provider, timezone, climbs)?;
+    }
+
+    if let Some(zones) = hr_zones {
+        if stages.iter().any(|s| !s.heart_rate_seconds.is_empty()) {
+            let zones_ws = workbook.add_worksheet();
+            zones_ws.set_name("HR Zones")?;
+            write_hr_zones(zones_ws, units, map_provider, timezone, stages, zones)?;
+        }
+    }
+
+    if !fastest.is_empty() {
+        let fastest_ws = workbook.add_worksheet();
+        fastest_ws.set_name("Fastest")?;
+        write_fastest(
+            fastest_ws,
+            units,
+            pace,
+            map_provider,
+            timezone,
+            &gpx.points,
+            fastest,
+        )?;
+    }
+
+    if !laps.is_empty() {
+        let laps_ws = workbook.add_worksheet();
+        laps_ws.set_name("Laps")?;
+        write_splits(
+            laps_ws,
+            units,
+            pace,
+            map_provider,
+            timezone,
+            &gpx.points,
+            "Lap",
+            laps,
+        )?;
+    }
+
+    // This will appear as the last sheet in the workbook.
+    if !splits.is_empty() {
+        let splits_ws = workbook.add_worksheet();
+        splits_ws.set_name("Splits")?;
+        write_splits(
+            splits_ws,
+            units,
+            pace,
+            map_provider,
+            timezone,
+            &gpx.points,
+            "Split",
+            splits,
+        )?;
+    }
+
     Ok(workbook)
 }
 
+/// Returns the name of the Stages tab for a track, e.g. "Track 2 - Day 2".
+/// Excel limits the names to 31 characters and does not allow some
+/// characters in them, or an apostrophe at the end.
+fn track_sheet_name(track: &TrackStages) -> String {
+    let name = match &track.gpx.track_name {
+        Some(name) => format!("Track {} - {name}", track.number),
+        None => format!("Track {}", track.number),
+    };
+
+    let name: String = name
+        .chars()
+        .map(|c| if r"[]:*?/\".contains(c) { '_' } else { c })
+        .take(31)
+        .collect();
+    name.trim_end_matches('\'').to_string()
+}
+
 /// Writes the summary workbook to file.
 #[time]
 pub fn write_summary_file(
     summary_filename: &Path,
     mut workbook: Workbook,
 ) -> Result<(), Box<dyn Error>> {
-    print!("Writing file {:?}", &summary_filename);
-    workbook.save(summary_filename).unwrap();
-    let metadata = std::fs::metadata(summary_filename).unwrap();
-    println!(", {} Kb", metadata.len() / 1024);
+    workbook.save(summary_filename)?;
+    let metadata = std::fs::metadata(summary_filename)?;
+    eprintln!(
+        "Wrote file {:?}, {} Kb",
+        &summary_filename,
+        metadata.len() / 1024
+    );
     Ok(())
 }
 
@@ -81,14 +232,24 @@ pub fn write_summary_file(
 /// are optional on the Track Points tab because there are thousands of them and
 /// they really slow down Calc.
 #[time]
+#[allow(clippy::too_many_arguments)]
 fn write_stages(
     ws: &mut Worksheet,
+    units: Units,
+    pace: PaceMode,
+    map_provider: &MapLinkProvider,
+    timezone: Option<&Tz>,
+    athlete: Option<&AthleteProfile>,
+    ftp: Option<f64>,
     gpx: &EnrichedGpx,
     stages: &StageList,
+    first_index: usize,
 ) -> Result<(), Box<dyn Error>> {
-    let mut fc = FormatControl::new();
+    let mut fc = FormatControl::new(units, map_provider, timezone)
+        .with_pace(pace)
+        .with_trackpoint_offset(first_index);
 
-    if stages.len() == 0 {
+    if stages.is_empty() {
         write_string(ws, &fc, "No stages detected")?;
         return Ok(());
     }
@@ -109,8 +270,19 @@ fn write_stages(
     output_min_elevation(ws, &mut fc, stages)?;
     output_max_elevation(ws, &mut fc, stages)?;
     output_max_speed(ws, &mut fc, stages)?;
+    output_gradient(ws, &mut fc, stages)?;
     output_heart_rate(ws, &mut fc, stages, gpx.avg_heart_rate())?;
+    if let Some(athlete) = athlete {
+        output_calories(ws, &mut fc, stages, athlete)?;
+    }
+    output_power(ws, &mut fc, stages, gpx, ftp)?;
     output_temperature(ws, &mut fc, stages, gpx.avg_temperature())?;
+    if gpx.points.iter().any(|p| p.water_temp().is_some()) {
+        output_water_temperature(ws, &mut fc, stages, gpx.avg_water_temperature())?;
+    }
+    if gpx.points.iter().any(|p| p.depth().is_some()) {
+        output_depth(ws, &mut fc, stages, gpx.avg_depth())?;
+    }
     output_track_points(ws, &mut fc, stages)?;
 
     Ok(())
@@ -273,10 +445,10 @@ fn output_duration(
     write_duration_option(ws, &fc.offset(2, 1), stages.total_control_time())?;
 
     write_string(ws, &fc.row_offset(3), "Moving")?;
-    write_percentage_option(ws, &fc.offset(3, 1), stages.moving_percent())?;
+    write_percentage_option(ws, &fc.offset(3, 1), stages.moving_ratio())?;
 
     write_string(ws, &fc.row_offset(4), "Controlling")?;
-    write_percentage_option(ws, &fc.offset(4, 1), stages.controlling_percent())?;
+    write_percentage_option(ws, &fc.offset(4, 1), stages.controlling_ratio())?;
 
     fc.next_colour_block(2);
     Ok(())
@@ -287,7 +459,12 @@ fn output_distance(
     fc: &mut FormatControl,
     stages: &StageList,
 ) -> Result<(), Box<dyn Error>> {
-    write_headers(ws, fc, "Distance (km)", &["Stage", "Running"])?;
+    write_headers(
+        ws,
+        fc,
+        &format!("Distance ({})", fc.distance_units()),
+        &["Stage", "Running"],
+    )?;
     ws.set_column_width(fc.col, KILOMETRES_COLUMN_WIDTH)?;
     ws.set_column_width(fc.col + 1, METRES_COLUMN_WIDTH)?;
 
@@ -316,7 +493,12 @@ fn output_average_speed(
     fc: &mut FormatControl,
     stages: &StageList,
 ) -> Result<(), Box<dyn Error>> {
-    write_headers(ws, fc, "Avg Speed (km/h)", &["Stage", "Running"])?;
+    write_headers(
+        ws,
+        fc,
+        &format!("Avg Speed ({})", fc.speed_units()),
+        &["Stage", "Running"],
+    )?;
     ws.set_column_width(fc.col, SPEED_COLUMN_WIDTH)?;
     ws.set_column_width(fc.col + 1, SPEED_COLUMN_WIDTH)?;
 
@@ -347,7 +529,12 @@ fn output_ascent(
     fc: &mut FormatControl,
     stages: &StageList,
 ) -> Result<(), Box<dyn Error>> {
-    write_headers(ws, fc, "Ascent (m)", &["Stage", "Running", "m/km"])?;
+    write_headers(
+        ws,
+        fc,
+        &format!("Ascent ({})", fc.elevation_units()),
+        &["Stage", "Running", fc.climb_rate_units()],
+    )?;
     ws.set_column_width(fc.col, METRES_COLUMN_WIDTH)?;
     ws.set_column_width(fc.col + 1, METRES_COLUMN_WIDTH)?;
     ws.set_column_width(fc.col + 2, METRES_COLUMN_WIDTH)?;
@@ -356,7 +543,7 @@ fn output_ascent(
         if stage.stage_type == StageType::Moving {
             write_metres_option(ws, fc, stage.ascent_metres())?;
             write_metres_option(ws, &fc.col_offset(1), stage.running_ascent_metres())?;
-            write_metres_option(ws, &fc.col_offset(2), stage.ascent_rate_per_km())?;
+            write_climb_rate_option(ws, &fc.col_offset(2), stage.ascent_rate_per_km())?;
         } else {
             write_blank(ws, fc)?;
             write_blank(ws, &fc.col_offset(1))?;
@@ -372,7 +559,7 @@ fn output_ascent(
     let rate = stages
         .total_ascent_metres()
         .map(|a| a / stages.distance_km());
-    write_metres_option(ws, &fc.col_offset(2), rate)?;
+    write_climb_rate_option(ws, &fc.col_offset(2), rate)?;
 
     fc.next_colour_block(3);
     Ok(())
@@ -383,7 +570,12 @@ fn output_descent(
     fc: &mut FormatControl,
     stages: &StageList,
 ) -> Result<(), Box<dyn Error>> {
-    write_headers(ws, fc, "Descent (m)", &["Stage", "Running", "m/km"])?;
+    write_headers(
+        ws,
+        fc,
+        &format!("Descent ({})", fc.elevation_units()),
+        &["Stage", "Running", fc.climb_rate_units()],
+    )?;
     ws.set_column_width(fc.col, METRES_COLUMN_WIDTH)?;
     ws.set_column_width(fc.col + 1, METRES_COLUMN_WIDTH)?;
     ws.set_column_width(fc.col + 2, METRES_COLUMN_WIDTH)?;
@@ -392,7 +584,7 @@ fn output_descent(
         if stage.stage_type == StageType::Moving {
             write_metres_option(ws, fc, stage.descent_metres())?;
             write_metres_option(ws, &fc.col_offset(1), stage.running_descent_metres())?;
-            write_metres_option(ws, &fc.col_offset(2), stage.descent_rate_per_km())?;
+            write_climb_rate_option(ws, &fc.col_offset(2), stage.descent_rate_per_km())?;
         } else {
             write_blank(ws, fc)?;
             write_blank(ws, &fc.col_offset(1))?;
@@ -408,7 +600,7 @@ fn output_descent(
     let rate = stages
         .total_descent_metres()
         .map(|a| a / stages.distance_km());
-    write_metres_option(ws, &fc.col_offset(2), rate)?;
+    write_climb_rate_option(ws, &fc.col_offset(2), rate)?;
 
     fc.next_colour_block(3);
     Ok(())
@@ -423,7 +615,11 @@ fn output_min_elevation(
         ws,
         fc,
         "Min Elevation",
-        &["Elevation (m)", "Distance (km)", "Point"],
+        &[
+            &format!("Elevation ({})", fc.elevation_units()),
+            &format!("Distance ({})", fc.distance_units()),
+            "Point",
+        ],
     )?;
     ws.set_column_width(fc.col, ELEVATION_COLUMN_WIDTH_WITH_UNITS)?;
     ws.set_column_width(fc.col + 1, KILOMETRES_COLUMN_WIDTH_WITH_UNITS)?;
@@ -456,7 +652,11 @@ fn output_max_elevation(
         ws,
         fc,
         "Max Elevation",
-        &["Elevation (m)", "Distance (km)", "Point"],
+        &[
+            &format!("Elevation ({})", fc.elevation_units()),
+            &format!("Distance ({})", fc.distance_units()),
+            "Point",
+        ],
     )?;
     ws.set_column_width(fc.col, ELEVATION_COLUMN_WIDTH_WITH_UNITS)?;
     ws.set_column_width(fc.col + 1, KILOMETRES_COLUMN_WIDTH_WITH_UNITS)?;
@@ -489,7 +689,11 @@ fn output_max_speed(
         ws,
         fc,
         "Max Speed",
-        &["Speed (km/h)", "Distance (km)", "Point"],
+        &[
+            &format!("Speed ({})", fc.speed_units()),
+            &format!("Distance ({})", fc.distance_units()),
+            "Point",
+        ],
     )?;
     ws.set_column_width(fc.col, SPEED_COLUMN_WIDTH_WITH_UNITS)?;
     ws.set_column_width(fc.col + 1, KILOMETRES_COLUMN_WIDTH_WITH_UNITS)?;
@@ -513,6 +717,44 @@ fn output_max_speed(
     Ok(())
 }
 
+fn output_gradient(
+    ws: &mut Worksheet,
+    fc: &mut FormatControl,
+    stages: &StageList,
+) -> Result<(), Box<dyn Error>> {
+    write_headers(
+        ws,
+        fc,
+        "Gradient",
+        &[
+            "Avg",
+            "Max",
+            &format!("Distance ({})", fc.distance_units()),
+            "Point",
+        ],
+    )?;
+    ws.set_column_width(fc.col + 2, KILOMETRES_COLUMN_WIDTH_WITH_UNITS)?;
+
+    for stage in stages {
+        if stage.stage_type == StageType::Moving {
+            write_gradient_data(ws, fc, stage.max_grade.as_ref(), stage.avg_grade())?;
+        } else {
+            write_blank(ws, fc)?;
+            write_blank(ws, &fc.col_offset(1))?;
+            write_blank(ws, &fc.col_offset(2))?;
+            write_blank(ws, &fc.col_offset(3))?;
+        }
+
+        fc.increment_row();
+    }
+
+    fc.start_summary_row();
+    write_gradient_data(ws, fc, stages.max_grade(), stages.avg_grade())?;
+
+    fc.next_colour_block(4);
+    Ok(())
+}
+
 fn output_heart_rate(
     ws: &mut Worksheet,
     fc: &mut FormatControl,
@@ -523,7 +765,12 @@ fn output_heart_rate(
         ws,
         fc,
         "Heart Rate",
-        &["Avg", "Max", "Distance (km)", "Point"],
+        &[
+            "Avg",
+            "Max",
+            &format!("Distance ({})", fc.distance_units()),
+            "Point",
+        ],
     )?;
     ws.set_column_width(fc.col + 2, KILOMETRES_COLUMN_WIDTH_WITH_UNITS)?;
 
@@ -539,16 +786,163 @@ fn output_heart_rate(
     Ok(())
 }
 
+fn output_calories(
+    ws: &mut Worksheet,
+    fc: &mut FormatControl,
+    stages: &StageList,
+    athlete: &AthleteProfile,
+) -> Result<(), Box<dyn Error>> {
+    write_headers(ws, fc, "", &["Calories (kcal)"])?;
+    ws.set_column_width(fc.col, HEART_RATE_WIDTH_WITH_UNITS)?;
+
+    for stage in stages {
+        write_integer_option(ws, fc, stage.estimated_calories(athlete))?;
+        fc.increment_row();
+    }
+
+    fc.start_summary_row();
+    write_integer_option(ws, fc, stages.estimated_calories(athlete))?;
+
+    fc.next_colour_block(1);
+    Ok(())
+}
+
+fn output_power(
+    ws: &mut Worksheet,
+    fc: &mut FormatControl,
+    stages: &StageList,
+    gpx: &EnrichedGpx,
+    ftp: Option<f64>,
+) -> Result<(), Box<dyn Error>> {
+    // The Intensity Factor and Training Stress Score need the FTP.
+    let headers: &[&str] = if ftp.is_some() {
+        &["Avg", "NP", "IF", "TSS"]
+    } else {
+        &["Avg", "NP"]
+    };
+    write_headers(ws, fc, "Power (W)", headers)?;
+
+    for stage in stages {
+        write_f64_option(ws, fc, stage.avg_power)?;
+        write_f64_option(ws, &fc.col_offset(1), stage.normalized_power)?;
+        if let Some(ftp) = ftp {
+            write_f64_option(ws, &fc.col_offset(2), stage.intensity_factor(ftp))?;
+            write_integer_option(ws, &fc.col_offset(3), stage.training_stress_score(ftp))?;
+        }
+        fc.increment_row();
+    }
+
+    fc.start_summary_row();
+    write_f64_option(ws, fc, gpx.avg_power())?;
+    write_f64_option(ws, &fc.col_offset(1), stages.normalized_power(gpx))?;
+    if let Some(ftp) = ftp {
+        write_f64_option(ws, &fc.col_offset(2), stages.intensity_factor(gpx, ftp))?;
+        write_integer_option(
+            ws,
+            &fc.col_offset(3),
+            stages.training_stress_score(gpx, ftp),
+        )?;
+    }
+
+    fc.next_colour_block(headers.len() as u16);
+    Ok(())
+}
+
 fn output_temperature(
     ws: &mut Worksheet,
     fc: &mut FormatControl,
     stages: &StageList,
     avg_temp: Option<f64>,
+) -> Result<(), Box<dyn Error>> {
+    output_min_max_avg(
+        ws,
+        fc,
+        &format!("Temp {}", fc.temperature_units()),
+        stages.iter().map(|s| {
+            (
+                s.min_air_temp.as_ref(),
+                s.max_air_temp.as_ref(),
+                s.avg_air_temp,
+            )
+        }),
+        (stages.min_temperature(), stages.max_temperature(), avg_temp),
+        EnrichedTrackPoint::air_temp,
+        write_temperature_option,
+    )
+}
+
+fn output_water_temperature(
+    ws: &mut Worksheet,
+    fc: &mut FormatControl,
+    stages: &StageList,
+    avg_temp: Option<f64>,
+) -> Result<(), Box<dyn Error>> {
+    output_min_max_avg(
+        ws,
+        fc,
+        &format!("Water Temp ({})", fc.temperature_units()),
+        stages.iter().map(|s| {
+            (
+                s.min_water_temp.as_ref(),
+                s.max_water_temp.as_ref(),
+                s.avg_water_temp,
+            )
+        }),
+        (
+            stages.min_water_temperature(),
+            stages.max_water_temperature(),
+            avg_temp,
+        ),
+        EnrichedTrackPoint::water_temp,
+        write_temperature_option,
+    )
+}
+
+fn output_depth(
+    ws: &mut Worksheet,
+    fc: &mut FormatControl,
+    stages: &StageList,
+    avg_depth: Option<f64>,
+) -> Result<(), Box<dyn Error>> {
+    output_min_max_avg(
+        ws,
+        fc,
+        &format!("Depth ({})", fc.elevation_units()),
+        stages
+            .iter()
+            .map(|s| (s.min_depth.as_ref(), s.max_depth.as_ref(), s.avg_depth)),
+        (stages.min_depth(), stages.max_depth(), avg_depth),
+        EnrichedTrackPoint::depth,
+        write_metres_option,
+    )
+}
+
+/// The points with the min and max of a value such as the temperature,
+/// and its average, for one stage or for the whole track.
+type MinMaxAvg<'a> = (
+    Option<&'a EnrichedTrackPoint>,
+    Option<&'a EnrichedTrackPoint>,
+    Option<f64>,
+);
+
+/// Writes a value to a cell, or a blank if there is no value.
+type WriteValueFn = fn(&mut Worksheet, &FormatControl, Option<f64>) -> Result<(), Box<dyn Error>>;
+
+/// Writes the block of columns for a value such as the temperature: the
+/// average, then the value, time and point number of the min and max.
+fn output_min_max_avg<'a>(
+    ws: &mut Worksheet,
+    fc: &mut FormatControl,
+    header: &str,
+    stage_data: impl Iterator<Item = MinMaxAvg<'a>>,
+    summary_data: MinMaxAvg<'a>,
+    value: fn(&EnrichedTrackPoint) -> Option<f64>,
+    write_value: WriteValueFn,
 ) -> Result<(), Box<dyn Error>> {
     write_headers(
         ws,
         fc,
-        "Temp °C",
+        header,
         &[
             "Avg",
             "Min",
@@ -562,26 +956,13 @@ fn output_temperature(
     ws.set_column_width(fc.col + 2, DATE_COLUMN_WIDTH)?;
     ws.set_column_width(fc.col + 5, DATE_COLUMN_WIDTH)?;
 
-    for stage in stages {
-        write_temperature_data(
-            ws,
-            fc,
-            stage.min_air_temp.as_ref(),
-            stage.max_air_temp.as_ref(),
-            stage.avg_air_temp,
-        )?;
-
+    for data in stage_data {
+        write_min_max_avg_data(ws, fc, data, value, write_value)?;
         fc.increment_row();
     }
 
     fc.start_summary_row();
-    write_temperature_data(
-        ws,
-        fc,
-        stages.min_temperature(),
-        stages.max_temperature(),
-        avg_temp,
-    )?;
+    write_min_max_avg_data(ws, fc, summary_data, value, write_value)?;
 
     fc.next_colour_block(7);
     Ok(())
@@ -607,38 +988,524 @@ fn output_track_points(
     }
 
     fc.start_summary_row();
-    write_trackpoint_number(ws, fc, stages.first_point().index)?;
-    write_trackpoint_number(ws, &fc.col_offset(1), stages.last_point().index)?;
-    let count = (stages.last_point().index - stages.first_point().index + 1).try_into()?;
-    write_integer(ws, &fc.col_offset(2), count)?;
+    if let (Some(first), Some(last)) = (stages.first_point(), stages.last_point()) {
+        write_trackpoint_number(ws, fc, first.index)?;
+        write_trackpoint_number(ws, &fc.col_offset(1), last.index)?;
+        let count = (last.index - first.index + 1).try_into()?;
+        write_integer(ws, &fc.col_offset(2), count)?;
+    } else {
+        write_blank(ws, fc)?;
+        write_blank(ws, &fc.col_offset(1))?;
+        write_blank(ws, &fc.col_offset(2))?;
+    }
 
     fc.next_colour_block(3);
     Ok(())
 }
 
+/// Writes the Splits or Laps sheet, which has one row for each (start, end)
+/// span of points. The spans share their boundary points. 'label' is what
+/// a span is called, e.g. "Split".
+#[time]
+#[allow(clippy::too_many_arguments)]
+fn write_splits(
+    ws: &mut Worksheet,
+    units: Units,
+    pace: PaceMode,
+    map_provider: &MapLinkProvider,
+    timezone: Option<&Tz>,
+    points: &[EnrichedTrackPoint],
+    label: &str,
+    splits: &[(usize, usize)],
+) -> Result<(), Box<dyn Error>> {
+    let mut fc = FormatControl::new(units, map_provider, timezone).with_pace(pace);
+
+    ws.set_freeze_panes(2, 0)?;
+
+    write_headers(ws, &fc, "", &[label])?;
+    for idx in 0..splits.len() {
+        write_integer(ws, &fc, idx as u32 + 1)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(1);
+
+    write_headers(ws, &fc, "Track Points", &["First", "Last"])?;
+    for &(start, end) in splits {
+        write_trackpoint_number(ws, &fc, start)?;
+        write_trackpoint_number(ws, &fc.col_offset(1), end)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(2);
+
+    write_headers(
+        ws,
+        &fc,
+        &format!("Distance ({})", fc.distance_units()),
+        &[label, "Running"],
+    )?;
+    ws.set_column_width(fc.col, KILOMETRES_COLUMN_WIDTH)?;
+    ws.set_column_width(fc.col + 1, KILOMETRES_COLUMN_WIDTH)?;
+    for &(start, end) in splits {
+        let metres = points[end].running_metres - points[start].running_metres;
+        write_kilometres(ws, &fc, metres / 1000.0)?;
+        write_kilometres(ws, &fc.col_offset(1), points[end].running_metres / 1000.0)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(2);
+
+    write_headers(ws, &fc, "Duration", &["hms", "Running"])?;
+    ws.set_column_width(fc.col, DURATION_COLUMN_WIDTH)?;
+    ws.set_column_width(fc.col + 1, DURATION_COLUMN_WIDTH)?;
+    for &(start, end) in splits {
+        write_duration_option(ws, &fc, split_duration(points, start, end))?;
+        write_duration_option(ws, &fc.col_offset(1), points[end].running_delta_time)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(2);
+
+    write_headers(ws, &fc, "", &[&format!("Avg Speed ({})", fc.speed_units())])?;
+    ws.set_column_width(fc.col, SPEED_COLUMN_WIDTH_WITH_UNITS)?;
+    for &(start, end) in splits {
+        let metres = points[end].running_metres - points[start].running_metres;
+        let speed = split_duration(points, start, end).map(|d| speed_kmh_from_duration(metres, d));
+        write_speed_option(ws, &fc, speed)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(1);
+
+    write_headers(
+        ws,
+        &fc,
+        &format!("Elevation ({})", fc.elevation_units()),
+        &["Ascent", "Descent"],
+    )?;
+    for &(start, end) in splits {
+        let ascent = match (
+            points[end].running_ascent_metres,
+            points[start].running_ascent_metres,
+        ) {
+            (Some(m1), Some(m2)) => Some(m1 - m2),
+            _ => None,
+        };
+        let descent = match (
+            points[end].running_descent_metres,
+            points[start].running_descent_metres,
+        ) {
+            (Some(m1), Some(m2)) => Some(m1 - m2),
+            _ => None,
+        };
+        write_metres_option(ws, &fc, ascent)?;
+        write_metres_option(ws, &fc.col_offset(1), descent)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(2);
+
+    Ok(())
+}
+
+/// Writes the "Fastest" tab, which has one row for each of the fastest
+/// parts of the track found by 'fastest_segment', e.g. the fastest 5km.
+/// Each entry is the target distance and the (start, end, duration)
+/// of the fastest part of the track covering it.
+#[time]
+fn write_fastest(
+    ws: &mut Worksheet,
+    units: Units,
+    pace: PaceMode,
+    map_provider: &MapLinkProvider,
+    timezone: Option<&Tz>,
+    points: &[EnrichedTrackPoint],
+    fastest: &[(f64, usize, usize, Duration)],
+) -> Result<(), Box<dyn Error>> {
+    let mut fc = FormatControl::new(units, map_provider, timezone).with_pace(pace);
+
+    ws.set_freeze_panes(2, 0)?;
+
+    write_headers(ws, &fc, "", &["Fastest"])?;
+    for &(km, ..) in fastest {
+        write_string(ws, &fc, &format!("{km} km"))?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(1);
+
+    write_headers(ws, &fc, "Track Points", &["First", "Last"])?;
+    for &(_, start, end, _) in fastest {
+        write_trackpoint_number(ws, &fc, start)?;
+        write_trackpoint_number(ws, &fc.col_offset(1), end)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(2);
+
+    write_headers(
+        ws,
+        &fc,
+        &format!("Distance ({})", fc.distance_units()),
+        &["Start", "Length"],
+    )?;
+    ws.set_column_width(fc.col, KILOMETRES_COLUMN_WIDTH)?;
+    ws.set_column_width(fc.col + 1, KILOMETRES_COLUMN_WIDTH)?;
+    for &(_, start, end, _) in fastest {
+        let metres = points[end].running_metres - points[start].running_metres;
+        write_kilometres_running_with_map_hyperlink(ws, &fc, &points[start])?;
+        write_kilometres(ws, &fc.col_offset(1), metres / 1000.0)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(2);
+
+    write_headers(ws, &fc, "", &["Duration"])?;
+    ws.set_column_width(fc.col, DURATION_COLUMN_WIDTH)?;
+    for &(.., duration) in fastest {
+        write_duration(ws, &fc, duration)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(1);
+
+    write_headers(ws, &fc, "", &[&format!("Avg Speed ({})", fc.speed_units())])?;
+    ws.set_column_width(fc.col, SPEED_COLUMN_WIDTH_WITH_UNITS)?;
+    for &(_, start, end, duration) in fastest {
+        let metres = points[end].running_metres - points[start].running_metres;
+        write_speed(ws, &fc, speed_kmh_from_duration(metres, duration))?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(1);
+
+    Ok(())
+}
+
+/// Returns the time taken to get from the 'start' point to the 'end' point.
+/// Writes the "Climbs" tab, one row per climb.
+fn write_climbs(
+    ws: &mut Worksheet,
+    units: Units,
+    map_provider: &MapLinkProvider,
+    timezone: Option<&Tz>,
+    climbs: &[Climb],
+) -> Result<(), Box<dyn Error>> {
+    let mut fc = FormatControl::new(units, map_provider, timezone);
+
+    ws.set_freeze_panes(2, 0)?;
+
+    write_headers(ws, &fc, "", &["Climb", "Category"])?;
+    for (idx, climb) in climbs.iter().enumerate() {
+        write_integer(ws, &fc, idx as u32 + 1)?;
+        write_string(ws, &fc.col_offset(1), &climb.category.to_string())?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(2);
+
+    write_headers(ws, &fc, "Track Points", &["First", "Last"])?;
+    for climb in climbs {
+        write_trackpoint_number(ws, &fc, climb.start.index)?;
+        write_trackpoint_number(ws, &fc.col_offset(1), climb.end.index)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(2);
+
+    write_headers(
+        ws,
+        &fc,
+        &format!("Distance ({})", fc.distance_units()),
+        &["Start", "Length"],
+    )?;
+    ws.set_column_width(fc.col, KILOMETRES_COLUMN_WIDTH)?;
+    ws.set_column_width(fc.col + 1, KILOMETRES_COLUMN_WIDTH)?;
+    for climb in climbs {
+        write_kilometres_running_with_map_hyperlink(ws, &fc, &climb.start)?;
+        write_kilometres(ws, &fc.col_offset(1), climb.length_metres() / 1000.0)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(2);
+
+    write_headers(
+        ws,
+        &fc,
+        &format!("Elevation ({})", fc.elevation_units()),
+        &["Bottom", "Top", "Gain", "Ascent"],
+    )?;
+    for climb in climbs {
+        write_metres_option(ws, &fc, climb.start.ele)?;
+        write_metres_option(ws, &fc.col_offset(1), climb.end.ele)?;
+        write_metres(ws, &fc.col_offset(2), climb.elevation_gain_metres())?;
+        write_metres(ws, &fc.col_offset(3), climb.ascent_metres)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(4);
+
+    write_headers(ws, &fc, "", &["Avg Gradient"])?;
+    for climb in climbs {
+        write_percentage(ws, &fc, climb.avg_grade() / 100.0)?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(1);
+
+    write_headers(ws, &fc, "", &["Duration"])?;
+    ws.set_column_width(fc.col, DURATION_COLUMN_WIDTH)?;
+    for climb in climbs {
+        write_duration_option(ws, &fc, climb.duration())?;
+        fc.increment_row();
+    }
+    fc.next_colour_block(1);
+
+    Ok(())
+}
+
+/// Writes the "HR Zones" tab, showing the time spent in each
+/// heart rate zone for each stage, with the totals underneath.
+fn write_hr_zones(
+    ws: &mut Worksheet,
+    units: Units,
+    map_provider: &MapLinkProvider,
+    timezone: Option<&Tz>,
+    stages: &StageList,
+    zones: &HrZones,
+) -> Result<(), Box<dyn Error>> {
+    let mut fc = FormatControl::new(units, map_provider, timezone);
+
+    ws.set_freeze_panes(2, 0)?;
+
+    write_headers(ws, &fc, "", &["Stage", "Type"])?;
+    for (idx, stage) in stages.iter().enumerate() {
+        write_integer(ws, &fc, idx as u32 + 1)?;
+        write_string(ws, &fc.col_offset(1), &stage.stage_type.to_string())?;
+        fc.increment_row();
+    }
+    fc.start_summary_row();
+    write_string_bold(ws, &fc.col_offset(1), "SUMMARY")?;
+    fc.next_colour_block(2);
+
+    let descriptions = zones.descriptions();
+    for zone in 0..HrZones::COUNT {
+        write_headers(
+            ws,
+            &fc,
+            &format!("Zone {}", zone + 1),
+            &[&descriptions[zone], "%"],
+        )?;
+        ws.set_column_width(fc.col, DURATION_COLUMN_WIDTH)?;
+
+        for stage in stages {
+            let times = stage.time_in_zones(zones);
+            write_duration(ws, &fc, times[zone])?;
+            write_percentage_option(ws, &fc.col_offset(1), zone_percent(&times, zone))?;
+            fc.increment_row();
+        }
+
+        fc.start_summary_row();
+        let totals = stages.time_in_zones(zones);
+        write_duration(ws, &fc, totals[zone])?;
+        write_percentage_option(ws, &fc.col_offset(1), zone_percent(&totals, zone))?;
+        fc.next_colour_block(2);
+    }
+
+    Ok(())
+}
+
+/// Returns the fraction of the total time spent in 'zone', or None
+/// if no time was recorded in any zone.
+fn zone_percent(times: &[Duration; HrZones::COUNT], zone: usize) -> Option<f64> {
+    let total: Duration = times.iter().sum();
+    if total.is_zero() {
+        None
+    } else {
+        Some(times[zone] / total)
+    }
+}
+
+fn split_duration(points: &[EnrichedTrackPoint], start: usize, end: usize) -> Option<Duration> {
+    match (points[end].time, points[start].time) {
+        (Some(t1), Some(t2)) => Some(t1 - t2),
+        _ => None,
+    }
+}
+
+/// The columns on the Track Points sheet that the Profile chart
+/// uses as its data source.
+struct TrackPointColumns {
+    elevation: u16,
+    running_km: u16,
+    speed: u16,
+}
+
+/// Writes an elevation vs distance chart, with speed on the secondary
+/// axis. The data is taken from the Track Points sheet, so that it
+/// is not duplicated in the workbook.
+/// Writes the "Map" tab: the position of each point, projected onto
+/// a flat plane by 'project_to_local_xy', and a chart of the route drawn
+/// from them. The chart is sized so that the route is not stretched.
+#[time]
+fn write_map_chart(
+    ws: &mut Worksheet,
+    units: Units,
+    gpx: &EnrichedGpx,
+) -> Result<(), Box<dyn Error>> {
+    // The chart has no hyperlinks, so the provider doesn't matter.
+    let map_provider = MapLinkProvider::default();
+    let mut fc = FormatControl::new(units, &map_provider, None);
+    let xy = gpx.project_to_local_xy();
+
+    write_headers(
+        ws,
+        &fc,
+        &format!("Position ({})", fc.distance_units()),
+        &["East", "North"],
+    )?;
+    ws.set_column_width(fc.col, KILOMETRES_COLUMN_WIDTH)?;
+    ws.set_column_width(fc.col + 1, KILOMETRES_COLUMN_WIDTH)?;
+    for &(x, y) in &xy {
+        write_kilometres(ws, &fc, x / 1000.0)?;
+        write_kilometres(ws, &fc.col_offset(1), y / 1000.0)?;
+        fc.increment_row();
+    }
+
+    let first_row = FormatControl::STARTING_ROW;
+    let last_row = first_row + xy.len() as u32 - 1;
+
+    let mut chart = Chart::new(ChartType::ScatterStraight);
+    chart.title().set_name("Route");
+    chart.legend().set_hidden();
+    chart
+        .add_series()
+        .set_name("Route")
+        .set_categories((MAP_SHEET_NAME, first_row, 0, last_row, 0))
+        .set_values((MAP_SHEET_NAME, first_row, 1, last_row, 1));
+
+    // Excel scales the axes to fill the chart, so make the chart the same
+    // shape as the route, within reason.
+    let (min_x, max_x) = min_max(xy.iter().map(|&(x, _)| x));
+    let (min_y, max_y) = min_max(xy.iter().map(|&(_, y)| y));
+    let aspect = ((max_y - min_y).max(1.0) / (max_x - min_x).max(1.0)).clamp(0.25, 4.0);
+    let width = 800.0;
+    chart
+        .set_width(width as u32)
+        .set_height((width * aspect) as u32);
+
+    ws.insert_chart(1, 3, &chart)?;
+    Ok(())
+}
+
+/// Returns the minimum and maximum of the values.
+fn min_max(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    values.fold((f64::MAX, f64::MIN), |(min, max), v| {
+        (min.min(v), max.max(v))
+    })
+}
+
+fn write_profile_chart(
+    ws: &mut Worksheet,
+    units: Units,
+    points: &[EnrichedTrackPoint],
+    columns: &TrackPointColumns,
+) -> Result<(), Box<dyn Error>> {
+    // The chart has no hyperlinks, so the provider doesn't matter.
+    let map_provider = MapLinkProvider::default();
+    let fc = FormatControl::new(units, &map_provider, None);
+    let first_row = FormatControl::STARTING_ROW;
+    let last_row = first_row + points.len() as u32 - 1;
+    let distance_range = (
+        TRACK_POINTS_SHEET_NAME,
+        first_row,
+        columns.running_km,
+        last_row,
+        columns.running_km,
+    );
+
+    let mut chart = Chart::new(ChartType::ScatterStraight);
+    chart.title().set_name("Elevation Profile");
+    chart
+        .x_axis()
+        .set_name(&format!("Distance ({})", fc.distance_units()));
+    chart
+        .y_axis()
+        .set_name(&format!("Elevation ({})", fc.elevation_units()));
+    chart.set_width(1200).set_height(600);
+
+    chart
+        .add_series()
+        .set_name("Elevation")
+        .set_categories(distance_range)
+        .set_values((
+            TRACK_POINTS_SHEET_NAME,
+            first_row,
+            columns.elevation,
+            last_row,
+            columns.elevation,
+        ));
+
+    if points.iter().any(|p| p.speed_kmh.is_some()) {
+        chart
+            .y2_axis()
+            .set_name(&format!("Speed ({})", fc.speed_units()));
+        chart
+            .add_series()
+            .set_name("Speed")
+            .set_categories(distance_range)
+            .set_values((
+                TRACK_POINTS_SHEET_NAME,
+                first_row,
+                columns.speed,
+                last_row,
+                columns.speed,
+            ))
+            .set_secondary_axis(true);
+    }
+
+    ws.insert_chart(1, 1, &chart)?;
+    Ok(())
+}
+
 #[time]
 fn write_trackpoints(
     ws: &mut Worksheet,
+    units: Units,
+    map_provider: &MapLinkProvider,
+    timezone: Option<&Tz>,
     points: &[EnrichedTrackPoint],
     hyperlink: Hyperlink,
     mandatory_hyperlinks: &HashSet<usize>,
-) -> Result<(), Box<dyn Error>> {
-    let mut fc = FormatControl::new();
+) -> Result<TrackPointColumns, Box<dyn Error>> {
+    let mut fc = FormatControl::new(units, map_provider, timezone);
 
     ws.set_freeze_panes(2, 0)?;
 
     output_tp_index(ws, &mut fc, points)?;
     output_tp_time(ws, &mut fc, points)?;
     output_tp_location(ws, &mut fc, points, hyperlink, mandatory_hyperlinks)?;
+    let elevation = fc.col;
     output_tp_elevation(ws, &mut fc, points)?;
+    // The running distance is the second column in the distance block.
+    let running_km = fc.col + 1;
     output_tp_distance(ws, &mut fc, points)?;
+    let speed = fc.col;
     output_tp_speed(ws, &mut fc, points)?;
-    output_tp_heart_rate(ws, &mut fc, points)?;
-    output_tp_air_temp(ws, &mut fc, points)?;
-    output_tp_cadence(ws, &mut fc, points)?;
 
-    ws.autofilter(1, 0, points.len() as u32 + 1, fc.col)?;
-    Ok(())
+    // The extension data is only written if the device recorded it, so
+    // that there aren't lots of empty columns.
+    if points.iter().any(|p| p.heart_rate().is_some()) {
+        output_tp_heart_rate(ws, &mut fc, points)?;
+    }
+    if points.iter().any(|p| p.air_temp().is_some()) {
+        output_tp_air_temp(ws, &mut fc, points)?;
+    }
+    if points.iter().any(|p| p.water_temp().is_some()) {
+        output_tp_water_temp(ws, &mut fc, points)?;
+    }
+    if points.iter().any(|p| p.depth().is_some()) {
+        output_tp_depth(ws, &mut fc, points)?;
+    }
+    if points.iter().any(|p| p.cadence().is_some()) {
+        output_tp_cadence(ws, &mut fc, points)?;
+    }
+    if points.iter().any(|p| p.power().is_some()) {
+        output_tp_power(ws, &mut fc, points)?;
+    }
+
+    // 'fc.col' is now the column after the last one written.
+    ws.autofilter(1, 0, points.len() as u32 + 1, fc.col - 1)?;
+    Ok(TrackPointColumns {
+        elevation,
+        running_km,
+        speed,
+    })
 }
 
 fn output_tp_index(
@@ -731,7 +1598,7 @@ fn output_tp_elevation(
     write_headers(
         ws,
         fc,
-        "Elevation (m)",
+        &format!("Elevation ({})", fc.elevation_units()),
         &["Height", "Delta", "Running Ascent", "Running Descent"],
     )?;
     ws.set_column_width(fc.col, METRES_COLUMN_WIDTH_WITH_UNITS)?;
@@ -764,7 +1631,15 @@ fn output_tp_distance(
     fc: &mut FormatControl,
     points: &[EnrichedTrackPoint],
 ) -> Result<(), Box<dyn Error>> {
-    write_headers(ws, fc, "Distance", &["Delta (m)", "Running (km)"])?;
+    write_headers(
+        ws,
+        fc,
+        "Distance",
+        &[
+            &format!("Delta ({})", fc.elevation_units()),
+            &format!("Running ({})", fc.distance_units()),
+        ],
+    )?;
     ws.set_column_width(fc.col, METRES_COLUMN_WIDTH_WITH_UNITS)?;
     ws.set_column_width(fc.col + 1, KILOMETRES_COLUMN_WIDTH_WITH_UNITS)?;
 
@@ -783,7 +1658,7 @@ fn output_tp_speed(
     fc: &mut FormatControl,
     points: &[EnrichedTrackPoint],
 ) -> Result<(), Box<dyn Error>> {
-    write_headers(ws, fc, "", &["Speed (km/h)"])?;
+    write_headers(ws, fc, "", &[&format!("Speed ({})", fc.speed_units())])?;
     ws.set_column_width(fc.col, SPEED_COLUMN_WIDTH_WITH_UNITS)?;
 
     for p in points {
@@ -822,11 +1697,50 @@ fn output_tp_air_temp(
     fc: &mut FormatControl,
     points: &[EnrichedTrackPoint],
 ) -> Result<(), Box<dyn Error>> {
-    write_headers(ws, fc, "", &["Temp (°C)"])?;
+    write_headers(ws, fc, "", &[&format!("Temp ({})", fc.temperature_units())])?;
     ws.set_column_width(fc.col, TEMPERATURE_COLUMN_WIDTH_WITH_UNITS)?;
 
     for p in points {
-        write_f64_option(ws, fc, p.air_temp())?;
+        write_temperature_option(ws, fc, p.air_temp())?;
+        fc.increment_row();
+    }
+
+    fc.next_colour_block(1);
+    Ok(())
+}
+
+fn output_tp_water_temp(
+    ws: &mut Worksheet,
+    fc: &mut FormatControl,
+    points: &[EnrichedTrackPoint],
+) -> Result<(), Box<dyn Error>> {
+    write_headers(
+        ws,
+        fc,
+        "",
+        &[&format!("Water Temp ({})", fc.temperature_units())],
+    )?;
+    ws.set_column_width(fc.col, TEMPERATURE_COLUMN_WIDTH_WITH_UNITS)?;
+
+    for p in points {
+        write_temperature_option(ws, fc, p.water_temp())?;
+        fc.increment_row();
+    }
+
+    fc.next_colour_block(1);
+    Ok(())
+}
+
+fn output_tp_depth(
+    ws: &mut Worksheet,
+    fc: &mut FormatControl,
+    points: &[EnrichedTrackPoint],
+) -> Result<(), Box<dyn Error>> {
+    write_headers(ws, fc, "", &[&format!("Depth ({})", fc.elevation_units())])?;
+    ws.set_column_width(fc.col, METRES_COLUMN_WIDTH_WITH_UNITS)?;
+
+    for p in points {
+        write_metres_option(ws, fc, p.depth())?;
         fc.increment_row();
     }
 
@@ -852,6 +1766,28 @@ fn output_tp_cadence(
         fc.increment_row();
     }
 
+    fc.next_colour_block(1);
+    Ok(())
+}
+
+fn output_tp_power(
+    ws: &mut Worksheet,
+    fc: &mut FormatControl,
+    points: &[EnrichedTrackPoint],
+) -> Result<(), Box<dyn Error>> {
+    write_headers(ws, fc, "", &["Power (W)"])?;
+    ws.set_column_width(fc.col, POWER_COLUMN_WIDTH_WITH_UNITS)?;
+
+    for p in points {
+        if let Some(pwr) = p.power() {
+            write_integer(ws, fc, pwr.into())?;
+        } else {
+            write_blank(ws, fc)?;
+        }
+
+        fc.increment_row();
+    }
+
     Ok(())
 }
 
@@ -888,7 +1824,7 @@ fn write_headers(
 
 /// Writes a lat-lon pair with the lat in the first cell as specified
 /// by 'rc' and the lon in the next column. If 'hyperlink' is yes then
-/// a hyperlink to Google Maps is written into the third column.
+/// a hyperlink to the map is written into the third column.
 fn write_lat_lon(
     ws: &mut Worksheet,
     fc: &FormatControl,
@@ -903,7 +1839,7 @@ fn write_lat_lon(
 
     match hyperlink {
         Hyperlink::Yes => {
-            let url = make_hyperlink((lat, lon));
+            let url = make_hyperlink(fc, (lat, lon));
             // TODO: Font still blue.
             let format = format.set_align(FormatAlign::Right);
             ws.write_url_with_format(fc.row, fc.col + 2, url, &format)?;
@@ -933,6 +1869,20 @@ fn write_integer(ws: &mut Worksheet, fc: &FormatControl, value: u32) -> Result<(
     Ok(())
 }
 
+/// Writes a value rounded to an integer, or a blank if there is no value.
+fn write_integer_option(
+    ws: &mut Worksheet,
+    fc: &FormatControl,
+    value: Option<f64>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(value) = value {
+        ws.write_number_with_format(fc.row, fc.col, value.round(), &fc.integer_format())?;
+    } else {
+        write_blank(ws, fc)?;
+    }
+    Ok(())
+}
+
 /// Writes a blank into a cell. We often want to do this when there is no data
 /// so that banding formatting is applied to the cell.
 fn write_blank(ws: &mut Worksheet, fc: &FormatControl) -> Result<(), Box<dyn Error>> {
@@ -1062,7 +2012,7 @@ fn write_utc_date_as_local(
     utc_date: OffsetDateTime,
 ) -> Result<(), Box<dyn Error>> {
     assert!(utc_date.offset().is_utc());
-    let excel_date = date_to_excel_date(to_local_date(utc_date))?;
+    let excel_date = date_to_excel_date(to_local_date(utc_date, fc.timezone))?;
     ws.write_with_format(fc.row, fc.col, &excel_date, &fc.local_date_format())?;
     Ok(())
 }
@@ -1162,12 +2112,37 @@ fn write_max_speed_data(
 
     let point = point.unwrap();
 
-    write_speed_option(ws, fc, point.speed_kmh)?;
+    write_speed_option(ws, fc, point.effective_speed_kmh())?;
     write_kilometres_running_with_map_hyperlink(ws, &fc.col_offset(1), point)?;
     write_trackpoint_number(ws, &fc.col_offset(2), point.index)?;
     Ok(())
 }
 
+/// Writes a gradient data block as found on the Stages tab. Gradients
+/// are stored as percentages, so are scaled to suit Excel's % format.
+fn write_gradient_data(
+    ws: &mut Worksheet,
+    fc: &FormatControl,
+    max_grade_point: Option<&EnrichedTrackPoint>,
+    avg_grade: Option<f64>,
+) -> Result<(), Box<dyn Error>> {
+    write_percentage_option(ws, fc, avg_grade.map(|g| g / 100.0))?;
+
+    if let Some(point) = max_grade_point {
+        if let Some(grade) = point.grade_percent {
+            write_percentage(ws, &fc.col_offset(1), grade / 100.0)?;
+            write_kilometres_running_with_map_hyperlink(ws, &fc.col_offset(2), point)?;
+            write_trackpoint_number(ws, &fc.col_offset(3), point.index)?;
+            return Ok(());
+        }
+    }
+
+    write_blank(ws, &fc.col_offset(1))?;
+    write_blank(ws, &fc.col_offset(2))?;
+    write_blank(ws, &fc.col_offset(3))?;
+    Ok(())
+}
+
 fn write_heart_rate_data(
     ws: &mut Worksheet,
     fc: &FormatControl,
@@ -1191,17 +2166,17 @@ fn write_heart_rate_data(
     Ok(())
 }
 
-fn write_temperature_data(
+fn write_min_max_avg_data(
     ws: &mut Worksheet,
     fc: &FormatControl,
-    min: Option<&EnrichedTrackPoint>,
-    max: Option<&EnrichedTrackPoint>,
-    avg: Option<f64>,
+    (min, max, avg): MinMaxAvg,
+    value: fn(&EnrichedTrackPoint) -> Option<f64>,
+    write_value: WriteValueFn,
 ) -> Result<(), Box<dyn Error>> {
-    write_f64_option(ws, fc, avg)?;
+    write_value(ws, fc, avg)?;
 
     if let Some(min) = min {
-        write_temperature_option(ws, &fc.col_offset(1), min.air_temp())?;
+        write_value(ws, &fc.col_offset(1), value(min))?;
         write_utc_date_as_local_option(ws, &fc.col_offset(2), min.time)?;
         write_trackpoint_number(ws, &fc.col_offset(3), min.index)?;
     } else {
@@ -1211,7 +2186,7 @@ fn write_temperature_data(
     }
 
     if let Some(max) = max {
-        write_temperature_option(ws, &fc.col_offset(4), max.air_temp())?;
+        write_value(ws, &fc.col_offset(4), value(max))?;
         write_utc_date_as_local_option(ws, &fc.col_offset(5), max.time)?;
         write_trackpoint_number(ws, &fc.col_offset(6), max.index)?;
     } else {
@@ -1229,7 +2204,7 @@ fn write_temperature(
     temperature: f64,
 ) -> Result<(), Box<dyn Error>> {
     let format = fc.temperature_format();
-    ws.write_number_with_format(fc.row, fc.col, temperature, &format)?;
+    ws.write_number_with_format(fc.row, fc.col, fc.temperature(temperature), &format)?;
     Ok(())
 }
 
@@ -1246,17 +2221,33 @@ fn write_temperature_option(
     Ok(())
 }
 
-fn make_hyperlink((lat, lon): (f64, f64)) -> Url {
+/// Returns a URL that shows the point on the map.
+fn map_url(provider: &MapLinkProvider, lat: f64, lon: f64) -> String {
+    match provider {
+        MapLinkProvider::GoogleMaps => {
+            format!("https://www.google.com/maps/search/?api=1&query={lat:.6},{lon:.6}")
+        }
+        MapLinkProvider::OpenStreetMap => {
+            format!("https://www.openstreetmap.org/?mlat={lat:.6}&mlon={lon:.6}#map=16/{lat:.6}/{lon:.6}")
+        }
+        MapLinkProvider::Bing => {
+            format!(
+                "https://www.bing.com/maps?cp={lat:.6}~{lon:.6}&lvl=16&sp=point.{lat:.6}_{lon:.6}"
+            )
+        }
+        MapLinkProvider::Custom(template) => template
+            .replace("{lat}", &format!("{lat:.6}"))
+            .replace("{lon}", &format!("{lon:.6}")),
+    }
+}
+
+fn make_hyperlink(fc: &FormatControl, (lat, lon): (f64, f64)) -> Url {
     let text = format!("{:.6}, {:.6}", lat, lon);
-    make_hyperlink_with_text((lat, lon), &text)
+    make_hyperlink_with_text(fc, (lat, lon), &text)
 }
 
-fn make_hyperlink_with_text((lat, lon): (f64, f64), text: &str) -> Url {
-    Url::new(format!(
-        "https://www.google.com/maps/search/?api=1&query={:.6},{:.6}",
-        lat, lon
-    ))
-    .set_text(text)
+fn make_hyperlink_with_text(fc: &FormatControl, (lat, lon): (f64, f64), text: &str) -> Url {
+    Url::new(map_url(fc.map_provider, lat, lon)).set_text(text)
 }
 
 /// Writes a TrackPoint index, including a hyperlink to
@@ -1266,6 +2257,7 @@ fn write_trackpoint_number(
     fc: &FormatControl,
     trackpoint_index: usize,
 ) -> Result<(), Box<dyn Error>> {
+    let trackpoint_index = trackpoint_index + fc.trackpoint_offset;
     let format = fc
         .integer_format()
         .set_font_color(Color::Black)
@@ -1282,7 +2274,7 @@ fn write_trackpoint_number(
 }
 
 fn write_metres(ws: &mut Worksheet, fc: &FormatControl, metres: f64) -> Result<(), Box<dyn Error>> {
-    ws.write_number_with_format(fc.row, fc.col, metres, &fc.metres_format())?;
+    ws.write_number_with_format(fc.row, fc.col, fc.elevation(metres), &fc.metres_format())?;
     // TODO: Use conditional formatting to indicate negatives?
     Ok(())
 }
@@ -1305,7 +2297,26 @@ fn write_kilometres(
     fc: &FormatControl,
     kilometres: f64,
 ) -> Result<(), Box<dyn Error>> {
-    ws.write_number_with_format(fc.row, fc.col, kilometres, &fc.kilometres_format())?;
+    ws.write_number_with_format(
+        fc.row,
+        fc.col,
+        fc.distance(kilometres),
+        &fc.kilometres_format(),
+    )?;
+    Ok(())
+}
+
+/// Writes a rate of climb (or descent), e.g. metres per kilometre.
+fn write_climb_rate_option(
+    ws: &mut Worksheet,
+    fc: &FormatControl,
+    metres_per_km: Option<f64>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(rate) = metres_per_km {
+        ws.write_number_with_format(fc.row, fc.col, fc.climb_rate(rate), &fc.metres_format())?;
+    } else {
+        write_blank(ws, fc)?;
+    }
     Ok(())
 }
 
@@ -1314,16 +2325,28 @@ fn write_kilometres_running_with_map_hyperlink(
     fc: &FormatControl,
     point: &EnrichedTrackPoint,
 ) -> Result<(), Box<dyn Error>> {
-    let km = point.running_metres / 1000.0;
-    let url = make_hyperlink_with_text((point.lat, point.lon), &format!("{:.3}", km));
+    let distance = fc.distance(point.running_metres / 1000.0);
+    let url = make_hyperlink_with_text(fc, (point.lat, point.lon), &format!("{:.3}", distance));
     let format = fc.kilometres_format();
     let format = format.set_align(FormatAlign::Right);
     ws.write_url_with_format(fc.row, fc.col, url, &format)?;
     Ok(())
 }
 
+/// Writes a speed, or a pace if a pace mode has been selected. Paces are
+/// written as durations so that they display as m:ss. A speed of zero
+/// has no pace, so is written as a blank.
 fn write_speed(ws: &mut Worksheet, fc: &FormatControl, speed: f64) -> Result<(), Box<dyn Error>> {
-    ws.write_number_with_format(fc.row, fc.col, speed, &fc.speed_format())?;
+    if fc.pace == PaceMode::SpeedKmh {
+        ws.write_number_with_format(fc.row, fc.col, fc.speed(speed), &fc.speed_format())?;
+    } else if let Some(minutes) = fc.pace_minutes(speed) {
+        // Excel durations are measured in days.
+        let days = minutes / (24.0 * 60.0);
+        ws.write_number_with_format(fc.row, fc.col, days, &fc.pace_format())?;
+    } else {
+        write_blank(ws, fc)?;
+    }
+
     Ok(())
 }
 
@@ -1346,24 +2369,50 @@ fn write_speed_option(
 /// the write* methods to do pass-by-value you have to de-reference in a million
 /// places in the output* methods. So it's best to leave it all as pass by
 /// reference.
-struct FormatControl {
+struct FormatControl<'a> {
     row: u32,
     col: u16,
     current_background_color: Color,
     always_set_background_color: bool,
+    units: Units,
+    pace: PaceMode,
+    map_provider: &'a MapLinkProvider,
+    timezone: Option<&'a Tz>,
+    /// Added to the point indexes to get the row on the 'Track Points'
+    /// sheet, when the points are part of a larger track.
+    trackpoint_offset: usize,
 }
 
-impl FormatControl {
+impl<'a> FormatControl<'a> {
     const COLOR1: Color = Color::Theme(3, 1);
     const COLOR2: Color = Color::Theme(2, 1);
     const STARTING_ROW: u32 = 2;
 
-    fn new() -> Self {
+    fn new(units: Units, map_provider: &'a MapLinkProvider, timezone: Option<&'a Tz>) -> Self {
         Self {
             current_background_color: Self::COLOR1,
             col: 0,
             row: Self::STARTING_ROW,
             always_set_background_color: false,
+            units,
+            pace: PaceMode::SpeedKmh,
+            map_provider,
+            timezone,
+            trackpoint_offset: 0,
+        }
+    }
+
+    /// Returns a new FormatControl which displays speeds using 'pace'.
+    fn with_pace(self, pace: PaceMode) -> Self {
+        Self { pace, ..self }
+    }
+
+    /// Returns a new FormatControl which adds 'trackpoint_offset' to the
+    /// point indexes it writes.
+    fn with_trackpoint_offset(self, trackpoint_offset: usize) -> Self {
+        Self {
+            trackpoint_offset,
+            ..self
         }
     }
 
@@ -1374,6 +2423,11 @@ impl FormatControl {
             current_background_color: self.current_background_color,
             row: self.row,
             col: self.col + col_offset,
+            units: self.units,
+            pace: self.pace,
+            map_provider: self.map_provider,
+            timezone: self.timezone,
+            trackpoint_offset: self.trackpoint_offset,
         }
     }
 
@@ -1384,6 +2438,11 @@ impl FormatControl {
             current_background_color: self.current_background_color,
             row: self.row + row_offset,
             col: self.col,
+            units: self.units,
+            pace: self.pace,
+            map_provider: self.map_provider,
+            timezone: self.timezone,
+            trackpoint_offset: self.trackpoint_offset,
         }
     }
 
@@ -1394,6 +2453,11 @@ impl FormatControl {
             current_background_color: self.current_background_color,
             row: self.row + row_offset,
             col: self.col + col_offset,
+            units: self.units,
+            pace: self.pace,
+            map_provider: self.map_provider,
+            timezone: self.timezone,
+            trackpoint_offset: self.trackpoint_offset,
         }
     }
 
@@ -1420,6 +2484,95 @@ impl FormatControl {
         self.always_set_background_color = false;
     }
 
+    /// Converts a distance in kilometres into the display units.
+    fn distance(&self, kilometres: f64) -> f64 {
+        match self.units {
+            Units::Metric => kilometres,
+            Units::Imperial => kilometres / KILOMETRES_PER_MILE,
+        }
+    }
+
+    /// Converts an elevation in metres into the display units.
+    fn elevation(&self, metres: f64) -> f64 {
+        match self.units {
+            Units::Metric => metres,
+            Units::Imperial => metres / METRES_PER_FOOT,
+        }
+    }
+
+    /// Converts a speed in km/h into the display units.
+    fn speed(&self, kmh: f64) -> f64 {
+        self.distance(kmh)
+    }
+
+    /// Converts a speed in km/h into a pace in minutes per kilometre or mile,
+    /// depending on the pace mode. Returns None if the speed is zero, because
+    /// the pace would be infinite.
+    fn pace_minutes(&self, kmh: f64) -> Option<f64> {
+        if kmh <= 0.0 {
+            return None;
+        }
+
+        match self.pace {
+            PaceMode::SpeedKmh => None,
+            PaceMode::PaceMinPerKm => Some(60.0 / kmh),
+            PaceMode::PaceMinPerMile => Some(60.0 * KILOMETRES_PER_MILE / kmh),
+        }
+    }
+
+    /// Converts a temperature in °C into the display units.
+    fn temperature(&self, celsius: f64) -> f64 {
+        match self.units {
+            Units::Metric => celsius,
+            Units::Imperial => celsius * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    /// Converts a rate of climb in metres per kilometre into the display units.
+    fn climb_rate(&self, metres_per_km: f64) -> f64 {
+        match self.units {
+            Units::Metric => metres_per_km,
+            Units::Imperial => metres_per_km * KILOMETRES_PER_MILE / METRES_PER_FOOT,
+        }
+    }
+
+    fn distance_units(&self) -> &'static str {
+        match self.units {
+            Units::Metric => "km",
+            Units::Imperial => "mi",
+        }
+    }
+
+    fn elevation_units(&self) -> &'static str {
+        match self.units {
+            Units::Metric => "m",
+            Units::Imperial => "ft",
+        }
+    }
+
+    fn speed_units(&self) -> &'static str {
+        match (self.pace, self.units) {
+            (PaceMode::SpeedKmh, Units::Metric) => "km/h",
+            (PaceMode::SpeedKmh, Units::Imperial) => "mph",
+            (PaceMode::PaceMinPerKm, _) => "min/km",
+            (PaceMode::PaceMinPerMile, _) => "min/mi",
+        }
+    }
+
+    fn temperature_units(&self) -> &'static str {
+        match self.units {
+            Units::Metric => "°C",
+            Units::Imperial => "°F",
+        }
+    }
+
+    fn climb_rate_units(&self) -> &'static str {
+        match self.units {
+            Units::Metric => "m/km",
+            Units::Imperial => "ft/mi",
+        }
+    }
+
     fn minor_header_format(&self) -> Format {
         Format::new()
             .set_bold()
@@ -1436,6 +2589,11 @@ impl FormatControl {
         self.apply_background_color_if_needed(format)
     }
 
+    fn pace_format(&self) -> Format {
+        let format = Format::new().set_num_format("[m]:ss");
+        self.apply_background_color_if_needed(format)
+    }
+
     fn lat_lon_format(&self) -> Format {
         let format = Format::new().set_num_format("0.000000");
         self.apply_background_color_if_needed(format)
@@ -1505,3 +2663,316 @@ impl FormatControl {
         format
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::{
+        model::{Extensions, GpxBuilder, TrackPoint},
+        stage::{detect_stages, enrich_trackpoints, StageDetectionParameters},
+        test_helpers::{enriched, ride, ride_points, xy_point},
+    };
+
+    /// Builds the summary workbook with the default options.
+    fn summary_workbook(gpx: &EnrichedGpx, stages: &StageList) -> Workbook {
+        summary_workbook_with(gpx, stages, PaceMode::SpeedKmh, &[])
+    }
+
+    fn summary_workbook_with(
+        gpx: &EnrichedGpx,
+        stages: &StageList,
+        pace: PaceMode,
+        track_stages: &[TrackStages],
+    ) -> Workbook {
+        create_summary_xlsx(
+            Hyperlink::No,
+            Units::Metric,
+            pace,
+            0.0,
+            &MapLinkProvider::default(),
+            None,
+            None,
+            None,
+            false,
+            gpx,
+            stages,
+            track_stages,
+            &[],
+            &[],
+            &[],
+            &[],
+        )
+        .unwrap()
+    }
+
+    fn sheet_names(workbook: &mut Workbook) -> Vec<String> {
+        workbook.worksheets().iter().map(|ws| ws.name()).collect()
+    }
+
+    /// Saves the workbook and returns the XML of one of its parts,
+    /// e.g. "xl/charts/chart1.xml".
+    fn part_xml(workbook: &mut Workbook, part: &str) -> String {
+        let bytes = workbook.save_to_buffer().unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut xml = String::new();
+        archive
+            .by_name(part)
+            .unwrap()
+            .read_to_string(&mut xml)
+            .unwrap();
+        xml
+    }
+
+    /// A ride with a gentle climb, so that it has an elevation profile.
+    fn hilly_ride() -> EnrichedGpx {
+        let mut gpx = ride(&[(20.0, 1200)]);
+        for p in &mut gpx.points {
+            p.ele = Some(100.0 + p.index as f64);
+        }
+        gpx
+    }
+
+    #[test]
+    fn map_urls_for_each_provider() {
+        let (lat, lon) = (51.5074, -0.1278);
+
+        assert_eq!(
+            map_url(&MapLinkProvider::GoogleMaps, lat, lon),
+            "https://www.google.com/maps/search/?api=1&query=51.507400,-0.127800"
+        );
+        assert_eq!(
+            map_url(&MapLinkProvider::OpenStreetMap, lat, lon),
+            "https://www.openstreetmap.org/?mlat=51.507400&mlon=-0.127800#map=16/51.507400/-0.127800"
+        );
+        assert_eq!(
+            map_url(&MapLinkProvider::Bing, lat, lon),
+            "https://www.bing.com/maps?cp=51.507400~-0.127800&lvl=16&sp=point.51.507400_-0.127800"
+        );
+
+        let custom = MapLinkProvider::Custom("https://maps.example.com/{lat}/{lon}".to_string());
+        assert_eq!(
+            map_url(&custom, lat, lon),
+            "https://maps.example.com/51.507400/-0.127800"
+        );
+    }
+
+    #[test]
+    fn the_profile_chart_has_elevation_and_speed_series() {
+        let gpx = hilly_ride();
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        let mut workbook = summary_workbook(&gpx, &stages);
+
+        assert_eq!(
+            sheet_names(&mut workbook),
+            ["Stages", TRACK_POINTS_SHEET_NAME, "Profile"]
+        );
+        let chart = part_xml(&mut workbook, "xl/charts/chart1.xml");
+        assert_eq!(chart.matches("<c:ser>").count(), 2);
+    }
+
+    #[test]
+    fn the_profile_chart_has_no_speed_series_without_times() {
+        let points = (0..100)
+            .map(|i| TrackPoint {
+                ele: Some(100.0 + i as f64),
+                ..xy_point(i as f64 * 10.0, 0.0)
+            })
+            .collect();
+        let gpx = enriched(points);
+        let mut workbook = summary_workbook(&gpx, &StageList::default());
+
+        let chart = part_xml(&mut workbook, "xl/charts/chart1.xml");
+        assert_eq!(chart.matches("<c:ser>").count(), 1);
+    }
+
+    #[test]
+    fn there_is_no_profile_without_elevations() {
+        let gpx = ride(&[(20.0, 600)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        let mut workbook = summary_workbook(&gpx, &stages);
+        assert!(!sheet_names(&mut workbook).contains(&"Profile".to_string()));
+    }
+
+    #[test]
+    fn imperial_units_convert_distances_elevations_and_temperatures() {
+        let map_provider = MapLinkProvider::default();
+        let fc = FormatControl::new(Units::Imperial, &map_provider, None);
+
+        assert!((fc.distance(1.0) - 0.621).abs() < 0.001);
+        assert!((fc.speed(16.09344) - 10.0).abs() < 1e-9);
+        assert!((fc.elevation(100.0) - 328.08).abs() < 0.01);
+        assert_eq!(fc.temperature(0.0), 32.0);
+        assert_eq!(fc.temperature(100.0), 212.0);
+        assert_eq!(
+            (fc.distance_units(), fc.elevation_units(), fc.speed_units()),
+            ("mi", "ft", "mph")
+        );
+    }
+
+    #[test]
+    fn metric_units_are_unchanged() {
+        let map_provider = MapLinkProvider::default();
+        let fc = FormatControl::new(Units::Metric, &map_provider, None);
+
+        assert_eq!(fc.distance(1.0), 1.0);
+        assert_eq!(fc.elevation(100.0), 100.0);
+        assert_eq!(fc.temperature(20.0), 20.0);
+        assert_eq!(fc.temperature_units(), "°C");
+    }
+
+    #[test]
+    fn water_temperature_and_depth_columns_are_populated() {
+        let points = ride_points(&[(5.0, 600)])
+            .into_iter()
+            .map(|p| TrackPoint {
+                extensions: Some(Extensions {
+                    water_temp: Some(12.5),
+                    depth: Some(1.5),
+                    ..Default::default()
+                }),
+                ..p
+            })
+            .collect();
+        let gpx = enriched(points);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        let mut workbook = summary_workbook(&gpx, &stages);
+
+        let strings = part_xml(&mut workbook, "xl/sharedStrings.xml");
+        assert!(strings.contains("Water Temp (°C)"), "{strings}");
+        assert!(strings.contains("Depth (m)"), "{strings}");
+        let track_points = part_xml(&mut workbook, "xl/worksheets/sheet2.xml");
+        assert_eq!(
+            track_points.matches("<v>12.5</v>").count(),
+            gpx.points.len()
+        );
+        assert_eq!(track_points.matches("<v>1.5</v>").count(), gpx.points.len());
+    }
+
+    #[test]
+    fn twelve_kmh_is_a_pace_of_five_minutes_per_km() {
+        let gpx = ride(&[(12.0, 600)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        let mut workbook = summary_workbook_with(&gpx, &stages, PaceMode::PaceMinPerKm, &[]);
+
+        let strings = part_xml(&mut workbook, "xl/sharedStrings.xml");
+        assert!(strings.contains("min/km"), "{strings}");
+        let styles = part_xml(&mut workbook, "xl/styles.xml");
+        assert!(styles.contains("[m]:ss"), "{styles}");
+
+        // Excel durations are in days, so 5:00 is 5 / 1440.
+        let map_provider = MapLinkProvider::default();
+        let mut fc = FormatControl::new(Units::Metric, &map_provider, None)
+            .with_pace(PaceMode::PaceMinPerKm);
+        let mut workbook = Workbook::new();
+        let ws = workbook.add_worksheet();
+        write_speed(ws, &fc, 12.0).unwrap();
+        fc.col += 1;
+        write_speed(ws, &fc, 0.0).unwrap();
+
+        let sheet = part_xml(&mut workbook, "xl/worksheets/sheet1.xml");
+        let value = sheet
+            .split("<v>")
+            .nth(1)
+            .unwrap()
+            .split("</v>")
+            .next()
+            .unwrap();
+        assert_eq!(value.parse::<f64>().unwrap(), 5.0 / (24.0 * 60.0));
+        // Zero speed has no pace, so is blank.
+        assert_eq!(sheet.matches("<v>").count(), 1, "{sheet}");
+    }
+
+    /// Returns the number of columns used by a worksheet, from the
+    /// dimension at the top of its XML, e.g. 'A1:Q123' is 17 columns.
+    fn column_count(sheet_xml: &str) -> u32 {
+        let dimension = sheet_xml.split(r#"<dimension ref="A1:"#).nth(1).unwrap();
+        dimension
+            .chars()
+            .take_while(char::is_ascii_uppercase)
+            .fold(0, |n, c| n * 26 + (c as u32 - 'A' as u32 + 1))
+    }
+
+    #[test]
+    fn the_heart_rate_column_is_only_written_when_there_is_heart_rate_data() {
+        let with_hr = enriched(
+            ride_points(&[(20.0, 600)])
+                .into_iter()
+                .map(|p| TrackPoint {
+                    extensions: Some(Extensions {
+                        heart_rate: Some(130),
+                        ..Default::default()
+                    }),
+                    ..p
+                })
+                .collect(),
+        );
+        let without_hr = enriched(ride_points(&[(20.0, 600)]));
+
+        let track_points = |gpx: &EnrichedGpx| {
+            let stages = detect_stages(gpx, StageDetectionParameters::default());
+            let mut workbook = summary_workbook(gpx, &stages);
+            part_xml(&mut workbook, "xl/worksheets/sheet2.xml")
+        };
+        let with_hr = track_points(&with_hr);
+        let without_hr = track_points(&without_hr);
+
+        assert!(with_hr.contains("<v>130</v>"));
+        assert!(!without_hr.contains("<v>130</v>"));
+        assert_eq!(column_count(&with_hr), column_count(&without_hr) + 1);
+    }
+
+    #[test]
+    fn each_track_gets_a_stages_tab_named_after_it() {
+        // Two days of a tour, each in a track of its own.
+        let track_names = ["Day 1", "Day 2: Hills/Coast and more"];
+        let mut builder = GpxBuilder::new("test.gpx");
+        for (day, name) in track_names.iter().enumerate() {
+            builder.add_track(Some(name.to_string()));
+            for p in ride_points(&[(20.0, 600)]) {
+                builder.push_point(TrackPoint {
+                    time: p.time.map(|t| t + Duration::days(day as i64)),
+                    ..p
+                });
+            }
+        }
+        let mut gpx = EnrichedGpx::from(builder.build());
+        enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap();
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+
+        let num_points = gpx.points.len() / 2;
+        let track_stages: Vec<_> = track_names
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| {
+                let first_index = idx * num_points;
+                let range = first_index..first_index + num_points;
+                let mut track_gpx = gpx.extract_track(range, Some(name.to_string()));
+                enrich_trackpoints(&mut track_gpx, 0.0, None, None).unwrap();
+                let stages = detect_stages(&track_gpx, StageDetectionParameters::default());
+                TrackStages {
+                    number: idx + 1,
+                    first_index,
+                    gpx: track_gpx,
+                    stages,
+                }
+            })
+            .collect();
+        let mut workbook = summary_workbook_with(&gpx, &stages, PaceMode::SpeedKmh, &track_stages);
+
+        // Excel does not allow ':' or '/' in a name, or more than 31 characters.
+        let names = sheet_names(&mut workbook);
+        assert_eq!(
+            names[..4],
+            [
+                "Stages",
+                "Track 1 - Day 1",
+                "Track 2 - Day 2_ Hills_Coast an",
+                TRACK_POINTS_SHEET_NAME
+            ]
+        );
+    }
+}
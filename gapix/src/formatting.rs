@@ -1,15 +1,28 @@
-use time::format_description::well_known;
+use time::format_description::{self, well_known, OwnedFormatItem};
 use time::{OffsetDateTime, UtcOffset};
+use time_tz::{OffsetDateTimeExt, Tz};
 
-/// Convert 'utc_date' to a local date by applying the current local offset of the
-/// user at the specified time.
-/// TODO: It would be better to determine the offset to apply based on the
-/// lat-lon of the trackpoint. We need a time-zone database to do that.
-pub fn to_local_date(utc_date: OffsetDateTime) -> OffsetDateTime {
+/// Convert 'utc_date' to a local date in 'timezone', which is normally
+/// the time zone where the track was recorded. If that is not known we
+/// fall back to the current local offset of the user at the specified time,
+/// or to UTC if that cannot be determined. The 'time' crate will not look
+/// up the local offset once other threads are running, as they are when
+/// the input files are processed in parallel.
+pub fn to_local_date(utc_date: OffsetDateTime, timezone: Option<&Tz>) -> OffsetDateTime {
     assert!(utc_date.offset().is_utc());
 
-    let local_offset = UtcOffset::local_offset_at(utc_date).unwrap();
-    utc_date.to_offset(local_offset)
+    match timezone {
+        Some(tz) => utc_date.to_timezone(tz),
+        None => {
+            let local_offset = UtcOffset::local_offset_at(utc_date).unwrap_or(UtcOffset::UTC);
+            utc_date.to_offset(local_offset)
+        }
+    }
+}
+
+/// Formats 'date' into a string like "2024-09-01T07:10:44+02:00".
+pub fn format_local_date(date: &OffsetDateTime) -> String {
+    date.format(&well_known::Rfc3339).unwrap()
 }
 
 /// Formats 'utc_date' into a string like "2024-09-01T05:10:44Z".
@@ -23,3 +36,89 @@ pub fn format_utc_date(utc_date: &OffsetDateTime) -> String {
         .unwrap();
     String::from_utf8(buf).unwrap()
 }
+
+/// Formats 'utc_date' like 'format_utc_date', but with exactly 'decimals'
+/// digits of fractional seconds (truncated, not rounded). 'format_utc_date'
+/// writes as many digits as are needed, so "44.120" becomes "44.12" and
+/// "44.000" becomes "44". This is for when you want to match the input,
+/// e.g. 3 gives "2024-09-01T05:10:44.000Z", which is what Garmin writes.
+pub fn format_utc_date_with_decimals(utc_date: &OffsetDateTime, decimals: usize) -> String {
+    assert!(utc_date.offset().is_utc());
+    assert!(decimals <= 9);
+
+    let mut s = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        utc_date.year(),
+        utc_date.month() as u8,
+        utc_date.day(),
+        utc_date.hour(),
+        utc_date.minute(),
+        utc_date.second()
+    );
+
+    if decimals > 0 {
+        s.push('.');
+        s.push_str(&format!("{:09}", utc_date.nanosecond())[..decimals]);
+    }
+
+    s.push('Z');
+    s
+}
+
+/// How times are written in the JSON, GeoJSON and text outputs.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub enum TimeFormat {
+    /// e.g. "2024-09-01T05:10:44Z", with as many fractional
+    /// digits as are needed.
+    #[default]
+    Rfc3339,
+    /// e.g. "2024-09-01T05:10:44.000Z", always with milliseconds.
+    Rfc3339Millis,
+    /// Whole seconds since 1970-01-01T00:00:00Z, e.g. "1725167444".
+    Epoch,
+    /// A format description for the 'time' crate, e.g.
+    /// "[day]/[month]/[year] [hour]:[minute]:[second]".
+    /// Use 'TimeFormat::custom' to make one.
+    Custom(OwnedFormatItem),
+}
+
+impl TimeFormat {
+    /// Parses a format description, and checks that it can be used to
+    /// format a date, so that 'format' does not fail later on.
+    pub fn custom(description: &str) -> Result<Self, String> {
+        let items = format_description::parse_owned::<1>(description).map_err(|e| e.to_string())?;
+        OffsetDateTime::UNIX_EPOCH
+            .format(&items)
+            .map_err(|e| e.to_string())?;
+        Ok(TimeFormat::Custom(items))
+    }
+
+    /// Formats 'date', keeping its offset.
+    pub fn format(&self, date: &OffsetDateTime) -> String {
+        match self {
+            TimeFormat::Rfc3339 => format_local_date(date),
+            TimeFormat::Rfc3339Millis => {
+                let millis = format_description::parse(
+                    "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]",
+                )
+                .unwrap();
+                let mut s = date.format(&millis).unwrap();
+                if date.offset().is_utc() {
+                    s.push('Z');
+                } else {
+                    let offset =
+                        format_description::parse("[offset_hour sign:mandatory]:[offset_minute]")
+                            .unwrap();
+                    s.push_str(&date.format(&offset).unwrap());
+                }
+                s
+            }
+            TimeFormat::Epoch => date.unix_timestamp().to_string(),
+            // 'custom' checked that the description can format a date, so
+            // this should not fail, but fall back to RFC 3339 rather than panic.
+            TimeFormat::Custom(items) => date
+                .format(items)
+                .unwrap_or_else(|_| format_local_date(date)),
+        }
+    }
+}
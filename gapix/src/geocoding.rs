@@ -0,0 +1,248 @@
+//! Contains the functionality relating to reverse geocoding, i.e.
+//! turning a lat-lon into a place name. This is done offline against
+//! a file of named places, such as an extract of the GeoNames
+//! "cities" dataset.
+
+use std::{error::Error, fs::read_to_string, path::Path};
+
+use log::info;
+use logging_timer::time;
+
+use crate::model::EnrichedGpx;
+
+/// A set of named places that we can search for the nearest one
+/// to a given lat-lon.
+#[derive(Debug, Default)]
+pub struct LocationDb {
+    index: SpatialIndex<String>,
+}
+
+/// A set of values, each at a lat-lon, that we can search for the
+/// nearest one to a given lat-lon in O(log n) time.
+///
+/// The places are stored as an implicit k-d tree: the slice is arranged
+/// so that the median element (on the current axis) is in the middle,
+/// with the lesser elements to the left and the greater to the right,
+/// recursively. Each place is stored as a point on the unit sphere so
+/// that straight-line distance orders places the same way as distance
+/// over the surface of the Earth, which avoids problems with longitude
+/// wrap-around and the convergence of meridians.
+#[derive(Debug)]
+pub struct SpatialIndex<T> {
+    places: Vec<Place<T>>,
+}
+
+#[derive(Debug)]
+struct Place<T> {
+    value: T,
+    xyz: [f64; 3],
+}
+
+impl LocationDb {
+    /// Loads a LocationDb from a text file. Each line of the file
+    /// should be of the form "name,lat,lon". The name may itself contain
+    /// commas. Blank lines and lines starting with '#' are ignored.
+    #[time]
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        info!("Reading location database {:?}", path);
+
+        let contents = read_to_string(path)?;
+        let mut places = Vec::new();
+
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.rsplitn(3, ',');
+            let (Some(lon), Some(lat), Some(name)) = (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(format!(
+                    "Line {} of {:?} is not of the form 'name,lat,lon'",
+                    line_number + 1,
+                    path
+                ))?;
+            };
+
+            places.push((
+                name.trim().to_string(),
+                lat.trim().parse()?,
+                lon.trim().parse()?,
+            ));
+        }
+
+        info!("Read {} places from {:?}", places.len(), path);
+        Ok(Self::new(places))
+    }
+
+    /// Creates a new LocationDb from a list of (name, lat, lon) tuples.
+    pub fn new(places: Vec<(String, f64, f64)>) -> Self {
+        Self {
+            index: SpatialIndex::new(places),
+        }
+    }
+
+    /// Returns the name of the nearest place to the specified lat-lon,
+    /// or None if the database is empty.
+    pub fn nearest(&self, lat: f64, lon: f64) -> Option<&str> {
+        self.index.nearest(lat, lon).map(|name| name.as_str())
+    }
+}
+
+impl<T> Default for SpatialIndex<T> {
+    fn default() -> Self {
+        Self { places: Vec::new() }
+    }
+}
+
+impl<T> SpatialIndex<T> {
+    /// Creates a new SpatialIndex from a list of (value, lat, lon) tuples.
+    pub fn new(places: Vec<(T, f64, f64)>) -> Self {
+        let mut places: Vec<_> = places
+            .into_iter()
+            .map(|(value, lat, lon)| Place {
+                value,
+                xyz: to_unit_sphere(lat, lon),
+            })
+            .collect();
+
+        build_tree(&mut places, 0);
+        Self { places }
+    }
+
+    /// Returns the value of the nearest place to the specified lat-lon,
+    /// or None if the index is empty.
+    pub fn nearest(&self, lat: f64, lon: f64) -> Option<&T> {
+        let target = to_unit_sphere(lat, lon);
+        let mut best = None;
+        find_nearest(&self.places, &target, 0, &mut best);
+        best.map(|(place, _)| &place.value)
+    }
+}
+
+/// Fills in the 'location' field of the points in the GPX with the name
+/// of the nearest place in the database. Each lookup is O(log n) in
+/// the size of the database, so it is cheap enough to do every point,
+/// which means that the Stages (which are built from clones of the points)
+/// will automatically pick up the locations if this is called before
+/// stage detection.
+#[time]
+pub fn reverse_geocode(gpx: &mut EnrichedGpx, db: &LocationDb) {
+    for p in gpx.points.iter_mut() {
+        p.location = db.nearest(p.lat, p.lon).map(|name| name.to_string());
+    }
+}
+
+fn to_unit_sphere(lat: f64, lon: f64) -> [f64; 3] {
+    let (lat, lon) = (lat.to_radians(), lon.to_radians());
+    [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+}
+
+fn distance_squared(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
+
+fn build_tree<T>(places: &mut [Place<T>], depth: usize) {
+    if places.len() <= 1 {
+        return;
+    }
+
+    let axis = depth % 3;
+    let mid = places.len() / 2;
+    places.select_nth_unstable_by(mid, |a, b| a.xyz[axis].total_cmp(&b.xyz[axis]));
+
+    let (left, right) = places.split_at_mut(mid);
+    build_tree(left, depth + 1);
+    build_tree(&mut right[1..], depth + 1);
+}
+
+fn find_nearest<'db, T>(
+    places: &'db [Place<T>],
+    target: &[f64; 3],
+    depth: usize,
+    best: &mut Option<(&'db Place<T>, f64)>,
+) {
+    if places.is_empty() {
+        return;
+    }
+
+    let axis = depth % 3;
+    let mid = places.len() / 2;
+    let place = &places[mid];
+
+    let d = distance_squared(&place.xyz, target);
+    if best.is_none_or(|(_, best_d)| d < best_d) {
+        *best = Some((place, d));
+    }
+
+    let diff = target[axis] - place.xyz[axis];
+    let (near, far) = if diff < 0.0 {
+        (&places[..mid], &places[mid + 1..])
+    } else {
+        (&places[mid + 1..], &places[..mid])
+    };
+
+    find_nearest(near, target, depth + 1, best);
+
+    // Only search the other side of the splitting plane if it
+    // could contain something closer than what we already have.
+    if best.is_none_or(|(_, best_d)| diff * diff < best_d) {
+        find_nearest(far, target, depth + 1, best);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{make_enriched_gpx, temp_file, track_point};
+
+    const PLACES: &str = "# name,lat,lon
+London,51.5074,-0.1278
+Oxford,51.7520,-1.2577
+
+Cambridge, UK,52.2053,0.1218
+Brighton,50.8225,-0.1372
+";
+
+    #[test]
+    fn reads_a_location_db_from_a_file() {
+        let db = LocationDb::from_file(&temp_file("places.csv", PLACES)).unwrap();
+        assert_eq!(db.nearest(52.2, 0.12), Some("Cambridge, UK"));
+    }
+
+    #[test]
+    fn rejects_a_malformed_location_db() {
+        let path = temp_file("bad-places.csv", "London,51.5074\n");
+        assert!(LocationDb::from_file(&path).is_err());
+    }
+
+    #[test]
+    fn reverse_geocodes_each_point_to_the_nearest_place() {
+        let db = LocationDb::from_file(&temp_file("geocode-places.csv", PLACES)).unwrap();
+        let mut gpx = make_enriched_gpx(vec![
+            track_point(51.50, -0.10, None),
+            track_point(51.74, -1.20, None),
+            track_point(50.85, -0.20, None),
+            track_point(52.10, 0.00, None),
+        ]);
+
+        reverse_geocode(&mut gpx, &db);
+
+        let locations: Vec<_> = gpx.points.iter().map(|p| p.location.as_deref()).collect();
+        assert_eq!(
+            locations,
+            [
+                Some("London"),
+                Some("Oxford"),
+                Some("Brighton"),
+                Some("Cambridge, UK")
+            ]
+        );
+    }
+
+    #[test]
+    fn an_empty_db_has_no_nearest_place() {
+        assert_eq!(LocationDb::new(Vec::new()).nearest(51.5, 0.0), None);
+    }
+}
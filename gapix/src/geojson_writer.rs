@@ -0,0 +1,195 @@
+//! Contains the functionality relating to writing GeoJSON files,
+//! see https://datatracker.ietf.org/doc/html/rfc7946
+//! These are handy for dropping a ride into web-based map tools.
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use logging_timer::time;
+
+use crate::{
+    formatting::TimeFormat,
+    model::{EnrichedGpx, EnrichedTrackPoint},
+    stage::StageList,
+};
+
+/// Writes the GPX, and any stages that have been detected in it,
+/// as a GeoJSON file.
+#[time]
+pub fn write_geojson_file(
+    output_file: &Path,
+    gpx: &EnrichedGpx,
+    stages: &StageList,
+    time_format: &TimeFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut w = BufWriter::new(File::create(output_file)?);
+    write_geojson_to_writer(&mut w, gpx, stages, time_format)?;
+    w.flush()?;
+
+    let metadata = std::fs::metadata(output_file)?;
+    eprintln!(
+        "Wrote file {:?}, {} Kb",
+        &output_file,
+        metadata.len() / 1024
+    );
+    Ok(())
+}
+
+/// Writes a GeoJSON FeatureCollection. The track is written as a single
+/// LineString feature, followed by one Point feature for the start of
+/// each Stage.
+pub fn write_geojson_to_writer<W: Write>(
+    w: &mut W,
+    gpx: &EnrichedGpx,
+    stages: &StageList,
+    time_format: &TimeFormat,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(w, "{{")?;
+    writeln!(w, "  \"type\": \"FeatureCollection\",")?;
+    writeln!(w, "  \"features\": [")?;
+    write_track_feature(w, gpx)?;
+
+    for (idx, stage) in stages.iter().enumerate() {
+        writeln!(w, ",")?;
+        writeln!(w, "    {{")?;
+        writeln!(w, "      \"type\": \"Feature\",")?;
+        writeln!(w, "      \"geometry\": {{")?;
+        writeln!(w, "        \"type\": \"Point\",")?;
+        writeln!(w, "        \"coordinates\": {}", coordinates(&stage.start))?;
+        writeln!(w, "      }},")?;
+        writeln!(w, "      \"properties\": {{")?;
+        writeln!(w, "        \"stage\": {},", idx + 1)?;
+        writeln!(
+            w,
+            "        \"stage_type\": {},",
+            json_string(&stage.stage_type.to_string())
+        )?;
+        writeln!(w, "        \"index\": {},", stage.start.index)?;
+        if let Some(time) = stage.start.time {
+            writeln!(
+                w,
+                "        \"time\": {},",
+                json_string(&time_format.format(&time))
+            )?;
+        }
+        writeln!(w, "        \"distance_km\": {:.3}", stage.distance_km())?;
+        writeln!(w, "      }}")?;
+        write!(w, "    }}")?;
+    }
+
+    writeln!(w)?;
+    writeln!(w, "  ]")?;
+    writeln!(w, "}}")?;
+    Ok(())
+}
+
+fn write_track_feature<W: Write>(w: &mut W, gpx: &EnrichedGpx) -> Result<(), Box<dyn Error>> {
+    writeln!(w, "    {{")?;
+    writeln!(w, "      \"type\": \"Feature\",")?;
+    writeln!(w, "      \"geometry\": {{")?;
+    writeln!(w, "        \"type\": \"LineString\",")?;
+    writeln!(w, "        \"coordinates\": [")?;
+    for (idx, p) in gpx.points.iter().enumerate() {
+        let separator = if idx == gpx.last_valid_idx() { "" } else { "," };
+        writeln!(w, "          {}{}", coordinates(p), separator)?;
+    }
+    writeln!(w, "        ]")?;
+    writeln!(w, "      }},")?;
+
+    writeln!(w, "      \"properties\": {{")?;
+    if let Some(name) = &gpx.track_name {
+        writeln!(w, "        \"name\": {},", json_string(name))?;
+    }
+    if let Some(last) = gpx.points.last() {
+        if let Some(duration) = last.running_delta_time {
+            writeln!(
+                w,
+                "        \"duration_seconds\": {},",
+                duration.whole_seconds()
+            )?;
+        }
+        if let Some(ascent) = last.running_ascent_metres {
+            writeln!(w, "        \"ascent_metres\": {:.1},", ascent)?;
+        }
+        writeln!(
+            w,
+            "        \"distance_km\": {:.3}",
+            last.running_metres / 1000.0
+        )?;
+    } else {
+        writeln!(w, "        \"distance_km\": 0")?;
+    }
+    writeln!(w, "      }}")?;
+    write!(w, "    }}")?;
+
+    Ok(())
+}
+
+/// Formats the coordinates of a point. Note that GeoJSON
+/// puts the longitude first.
+fn coordinates(p: &EnrichedTrackPoint) -> String {
+    match p.ele {
+        Some(ele) => format!("[{:.6}, {:.6}, {:.1}]", p.lon, p.lat, ele),
+        None => format!("[{:.6}, {:.6}]", p.lon, p.lat),
+    }
+}
+
+/// Makes a quoted JSON string, escaping any characters that need it.
+fn json_string(value: &str) -> String {
+    let mut s = String::with_capacity(value.len() + 2);
+    s.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            c if c.is_control() => s.push_str(&format!("\\u{:04x}", c as u32)),
+            c => s.push(c),
+        }
+    }
+    s.push('"');
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{
+        stage::{detect_stages, StageDetectionParameters},
+        test_helpers::ride,
+    };
+
+    #[test]
+    fn writes_the_track_and_one_point_per_stage() {
+        let gpx = ride(&[(20.0, 1200), (0.0, 900), (20.0, 1200)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 3);
+
+        let mut bytes = Vec::new();
+        write_geojson_to_writer(&mut bytes, &gpx, &stages, &TimeFormat::default()).unwrap();
+        let json: Value = serde_json::from_slice(&bytes).unwrap();
+
+        assert_eq!(json["type"], "FeatureCollection");
+        let features = json["features"].as_array().unwrap();
+        assert_eq!(features.len(), 1 + stages.len());
+        assert_eq!(features[0]["geometry"]["type"], "LineString");
+        assert_eq!(
+            features[0]["geometry"]["coordinates"]
+                .as_array()
+                .unwrap()
+                .len(),
+            gpx.points.len()
+        );
+        for feature in &features[1..] {
+            assert_eq!(feature["geometry"]["type"], "Point");
+        }
+    }
+}
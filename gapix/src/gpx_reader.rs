@@ -1,25 +1,30 @@
 #![allow(clippy::single_match)]
 
-use core::{panic, str};
+use core::str;
 use std::{
-    borrow::{Borrow, Cow},
+    borrow::Cow,
     collections::{hash_map::Entry, HashMap},
-    error::Error,
+    fmt::Display,
     fs::File,
-    io::BufReader,
+    io::{BufRead, BufReader},
     path::Path,
+    str::FromStr,
 };
 
 use log::info;
 use logging_timer::time;
 use quick_xml::{
     events::{BytesDecl, BytesStart, Event},
-    Reader,
+    Reader, Writer,
 };
 use time::{format_description::well_known, OffsetDateTime};
 
-use crate::model::{
-    Declaration, Extensions, Gpx, GpxInfo, Link, Metadata, Track, TrackPoint, TrackSegment,
+use crate::{
+    error::GapixError,
+    model::{
+        Bounds, Copyright, Declaration, Email, Extensions, FixInfo, FixType, Gpx, GpxInfo, Link,
+        Metadata, Person, Track, TrackExtensions, TrackPoint, TrackSegment,
+    },
 };
 
 /*
@@ -28,25 +33,46 @@ use crate::model::{
    <metadata>                  type="metadataType"     parse_metadata
    <wpt>                       type="wptType"          n.a.
    <rte>                       type="rteType"          n.a.
-   <extensions>                type="extensionsType"   n.a.
+   <extensions>                type="extensionsType"   parse_raw_extensions
    <trk>                       type="trkType"          parse_track
+       <extensions>            type="extensionsType"   parse_track_extensions
        <trkseg>                type="trksegType"       parse_track_segment
            <trkpt>             type="wptType"          parse_trackpoint
                <extensions>    type="extensions"       parse_trackpoint_extensions
 
+GPX 1.0 (https://www.topografix.com/GPX/1/0/gpx.xsd) has no <metadata>
+element. Instead, the equivalent elements appear directly under <gpx>, and
+links are written as <url> and <urlname> pairs. We collect those in a
+Gpx10Header and convert them into a Metadata so that the rest of the
+program sees the same model shape regardless of the version. Trackpoints
+may have <speed> and <course> as direct children, which we move into the
+Extensions.
 */
 /// The XSD, which defines the format of a GPX file, is at https://www.topografix.com/GPX/1/1/gpx.xsd
 /// This function doesn't parse everything, just the things that appear in my Garmin files.
+/// GPX 1.0 files are also accepted, and are upgraded to 1.1 as they are read.
 #[time]
-pub fn read_gpx_file(input_file: &Path) -> Result<Gpx, Box<dyn Error>> {
+pub fn read_gpx_file(input_file: &Path) -> Result<Gpx, GapixError> {
     info!("Reading GPX file {:?}", input_file);
-    let mut reader = Reader::from_file(input_file)?;
+    read_gpx_from_reader(BufReader::new(File::open(input_file)?), input_file)
+}
+
+/// Reads a GPX from any source, such as stdin. 'filename' is only used
+/// to name the Gpx, from which the output filenames are made.
+pub fn read_gpx_from_reader<R: BufRead>(input: R, filename: &Path) -> Result<Gpx, GapixError> {
+    let mut reader = Reader::from_reader(input);
     let mut buf: Vec<u8> = Vec::with_capacity(512);
 
     let mut declaration = None;
     let mut gpx_info = None;
     let mut metadata = None;
     let mut tracks: Vec<Track> = Vec::new();
+    let mut extensions = None;
+    // The elements that we do not understand.
+    let mut raw_children = Writer::new(Vec::new());
+    let mut is_gpx_10 = false;
+    let mut header_10 = Gpx10Header::default();
+    let mut skip_buf: Vec<u8> = Vec::new();
 
     loop {
         match reader.read_event_into(&mut buf) {
@@ -55,7 +81,9 @@ pub fn read_gpx_file(input_file: &Path) -> Result<Gpx, Box<dyn Error>> {
             }
             Ok(Event::Start(e)) => match e.name().as_ref() {
                 b"gpx" => {
-                    gpx_info = Some(parse_gpx_info(&e)?);
+                    let info = parse_gpx_info(&e)?;
+                    is_gpx_10 = info.version == "1.0";
+                    gpx_info = Some(info);
                 }
                 b"metadata" => {
                     metadata = Some(parse_metadata(&mut buf, &mut reader)?);
@@ -64,36 +92,95 @@ pub fn read_gpx_file(input_file: &Path) -> Result<Gpx, Box<dyn Error>> {
                     let track = parse_track(&mut buf, &mut reader)?;
                     tracks.push(track);
                 }
-                _ => (),
+                b"extensions" => {
+                    extensions = parse_raw_extensions(&mut buf, &mut reader)?;
+                }
+                b"wpt" | b"rte" => {
+                    // Not supported. Skip the entire element so that its
+                    // children are not mistaken for top-level elements.
+                    let end = e.to_end().into_owned();
+                    reader
+                        .read_to_end_into(end.name(), &mut skip_buf)
+                        .map_err(|e| xml_error(&reader, e))?;
+                    skip_buf.clear();
+                }
+                b"name" if is_gpx_10 => {
+                    header_10.name = Some(read_inner_as_string(&mut buf, &mut reader)?);
+                }
+                b"desc" if is_gpx_10 => {
+                    header_10.desc = Some(read_inner_as_string(&mut buf, &mut reader)?);
+                }
+                b"author" if is_gpx_10 => {
+                    header_10.author = Some(read_inner_as_string(&mut buf, &mut reader)?);
+                }
+                b"email" if is_gpx_10 => {
+                    header_10.email = Some(read_inner_as_string(&mut buf, &mut reader)?);
+                }
+                b"url" if is_gpx_10 => {
+                    header_10.url = Some(read_inner_as_string(&mut buf, &mut reader)?);
+                }
+                b"urlname" if is_gpx_10 => {
+                    header_10.urlname = Some(read_inner_as_string(&mut buf, &mut reader)?);
+                }
+                b"time" if is_gpx_10 => {
+                    header_10.time = Some(read_inner_as_time(&mut buf, &mut reader)?);
+                }
+                b"keywords" if is_gpx_10 => {
+                    header_10.keywords = Some(read_inner_as_string(&mut buf, &mut reader)?);
+                }
+                b"bounds" if is_gpx_10 => {
+                    header_10.bounds = Some(parse_bounds(&e)?);
+                }
+                _ => {
+                    let start = e.into_owned();
+                    copy_element(start, &mut buf, &mut reader, &mut raw_children)?;
+                }
+            },
+            Ok(Event::Empty(e)) => match e.name().as_ref() {
+                b"bounds" if is_gpx_10 => {
+                    header_10.bounds = Some(parse_bounds(&e)?);
+                }
+                _ => {
+                    raw_children
+                        .write_event(Event::Empty(e))
+                        .map_err(|err| xml_error(&reader, err))?;
+                }
             },
             Ok(Event::End(e)) => match e.name().as_ref() {
                 b"gpx" => {
                     if declaration.is_none() {
-                        Err("Did not find the 'xml' declaration element")?;
+                        return Err(GapixError::MissingElement("xml"));
                     }
                     if gpx_info.is_none() {
-                        Err("Did not find the 'gpx' element")?;
+                        return Err(GapixError::MissingElement("gpx"));
                     }
+
+                    let mut info = gpx_info.unwrap();
+                    if is_gpx_10 {
+                        metadata = Some(header_10.into_metadata());
+                        upgrade_gpx_info_to_11(&mut info);
+                    }
+
                     if metadata.is_none() {
-                        Err("Did not find the 'metadata' element")?;
+                        return Err(GapixError::MissingElement("metadata"));
                     }
 
                     let gpx = Gpx {
-                        filename: input_file.to_owned(),
+                        filename: filename.to_owned(),
                         declaration: declaration.unwrap(),
-                        info: gpx_info.unwrap(),
+                        info,
                         metadata: metadata.unwrap(),
                         tracks,
+                        extensions,
+                        raw_children: raw_to_string(raw_children)?,
                     };
 
                     return Ok(gpx);
                 }
                 _ => (),
             },
-            Ok(Event::Eof) => {
-                panic!("Reached EOF unexpectedly (before the closing GPX tag). File is probably corrupt.");
-            }
-            Err(e) => panic!("Error at position {}: {:?}", reader.error_position(), e),
+            Ok(Event::Eof) => return Err(GapixError::UnexpectedEof("gpx")),
+            Err(e) => return Err(xml_error(&reader, e)),
             _ => (),
         }
 
@@ -103,7 +190,7 @@ pub fn read_gpx_file(input_file: &Path) -> Result<Gpx, Box<dyn Error>> {
 
 /// Parses an XML declaration, i.e. the very first line of the file which is:
 ///     <?xml version="1.0" encoding="UTF-8"?>
-fn parse_decl(decl: &BytesDecl<'_>) -> Result<Declaration, Box<dyn Error>> {
+pub fn parse_decl(decl: &BytesDecl<'_>) -> Result<Declaration, GapixError> {
     Ok(Declaration {
         version: rcow_to_string(decl.version())?,
         encoding: orcow_to_string(decl.encoding())?,
@@ -111,17 +198,17 @@ fn parse_decl(decl: &BytesDecl<'_>) -> Result<Declaration, Box<dyn Error>> {
     })
 }
 
-fn parse_gpx_info(tag: &BytesStart<'_>) -> Result<GpxInfo, Box<dyn Error>> {
+fn parse_gpx_info(tag: &BytesStart<'_>) -> Result<GpxInfo, GapixError> {
     let mut attributes = parse_attributes(tag)?;
 
     let creator = match attributes.entry("creator".to_string()) {
         Entry::Occupied(occupied_entry) => occupied_entry.remove(),
-        _ => return Err("Mandatory attribute 'creator' was missing on the GPX element")?,
+        _ => return Err(missing_attribute(tag, "creator")),
     };
 
     let version = match attributes.entry("version".to_string()) {
         Entry::Occupied(occupied_entry) => occupied_entry.remove(),
-        _ => return Err("Mandatory attribute 'version' was missing on the GPX element")?,
+        _ => return Err(missing_attribute(tag, "version")),
     };
 
     Ok(GpxInfo {
@@ -131,71 +218,309 @@ fn parse_gpx_info(tag: &BytesStart<'_>) -> Result<GpxInfo, Box<dyn Error>> {
     })
 }
 
-fn parse_metadata(
+/// Changes the version and namespaces of a GPX 1.0 file to 1.1, since
+/// that is the format we write.
+fn upgrade_gpx_info_to_11(info: &mut GpxInfo) {
+    info.version = "1.1".to_string();
+
+    for value in info.attributes.values_mut() {
+        *value = value.replace(
+            "http://www.topografix.com/GPX/1/0",
+            "http://www.topografix.com/GPX/1/1",
+        );
+    }
+}
+
+/// The elements that appear directly under the <gpx> element in
+/// GPX 1.0 and which were moved into <metadata> in GPX 1.1.
+#[derive(Default)]
+struct Gpx10Header {
+    name: Option<String>,
+    desc: Option<String>,
+    author: Option<String>,
+    email: Option<String>,
+    url: Option<String>,
+    urlname: Option<String>,
+    time: Option<OffsetDateTime>,
+    keywords: Option<String>,
+    bounds: Option<Bounds>,
+}
+
+impl Gpx10Header {
+    fn into_metadata(self) -> Metadata {
+        // In 1.0 the email is a plain string; in 1.1 it is split into
+        // an id and a domain.
+        let email = self.email.and_then(|e| {
+            e.split_once('@').map(|(id, domain)| Email {
+                id: id.to_string(),
+                domain: domain.to_string(),
+            })
+        });
+
+        let author = if self.author.is_some() || email.is_some() {
+            Some(Person {
+                name: self.author,
+                email,
+                link: None,
+            })
+        } else {
+            None
+        };
+
+        let links = self
+            .url
+            .map(|href| Link {
+                href,
+                text: self.urlname,
+                r#type: None,
+            })
+            .into_iter()
+            .collect();
+
+        Metadata {
+            name: self.name,
+            desc: self.desc,
+            author,
+            copyright: None,
+            links,
+            time: self.time,
+            keywords: self.keywords,
+            bounds: self.bounds,
+        }
+    }
+}
+
+fn parse_metadata<R: BufRead>(
     buf: &mut Vec<u8>,
-    reader: &mut Reader<BufReader<File>>,
-) -> Result<Metadata, Box<dyn Error>> {
-    let mut href = None;
-    let mut text = None;
-    let mut mime_type = None;
-    let mut time = None;
+    reader: &mut Reader<R>,
+) -> Result<Metadata, GapixError> {
+    let mut name = None;
     let mut desc = None;
+    let mut author = None;
+    let mut copyright = None;
+    let mut links = Vec::new();
+    let mut time = None;
+    let mut keywords = None;
+    let mut bounds = None;
 
     loop {
         match reader.read_event_into(buf) {
-            // TODO: We could break out a 'parse_link' function, as it is a defined
-            // element type in the XSD.
             Ok(Event::Start(e)) => match e.name().as_ref() {
+                b"name" => {
+                    name = Some(read_inner_as_string(buf, reader)?);
+                }
+                b"desc" => {
+                    desc = Some(read_inner_as_string(buf, reader)?);
+                }
+                b"author" => {
+                    author = Some(parse_person(buf, reader)?);
+                }
+                b"copyright" => {
+                    let copyright_author = read_attribute_as_string(&e, "author")?;
+                    copyright = Some(parse_copyright(copyright_author, buf, reader)?);
+                }
+                b"link" => {
+                    let href = read_attribute_as_string(&e, "href")?;
+                    links.push(parse_link(href, buf, reader)?);
+                }
+                b"time" => {
+                    time = Some(read_inner_as_time(buf, reader)?);
+                }
+                b"keywords" => {
+                    keywords = Some(read_inner_as_string(buf, reader)?);
+                }
+                b"bounds" => {
+                    bounds = Some(parse_bounds(&e)?);
+                }
+                e => return Err(unexpected_element(reader, e)),
+            },
+            Ok(Event::Empty(e)) => match e.name().as_ref() {
                 b"link" => {
-                    href = Some(read_attribute_as_string(&e, "href")?);
+                    links.push(Link {
+                        href: read_attribute_as_string(&e, "href")?,
+                        text: None,
+                        r#type: None,
+                    });
+                }
+                b"bounds" => {
+                    bounds = Some(parse_bounds(&e)?);
                 }
+                e => return Err(unexpected_element(reader, e)),
+            },
+            Ok(Event::End(e)) => match e.name().as_ref() {
+                b"metadata" => {
+                    return Ok(Metadata {
+                        name,
+                        desc,
+                        author,
+                        copyright,
+                        links,
+                        time,
+                        keywords,
+                        bounds,
+                    });
+                }
+                _ => {}
+            },
+            // Ignore spurious Event::Text, I think they are newlines.
+            Ok(Event::Text(_)) => {}
+            e => return Err(unexpected_event(reader, e, "metadata")),
+        }
+    }
+}
+
+/// Parses a <link> element. The 'href' attribute is mandatory and must
+/// have already been extracted from the opening tag by the caller.
+fn parse_link<R: BufRead>(
+    href: String,
+    buf: &mut Vec<u8>,
+    reader: &mut Reader<R>,
+) -> Result<Link, GapixError> {
+    let mut text = None;
+    let mut mime_type = None;
+
+    loop {
+        match reader.read_event_into(buf) {
+            Ok(Event::Start(e)) => match e.name().as_ref() {
                 b"text" => {
                     text = Some(read_inner_as_string(buf, reader)?);
                 }
                 b"type" => {
                     mime_type = Some(read_inner_as_string(buf, reader)?);
                 }
-                b"time" => {
-                    time = Some(read_inner_as_time(buf, reader)?);
+                e => return Err(unexpected_element(reader, e)),
+            },
+            Ok(Event::End(e)) => match e.name().as_ref() {
+                b"link" => {
+                    return Ok(Link {
+                        href,
+                        text,
+                        r#type: mime_type,
+                    });
                 }
-                b"desc" => {
-                    desc = Some(read_inner_as_string(buf, reader)?);
+                _ => {}
+            },
+            // Ignore spurious Event::Text, I think they are newlines.
+            Ok(Event::Text(_)) => {}
+            e => return Err(unexpected_event(reader, e, "link")),
+        }
+    }
+}
+
+/// Parses an <author> element, which is of type 'personType' in the XSD.
+fn parse_person<R: BufRead>(
+    buf: &mut Vec<u8>,
+    reader: &mut Reader<R>,
+) -> Result<Person, GapixError> {
+    let mut name = None;
+    let mut email = None;
+    let mut link = None;
+
+    loop {
+        match reader.read_event_into(buf) {
+            Ok(Event::Start(e)) => match e.name().as_ref() {
+                b"name" => {
+                    name = Some(read_inner_as_string(buf, reader)?);
+                }
+                b"email" => {
+                    email = Some(parse_email(&e)?);
+                }
+                b"link" => {
+                    let href = read_attribute_as_string(&e, "href")?;
+                    link = Some(parse_link(href, buf, reader)?);
+                }
+                e => return Err(unexpected_element(reader, e)),
+            },
+            Ok(Event::Empty(e)) => match e.name().as_ref() {
+                b"email" => {
+                    email = Some(parse_email(&e)?);
+                }
+                b"link" => {
+                    link = Some(Link {
+                        href: read_attribute_as_string(&e, "href")?,
+                        text: None,
+                        r#type: None,
+                    });
                 }
-                e => panic!("Unexpected element {:?}", bytes_to_string(e)?),
+                e => return Err(unexpected_element(reader, e)),
             },
             Ok(Event::End(e)) => match e.name().as_ref() {
-                b"metadata" => {
-                    if let Some(href) = href {
-                        return Ok(Metadata {
-                            link: Link {
-                                href,
-                                text,
-                                r#type: mime_type,
-                            },
-                            time,
-                            desc,
-                        });
-                    } else {
-                        Err("href attribute not found, but it is mandatory according to the XSD")?;
-                    }
+                b"author" => {
+                    return Ok(Person { name, email, link });
                 }
                 _ => {}
             },
             // Ignore spurious Event::Text, I think they are newlines.
             Ok(Event::Text(_)) => {}
-            e => panic!("Unexpected element {:?}", e),
+            e => return Err(unexpected_event(reader, e, "author")),
         }
     }
 }
 
-fn parse_track(
+/// Parses an <email id="..." domain="..." /> element.
+fn parse_email(tag: &BytesStart<'_>) -> Result<Email, GapixError> {
+    Ok(Email {
+        id: read_attribute_as_string(tag, "id")?,
+        domain: read_attribute_as_string(tag, "domain")?,
+    })
+}
+
+/// Parses a <copyright> element. The 'author' attribute is mandatory and must
+/// have already been extracted from the opening tag by the caller.
+fn parse_copyright<R: BufRead>(
+    author: String,
     buf: &mut Vec<u8>,
-    reader: &mut Reader<BufReader<File>>,
-) -> Result<Track, Box<dyn Error>> {
+    reader: &mut Reader<R>,
+) -> Result<Copyright, GapixError> {
+    let mut year = None;
+    let mut license = None;
+
+    loop {
+        match reader.read_event_into(buf) {
+            Ok(Event::Start(e)) => match e.name().as_ref() {
+                b"year" => {
+                    year = Some(parse_value(&read_inner_as_string(buf, reader)?)?);
+                }
+                b"license" => {
+                    license = Some(read_inner_as_string(buf, reader)?);
+                }
+                e => return Err(unexpected_element(reader, e)),
+            },
+            Ok(Event::End(e)) => match e.name().as_ref() {
+                b"copyright" => {
+                    return Ok(Copyright {
+                        author,
+                        year,
+                        license,
+                    });
+                }
+                _ => {}
+            },
+            // Ignore spurious Event::Text, I think they are newlines.
+            Ok(Event::Text(_)) => {}
+            e => return Err(unexpected_event(reader, e, "copyright")),
+        }
+    }
+}
+
+/// Parses a <bounds minlat="..." minlon="..." maxlat="..." maxlon="..." /> element.
+fn parse_bounds(tag: &BytesStart<'_>) -> Result<Bounds, GapixError> {
+    Ok(Bounds {
+        min_lat: read_attribute_as_f64(tag, "minlat")?,
+        min_lon: read_attribute_as_f64(tag, "minlon")?,
+        max_lat: read_attribute_as_f64(tag, "maxlat")?,
+        max_lon: read_attribute_as_f64(tag, "maxlon")?,
+    })
+}
+
+fn parse_track<R: BufRead>(buf: &mut Vec<u8>, reader: &mut Reader<R>) -> Result<Track, GapixError> {
     let mut name = None;
     let mut track_type = None;
     let mut segments = Vec::new();
+    let mut comment = None;
     let mut desc = None;
+    let mut extensions = None;
+    let mut raw_children = Writer::new(Vec::new());
 
     loop {
         match reader.read_event_into(buf) {
@@ -206,21 +531,42 @@ fn parse_track(
                 b"type" => {
                     track_type = Some(read_inner_as_string(buf, reader)?);
                 }
+                b"cmt" => {
+                    comment = Some(read_inner_as_string(buf, reader)?);
+                }
                 b"desc" => {
                     desc = Some(read_inner_as_string(buf, reader)?);
                 }
+                b"extensions" => {
+                    extensions = Some(parse_track_extensions(buf, reader)?);
+                }
                 b"trkseg" => {
                     let segment = parse_track_segment(buf, reader)?;
                     segments.push(segment);
                 }
-                e => panic!("Unexpected element {:?}", bytes_to_string(e)?),
+                b"url" | b"urlname" => {
+                    // GPX 1.0 only. We have nowhere to store track links.
+                    read_inner_as_string(buf, reader)?;
+                }
+                _ => {
+                    let start = e.into_owned();
+                    copy_element(start, buf, reader, &mut raw_children)?;
+                }
             },
+            Ok(Event::Empty(e)) => {
+                raw_children
+                    .write_event(Event::Empty(e))
+                    .map_err(|err| xml_error(reader, err))?;
+            }
             Ok(Event::End(e)) => match e.name().as_ref() {
                 b"trk" => {
                     return Ok(Track {
                         name,
                         r#type: track_type,
+                        comment,
                         desc,
+                        extensions,
+                        raw_children: raw_to_string(raw_children)?,
                         segments,
                     })
                 }
@@ -228,99 +574,299 @@ fn parse_track(
             },
             // Ignore spurious Event::Text, I think they are newlines.
             Ok(Event::Text(_)) => {}
-            e => panic!("Unexpected element {:?}", e),
+            e => return Err(unexpected_event(reader, e, "trk")),
         }
     }
 }
 
-fn parse_track_segment(
+/// Parses the <extensions> of a <trk>. The Garmin <DisplayColor> is parsed,
+/// any other extension elements are kept as raw XML.
+fn parse_track_extensions<R: BufRead>(
     buf: &mut Vec<u8>,
-    reader: &mut Reader<BufReader<File>>,
-) -> Result<TrackSegment, Box<dyn Error>> {
-    let mut points = Vec::new();
+    reader: &mut Reader<R>,
+) -> Result<TrackExtensions, GapixError> {
+    let mut display_color = None;
+    let mut in_track_extension = false;
+    let mut other = Writer::new(Vec::new());
+    // How deep we are inside an element that we do not understand.
+    let mut other_depth = 0;
+
+    loop {
+        match reader.read_event_into(buf) {
+            Ok(Event::Start(e)) if other_depth == 0 => match e.local_name().as_ref() {
+                b"TrackExtension" => in_track_extension = true,
+                b"DisplayColor" if in_track_extension => {
+                    display_color = Some(read_inner_as_string(buf, reader)?);
+                }
+                name if in_track_extension => return Err(unexpected_element(reader, name)),
+                _ => {
+                    other_depth = 1;
+                    other
+                        .write_event(Event::Start(e))
+                        .map_err(|err| xml_error(reader, err))?;
+                }
+            },
+            Ok(Event::Empty(e)) if other_depth == 0 && !in_track_extension => {
+                other
+                    .write_event(Event::Empty(e))
+                    .map_err(|err| xml_error(reader, err))?;
+            }
+            Ok(Event::End(e)) if other_depth == 0 => match e.local_name().as_ref() {
+                b"TrackExtension" => in_track_extension = false,
+                b"DisplayColor" => { /* ignore, just the closing tag */ }
+                b"extensions" => {
+                    let other = bytes_to_string(&other.into_inner())?;
+                    return Ok(TrackExtensions {
+                        display_color,
+                        other: (!other.is_empty()).then_some(other),
+                    });
+                }
+                name => return Err(unexpected_element(reader, name)),
+            },
+            // Ignore spurious Event::Text, I think they are newlines.
+            Ok(Event::Text(_)) if other_depth == 0 => {}
+            Ok(e) if other_depth > 0 && !matches!(e, Event::Eof) => {
+                match e {
+                    Event::Start(_) => other_depth += 1,
+                    Event::End(_) => other_depth -= 1,
+                    _ => {}
+                }
+                other.write_event(e).map_err(|err| xml_error(reader, err))?;
+            }
+            e => return Err(unexpected_event(reader, e, "extensions")),
+        }
+    }
+}
 
-    while let Some(point) = parse_trackpoint(buf, reader) {
-        let point = point?;
-        points.push(point);
+/// Reads the children of an <extensions> element as raw XML, without
+/// trying to understand them. Returns None if there were no children.
+fn parse_raw_extensions<R: BufRead>(
+    buf: &mut Vec<u8>,
+    reader: &mut Reader<R>,
+) -> Result<Option<String>, GapixError> {
+    let mut raw = Writer::new(Vec::new());
+
+    loop {
+        match reader.read_event_into(buf) {
+            Ok(Event::Start(e)) => {
+                let start = e.into_owned();
+                copy_element(start, buf, reader, &mut raw)?;
+            }
+            Ok(Event::Empty(e)) => {
+                raw.write_event(Event::Empty(e))
+                    .map_err(|err| xml_error(reader, err))?;
+            }
+            Ok(Event::End(e)) if e.name().as_ref() == b"extensions" => {
+                return raw_to_string(raw);
+            }
+            // Ignore spurious Event::Text, I think they are newlines.
+            Ok(Event::Text(_)) => {}
+            e => return Err(unexpected_event(reader, e, "extensions")),
+        }
     }
+}
+
+/// Copies the element opened by 'start', including all its children,
+/// to 'raw'. This is how we keep the elements, and the extension
+/// elements, that we do not understand.
+fn copy_element<R: BufRead>(
+    start: BytesStart<'static>,
+    buf: &mut Vec<u8>,
+    reader: &mut Reader<R>,
+    raw: &mut Writer<Vec<u8>>,
+) -> Result<(), GapixError> {
+    raw.write_event(Event::Start(start))
+        .map_err(|err| xml_error(reader, err))?;
+    let mut depth = 1;
 
-    Ok(TrackSegment { points })
+    while depth > 0 {
+        match reader.read_event_into(buf) {
+            Ok(e) if !matches!(e, Event::Eof) => {
+                match e {
+                    Event::Start(_) => depth += 1,
+                    Event::End(_) => depth -= 1,
+                    _ => {}
+                }
+                raw.write_event(e).map_err(|err| xml_error(reader, err))?;
+            }
+            e => return Err(unexpected_event(reader, e, "extensions")),
+        }
+    }
+
+    Ok(())
 }
 
-fn parse_trackpoint(
+/// Returns the XML that was copied to 'raw', or None if nothing was.
+fn raw_to_string(raw: Writer<Vec<u8>>) -> Result<Option<String>, GapixError> {
+    let raw = bytes_to_string(&raw.into_inner())?;
+    Ok((!raw.is_empty()).then_some(raw))
+}
+
+fn parse_track_segment<R: BufRead>(
     buf: &mut Vec<u8>,
-    reader: &mut Reader<BufReader<File>>,
-) -> Option<Result<TrackPoint, Box<dyn Error>>> {
-    let mut lat = None;
-    let mut lon = None;
+    reader: &mut Reader<R>,
+) -> Result<TrackSegment, GapixError> {
+    let mut points = Vec::new();
+    let mut extensions = None;
+    let mut raw_children = Writer::new(Vec::new());
+
+    loop {
+        match reader.read_event_into(buf) {
+            Ok(Event::Start(e)) => match e.name().as_ref() {
+                b"trkpt" => {
+                    let lat = read_attribute_as_f64(&e, "lat")?;
+                    let lon = read_attribute_as_f64(&e, "lon")?;
+                    points.push(parse_trackpoint(lat, lon, buf, reader)?);
+                }
+                b"extensions" => {
+                    extensions = parse_raw_extensions(buf, reader)?;
+                }
+                _ => {
+                    let start = e.into_owned();
+                    copy_element(start, buf, reader, &mut raw_children)?;
+                }
+            },
+            Ok(Event::Empty(e)) => {
+                raw_children
+                    .write_event(Event::Empty(e))
+                    .map_err(|err| xml_error(reader, err))?;
+            }
+            Ok(Event::End(e)) if e.name().as_ref() == b"trkseg" => {
+                return Ok(TrackSegment {
+                    points,
+                    extensions,
+                    raw_children: raw_to_string(raw_children)?,
+                });
+            }
+            // Ignore spurious Event::Text, I think they are newlines.
+            Ok(Event::Text(_)) => {}
+            e => return Err(unexpected_event(reader, e, "trkseg")),
+        }
+    }
+}
+
+/// Parses the children of a <trkpt>. The 'lat' and 'lon'
+/// have already been read from its attributes.
+fn parse_trackpoint<R: BufRead>(
+    lat: f64,
+    lon: f64,
+    buf: &mut Vec<u8>,
+    reader: &mut Reader<R>,
+) -> Result<TrackPoint, GapixError> {
     let mut ele = None;
     let mut time = None;
     let mut extensions = None;
+    let mut speed = None;
+    let mut course = None;
+    let mut fix_info = FixInfo::default();
+    let mut raw_children = Writer::new(Vec::new());
 
     loop {
         match reader.read_event_into(buf) {
             Ok(Event::Start(e)) => match e.name().as_ref() {
-                b"trkpt" => {
-                    match read_attribute_as_f64(&e, "lat") {
-                        Ok(attr_value) => lat = Some(attr_value),
-                        Err(err) => return Some(Err(err)),
-                    }
-                    match read_attribute_as_f64(&e, "lon") {
-                        Ok(attr_value) => lon = Some(attr_value),
-                        Err(err) => return Some(Err(err)),
-                    }
+                b"ele" => {
+                    ele = Some(read_inner_as_f64(buf, reader)?);
+                }
+                b"time" => {
+                    time = Some(read_inner_as_time(buf, reader)?);
+                }
+                b"extensions" => {
+                    extensions = Some(parse_trackpoint_extensions(buf, reader)?);
+                }
+                b"magvar" => {
+                    fix_info.magvar = Some(read_inner_as_f64(buf, reader)?);
+                }
+                b"geoidheight" => {
+                    fix_info.geoid_height = Some(read_inner_as_f64(buf, reader)?);
+                }
+                b"fix" => {
+                    fix_info.fix = Some(
+                        read_inner_as_string(buf, reader)
+                            .and_then(|s| parse_value::<FixType>(&s))?,
+                    );
+                }
+                b"sat" => {
+                    fix_info.sat = Some(read_inner_as_u16(buf, reader)?);
+                }
+                b"hdop" => {
+                    fix_info.hdop = Some(read_inner_as_f64(buf, reader)?);
+                }
+                b"vdop" => {
+                    fix_info.vdop = Some(read_inner_as_f64(buf, reader)?);
+                }
+                b"pdop" => {
+                    fix_info.pdop = Some(read_inner_as_f64(buf, reader)?);
+                }
+                b"ageofdgpsdata" => {
+                    fix_info.age_of_dgps_data = Some(read_inner_as_f64(buf, reader)?);
+                }
+                b"dgpsid" => {
+                    fix_info.dgps_id = Some(read_inner_as_u16(buf, reader)?);
+                }
+                // GPX 1.0 only, these are extensions in 1.1.
+                b"speed" => {
+                    speed = Some(read_inner_as_f64(buf, reader)?);
+                }
+                b"course" => {
+                    course = Some(read_inner_as_f64(buf, reader)?);
+                }
+                _ => {
+                    let start = e.into_owned();
+                    copy_element(start, buf, reader, &mut raw_children)?;
                 }
-                b"ele" => match read_inner_as_f64(buf, reader) {
-                    Ok(inner) => ele = Some(inner),
-                    Err(err) => return Some(Err(err)),
-                },
-                b"time" => match read_inner_as_time(buf, reader) {
-                    Ok(inner) => time = Some(inner),
-                    Err(err) => return Some(Err(err)),
-                },
-                b"extensions" => match parse_trackpoint_extensions(buf, reader) {
-                    Ok(ext) => extensions = Some(ext),
-                    Err(err) => return Some(Err(err)),
-                },
-                e => panic!("Unexpected element {:?}", bytes_to_string(e).unwrap()),
             },
+            Ok(Event::Empty(e)) => {
+                raw_children
+                    .write_event(Event::Empty(e))
+                    .map_err(|err| xml_error(reader, err))?;
+            }
             Ok(Event::End(e)) => match e.name().as_ref() {
                 b"trkpt" => {
-                    return Some(Ok(TrackPoint {
-                        lat: lat.unwrap(),
-                        lon: lon.unwrap(),
+                    if speed.is_some() || course.is_some() {
+                        let ext = extensions.get_or_insert_with(Extensions::default);
+                        ext.speed = ext.speed.or(speed);
+                        ext.course = ext.course.or(course);
+                    }
+
+                    return Ok(TrackPoint {
+                        lat,
+                        lon,
                         ele,
                         time,
+                        fix_info: (fix_info != FixInfo::default()).then_some(fix_info),
                         extensions,
-                    }))
-                }
-                b"trkseg" => {
-                    // Reached the end of the trackpoints for this segment.
-                    return None;
+                        raw_children: raw_to_string(raw_children)?,
+                    });
                 }
                 _ => {}
             },
             // Ignore spurious Event::Text, I think they are newlines.
             Ok(Event::Text(_)) => {}
-            e => panic!("Unexpected element {:?}", e),
+            e => return Err(unexpected_event(reader, e, "trkpt")),
         }
     }
 }
 
-fn parse_trackpoint_extensions(
+fn parse_trackpoint_extensions<R: BufRead>(
     buf: &mut Vec<u8>,
-    reader: &mut Reader<BufReader<File>>,
-) -> Result<Extensions, Box<dyn Error>> {
+    reader: &mut Reader<R>,
+) -> Result<Extensions, GapixError> {
     let mut air_temp = None;
     let mut water_temp = None;
     let mut depth = None;
     let mut heart_rate = None;
     let mut cadence = None;
+    let mut speed = None;
+    let mut course = None;
+    let mut power = None;
+    // Elements outside the Garmin containers that we do not understand.
+    let mut other = Writer::new(Vec::new());
+    let mut in_container = false;
 
     loop {
         match reader.read_event_into(buf) {
             Ok(Event::Start(e)) => match e.local_name().as_ref() {
-                b"TrackPointExtension" => { /* ignore, just a container element */ }
+                b"TrackPointExtension" | b"PowerExtension" => in_container = true,
                 b"atemp" => {
                     air_temp = Some(read_inner_as_f64(buf, reader)?);
                 }
@@ -336,38 +882,67 @@ fn parse_trackpoint_extensions(
                 b"cad" => {
                     cadence = Some(read_inner_as_u16(buf, reader)?);
                 }
-                e => panic!("Unexpected element {:?}", bytes_to_string(e)),
+                b"speed" => {
+                    speed = Some(read_inner_as_f64(buf, reader)?);
+                }
+                b"course" => {
+                    course = Some(read_inner_as_f64(buf, reader)?);
+                }
+                b"power" | b"PowerInWatts" => {
+                    power = Some(read_inner_as_u16(buf, reader)?);
+                }
+                name if in_container => return Err(unexpected_element(reader, name)),
+                _ => {
+                    let start = e.into_owned();
+                    copy_element(start, buf, reader, &mut other)?;
+                }
             },
+            Ok(Event::Empty(e)) if !in_container => {
+                other
+                    .write_event(Event::Empty(e))
+                    .map_err(|err| xml_error(reader, err))?;
+            }
             Ok(Event::End(e)) => match e.local_name().as_ref() {
-                b"TrackPointExtension" => { /* ignore, just a container element */ }
+                b"TrackPointExtension" | b"PowerExtension" => in_container = false,
                 b"extensions" => {
+                    let other = bytes_to_string(&other.into_inner())?;
                     return Ok(Extensions {
                         air_temp,
                         water_temp,
                         depth,
                         heart_rate,
                         cadence,
+                        speed,
+                        course,
+                        power,
+                        other: (!other.is_empty()).then_some(other),
                     });
                 }
-                b"atemp" | b"wtemp" | b"depth" | b"hr" | b"cad" => { /* ignore, just the closing tags */
-                }
-                e => panic!("Unexpected element {:?}", bytes_to_string(e)),
+                b"atemp" | b"wtemp" | b"depth" | b"hr" | b"cad" | b"speed" | b"course"
+                | b"power" | b"PowerInWatts" => { /* ignore, just the closing tags */ }
+                e => return Err(unexpected_element(reader, e)),
             },
             // Ignore spurious Event::Text, I think they are newlines.
             Ok(Event::Text(_)) => {}
-            e => panic!("Unexpected element {:?}", e),
+            e => return Err(unexpected_event(reader, e, "extensions")),
         }
     }
 }
 
-fn parse_attributes(tag: &BytesStart<'_>) -> Result<HashMap<String, String>, Box<dyn Error>> {
+fn parse_attributes(tag: &BytesStart<'_>) -> Result<HashMap<String, String>, GapixError> {
     let mut result = HashMap::new();
 
     for attr in tag.attributes() {
         let attr = attr?;
         let key = attr.key.into_inner();
         let key = bytes_to_string(key)?;
-        let value = cow_to_string(attr.value)?;
+        let value = attr
+            .unescape_value()
+            .map_err(|source| GapixError::Xml {
+                position: 0,
+                source,
+            })?
+            .into_owned();
 
         result.insert(key, value);
     }
@@ -378,90 +953,505 @@ fn parse_attributes(tag: &BytesStart<'_>) -> Result<HashMap<String, String>, Box
 fn read_attribute_as_string(
     tag: &BytesStart<'_>,
     attribute_name: &str,
-) -> Result<String, Box<dyn Error>> {
+) -> Result<String, GapixError> {
     let attrs = parse_attributes(tag)?;
     match attrs.get(attribute_name) {
         Some(value) => Ok(value.clone()),
-        None => Err(format!("No attribute named {attribute_name}"))?,
+        None => Err(missing_attribute(tag, attribute_name)),
     }
 }
 
-fn read_attribute_as_f64(
-    tag: &BytesStart<'_>,
-    attribute_name: &str,
-) -> Result<f64, Box<dyn Error>> {
+fn read_attribute_as_f64(tag: &BytesStart<'_>, attribute_name: &str) -> Result<f64, GapixError> {
     let s = read_attribute_as_string(tag, attribute_name)?;
-    Ok(s.parse::<f64>()?)
+    parse_value(&s)
 }
 
 /// Reads the 'INNER TEXT' from a tag such as <tag>INNER TEXT</tag>.
-fn read_inner_as_string(
+/// Entities such as "&amp;" are unescaped; the writer escapes them again.
+fn read_inner_as_string<R: BufRead>(
     buf: &mut Vec<u8>,
-    reader: &mut Reader<BufReader<File>>,
-) -> Result<String, Box<dyn Error>> {
+    reader: &mut Reader<R>,
+) -> Result<String, GapixError> {
     match reader.read_event_into(buf) {
-        Ok(Event::Text(ele)) => Ok(bytes_to_string(ele.as_ref())?),
-        e => Err(format!(
-            "Got unexpected XML node, document is probably corrupt: {:?}",
-            e
-        )
-        .into()),
+        Ok(Event::Text(ele)) => Ok(ele
+            .unescape()
+            .map_err(|err| xml_error(reader, err))?
+            .into_owned()),
+        e => Err(unexpected_event(reader, e, "text")),
     }
 }
 
 /// Reads a <time>2024-09-21T06:59:46.000Z</time> tag.
-fn read_inner_as_time(
+fn read_inner_as_time<R: BufRead>(
     buf: &mut Vec<u8>,
-    reader: &mut Reader<BufReader<File>>,
-) -> Result<OffsetDateTime, Box<dyn Error>> {
+    reader: &mut Reader<R>,
+) -> Result<OffsetDateTime, GapixError> {
     let t = read_inner_as_string(buf, reader)?;
-    Ok(OffsetDateTime::parse(&t, &well_known::Rfc3339)?)
+    parse_time(&t)
 }
 
 /// Reads inner text and converts it to an f64.
-fn read_inner_as_f64(
+fn read_inner_as_f64<R: BufRead>(
     buf: &mut Vec<u8>,
-    reader: &mut Reader<BufReader<File>>,
-) -> Result<f64, Box<dyn Error>> {
+    reader: &mut Reader<R>,
+) -> Result<f64, GapixError> {
     let t = read_inner_as_string(buf, reader)?;
-    Ok(t.parse::<f64>()?)
+    parse_value(&t)
 }
 
 /// Reads inner text and converts it to a u16.
-fn read_inner_as_u16(
+fn read_inner_as_u16<R: BufRead>(
     buf: &mut Vec<u8>,
-    reader: &mut Reader<BufReader<File>>,
-) -> Result<u16, Box<dyn Error>> {
+    reader: &mut Reader<R>,
+) -> Result<u16, GapixError> {
     let t = read_inner_as_string(buf, reader)?;
-    Ok(t.parse::<u16>()?)
+    parse_value(&t)
+}
+
+/// Parses a value such as a number, converting the error into
+/// a GapixError which includes the text that we failed to parse.
+pub(crate) fn parse_value<T>(value: &str) -> Result<T, GapixError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.parse::<T>().map_err(|e| GapixError::InvalidValue {
+        value: value.to_string(),
+        message: e.to_string(),
+    })
+}
+
+/// Parses a time such as "2024-09-21T06:59:46.000Z".
+pub(crate) fn parse_time(value: &str) -> Result<OffsetDateTime, GapixError> {
+    OffsetDateTime::parse(value, &well_known::Rfc3339).map_err(|e| GapixError::InvalidValue {
+        value: value.to_string(),
+        message: e.to_string(),
+    })
+}
+
+/// Makes the error for an XML error reported by the reader.
+pub(crate) fn xml_error<R>(reader: &Reader<R>, source: quick_xml::Error) -> GapixError {
+    GapixError::Xml {
+        position: reader.error_position(),
+        source,
+    }
 }
 
-fn cow_to_string(v: Cow<'_, [u8]>) -> Result<String, Box<dyn Error>> {
-    bytes_to_string(v.borrow())
+fn missing_attribute(tag: &BytesStart<'_>, attribute_name: &str) -> GapixError {
+    GapixError::MissingAttribute {
+        element: String::from_utf8_lossy(tag.name().as_ref()).into_owned(),
+        attribute: attribute_name.to_string(),
+    }
 }
 
-fn rcow_to_string(v: Result<Cow<'_, [u8]>, quick_xml::Error>) -> Result<String, Box<dyn Error>> {
+/// Makes the error for an element that we did not expect to find
+/// in the element that we are currently parsing.
+fn unexpected_element<R>(reader: &Reader<R>, name: &[u8]) -> GapixError {
+    GapixError::UnexpectedElement {
+        element: format!("'{}'", String::from_utf8_lossy(name)),
+        position: reader.buffer_position(),
+    }
+}
+
+/// Makes the error for an event that we did not expect while parsing
+/// the contents of 'parent'.
+fn unexpected_event<R>(
+    reader: &Reader<R>,
+    event: Result<Event<'_>, quick_xml::Error>,
+    parent: &'static str,
+) -> GapixError {
+    match event {
+        Err(source) => xml_error(reader, source),
+        Ok(Event::Eof) => GapixError::UnexpectedEof(parent),
+        Ok(Event::Start(e)) | Ok(Event::Empty(e)) => unexpected_element(reader, e.name().as_ref()),
+        Ok(e) => GapixError::UnexpectedElement {
+            element: format!("{:?}", e),
+            position: reader.buffer_position(),
+        },
+    }
+}
+
+fn rcow_to_string(v: Result<Cow<'_, [u8]>, quick_xml::Error>) -> Result<String, GapixError> {
     match v {
         Ok(Cow::Borrowed(s)) => Ok(bytes_to_string(s)?),
         Ok(Cow::Owned(s)) => Ok(bytes_to_string(&s)?),
-        Err(err) => Err(Box::new(err)),
+        Err(source) => Err(GapixError::Xml {
+            position: 0,
+            source,
+        }),
     }
 }
 
 fn orcow_to_string(
     v: Option<Result<Cow<'_, [u8]>, quick_xml::Error>>,
-) -> Result<Option<String>, Box<dyn Error>> {
-    match v {
-        Some(Ok(Cow::Borrowed(s))) => Ok(Some(bytes_to_string(s)?)),
-        Some(Ok(Cow::Owned(s))) => Ok(Some(bytes_to_string(&s)?)),
-        Some(Err(err)) => Err(Box::new(err)),
-        None => Ok(None),
-    }
+) -> Result<Option<String>, GapixError> {
+    v.map(rcow_to_string).transpose()
 }
 
-fn bytes_to_string(value: &[u8]) -> Result<String, Box<dyn Error>> {
-    match str::from_utf8(value) {
-        Ok(s) => Ok(s.to_string()),
-        Err(err) => Err(Box::new(err)),
+fn bytes_to_string(value: &[u8]) -> Result<String, GapixError> {
+    Ok(str::from_utf8(value)?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        simplification::WriteOptions,
+        test_helpers::{read_gpx_str, round_trip, write_gpx_str},
+    };
+
+    /// Wraps the XML of a single <trkpt> in a minimal GPX 1.1 document.
+    fn gpx_with_trackpoint(trkpt: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.1" xmlns="http://www.topografix.com/GPX/1/1" xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v2">
+  <metadata>
+    <time>2024-06-01T08:00:00Z</time>
+  </metadata>
+  <trk>
+    <trkseg>
+      {trkpt}
+    </trkseg>
+  </trk>
+</gpx>
+"#
+        )
+    }
+
+    #[test]
+    fn parses_all_the_trackpoint_extension_v2_fields() {
+        let gpx = read_gpx_str(&gpx_with_trackpoint(
+            r#"<trkpt lat="51.5" lon="0.0">
+        <ele>10</ele>
+        <time>2024-06-01T08:00:00Z</time>
+        <extensions>
+          <gpxtpx:TrackPointExtension>
+            <gpxtpx:atemp>21.5</gpxtpx:atemp>
+            <gpxtpx:wtemp>15.0</gpxtpx:wtemp>
+            <gpxtpx:depth>2.5</gpxtpx:depth>
+            <gpxtpx:hr>142</gpxtpx:hr>
+            <gpxtpx:cad>88</gpxtpx:cad>
+            <gpxtpx:speed>8.25</gpxtpx:speed>
+            <gpxtpx:course>270.5</gpxtpx:course>
+          </gpxtpx:TrackPointExtension>
+        </extensions>
+      </trkpt>"#,
+        ));
+
+        let ext = gpx.tracks[0].segments[0].points[0]
+            .extensions
+            .as_ref()
+            .unwrap();
+        assert_eq!(ext.air_temp, Some(21.5));
+        assert_eq!(ext.water_temp, Some(15.0));
+        assert_eq!(ext.depth, Some(2.5));
+        assert_eq!(ext.heart_rate, Some(142));
+        assert_eq!(ext.cadence, Some(88));
+        assert_eq!(ext.speed, Some(8.25));
+        assert_eq!(ext.course, Some(270.5));
+        assert_eq!(ext.other, None);
+    }
+
+    #[test]
+    fn parses_power_from_a_bare_element_and_a_power_extension() {
+        let gpx = read_gpx_str(&gpx_with_trackpoint(
+            r#"<trkpt lat="51.5" lon="0.0">
+        <extensions>
+          <power>250</power>
+        </extensions>
+      </trkpt>
+      <trkpt lat="51.5" lon="0.001">
+        <extensions>
+          <pwr:PowerExtension xmlns:pwr="http://www.garmin.com/xmlschemas/PowerExtension/v1">
+            <pwr:PowerInWatts>310</pwr:PowerInWatts>
+          </pwr:PowerExtension>
+        </extensions>
+      </trkpt>"#,
+        ));
+
+        let powers: Vec<_> = gpx.tracks[0].segments[0]
+            .points
+            .iter()
+            .map(|p| p.extensions.as_ref().unwrap().power)
+            .collect();
+        assert_eq!(powers, [Some(250), Some(310)]);
+    }
+
+    const GPX_10: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.0" xmlns="http://www.topografix.com/GPX/1/0">
+  <name>Morning Ride</name>
+  <author>Phil</author>
+  <email>phil@example.com</email>
+  <url>https://example.com/ride</url>
+  <urlname>The ride</urlname>
+  <time>2024-06-01T08:00:00Z</time>
+  <trk>
+    <name>Loop</name>
+    <url>https://example.com/loop</url>
+    <trkseg>
+      <trkpt lat="51.5" lon="-0.1">
+        <ele>12.5</ele>
+        <time>2024-06-01T08:00:00Z</time>
+        <course>90.5</course>
+        <speed>5.5</speed>
+      </trkpt>
+    </trkseg>
+  </trk>
+</gpx>
+"#;
+
+    /// The same document as GPX_10, in GPX 1.1.
+    const GPX_11: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.1" xmlns="http://www.topografix.com/GPX/1/1" xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v2">
+  <metadata>
+    <name>Morning Ride</name>
+    <author>
+      <name>Phil</name>
+      <email id="phil" domain="example.com" />
+    </author>
+    <link href="https://example.com/ride">
+      <text>The ride</text>
+    </link>
+    <time>2024-06-01T08:00:00Z</time>
+  </metadata>
+  <trk>
+    <name>Loop</name>
+    <trkseg>
+      <trkpt lat="51.5" lon="-0.1">
+        <ele>12.5</ele>
+        <time>2024-06-01T08:00:00Z</time>
+        <extensions>
+          <gpxtpx:TrackPointExtension>
+            <gpxtpx:speed>5.5</gpxtpx:speed>
+            <gpxtpx:course>90.5</gpxtpx:course>
+          </gpxtpx:TrackPointExtension>
+        </extensions>
+      </trkpt>
+    </trkseg>
+  </trk>
+</gpx>
+"#;
+
+    #[test]
+    fn gpx_10_is_read_into_the_same_shape_as_gpx_11() {
+        let gpx = read_gpx_str(GPX_10);
+
+        assert_eq!(gpx.info.version, "1.1");
+        assert_eq!(gpx.metadata.name.as_deref(), Some("Morning Ride"));
+        let author = gpx.metadata.author.as_ref().unwrap();
+        assert_eq!(author.name.as_deref(), Some("Phil"));
+        assert_eq!(author.email.as_ref().unwrap().domain, "example.com");
+        assert_eq!(gpx.metadata.links.len(), 1);
+        assert_eq!(gpx.metadata.links[0].href, "https://example.com/ride");
+        assert_eq!(gpx.metadata.links[0].text.as_deref(), Some("The ride"));
+        assert!(gpx.metadata.time.is_some());
+
+        assert_eq!(gpx.tracks.len(), 1);
+        assert_eq!(gpx.tracks[0].name.as_deref(), Some("Loop"));
+        let p = &gpx.tracks[0].segments[0].points[0];
+        assert_eq!(p.ele, Some(12.5));
+        let ext = p.extensions.as_ref().unwrap();
+        assert_eq!(ext.speed, Some(5.5));
+        assert_eq!(ext.course, Some(90.5));
+
+        let gpx_11 = read_gpx_str(GPX_11);
+        assert_eq!(
+            format!("{:?}", gpx.metadata),
+            format!("{:?}", gpx_11.metadata)
+        );
+        assert_eq!(format!("{:?}", gpx.tracks), format!("{:?}", gpx_11.tracks));
+    }
+
+    fn read_error(xml: &str) -> GapixError {
+        read_gpx_from_reader(xml.as_bytes(), Path::new("corrupt.gpx")).unwrap_err()
+    }
+
+    #[test]
+    fn a_corrupt_file_is_an_xml_error() {
+        let err = read_error(&gpx_with_trackpoint(
+            r#"<trkpt lat="51.5" lon="0.0"></trkseg>"#,
+        ));
+        assert!(matches!(err, GapixError::Xml { .. }), "{err:?}");
+    }
+
+    #[test]
+    fn a_missing_or_invalid_attribute_is_reported() {
+        let err = read_error(&gpx_with_trackpoint(r#"<trkpt lon="0.0"></trkpt>"#));
+        assert!(
+            matches!(&err, GapixError::MissingAttribute { element, attribute }
+                if element == "trkpt" && attribute == "lat"),
+            "{err:?}"
+        );
+
+        let err = read_error(&gpx_with_trackpoint(
+            r#"<trkpt lat="north" lon="0.0"></trkpt>"#,
+        ));
+        assert!(
+            matches!(&err, GapixError::InvalidValue { value, .. } if value == "north"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn parses_and_round_trips_the_track_display_colour() {
+        let gpx = read_gpx_str(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.1" xmlns="http://www.topografix.com/GPX/1/1" xmlns:gpxx="http://www.garmin.com/xmlschemas/GpxExtensions/v3">
+  <metadata>
+    <time>2024-06-01T08:00:00Z</time>
+  </metadata>
+  <trk>
+    <name>Evening Ride</name>
+    <extensions>
+      <gpxx:TrackExtension>
+        <gpxx:DisplayColor>Magenta</gpxx:DisplayColor>
+      </gpxx:TrackExtension>
+    </extensions>
+    <trkseg>
+      <trkpt lat="51.5" lon="0.0">
+        <time>2024-06-01T08:00:00Z</time>
+      </trkpt>
+    </trkseg>
+  </trk>
+</gpx>
+"#,
+        );
+
+        let expected = TrackExtensions {
+            display_color: Some("Magenta".to_string()),
+            other: None,
+        };
+        assert_eq!(gpx.tracks[0].extensions.as_ref(), Some(&expected));
+
+        let gpx = round_trip(&gpx);
+        assert_eq!(gpx.tracks[0].extensions.as_ref(), Some(&expected));
+    }
+
+    #[test]
+    fn segment_extensions_survive_a_round_trip() {
+        let gpx = read_gpx_str(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.1" xmlns="http://www.topografix.com/GPX/1/1" xmlns:foo="http://example.com/foo">
+  <metadata>
+    <time>2024-06-01T08:00:00Z</time>
+  </metadata>
+  <trk>
+    <trkseg>
+      <trkpt lat="51.5" lon="0.0">
+        <time>2024-06-01T08:00:00Z</time>
+      </trkpt>
+      <extensions>
+        <foo:colour>red</foo:colour>
+      </extensions>
+    </trkseg>
+  </trk>
+</gpx>
+"#,
+        );
+
+        let expected = Some("<foo:colour>red</foo:colour>");
+        assert_eq!(gpx.tracks[0].segments[0].extensions.as_deref(), expected);
+
+        let preserve = WriteOptions {
+            preserve_unknown: true,
+            ..Default::default()
+        };
+        let written = read_gpx_str(&write_gpx_str(&gpx, &preserve));
+        assert_eq!(
+            written.tracks[0].segments[0].extensions.as_deref(),
+            expected
+        );
+
+        let gpx = round_trip(&gpx);
+        assert_eq!(gpx.tracks[0].segments[0].extensions, None);
+    }
+
+    #[test]
+    fn unknown_elements_are_kept_where_they_were() {
+        let gpx = read_gpx_str(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.0" xmlns="http://www.topografix.com/GPX/1/0" xmlns:foo="http://example.com/foo">
+  <time>2024-06-01T08:00:00Z</time>
+  <trk>
+    <name>Audax</name>
+    <number>3</number>
+    <trkseg>
+      <trkpt lat="51.5" lon="0.0">
+        <time>2024-06-01T08:00:00Z</time>
+        <sym>Flag</sym>
+      </trkpt>
+      <foo:marker />
+    </trkseg>
+  </trk>
+  <foo:top>x</foo:top>
+</gpx>
+"#,
+        );
+
+        let check = |gpx: &Gpx| {
+            assert_eq!(gpx.raw_children.as_deref(), Some("<foo:top>x</foo:top>"));
+            let track = &gpx.tracks[0];
+            assert_eq!(track.raw_children.as_deref(), Some("<number>3</number>"));
+            let segment = &track.segments[0];
+            assert_eq!(segment.raw_children.as_deref(), Some("<foo:marker />"));
+            let point = &segment.points[0];
+            assert_eq!(point.raw_children.as_deref(), Some("<sym>Flag</sym>"));
+            assert!(point.time.is_some());
+        };
+        check(&gpx);
+
+        let preserve = WriteOptions {
+            preserve_unknown: true,
+            ..Default::default()
+        };
+        check(&read_gpx_str(&write_gpx_str(&gpx, &preserve)));
+
+        let dropped = round_trip(&gpx);
+        assert_eq!(dropped.raw_children, None);
+        assert_eq!(dropped.tracks[0].raw_children, None);
+        assert_eq!(dropped.tracks[0].segments[0].raw_children, None);
+        assert_eq!(dropped.tracks[0].segments[0].points[0].raw_children, None);
+    }
+
+    #[test]
+    fn the_author_name_email_and_link_survive_a_round_trip() {
+        let gpx = read_gpx_str(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+  <metadata>
+    <author>
+      <name>Jo Bloggs</name>
+      <email id="jo" domain="example.com"/>
+      <link href="https://example.com/rides?id=1&amp;page=2">
+        <text>My rides</text>
+        <type>text/html</type>
+      </link>
+    </author>
+    <time>2024-06-01T08:00:00Z</time>
+  </metadata>
+  <trk>
+    <trkseg>
+      <trkpt lat="51.5" lon="0.0">
+        <time>2024-06-01T08:00:00Z</time>
+      </trkpt>
+    </trkseg>
+  </trk>
+</gpx>
+"#,
+        );
+
+        for gpx in [&gpx, &round_trip(&gpx)] {
+            let author = gpx.metadata.author.as_ref().unwrap();
+            assert_eq!(author.name.as_deref(), Some("Jo Bloggs"));
+            let email = author.email.as_ref().unwrap();
+            assert_eq!(
+                (email.id.as_str(), email.domain.as_str()),
+                ("jo", "example.com")
+            );
+            let link = author.link.as_ref().unwrap();
+            assert_eq!(link.href, "https://example.com/rides?id=1&page=2");
+            assert_eq!(link.text.as_deref(), Some("My rides"));
+            assert_eq!(link.r#type.as_deref(), Some("text/html"));
+        }
     }
 }
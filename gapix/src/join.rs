@@ -0,0 +1,195 @@
+//! Contains the functionality relating to joining several input
+//! files into a single track.
+
+use std::error::Error;
+
+use clap::ValueEnum;
+use time::OffsetDateTime;
+
+use crate::model::{Gpx, TrackPoint};
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, ValueEnum)]
+/// The order in which input files are joined.
+pub enum JoinStrategy {
+    /// Order the files by the time of their first point.
+    #[default]
+    ByTime,
+    /// Keep the files in filename order.
+    ByFilename,
+}
+
+/// Joins the input files into a single Gpx with one track. Each input
+/// must already be a single track. The files are ordered according to
+/// 'strategy' and their points are appended in that order.
+///
+/// If a file starts before the previous one ends the files overlap in time
+/// and joining them would make the track jump backwards and forwards. This
+/// is an error if 'strict' is set, otherwise we warn and, when joining by
+/// time, fall back to sorting all the points by time. When joining by
+/// filename the order of the files is kept, so instead the points which
+/// are not later than the end of the previous file are dropped.
+pub fn join_input_files(
+    mut input_files: Vec<Gpx>,
+    strategy: JoinStrategy,
+    strict: bool,
+) -> Result<Gpx, Box<dyn Error>> {
+    for gpx in &input_files {
+        assert!(gpx.is_single_track());
+    }
+
+    if strategy == JoinStrategy::ByTime {
+        // A stable sort, so files with the same start time (or no times at
+        // all, which sort first) stay in filename order.
+        input_files.sort_by_key(first_time);
+    }
+
+    let overlaps = find_overlaps(&input_files);
+    for (prev, next) in &overlaps {
+        let msg = format!(
+            "{:?} starts before the end of {:?}",
+            input_files[*next].filename, input_files[*prev].filename
+        );
+        if strict {
+            Err(format!("The input files overlap in time: {msg}"))?;
+        }
+        println!("Warning: {msg}");
+    }
+
+    // We can't simply re-use the first track/segment due to
+    // multiple mut borrows. So create a new vec of points.
+    let required_capacity: usize = input_files.iter().map(|f| f.num_points()).sum();
+    let mut points = Vec::with_capacity(required_capacity);
+
+    for f in &mut input_files {
+        println!("Joining {:?}", f.filename);
+        let file_points = &mut f.tracks[0].segments[0].points;
+
+        if strategy == JoinStrategy::ByFilename {
+            if let Some(prev_end) = points.iter().rev().find_map(|p: &TrackPoint| p.time) {
+                let count = file_points.len();
+                file_points.retain(|p| p.time.is_none_or(|t| t > prev_end));
+                if file_points.len() < count {
+                    println!(
+                        "Dropped {} points from {:?} which overlap the previous file",
+                        count - file_points.len(),
+                        f.filename
+                    );
+                }
+            }
+        }
+
+        points.append(file_points);
+    }
+
+    if strategy == JoinStrategy::ByTime && !overlaps.is_empty() {
+        // The best we can do is to interleave the points. Two points
+        // recorded at the same time can't both be right, so keep the first.
+        // Points without a time are all equal but are not duplicates.
+        points.sort_by_key(|p| p.time);
+        points.dedup_by(|p, prev| p.time.is_some() && p.time == prev.time);
+    }
+
+    println!("Joined {} files", input_files.len());
+
+    let mut gpx = input_files.swap_remove(0);
+    gpx.tracks[0].segments[0].points = points;
+    Ok(gpx)
+}
+
+/// Returns the pairs of indexes of consecutive files where the
+/// second one starts before the first one ends.
+fn find_overlaps(input_files: &[Gpx]) -> Vec<(usize, usize)> {
+    let mut overlaps = Vec::new();
+
+    for idx in 1..input_files.len() {
+        if let (Some(prev_end), Some(next_start)) = (
+            last_time(&input_files[idx - 1]),
+            first_time(&input_files[idx]),
+        ) {
+            if next_start < prev_end {
+                overlaps.push((idx - 1, idx));
+            }
+        }
+    }
+
+    overlaps
+}
+
+fn first_time(gpx: &Gpx) -> Option<OffsetDateTime> {
+    gpx.tracks[0].segments[0].points.iter().find_map(|p| p.time)
+}
+
+fn last_time(gpx: &Gpx) -> Option<OffsetDateTime> {
+    gpx.tracks[0].segments[0]
+        .points
+        .iter()
+        .rev()
+        .find_map(|p| p.time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{make_gpx, start_time, xy_point};
+
+    /// Makes a file with a point at each of the given offsets (in seconds)
+    /// from the start time.
+    fn file(name: &str, offsets: &[i64]) -> Gpx {
+        let points = offsets
+            .iter()
+            .map(|&secs| TrackPoint {
+                time: Some(start_time() + time::Duration::seconds(secs)),
+                ..xy_point(secs as f64, 0.0)
+            })
+            .collect();
+        let mut gpx = make_gpx(points);
+        gpx.filename = name.into();
+        gpx
+    }
+
+    /// The offsets of the joined points, or None for a point without a time.
+    fn offsets(gpx: &Gpx) -> Vec<Option<i64>> {
+        gpx.tracks[0].segments[0]
+            .points
+            .iter()
+            .map(|p| p.time.map(|t| (t - start_time()).whole_seconds()))
+            .collect()
+    }
+
+    #[test]
+    fn files_which_do_not_overlap_are_joined_in_time_order() {
+        let files = vec![file("b.gpx", &[30, 40]), file("a.gpx", &[0, 10, 20])];
+        let gpx = join_input_files(files, JoinStrategy::ByTime, true).unwrap();
+        assert_eq!(
+            offsets(&gpx),
+            [Some(0), Some(10), Some(20), Some(30), Some(40)]
+        );
+    }
+
+    #[test]
+    fn overlapping_files_are_an_error_when_strict() {
+        let files = vec![file("a.gpx", &[0, 10, 20]), file("b.gpx", &[15, 25])];
+        assert!(join_input_files(files, JoinStrategy::ByTime, true).is_err());
+    }
+
+    #[test]
+    fn overlapping_files_are_interleaved_and_deduplicated() {
+        let mut b = file("b.gpx", &[10, 15, 25]);
+        b.tracks[0].segments[0].points[1].time = None;
+        let files = vec![file("a.gpx", &[0, 10, 20]), b];
+
+        let gpx = join_input_files(files, JoinStrategy::ByTime, false).unwrap();
+        // The duplicate at 10s is dropped, the point without a time is kept.
+        assert_eq!(offsets(&gpx), [None, Some(0), Some(10), Some(20), Some(25)]);
+    }
+
+    #[test]
+    fn joining_by_filename_keeps_the_order_and_drops_the_overlap() {
+        let files = vec![
+            file("a.gpx", &[100, 110, 120]),
+            file("b.gpx", &[0, 115, 130]),
+        ];
+        let gpx = join_input_files(files, JoinStrategy::ByFilename, false).unwrap();
+        assert_eq!(offsets(&gpx), [Some(100), Some(110), Some(120), Some(130)]);
+    }
+}
@@ -0,0 +1,217 @@
+//! Contains the functionality relating to writing the stage analysis as
+//! JSON. This contains the same information as the Stages tab of the
+//! summary workbook, but in a form that is easy to consume from other
+//! programs.
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use logging_timer::time;
+use serde_json::{json, Value};
+use time::{Duration, OffsetDateTime};
+use time_tz::Tz;
+
+use crate::{
+    formatting::{to_local_date, TimeFormat},
+    model::{EnrichedGpx, EnrichedTrackPoint},
+    stage::StageList,
+    timezone::timezone_for_point,
+};
+
+/// Writes the stage analysis as a JSON file.
+#[time]
+pub fn write_analysis_json_file(
+    output_file: &Path,
+    gpx: &EnrichedGpx,
+    stages: &StageList,
+    time_format: &TimeFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut w = BufWriter::new(File::create(output_file)?);
+    serde_json::to_writer_pretty(&mut w, &stages_to_json(gpx, stages, time_format))?;
+    writeln!(w)?;
+    w.flush()?;
+
+    let metadata = std::fs::metadata(output_file)?;
+    eprintln!(
+        "Wrote file {:?}, {} Kb",
+        &output_file,
+        metadata.len() / 1024
+    );
+    Ok(())
+}
+
+/// Converts the stages into a JSON object with a 'stages' array, one
+/// element per stage, and a 'totals' object which mirrors the SUMMARY
+/// row of the Stages tab. Values that cannot be calculated (for example,
+/// heart rate when the device did not record it) are written as null.
+pub fn stages_to_json(gpx: &EnrichedGpx, stages: &StageList, time_format: &TimeFormat) -> Value {
+    // The same time zone as the summary workbook uses.
+    let timezone = gpx.points.first().and_then(timezone_for_point);
+
+    let stages_json: Vec<Value> = stages
+        .iter()
+        .enumerate()
+        .map(|(idx, stage)| {
+            json!({
+                "stage": idx + 1,
+                "stage_type": stage.stage_type.to_string(),
+                "start_index": stage.start.index,
+                "end_index": stage.end.index,
+                "start_time": time_to_json(time_format, stage.start.start_time()),
+                "end_time": time_to_json(time_format, stage.end.time),
+                "duration_seconds": duration_to_json(stage.duration()),
+                "distance_km": stage.distance_km(),
+                "running_distance_km": stage.running_distance_km(),
+                "average_speed_kmh": stage.average_speed_kmh(),
+                "running_average_speed_kmh": stage.running_average_speed_kmh(),
+                "ascent_metres": stage.ascent_metres(),
+                "descent_metres": stage.descent_metres(),
+                "min_elevation": point_to_json(time_format, timezone, stage.min_elevation.as_ref(), |p| p.ele),
+                "max_elevation": point_to_json(time_format, timezone, stage.max_elevation.as_ref(), |p| p.ele),
+                "max_speed": point_to_json(time_format, timezone, stage.max_speed.as_ref(), |p| {
+                    p.effective_speed_kmh()
+                }),
+                "heart_rate": {
+                    "avg": stage.avg_heart_rate,
+                    "max": point_to_json(time_format, timezone, stage.max_heart_rate.as_ref(), |p| {
+                        p.heart_rate().map(f64::from)
+                    }),
+                },
+                "temperature": {
+                    "avg": stage.avg_air_temp,
+                    "min": point_to_json(time_format, timezone, stage.min_air_temp.as_ref(), |p| p.air_temp()),
+                    "max": point_to_json(time_format, timezone, stage.max_air_temp.as_ref(), |p| p.air_temp()),
+                },
+            })
+        })
+        .collect();
+
+    let totals = json!({
+        "start_time": time_to_json(time_format, stages.start_time()),
+        "end_time": time_to_json(time_format, stages.end_time()),
+        "duration_seconds": duration_to_json(stages.duration()),
+        "moving_seconds": duration_to_json(stages.total_moving_time()),
+        "control_seconds": duration_to_json(stages.total_control_time()),
+        "distance_km": stages.distance_km(),
+        "average_moving_speed_kmh": stages.average_moving_speed(),
+        "average_overall_speed_kmh": stages.average_overall_speed(),
+        "ascent_metres": stages.total_ascent_metres(),
+        "descent_metres": stages.total_descent_metres(),
+        "min_elevation": point_to_json(time_format, timezone, stages.min_elevation(), |p| p.ele),
+        "max_elevation": point_to_json(time_format, timezone, stages.max_elevation(), |p| p.ele),
+        "max_speed": point_to_json(time_format, timezone, stages.max_speed(), |p| p.effective_speed_kmh()),
+        "heart_rate": {
+            "avg": gpx.avg_heart_rate(),
+            "max": point_to_json(time_format, timezone, stages.max_heart_rate(), |p| p.heart_rate().map(f64::from)),
+        },
+        "temperature": {
+            "avg": gpx.avg_temperature(),
+            "min": point_to_json(time_format, timezone, stages.min_temperature(), |p| p.air_temp()),
+            "max": point_to_json(time_format, timezone, stages.max_temperature(), |p| p.air_temp()),
+        },
+    });
+
+    json!({
+        "stages": stages_json,
+        "totals": totals,
+    })
+}
+
+/// Describes a 'special' point such as the point of max elevation: the
+/// value of interest, where it is in the track and when it was recorded.
+/// The local time is in 'timezone', the time zone of the start of the track.
+fn point_to_json<F>(
+    time_format: &TimeFormat,
+    timezone: Option<&Tz>,
+    point: Option<&EnrichedTrackPoint>,
+    value: F,
+) -> Value
+where
+    F: Fn(&EnrichedTrackPoint) -> Option<f64>,
+{
+    match point {
+        Some(p) => json!({
+            "value": value(p),
+            "index": p.index,
+            "time": time_to_json(time_format, p.time),
+            "local_time": p.time.map(|t| time_format.format(&to_local_date(t, timezone))),
+            "running_km": p.running_metres / 1000.0,
+            "lat": p.lat,
+            "lon": p.lon,
+        }),
+        None => Value::Null,
+    }
+}
+
+fn time_to_json(time_format: &TimeFormat, time: Option<OffsetDateTime>) -> Value {
+    match time {
+        Some(t) => Value::String(time_format.format(&t)),
+        None => Value::Null,
+    }
+}
+
+fn duration_to_json(duration: Option<Duration>) -> Value {
+    match duration {
+        Some(d) => json!(d.whole_seconds()),
+        None => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        stage::{detect_stages, StageDetectionParameters},
+        test_helpers::ride,
+    };
+
+    #[test]
+    fn the_totals_round_trip_and_match_the_stage_list() {
+        let gpx = ride(&[(20.0, 1200), (0.0, 600), (25.0, 1200)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 3);
+
+        let text =
+            serde_json::to_string(&stages_to_json(&gpx, &stages, &TimeFormat::Epoch)).unwrap();
+        let json: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(json["stages"].as_array().unwrap().len(), stages.len());
+
+        let totals = &json["totals"];
+        assert_eq!(
+            totals["duration_seconds"].as_i64(),
+            stages.duration().map(|d| d.whole_seconds())
+        );
+        assert_eq!(
+            totals["moving_seconds"].as_i64(),
+            stages.total_moving_time().map(|d| d.whole_seconds())
+        );
+        // serde_json does not guarantee that floats round-trip exactly.
+        let close =
+            |value: &Value, expected: f64| (value.as_f64().unwrap() - expected).abs() < 1e-9;
+        assert!(close(&totals["distance_km"], stages.distance_km()));
+        assert!(close(
+            &totals["average_moving_speed_kmh"],
+            stages.average_moving_speed().unwrap()
+        ));
+        assert_eq!(
+            totals["start_time"].as_str(),
+            Some(
+                stages
+                    .start_time()
+                    .unwrap()
+                    .unix_timestamp()
+                    .to_string()
+                    .as_str()
+            )
+        );
+        assert_eq!(
+            totals["max_speed"]["index"].as_u64(),
+            stages.max_speed().map(|p| p.index as u64)
+        );
+        assert!(totals["heart_rate"]["avg"].is_null());
+    }
+}
@@ -0,0 +1,239 @@
+//! Contains the functionality relating to writing KML files,
+//! see https://developers.google.com/kml/documentation/kmlreference
+//! These are for opening a ride in Google Earth.
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use logging_timer::time;
+use quick_xml::escape::escape;
+
+use crate::{
+    args::AltitudeMode,
+    model::{Gpx, TrackPoint, Waypoint},
+};
+
+/// Writes the GPX, and its waypoints, as a KML file.
+#[time]
+pub fn write_kml_file(
+    output_file: &Path,
+    gpx: &Gpx,
+    waypoints: &[Waypoint],
+    altitude_mode: AltitudeMode,
+) -> Result<(), Box<dyn Error>> {
+    let mut w = BufWriter::new(File::create(output_file)?);
+    write_kml_to_writer(&mut w, gpx, waypoints, altitude_mode)?;
+    w.flush()?;
+
+    let metadata = std::fs::metadata(output_file)?;
+    eprintln!(
+        "Wrote file {:?}, {} Kb",
+        &output_file,
+        metadata.len() / 1024
+    );
+    Ok(())
+}
+
+/// Writes a KML Document. Each track is written as a Placemark containing
+/// a LineString, or a MultiGeometry of LineStrings if it has more than
+/// one segment, followed by a Placemark for each waypoint.
+pub fn write_kml_to_writer<W: Write>(
+    w: &mut W,
+    gpx: &Gpx,
+    waypoints: &[Waypoint],
+    altitude_mode: AltitudeMode,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(w, r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#)?;
+    writeln!(w, "  <Document>")?;
+    if let Some(name) = &gpx.metadata.name {
+        writeln!(w, "    <name>{}</name>", escape(name))?;
+    }
+
+    for track in &gpx.tracks {
+        writeln!(w, "    <Placemark>")?;
+        if let Some(name) = &track.name {
+            writeln!(w, "      <name>{}</name>", escape(name))?;
+        }
+        if let Some(desc) = &track.desc {
+            writeln!(w, "      <description>{}</description>", escape(desc))?;
+        }
+
+        let multi = track.segments.len() > 1;
+        if multi {
+            writeln!(w, "      <MultiGeometry>")?;
+        }
+        for segment in &track.segments {
+            write_line_string(w, &segment.points, altitude_mode)?;
+        }
+        if multi {
+            writeln!(w, "      </MultiGeometry>")?;
+        }
+        writeln!(w, "    </Placemark>")?;
+    }
+
+    for waypoint in waypoints {
+        writeln!(w, "    <Placemark>")?;
+        if let Some(name) = &waypoint.name {
+            writeln!(w, "      <name>{}</name>", escape(name))?;
+        }
+        if let Some(desc) = &waypoint.desc {
+            writeln!(w, "      <description>{}</description>", escape(desc))?;
+        }
+        writeln!(w, "      <Point>")?;
+        writeln!(
+            w,
+            "        <altitudeMode>{}</altitudeMode>",
+            altitude_mode_name(altitude_mode)
+        )?;
+        writeln!(
+            w,
+            "        <coordinates>{}</coordinates>",
+            coordinates(waypoint.lat, waypoint.lon, waypoint.ele)
+        )?;
+        writeln!(w, "      </Point>")?;
+        writeln!(w, "    </Placemark>")?;
+    }
+
+    writeln!(w, "  </Document>")?;
+    writeln!(w, "</kml>")?;
+    Ok(())
+}
+
+fn write_line_string<W: Write>(
+    w: &mut W,
+    points: &[TrackPoint],
+    altitude_mode: AltitudeMode,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(w, "      <LineString>")?;
+    writeln!(w, "        <tessellate>1</tessellate>")?;
+    writeln!(
+        w,
+        "        <altitudeMode>{}</altitudeMode>",
+        altitude_mode_name(altitude_mode)
+    )?;
+    writeln!(w, "        <coordinates>")?;
+    for p in points {
+        writeln!(w, "          {}", coordinates(p.lat, p.lon, p.ele))?;
+    }
+    writeln!(w, "        </coordinates>")?;
+    writeln!(w, "      </LineString>")?;
+    Ok(())
+}
+
+/// Formats the coordinates of a point. Note that KML, like
+/// GeoJSON, puts the longitude first.
+fn coordinates(lat: f64, lon: f64, ele: Option<f64>) -> String {
+    match ele {
+        Some(ele) => format!("{:.6},{:.6},{:.1}", lon, lat, ele),
+        None => format!("{:.6},{:.6}", lon, lat),
+    }
+}
+
+fn altitude_mode_name(altitude_mode: AltitudeMode) -> &'static str {
+    match altitude_mode {
+        AltitudeMode::ClampToGround => "clampToGround",
+        AltitudeMode::Absolute => "absolute",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quick_xml::{events::Event, Reader};
+
+    use super::*;
+    use crate::{
+        model::GpxBuilder,
+        test_helpers::{read_gpx_str, ride_points, track_point},
+    };
+
+    fn kml_string(gpx: &Gpx, waypoints: &[Waypoint], altitude_mode: AltitudeMode) -> String {
+        let mut bytes = Vec::new();
+        write_kml_to_writer(&mut bytes, gpx, waypoints, altitude_mode).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    /// Parses the KML, which fails if it is not well-formed, and returns
+    /// the number of coordinate tuples in each <coordinates> element.
+    fn coordinate_counts(kml: &str) -> Vec<usize> {
+        let mut reader = Reader::from_str(kml);
+        reader.config_mut().check_end_names = true;
+        let mut counts = Vec::new();
+        let mut in_coordinates = false;
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(e) if e.name().as_ref() == b"coordinates" => in_coordinates = true,
+                Event::End(e) if e.name().as_ref() == b"coordinates" => in_coordinates = false,
+                Event::Text(text) if in_coordinates => {
+                    counts.push(text.unescape().unwrap().split_whitespace().count());
+                }
+                Event::Eof => return counts,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn writes_a_line_string_per_segment_and_a_point_per_waypoint() {
+        let mut builder = GpxBuilder::new("test.gpx");
+        builder.add_track(Some("Fish & Chips".to_string()));
+        for p in ride_points(&[(20.0, 600)]) {
+            builder.push_point(p);
+        }
+        builder
+            .add_segment()
+            .push_point(track_point(51.6, -0.1, Some(20.0)))
+            .push_point(track_point(51.7, -0.1, Some(30.0)));
+        let gpx = builder.build();
+        let waypoints = [Waypoint {
+            lat: 51.5,
+            lon: -0.1,
+            ele: None,
+            time: None,
+            name: Some("Cafe".to_string()),
+            desc: None,
+        }];
+
+        let kml = kml_string(&gpx, &waypoints, AltitudeMode::ClampToGround);
+
+        assert_eq!(coordinate_counts(&kml), [61, 2, 1]);
+        assert!(kml.contains("<MultiGeometry>"));
+        assert!(kml.contains("<name>Fish &amp; Chips</name>"));
+        assert!(kml.contains("<altitudeMode>clampToGround</altitudeMode>"));
+        assert!(kml.contains("-0.100000,51.600000,20.0"), "{kml}");
+
+        let kml = kml_string(&gpx, &waypoints, AltitudeMode::Absolute);
+        assert!(!kml.contains("clampToGround"));
+        assert!(kml.contains("<altitudeMode>absolute</altitudeMode>"));
+    }
+
+    #[test]
+    fn a_name_read_from_gpx_is_escaped_once() {
+        let gpx = read_gpx_str(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+  <metadata>
+    <time>2024-06-01T08:00:00Z</time>
+  </metadata>
+  <trk>
+    <name>Fish &amp; Chips</name>
+    <trkseg>
+      <trkpt lat="51.5" lon="-0.1"></trkpt>
+      <trkpt lat="51.6" lon="-0.1"></trkpt>
+    </trkseg>
+  </trk>
+</gpx>
+"#,
+        );
+
+        let kml = kml_string(&gpx, &[], AltitudeMode::ClampToGround);
+
+        assert_eq!(coordinate_counts(&kml), [2]);
+        assert!(kml.contains("<name>Fish &amp; Chips</name>"), "{kml}");
+        assert!(!kml.contains("&amp;amp;"), "{kml}");
+    }
+}
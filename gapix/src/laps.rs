@@ -0,0 +1,115 @@
+//! Contains the functionality relating to Laps. On a closed-loop course,
+//! such as a criterium or a session on a track, the rider passes the start
+//! point again and again, and each time they do a new lap begins.
+
+use geo::GeodesicDistance;
+use log::info;
+use logging_timer::time;
+
+use crate::model::EnrichedGpx;
+
+/// Detects the laps of a closed-loop course and returns the (start, end)
+/// indexes of each lap, in the same form as 'split_by_distance', so each
+/// lap starts on the point where the previous one ended.
+///
+/// A lap ends when the track comes back to within 'proximity_metres' of
+/// the first point, having travelled at least 'min_lap_distance_metres'
+/// since the lap started. The minimum distance stops the points just after
+/// the start (and the dawdling around it) counting as laps. Of the points
+/// in each pass near the start the nearest one is used as the end of the
+/// lap. Any part of the track after the last return to the start is not
+/// a lap.
+///
+/// Must be called after the points have been enriched.
+#[time]
+pub fn detect_laps(
+    gpx: &EnrichedGpx,
+    proximity_metres: f64,
+    min_lap_distance_metres: f64,
+) -> Vec<(usize, usize)> {
+    let mut laps = Vec::new();
+    let Some(first) = gpx.points.first() else {
+        return laps;
+    };
+
+    let start = first.as_geo_point();
+    let mut lap_start_idx = 0;
+    // The nearest point to the start, and its distance, in the current
+    // pass near the start.
+    let mut nearest: Option<(usize, f64)> = None;
+
+    for (idx, p) in gpx.points.iter().enumerate().skip(1) {
+        let metres_to_start = p.as_geo_point().geodesic_distance(&start);
+        let lap_metres = p.running_metres - gpx.points[lap_start_idx].running_metres;
+
+        if metres_to_start <= proximity_metres && lap_metres >= min_lap_distance_metres {
+            if nearest.is_none_or(|(_, m)| metres_to_start < m) {
+                nearest = Some((idx, metres_to_start));
+            }
+        } else if let Some((end_idx, _)) = nearest.take() {
+            laps.push((lap_start_idx, end_idx));
+            lap_start_idx = end_idx;
+        }
+    }
+
+    // The track may finish while still near the start.
+    if let Some((end_idx, _)) = nearest {
+        laps.push((lap_start_idx, end_idx));
+    }
+
+    info!("Detected {} laps in {:?}", laps.len(), gpx.filename);
+    laps
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::{
+        model::TrackPoint,
+        test_helpers::{enriched, xy_point},
+    };
+
+    /// Makes a figure-of-eight, starting and finishing at the crossing
+    /// point: a loop with a radius of 200m to the north, then one to the
+    /// south, repeated 'count' times. Each loop is about 1257m long.
+    fn figures_of_eight(count: usize) -> Vec<TrackPoint> {
+        let radius = 200.0;
+        let steps = 72;
+        let mut points = Vec::new();
+        for _ in 0..count {
+            for (centre_y, direction) in [(radius, 1.0), (-radius, -1.0)] {
+                for step in 0..steps {
+                    // Start at the bottom of the north loop, or the top of
+                    // the south one, which are both the crossing point.
+                    let angle =
+                        -direction * PI / 2.0 + direction * 2.0 * PI * step as f64 / steps as f64;
+                    points.push(xy_point(
+                        radius * angle.cos(),
+                        centre_y + radius * angle.sin(),
+                    ));
+                }
+            }
+        }
+        points.push(xy_point(0.0, 0.0));
+        points
+    }
+
+    #[test]
+    fn each_figure_of_eight_is_a_lap() {
+        let gpx = enriched(figures_of_eight(3));
+
+        let laps = detect_laps(&gpx, 20.0, 2000.0);
+
+        let last = gpx.points.len() - 1;
+        assert_eq!(laps, [(0, 144), (144, 288), (288, last)]);
+    }
+
+    #[test]
+    fn a_shorter_minimum_counts_each_loop_as_a_lap() {
+        let gpx = enriched(figures_of_eight(3));
+
+        assert_eq!(detect_laps(&gpx, 20.0, 1000.0).len(), 6);
+    }
+}
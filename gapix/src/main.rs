@@ -1,106 +1,988 @@
-use args::parse_args;
+use args::{parse_args, Args, SimplifyMethod};
 use clap::builder::styling::AnsiColor;
-use env_logger::Builder;
+use climbs::{detect_climbs, ClimbParameters};
+use dem::{correct_elevation_from_dem, DemProvider};
+use env_logger::{Builder, Env};
 use excel::{create_summary_xlsx, write_summary_file};
-use gpx_reader::read_gpx_file;
-use log::info;
+use formatting::TimeFormat;
+use geo::{point, GeodesicDistance};
+use geocoding::{reverse_geocode, LocationDb};
+use geojson_writer::write_geojson_file;
+use gpx_reader::{read_gpx_file, read_gpx_from_reader};
+use join::join_input_files;
+use json_writer::write_analysis_json_file;
+use kml_writer::write_kml_file;
+use laps::detect_laps;
+use log::{error, info, LevelFilter};
 use logging_timer::time;
 use model::{EnrichedGpx, Gpx};
-use simplification::{metres_to_epsilon, reduce_trackpoints_by_rdp, write_simplified_gpx_file};
-use stage::{detect_stages, enrich_trackpoints, StageDetectionParameters};
+use progress::{Progress, ProgressBarReporter};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use simplification::{
+    collapse_stationary_clusters, metres_to_epsilon, metres_to_vw_area,
+    reduce_trackpoints_adaptive, reduce_trackpoints_by_rdp, reduce_trackpoints_by_rdp_keeping,
+    reduce_trackpoints_by_rdp_preserving_elevation, reduce_trackpoints_by_vw,
+    reduce_trackpoints_keeping, reduce_trackpoints_to_count, simplify_each_segment,
+    write_segmented_gpx_file, write_simplified_gpx_file, WriteOptions, STDOUT_FILENAME,
+};
+use stage::{
+    despike, detect_stages, enrich_trackpoints, enriched_track_length_metres,
+    fill_missing_elevation, filter_by_hdop, smooth_elevation, split_into_stage_gpx,
+    stages_to_waypoints, track_length_metres, StageDetectionParameters, StageList, TrackStages,
+};
 use std::{
-    fs::read_dir,
-    io::Write,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::{create_dir_all, read_dir},
+    io::{stdin, Write},
     path::{Path, PathBuf},
 };
+use tcx_reader::{is_tcx_file, read_tcx_file};
+use time::Duration;
+use validation::validate_gpx;
 
 mod args;
+mod climbs;
+mod dem;
+mod error;
 mod excel;
 mod formatting;
+mod geocoding;
+mod geojson_writer;
 mod gpx_reader;
+mod join;
+mod json_writer;
+mod kml_writer;
+mod laps;
 mod model;
+mod progress;
 mod simplification;
 mod stage;
+mod tcx_reader;
+#[cfg(test)]
+mod test_helpers;
+mod timezone;
+mod validation;
 
 pub const PROGRAM_NAME: &str = env!("CARGO_PKG_NAME");
 pub const AUTHOR: &str = env!("CARGO_PKG_AUTHORS");
 
+/// The name given to a GPX read from stdin, from which the names of
+/// any outputs other than the simplified file are made.
+const STDIN_FILENAME: &str = "stdin.gpx";
+
 #[time]
 fn main() {
-    configure_logging();
+    let args = parse_args();
+
+    configure_logging(args.log_level());
     info!("Starting {PROGRAM_NAME}");
 
-    let args = parse_args();
+    let input_files = if args.stdin {
+        Vec::new()
+    } else {
+        let input_files = get_list_of_input_files(&get_exe_dir());
+        if input_files.is_empty() {
+            println!("No .gpx or .tcx files found");
+            return;
+        }
+        input_files
+    };
+
+    if !args.join || args.analyse_individually {
+        warn_about_output_collisions(&input_files);
+    }
+
+    if args.dry_run {
+        if args.join {
+            if args.analyse_individually {
+                let individual_args = args.individual_analysis_args();
+                for f in &input_files {
+                    report_planned_outputs(f, &individual_args);
+                }
+            }
+
+            // The joined file is named after the first file once they are
+            // sorted, and we can't sort by time without reading them, so
+            // do the join to be sure of getting the same name.
+            let gpx = match read_files_to_join(&input_files)
+                .and_then(|gpxs| join_input_files(gpxs, args.join_order, args.strict))
+            {
+                Ok(gpx) => gpx,
+                Err(err) => {
+                    println!("Could not join the input files: {err}");
+                    return;
+                }
+            };
+            println!("Would join {} files into a single track", input_files.len());
+            if args.analyse_individually {
+                report_planned_outputs(&make_joined_filename(&gpx.filename), &args);
+            } else {
+                report_planned_outputs(&gpx.filename, &args);
+            }
+        } else {
+            for f in &input_files {
+                report_planned_outputs(f, &args);
+            }
+        }
+        return;
+    }
 
-    let exe_dir = get_exe_dir();
-    let input_files = get_list_of_input_files(&exe_dir);
-    if input_files.is_empty() {
-        println!("No .gpx files found");
+    if args.validate {
+        if !validate_input_files(&input_files) {
+            std::process::exit(1);
+        }
         return;
     }
 
-    // Read all files into RAM.
-    let mut gpxs: Vec<_> = input_files.iter().map(|f| read_gpx_file(f).unwrap()).collect();
+    if args.diagnose {
+        diagnose_input_files(&input_files);
+        return;
+    }
 
-    // Within each file, merge multiple tracks and segments into a single
-    // track-segment. (join_input_files also does that)
-    gpxs = gpxs
-        .into_iter()
-        .map(|gpx| gpx.into_single_track())
-        .collect();
+    if let Some(dir) = &args.output_dir {
+        if let Err(err) = create_dir_all(dir) {
+            eprintln!("Could not create the output directory {:?}: {err}", dir);
+            return;
+        }
+    }
+
+    let location_db = match args
+        .geocode
+        .as_ref()
+        .map(|f| LocationDb::from_file(f))
+        .transpose()
+    {
+        Ok(location_db) => location_db,
+        Err(err) => {
+            eprintln!("Could not read the location database: {err}");
+            return;
+        }
+    };
+
+    let dem = match args
+        .dem
+        .as_ref()
+        .map(|f| DemProvider::from_file(f))
+        .transpose()
+    {
+        Ok(dem) => dem,
+        Err(err) => {
+            eprintln!("Could not read the DEM: {err}");
+            return;
+        }
+    };
+
+    if args.stdin {
+        // Stdout is reserved for the simplified file, so everything
+        // else, including any errors, must go to stderr.
+        let result = read_gpx_from_reader(stdin().lock(), Path::new(STDIN_FILENAME))
+            .map_err(|err| err.into())
+            .and_then(|gpx| process_gpx(gpx, &args, location_db.as_ref(), dem.as_ref()));
+        if let Err(err) = result {
+            error!("Failed to process stdin: {}", err);
+            eprintln!("Could not process stdin: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    // Join if necessary. Keep as a vec (of one element) so that
-    // following loop can be used whether we join or not.
     if args.join {
-        gpxs = vec![join_input_files(gpxs)];
+        // Joining requires all the files to be in RAM at once.
+        // Within each file, merge multiple tracks and segments into a single
+        // track-segment. (join_input_files also does that)
+        let gpxs = match read_files_to_join(&input_files) {
+            Ok(gpxs) => gpxs,
+            Err(err) => {
+                error!("Failed to read the input files: {}", err);
+                println!("Could not read the input files: {err}");
+                return;
+            }
+        };
+
+        if args.analyse_individually {
+            let individual_args = args.individual_analysis_args();
+            for gpx in &gpxs {
+                if let Err(err) = process_gpx(
+                    gpx.clone(),
+                    &individual_args,
+                    location_db.as_ref(),
+                    dem.as_ref(),
+                ) {
+                    error!("Failed to process {:?}: {}", gpx.filename, err);
+                    println!("Could not process {:?}: {err}", gpx.filename);
+                }
+            }
+        }
+
+        let mut gpx = match join_input_files(gpxs, args.join_order, args.strict) {
+            Ok(gpx) => gpx,
+            Err(err) => {
+                println!("Could not join the input files: {err}");
+                return;
+            }
+        };
+        if args.analyse_individually {
+            gpx.filename = make_joined_filename(&gpx.filename);
+        }
+        if let Err(err) = process_gpx(gpx, &args, location_db.as_ref(), dem.as_ref()) {
+            error!("Failed to process the joined file: {}", err);
+            println!("Could not process the joined file: {err}");
+        }
+    } else {
+        // Each file is independent of the others, so we can process them in
+        // parallel. One bad file should not stop the others from being
+        // processed, so collect the failures and report them at the end.
+        let failures: Vec<_> = input_files
+            .par_iter()
+            .filter_map(|f| {
+                let result = read_input_file(f)
+                    .and_then(|gpx| process_gpx(gpx, &args, location_db.as_ref(), dem.as_ref()));
+                result.err().map(|err| (f, err.to_string()))
+            })
+            .collect();
+
+        for (f, err) in &failures {
+            error!("Failed to process {:?}: {}", f, err);
+        }
+
+        if !failures.is_empty() {
+            println!(
+                "{} of {} files could not be processed",
+                failures.len(),
+                input_files.len()
+            );
+        }
     }
+}
 
-    for gpx in gpxs.into_iter() {
-        let summary_filename = make_summary_filename(&gpx.filename);
-        let simplified_filename = make_simplified_filename(&gpx.filename);
+/// Performs all the requested processing on a single GPX: stage detection
+/// and simplification. Multiple tracks and segments are joined into a
+/// single track, unless we are keeping the segments in the simplified file.
+fn process_gpx(
+    mut gpx: Gpx,
+    args: &Args,
+    location_db: Option<&LocationDb>,
+    dem: Option<&DemProvider>,
+) -> Result<(), Box<dyn Error>> {
+    let summary_filename = in_output_dir(make_summary_filename(&gpx.filename), args);
+    let simplified_filename = if args.stdin {
+        PathBuf::from(STDOUT_FILENAME)
+    } else {
+        in_output_dir(make_simplified_filename(&gpx.filename), args)
+    };
+    let geojson_filename = in_output_dir(make_geojson_filename(&gpx.filename), args);
+    let kml_filename = in_output_dir(make_kml_filename(&gpx.filename), args);
+    let json_filename = in_output_dir(make_json_filename(&gpx.filename), args);
 
-        if summary_filename.exists() && simplified_filename.exists() {
-            continue;
+    if summary_filename.exists()
+        && simplified_filename.exists()
+        && (!args.geojson || geojson_filename.exists())
+        && (!args.kml || kml_filename.exists())
+        && (!args.json || json_filename.exists())
+    {
+        return Ok(());
+    }
+
+    if args.repair {
+        let num_sorted = gpx.sort_points_by_time();
+        let num_removed = gpx.remove_duplicate_times();
+        eprintln!(
+            "Repairing {:?} sorted {num_sorted} segments into time order and removed {num_removed} trackpoints with duplicate times",
+            gpx.filename
+        );
+    }
+    if let Some(seconds) = args.time_shift {
+        gpx.shift_times(Duration::seconds(seconds));
+    }
+    if args.reverse {
+        gpx.reverse();
+    }
+    if let Some(bounds) = &args.crop {
+        let removed = gpx.crop_to_bounds(bounds);
+        let remaining_metres: f64 = gpx
+            .tracks
+            .iter()
+            .flat_map(|track| &track.segments)
+            .map(|segment| track_length_metres(&segment.points))
+            .sum();
+        eprintln!(
+            "Cropping removed {removed} trackpoints from {:?}, leaving {} ({:.2}km)",
+            gpx.filename,
+            gpx.num_points(),
+            remaining_metres / 1000.0
+        );
+        if gpx.tracks.is_empty() {
+            return Err("There are no trackpoints inside the crop box".into());
+        }
+    }
+    if args.since.is_some() || args.until.is_some() {
+        let removed = gpx.filter_time_range(args.since, args.until);
+        eprintln!(
+            "Filtering by time removed {removed} trackpoints from {:?}, leaving {}",
+            gpx.filename,
+            gpx.num_points()
+        );
+        if gpx.tracks.is_empty() {
+            return Err("There are no trackpoints inside the time range".into());
+        }
+    }
+    if let Some(radius) = args.privacy_radius {
+        let removed = gpx.apply_privacy_zone(radius);
+        eprintln!(
+            "Removed {removed} trackpoints within {radius}m of the start and end of {:?}, leaving {}",
+            gpx.filename,
+            gpx.num_points()
+        );
+        if gpx.tracks.is_empty() {
+            return Err("There are no trackpoints outside the privacy radius".into());
+        }
+    }
+    if let Some(dem) = dem {
+        let num_corrected = correct_elevation_from_dem(&mut gpx, dem);
+        eprintln!(
+            "Replaced {num_corrected} of {} elevations in {:?} from the DEM",
+            gpx.num_points(),
+            gpx.filename
+        );
+    }
+
+    if !gpx.is_single_track() {
+        eprintln!(
+            "{:?} has {} tracks and {} segments, with {:?} points in each segment{}",
+            gpx.filename,
+            gpx.num_tracks(),
+            gpx.num_segments(),
+            gpx.track_point_counts(),
+            if args.keep_segments {
+                ""
+            } else {
+                ". They will be joined into one; use --keep-segments to keep them in the simplified file"
+            }
+        );
+    }
+
+    let mut segmented_gpx = args.keep_segments.then(|| {
+        let mut segmented_gpx = gpx.clone();
+        if let Some(seconds) = args.split_on_gap {
+            let num_added = segmented_gpx.split_segments_on_time_gap(Duration::seconds(seconds));
+            eprintln!(
+                "Split the segments of {:?} at {num_added} gaps of more than {seconds}s",
+                gpx.filename
+            );
+        }
+        segmented_gpx
+    });
+
+    // The name and number of points of each track, so that they can be
+    // analysed separately once the whole file has been enriched.
+    let tracks: Vec<(Option<String>, usize)> = gpx
+        .tracks
+        .iter()
+        .zip(gpx.track_point_counts())
+        .map(|(track, counts)| (track.name.clone(), counts.iter().sum()))
+        .collect();
+
+    // Always enrich the TrackPoints. Keeps the flow simple and though
+    // it is one of the most expensive operations, it's still quick enough -
+    // yay Rust!
+    let mut gpx = EnrichedGpx::from(gpx);
+    if let Some(max_hdop) = args.max_hdop {
+        let removed = filter_by_hdop(&mut gpx, max_hdop);
+        eprintln!(
+            "Removed {removed} trackpoints with an HDOP greater than {max_hdop} from {:?}, leaving {}",
+            gpx.filename,
+            gpx.points.len()
+        );
+        if gpx.points.is_empty() {
+            return Err("There are no trackpoints with a good enough HDOP".into());
+        }
+    }
+    if let Some(max_speed_kmh) = args.despike {
+        despike(&mut gpx, max_speed_kmh);
+    }
+    if args.fill_elevation {
+        fill_missing_elevation(&mut gpx);
+    }
+    if let Some(window) = args.smooth_elevation {
+        smooth_elevation(&mut gpx, window as usize);
+    }
+    enrich(&mut gpx, args)?;
+
+    let params = StageDetectionParameters {
+        stopped_speed_kmh: args.stopped_speed,
+        min_metres_to_resume: args.stop_resumption_distance,
+        min_duration_seconds: args.min_stop_time * 60.0,
+        initial_classification_speed_kmh: args.initial_stage_speed,
+        stopped_cadence_rpm: args.stopped_cadence,
+        ..Default::default()
+    };
+
+    if args.trim_ends {
+        let removed = gpx.trim_stationary_ends(&params);
+        if removed > 0 {
+            eprintln!(
+                "Trimmed {removed} stationary points from the ends of {:?}",
+                gpx.filename
+            );
+            enrich(&mut gpx, args)?;
+        }
+    }
+
+    if let Some(seconds) = args.resample {
+        let start_count = gpx.points.len();
+        gpx = gpx.resample_by_time(
+            Duration::seconds_f64(seconds),
+            Duration::seconds_f64(args.resample_max_gap),
+        );
+        eprintln!(
+            "Resampling every {seconds}s changed the trackpoint count from {start_count} to {} for {:?}",
+            gpx.points.len(),
+            gpx.filename
+        );
+        enrich(&mut gpx, args)?;
+    }
+
+    // If we are detecting stops (really Stages now), then do that on
+    // the original file, for more precision. Though whether it matters
+    // much in practice is debatable - it only really makes a difference
+    // if your 'metres' input to RDP is largish.
+    report_nearest_points(&gpx, &args.nearest_point, &args.time_format);
+    report_points_at_distance(&gpx, &args.at_km);
+    if args.summary {
+        print_ride_summary(&gpx);
+    }
+
+    let stages = if args.detect_stages {
+        // Do this before detecting the stages, because the stages
+        // take copies of the points.
+        if let Some(db) = location_db {
+            reverse_geocode(&mut gpx, db);
+        }
+
+        let mut stages = detect_stages(&gpx, params.clone());
+        if let Some(min_stage_time) = args.min_stage_time {
+            stages.merge_short_stages(&gpx, min_stage_time * 60.0);
+        }
+        let track_stages = if args.per_track && tracks.len() > 1 {
+            detect_track_stages(&gpx, &tracks, args, &params)?
+        } else {
+            Vec::new()
+        };
+        let climbs = if args.detect_climbs {
+            detect_climbs(&gpx, ClimbParameters::default())
+        } else {
+            Vec::new()
+        };
+        let splits = args
+            .split_km
+            .map(|km| gpx.split_by_distance(km))
+            .unwrap_or_default();
+        let laps = args
+            .lap_proximity
+            .map(|metres| detect_laps(&gpx, metres, args.min_lap_metres))
+            .unwrap_or_default();
+        let fastest: Vec<_> = args
+            .fastest_km
+            .iter()
+            .filter_map(|&km| {
+                gpx.fastest_segment(km * 1000.0)
+                    .map(|(start, end, duration)| (km, start, end, duration))
+            })
+            .collect();
+        let workbook = create_summary_xlsx(
+            args.trackpoint_hyperlinks(),
+            args.units,
+            args.pace,
+            args.min_highlight_metres,
+            &args.map_provider,
+            args.athlete_profile().as_ref(),
+            args.athlete_ftp,
+            args.hr_zones().as_ref(),
+            args.map,
+            &gpx,
+            &stages,
+            &track_stages,
+            &climbs,
+            &splits,
+            &laps,
+            &fastest,
+        )?;
+        write_summary_file(&summary_filename, workbook)?;
+        if args.emit_stop_waypoints {
+            gpx.add_waypoints(stages_to_waypoints(&stages));
+        }
+        stages
+    } else {
+        StageList::default()
+    };
+
+    if args.geojson && !geojson_filename.exists() {
+        write_geojson_file(&geojson_filename, &gpx, &stages, &args.time_format)?;
+    }
+
+    if args.kml && !kml_filename.exists() {
+        write_kml_file(
+            &kml_filename,
+            &gpx.to_gpx(),
+            &gpx.waypoints,
+            args.kml_altitude_mode,
+        )?;
+    }
+
+    if args.json && !json_filename.exists() {
+        write_analysis_json_file(&json_filename, &gpx, &stages, &args.time_format)?;
+    }
+
+    let write_options = WriteOptions {
+        coord_decimals: args.coord_decimals.into(),
+        ele_decimals: args.ele_decimals.into(),
+        pretty: !args.compact,
+        time_decimals: args.time_decimals.map(usize::from),
+        preserve_unknown: args.preserve_unknown,
+        strip_extensions: args.strip_extensions,
+    };
+
+    if args.split_stages {
+        for stage_gpx in split_into_stage_gpx(&gpx, &stages) {
+            let stage_filename = in_output_dir(stage_gpx.filename.clone(), args);
+            if !stage_filename.exists() {
+                write_segmented_gpx_file(&stage_filename, &stage_gpx, &[], &write_options)?;
+            }
+        }
+    }
+
+    // Always do simplification last because it mutates the track,
+    // reducing its accuracy.
+    if !simplified_filename.exists() {
+        // Keep the stage boundaries so that the simplified
+        // track still matches the stages in the summary.
+        let keep = if args.detect_stages {
+            stages.highlighted_trackpoints()
+        } else {
+            HashSet::new()
+        };
+        // The points of the segmented file have no index, so they are
+        // matched to the stage boundaries by their position and time.
+        let keep_positions: HashSet<_> = gpx
+            .points
+            .iter()
+            .filter(|p| keep.contains(&p.index))
+            .map(|p| (p.lat.to_bits(), p.lon.to_bits(), p.time))
+            .collect();
+
+        if let Some(radius) = args.min_metres.filter(|_| args.is_simplifying()) {
+            let removed = collapse_stationary_clusters(&mut gpx.points, radius);
+            eprintln!(
+                "Collapsing stationary clusters within {radius}m removed {removed} trackpoints from {:?}",
+                gpx.filename
+            );
         }
 
-        // Always enrich the TrackPoints. Keeps the flow simple and though
-        // it is one of the most expensive operations, it's still quick enough -
-        // yay Rust!
-        let mut gpx = EnrichedGpx::from(gpx);
-        enrich_trackpoints(&mut gpx);
-
-        // If we are detecting stops (really Stages now), then do that on
-        // the original file, for more precision. Though whether it matters
-        // much in practice is debatable - it only really makes a difference
-        // if your 'metres' input to RDP is largish.
-        if args.detect_stages {
-            let params = StageDetectionParameters {
-                stopped_speed_kmh: args.stopped_speed,
-                min_metres_to_resume: args.stop_resumption_distance,
-                min_duration_seconds: args.min_stop_time * 60.0,
+        if let (Some(epsilon), Some(segmented_gpx)) = (args.rdp_epsilon(), segmented_gpx.as_mut()) {
+            let start_count = segmented_gpx.num_points();
+            simplify_each_segment(segmented_gpx, epsilon, |p| {
+                keep_positions.contains(&(p.lat.to_bits(), p.lon.to_bits(), p.time))
+            });
+            let accuracy = match args.metres {
+                Some(metres) => format!("a precision of {metres}m (epsilon={epsilon})"),
+                None => format!("epsilon={epsilon}"),
             };
+            eprintln!(
+                "Using Ramer-Douglas-Peucker on each segment with {accuracy} reduced the trackpoint count from {start_count} to {} for {:?}",
+                segmented_gpx.num_points(),
+                gpx.filename
+            );
+
+            write_segmented_gpx_file(
+                &simplified_filename,
+                segmented_gpx,
+                &gpx.waypoints,
+                &write_options,
+            )?;
+        } else if let Some(metres) = args.metres {
+            let start_count = gpx.points.len();
+
+            match args.algorithm {
+                SimplifyMethod::Rdp => {
+                    let epsilon = metres_to_epsilon(metres);
+                    if args.preserve_elevation {
+                        reduce_trackpoints_keeping(&mut gpx.points, &keep, |points| {
+                            reduce_trackpoints_by_rdp_preserving_elevation(
+                                points,
+                                epsilon,
+                                metres.into(),
+                            )
+                        });
+                    } else if args.adaptive {
+                        reduce_trackpoints_keeping(&mut gpx.points, &keep, |points| {
+                            reduce_trackpoints_adaptive(points, metres)
+                        });
+                    } else {
+                        simplify_by_rdp(&mut gpx, epsilon, &keep, args);
+                    }
+                    eprintln!(
+                        "Using Ramer-Douglas-Peucker with a precision of {metres}m (epsilon={epsilon}) reduced the trackpoint count from {start_count} to {} for {:?}",
+                        gpx.points.len(),
+                        gpx.filename
+                    );
+                }
+                SimplifyMethod::Vw => {
+                    let area = metres_to_vw_area(metres);
+                    reduce_trackpoints_keeping(&mut gpx.points, &keep, |points| {
+                        reduce_trackpoints_by_vw(points, area)
+                    });
+                    eprintln!(
+                        "Using Visvalingam-Whyatt with a precision of {metres}m (area={area}) reduced the trackpoint count from {start_count} to {} for {:?}",
+                        gpx.points.len(),
+                        gpx.filename
+                    );
+                }
+            }
+
+            write_simplified_gpx_file(&simplified_filename, &gpx, &write_options)?;
+        } else if let Some(epsilon) = args.epsilon {
+            let start_count = gpx.points.len();
+            simplify_by_rdp(&mut gpx, epsilon, &keep, args);
+            eprintln!(
+                "Using Ramer-Douglas-Peucker with epsilon={epsilon} reduced the trackpoint count from {start_count} to {} for {:?}",
+                gpx.points.len(),
+                gpx.filename
+            );
+
+            write_simplified_gpx_file(&simplified_filename, &gpx, &write_options)?;
+        } else if let Some(max_points) = args.max_points {
+            let start_count = gpx.points.len();
+            let mut epsilon = 0.0;
+            reduce_trackpoints_keeping(&mut gpx.points, &keep, |points| {
+                epsilon = reduce_trackpoints_to_count(points, max_points as usize)
+            });
+            eprintln!(
+                "Using Ramer-Douglas-Peucker with a target of {max_points} points (epsilon={epsilon}) reduced the trackpoint count from {start_count} to {} for {:?}",
+                gpx.points.len(),
+                gpx.filename
+            );
+
+            write_simplified_gpx_file(&simplified_filename, &gpx, &write_options)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Detects the stages of each track of a file with several tracks, such
+/// as a multi-day tour. 'tracks' is the name and number of points of each
+/// track, and 'gpx' is the whole file after it has been enriched. Tracks
+/// with fewer than 2 points are skipped.
+fn detect_track_stages(
+    gpx: &EnrichedGpx,
+    tracks: &[(Option<String>, usize)],
+    args: &Args,
+    params: &StageDetectionParameters,
+) -> Result<Vec<TrackStages>, Box<dyn Error>> {
+    assert_eq!(
+        tracks
+            .iter()
+            .map(|(_, num_points)| num_points)
+            .sum::<usize>(),
+        gpx.points.len()
+    );
+
+    let mut track_stages = Vec::with_capacity(tracks.len());
+    let mut first_index = 0;
+
+    for (idx, (name, num_points)) in tracks.iter().enumerate() {
+        let range = first_index..first_index + num_points;
+        first_index += num_points;
+        if range.len() < 2 {
+            continue;
+        }
+
+        let mut track_gpx = gpx.extract_track(range.clone(), name.clone());
+        enrich(&mut track_gpx, args)?;
+        let mut stages = detect_stages(&track_gpx, params.clone());
+        if let Some(min_stage_time) = args.min_stage_time {
+            stages.merge_short_stages(&track_gpx, min_stage_time * 60.0);
+        }
+
+        track_stages.push(TrackStages {
+            number: idx + 1,
+            first_index: range.start,
+            gpx: track_gpx,
+            stages,
+        });
+    }
+
+    Ok(track_stages)
+}
+
+/// Simplifies the points using Ramer-Douglas-Peucker with 'epsilon',
+/// never removing the points in 'keep' and showing a progress bar if requested.
+fn simplify_by_rdp(gpx: &mut EnrichedGpx, epsilon: f64, keep: &HashSet<usize>, args: &Args) {
+    if !keep.is_empty() {
+        reduce_trackpoints_by_rdp_keeping(&mut gpx.points, epsilon, keep);
+    } else {
+        let progress = args
+            .progress
+            .then(|| ProgressBarReporter::new("Simplifying", &gpx.filename));
+        reduce_trackpoints_by_rdp(
+            &mut gpx.points,
+            epsilon,
+            progress.as_ref().map(|p| p as &dyn Progress),
+        );
+    }
+}
+
+/// Enriches the points with the settings from 'args', showing
+/// a progress bar if requested.
+fn enrich(gpx: &mut EnrichedGpx, args: &Args) -> Result<(), Box<dyn Error>> {
+    let progress = args
+        .progress
+        .then(|| ProgressBarReporter::new("Enriching", &gpx.filename));
+    enrich_trackpoints(
+        gpx,
+        args.ascent_threshold,
+        args.speed_smoothing_window,
+        progress.as_ref().map(|p| p as &dyn Progress),
+    )?;
+    Ok(())
+}
+
+/// Reads and validates each of the input files, printing any problems.
+/// Returns true if all the files are valid.
+fn validate_input_files(input_files: &[PathBuf]) -> bool {
+    let mut all_valid = true;
+
+    for f in input_files {
+        match read_input_file(f) {
+            Ok(gpx) => {
+                let issues = validate_gpx(&gpx);
+                if issues.is_empty() {
+                    println!("{:?} is valid", f);
+                } else {
+                    println!("{:?} has {} problems:", f, issues.len());
+                    for issue in &issues {
+                        println!("    {issue}");
+                    }
+                    all_valid = false;
+                }
+            }
+            Err(err) => {
+                println!("{:?} could not be read: {err}", f);
+                all_valid = false;
+            }
+        }
+    }
 
-            let stages = detect_stages(&gpx, params);
-            let workbook = create_summary_xlsx(args.trackpoint_hyperlinks(), &gpx, &stages).unwrap();
-            write_summary_file(&summary_filename, workbook).unwrap();
+    all_valid
+}
+
+/// Reads each of the input files and prints any anomalies in the data.
+fn diagnose_input_files(input_files: &[PathBuf]) {
+    for f in input_files {
+        match read_input_file(f) {
+            Ok(gpx) => {
+                let anomalies = EnrichedGpx::from(gpx).diagnostics();
+                if anomalies.is_empty() {
+                    println!("{:?} has no anomalies", f);
+                } else {
+                    println!("{:?} has {} anomalies:", f, anomalies.len());
+                    for anomaly in &anomalies {
+                        println!("    {anomaly}");
+                    }
+                }
+            }
+            Err(err) => println!("{:?} could not be read: {err}", f),
         }
+    }
+}
+
+/// Prints the output files that 'process_gpx' would write for the input file,
+/// using the same rules to decide whether each one is needed and whether it
+/// already exists (in which case it is skipped).
+fn report_planned_outputs(input_file: &Path, args: &Args) {
+    let summary_filename = in_output_dir(make_summary_filename(input_file), args);
+    let simplified_filename = in_output_dir(make_simplified_filename(input_file), args);
+    let geojson_filename = in_output_dir(make_geojson_filename(input_file), args);
+    let kml_filename = in_output_dir(make_kml_filename(input_file), args);
+    let json_filename = in_output_dir(make_json_filename(input_file), args);
+
+    println!("{:?}", input_file);
+
+    if summary_filename.exists()
+        && simplified_filename.exists()
+        && (!args.geojson || geojson_filename.exists())
+        && (!args.kml || kml_filename.exists())
+        && (!args.json || json_filename.exists())
+    {
+        println!("    All outputs already exist, would be skipped");
+        return;
+    }
 
-        // Always do simplification last because it mutates the track,
-        // reducing its accuracy.
-        if !simplified_filename.exists() {
-            if let Some(metres) = args.metres {
-                let epsilon = metres_to_epsilon(metres);
+    let outputs = [
+        (args.detect_stages, &summary_filename, true),
+        (args.geojson, &geojson_filename, false),
+        (args.kml, &kml_filename, false),
+        (args.json, &json_filename, false),
+        (args.is_simplifying(), &simplified_filename, false),
+    ];
 
-                let start_count = gpx.points.len();
-                reduce_trackpoints_by_rdp(&mut gpx.points, epsilon);
+    for (required, filename, overwrite) in outputs {
+        if !required {
+            continue;
+        }
+
+        if !filename.exists() {
+            println!("    Would write {:?}", filename);
+        } else if overwrite {
+            println!("    Would overwrite {:?}", filename);
+        } else {
+            println!("    Would skip {:?}, it already exists", filename);
+        }
+    }
+
+    // We don't know how many stages there are without reading the file,
+    // and each stage file is skipped if it already exists.
+    if args.split_stages {
+        let stage_filename = in_output_dir(input_file.with_extension("stageNN.gpx"), args);
+        println!(
+            "    Would write a file for each Moving stage, named {:?}",
+            stage_filename
+        );
+    }
+}
+
+/// Prints the overall statistics for the track. Values which could not be
+/// calculated are shown as '-'.
+fn print_ride_summary(gpx: &EnrichedGpx) {
+    fn fmt_opt(value: Option<f64>, decimals: usize, unit: &str) -> String {
+        value
+            .map(|v| format!("{v:.decimals$}{unit}"))
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    let summary = gpx.summary();
+    let duration = summary
+        .duration
+        .map(|d| {
+            let secs = d.whole_seconds();
+            format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+        })
+        .unwrap_or_else(|| "-".to_string());
+
+    println!(
+        "Summary of {:?}: distance {:.2}km, duration {duration}, ascent {}, descent {}, avg speed {}, max speed {}, avg HR {}",
+        gpx.filename,
+        summary.distance_km,
+        fmt_opt(summary.ascent_metres, 0, "m"),
+        fmt_opt(summary.descent_metres, 0, "m"),
+        fmt_opt(summary.avg_speed_kmh, 1, "km/h"),
+        fmt_opt(summary.max_speed_kmh, 1, "km/h"),
+        fmt_opt(summary.avg_heart_rate, 0, "bpm"),
+    );
+}
+
+/// Prints the trackpoint nearest to each of the locations. For more than a
+/// handful of locations it is quicker to build an index first.
+fn report_nearest_points(gpx: &EnrichedGpx, locations: &[(f64, f64)], time_format: &TimeFormat) {
+    const INDEX_THRESHOLD: usize = 10;
+
+    let index = (locations.len() > INDEX_THRESHOLD).then(|| gpx.point_index());
+
+    for &(lat, lon) in locations {
+        let nearest = match &index {
+            Some(index) => gpx.nearest_point_indexed(index, lat, lon),
+            None => gpx.nearest_point(lat, lon),
+        };
+
+        if let Some(p) = nearest {
+            let time = p
+                .time
+                .map(|t| time_format.format(&t))
+                .unwrap_or_else(|| "no time".to_string());
+            println!(
+                "Nearest point to {lat},{lon} in {:?} is point {} at {:.3}km ({}), {:.0}m away",
+                gpx.filename,
+                p.index,
+                p.running_metres / 1000.0,
+                time,
+                p.as_geo_point()
+                    .geodesic_distance(&point! { x: lon, y: lat })
+            );
+        }
+    }
+}
+
+/// Prints the location and elevation at each of the distances along the track.
+fn report_points_at_distance(gpx: &EnrichedGpx, distances_km: &[f64]) {
+    for &km in distances_km {
+        match gpx.point_at_distance(km * 1000.0) {
+            Some((lat, lon)) => {
+                let ele = gpx
+                    .elevation_at_distance(km * 1000.0)
+                    .map(|ele| format!("{ele:.1}m"))
+                    .unwrap_or_else(|| "unknown".to_string());
                 println!(
-                    "Using Ramer-Douglas-Peucker with a precision of {metres}m (epsilon={epsilon}) reduced the trackpoint count from {start_count} to {} for {:?}",
-                    gpx.points.len(),
+                    "At {km}km in {:?} the location is {lat:.6},{lon:.6} and the elevation is {ele}",
                     gpx.filename
                 );
-
-                write_simplified_gpx_file(&simplified_filename, &gpx).unwrap();
             }
+            None => println!(
+                "{km}km is not within the track in {:?}, which is {:.3}km long",
+                gpx.filename,
+                enriched_track_length_metres(&gpx.points) / 1000.0
+            ),
+        }
+    }
+}
+
+/// Reads a GPX or TCX file, deciding which it is by looking at the
+/// root element rather than trusting the file extension.
+fn read_input_file(input_file: &Path) -> Result<Gpx, Box<dyn Error>> {
+    let gpx = if is_tcx_file(input_file)? {
+        read_tcx_file(input_file)?
+    } else {
+        read_gpx_file(input_file)?
+    };
+
+    Ok(gpx)
+}
+
+/// Reads all the files to be joined. Within each file, multiple tracks
+/// and segments are merged into a single track-segment.
+fn read_files_to_join(input_files: &[PathBuf]) -> Result<Vec<Gpx>, Box<dyn Error>> {
+    let mut gpxs = Vec::with_capacity(input_files.len());
+    for f in input_files {
+        let gpx = read_input_file(f).map_err(|err| format!("{f:?}: {err}"))?;
+        gpxs.push(gpx.into_single_track());
+    }
+    Ok(gpxs)
+}
+
+/// Moves an output file into the --output-dir, if one was specified,
+/// keeping its filename.
+fn in_output_dir(p: PathBuf, args: &Args) -> PathBuf {
+    match (&args.output_dir, p.file_name()) {
+        (Some(dir), Some(filename)) => dir.join(filename),
+        _ => p,
+    }
+}
+
+/// Warns about input files which have the same name apart from the
+/// extension, such as 'ride.gpx' and 'ride.tcx', since they would
+/// write the same output files.
+fn warn_about_output_collisions(input_files: &[PathBuf]) {
+    let mut seen: HashMap<_, &PathBuf> = HashMap::new();
+    for f in input_files {
+        let Some(stem) = f.file_stem() else {
+            continue;
+        };
+
+        if let Some(other) = seen.insert(stem.to_ascii_lowercase(), f) {
+            println!(
+                "Warning: {:?} and {:?} have the same name, so their output files will overwrite each other",
+                other, f
+            );
         }
     }
 }
@@ -117,34 +999,38 @@ fn make_summary_filename(p: &Path) -> PathBuf {
     p
 }
 
-fn join_input_files(mut input_files: Vec<Gpx>) -> Gpx {
-    for gpx in &input_files {
-        assert!(gpx.is_single_track());
-    }
-
-    // We can't simply re-use the first track/segment due to
-    // multiple mut borrows. So create a new vec of points.
-    let required_capacity: usize = input_files.iter().map(|f| f.num_points()).sum();
-    let mut points = Vec::with_capacity(required_capacity);
-
-    for f in &mut input_files {
-        println!("Joining {:?}", f.filename);
-        points.append(&mut f.tracks[0].segments[0].points);
-    }
+/// The joined track is named after the first file. When the input files
+/// are also analysed individually this is used to give the joined track
+/// a different name, so that its outputs don't overwrite the first file's.
+fn make_joined_filename(p: &Path) -> PathBuf {
+    let mut p = p.to_owned();
+    p.set_extension("joined.gpx");
+    p
+}
 
-    // Sort all the points by ascending time in case
-    // we got the files in a wacky order.
-    points.sort_by_key(|p| p.time);
+fn make_geojson_filename(p: &Path) -> PathBuf {
+    let mut p = p.to_owned();
+    p.set_extension("geojson");
+    p
+}
 
-    println!("Joined {} files", input_files.len());
+fn make_kml_filename(p: &Path) -> PathBuf {
+    let mut p = p.to_owned();
+    p.set_extension("kml");
+    p
+}
 
-    input_files.swap_remove(0)
+fn make_json_filename(p: &Path) -> PathBuf {
+    let mut p = p.to_owned();
+    p.set_extension("analysis.json");
+    p
 }
 
-/// Get a list of all files in the exe_dir that have the ".gpx" extension.
+/// Get a list of all files in the exe_dir that have the ".gpx" or ".tcx" extension.
 /// Be careful to exclude files that actually end in ".simplified.gpx" -
 /// they are output files we already created! If we don't exclude them here,
-/// we end up generating ".simplified.simplified.gpx", etc.
+/// we end up generating ".simplified.simplified.gpx", etc. The same goes
+/// for the ".stageNN.gpx" files written by --split-stages.
 /// Remarks: the list of files is guaranteed to be sorted, this is
 /// important for the joining algorithm (the first file is expected to
 /// be the first part of the track, and so on).
@@ -161,9 +1047,10 @@ fn get_list_of_input_files(exe_dir: &PathBuf) -> Vec<PathBuf> {
             let s = &entry.file_name();
             let p = Path::new(s);
             if let Some(ext) = p.extension() {
-                if ext.to_ascii_lowercase() == "gpx" {
+                let ext = ext.to_ascii_lowercase();
+                if ext == "gpx" || ext == "tcx" {
                     let s = s.to_string_lossy().to_ascii_lowercase();
-                    if !s.ends_with(".simplified.gpx") {
+                    if !s.ends_with(".simplified.gpx") && !is_stage_filename(&s) {
                         files.push(entry.path());
                     }
                 }
@@ -180,14 +1067,33 @@ fn get_list_of_input_files(exe_dir: &PathBuf) -> Vec<PathBuf> {
     files
 }
 
+/// Returns true if the filename is one written by --split-stages,
+/// such as "ride.stage03.gpx".
+fn is_stage_filename(filename: &str) -> bool {
+    filename
+        .strip_suffix(".gpx")
+        .and_then(|s| s.rsplit_once(".stage"))
+        .is_some_and(|(_, number)| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
 fn get_exe_dir() -> PathBuf {
     let mut exe_path = std::env::current_exe().unwrap();
     exe_path.pop();
     exe_path
 }
 
-fn configure_logging() {
-    let mut builder = Builder::from_default_env();
+/// Configures the logger. If 'level' is specified it replaces any
+/// filters in RUST_LOG, otherwise RUST_LOG is used as normal.
+fn configure_logging(level: Option<LevelFilter>) {
+    let mut builder = match level {
+        Some(level) => {
+            // Still respect RUST_LOG_STYLE.
+            let mut builder = Builder::from_env(Env::new().write_style("RUST_LOG_STYLE"));
+            builder.filter_level(level);
+            builder
+        }
+        None => Builder::from_default_env(),
+    };
 
     builder.format(|buf, record| {
         let level_style = buf.default_level_style(record.level());
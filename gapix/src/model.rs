@@ -1,11 +1,19 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    ops::Range,
+    path::PathBuf,
+    str::FromStr,
+};
 
-use geo::{point, Point};
+use geo::{point, GeodesicDistance, Point};
 use time::{Duration, OffsetDateTime};
 
+use crate::{geocoding::SpatialIndex, stage::speed_kmh_from_duration, PROGRAM_NAME};
+
 /// Data parsed from a GPX file, based on the XSD description at
 /// https://www.topografix.com/GPX/1/1/gpx.xsd
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Gpx {
     pub filename: PathBuf,
     pub declaration: Declaration,
@@ -13,6 +21,10 @@ pub struct Gpx {
     pub metadata: Metadata,
     pub tracks: Vec<Track>,
     // TODO: There can also be a list of waypoints and/or routes.
+    /// The children of the <extensions> of the <gpx>, as raw XML.
+    pub extensions: Option<String>,
+    /// The children of the <gpx> that we do not understand, as raw XML.
+    pub raw_children: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -33,12 +45,95 @@ pub struct GpxInfo {
     pub attributes: HashMap<String, String>,
 }
 
-/// TODO: Parse all fields.
-#[derive(Debug, Clone)]
+/// Data parsed from the <metadata> tag.
+/// This is all the fields per the XSD except 'extensions'.
+#[derive(Debug, Clone, Default)]
 pub struct Metadata {
-    pub link: Link,
-    pub time: Option<OffsetDateTime>,
+    /// The name of the GPX file.
+    pub name: Option<String>,
+    /// A description of the contents of the GPX file.
     pub desc: Option<String>,
+    /// The person or organization who created the GPX file.
+    pub author: Option<Person>,
+    /// Copyright and license information governing use of the file.
+    pub copyright: Option<Copyright>,
+    /// URLs associated with the location described in the file.
+    pub links: Vec<Link>,
+    /// The creation date of the file.
+    pub time: Option<OffsetDateTime>,
+    /// Keywords associated with the file.
+    pub keywords: Option<String>,
+    /// Minimum and maximum coordinates which describe the extent
+    /// of the coordinates in the file.
+    pub bounds: Option<Bounds>,
+}
+
+/// Data parsed from a <author> tag (personType in the XSD).
+#[derive(Debug, Clone)]
+pub struct Person {
+    /// Name of person or organization.
+    pub name: Option<String>,
+    /// Email address.
+    pub email: Option<Email>,
+    /// Link to Web site or other external information about person.
+    pub link: Option<Link>,
+}
+
+/// Data parsed from an <email> tag. The email address is
+/// split into two parts, e.g. "id@domain".
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub id: String,
+    pub domain: String,
+}
+
+/// Data parsed from a <copyright> tag.
+#[derive(Debug, Clone)]
+pub struct Copyright {
+    /// Copyright holder.
+    pub author: String,
+    /// Year of copyright.
+    pub year: Option<i32>,
+    /// Link to external file containing license text.
+    pub license: Option<String>,
+}
+
+/// Data parsed from a <bounds> tag. Two lat/lon pairs defining
+/// the extent of an element.
+#[derive(Debug, Clone)]
+pub struct Bounds {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+impl Bounds {
+    /// Calculates the smallest Bounds that encloses all the (lat, lon)
+    /// pairs. Returns None if there are no pairs.
+    fn enclosing(mut lat_lons: impl Iterator<Item = (f64, f64)>) -> Option<Self> {
+        let (lat, lon) = lat_lons.next()?;
+        let mut bounds = Self {
+            min_lat: lat,
+            min_lon: lon,
+            max_lat: lat,
+            max_lon: lon,
+        };
+
+        for (lat, lon) in lat_lons {
+            bounds.min_lat = bounds.min_lat.min(lat);
+            bounds.min_lon = bounds.min_lon.min(lon);
+            bounds.max_lat = bounds.max_lat.max(lat);
+            bounds.max_lon = bounds.max_lon.max(lon);
+        }
+
+        Some(bounds)
+    }
+
+    /// Returns true if the lat-lon is inside the bounds (or on the edge).
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        (self.min_lat..=self.max_lat).contains(&lat) && (self.min_lon..=self.max_lon).contains(&lon)
+    }
 }
 
 /// Data parsed from a <link> tag.
@@ -54,17 +149,52 @@ pub struct Link {
 }
 
 /// TODO: Parse all fields.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Track {
     pub name: Option<String>,
     pub r#type: Option<String>,
+    /// GPS comment for track.
+    pub comment: Option<String>,
     pub desc: Option<String>,
+    pub extensions: Option<TrackExtensions>,
+    /// The children that we do not understand, such as the GPX 1.0
+    /// <number>, as raw XML.
+    pub raw_children: Option<String>,
     pub segments: Vec<TrackSegment>,
 }
 
-#[derive(Debug)]
+/// The <extensions> of a <trk>. The only one we understand is the Garmin
+/// display colour, from https://www8.garmin.com/xmlschemas/GpxExtensionsv3.xsd,
+/// which devices and Garmin Connect use to draw the track.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackExtensions {
+    /// From <gpxx:TrackExtension><gpxx:DisplayColor>, e.g. "Magenta".
+    pub display_color: Option<String>,
+    /// Any other extension elements, as raw XML, so that they can
+    /// be written back out unchanged.
+    pub other: Option<String>,
+}
+
+/// A waypoint, written as a <wpt> tag. Only the fields we
+/// need to mark places of interest are supported.
+#[derive(Debug, Clone)]
+pub struct Waypoint {
+    pub lat: f64,
+    pub lon: f64,
+    pub ele: Option<f64>,
+    pub time: Option<OffsetDateTime>,
+    pub name: Option<String>,
+    pub desc: Option<String>,
+}
+
+#[derive(Debug, Clone)]
 pub struct TrackSegment {
     pub points: Vec<TrackPoint>,
+    /// The children of the <extensions> of the <trkseg>, as raw XML.
+    /// Some programs store the colour or visibility of the segment here.
+    pub extensions: Option<String>,
+    /// The children that we do not understand, as raw XML.
+    pub raw_children: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -73,21 +203,134 @@ pub struct TrackPoint {
     pub lon: f64,
     pub ele: Option<f64>,
     pub time: Option<OffsetDateTime>,
+    /// The less common position and accuracy elements.
+    pub fix_info: Option<FixInfo>,
     pub extensions: Option<Extensions>,
+    /// The children that we do not understand, such as the GPX 1.0
+    /// <name> or <sym>, as raw XML.
+    pub raw_children: Option<String>,
+}
+
+impl TrackPoint {
+    /// Converts to a geo Point. As for 'EnrichedTrackPoint::as_geo_point',
+    /// x=lon and y=lat.
+    pub fn as_geo_point(&self) -> Point {
+        point! { x: self.lon, y: self.lat }
+    }
+}
+
+/// The elements of a <trkpt> (type="wptType" in the XSD) which describe
+/// the position and the quality of the GPS fix, other than lat, lon, ele
+/// and time. Few devices write these, but when they do we keep them so
+/// that they survive the round-trip into the simplified file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FixInfo {
+    /// Magnetic variation, in degrees.
+    pub magvar: Option<f64>,
+    /// Height of the geoid (mean sea level) above the WGS84 ellipsoid, in metres.
+    pub geoid_height: Option<f64>,
+    pub fix: Option<FixType>,
+    /// The number of satellites used to calculate the fix.
+    pub sat: Option<u16>,
+    /// Horizontal dilution of precision.
+    pub hdop: Option<f64>,
+    /// Vertical dilution of precision.
+    pub vdop: Option<f64>,
+    /// Position dilution of precision.
+    pub pdop: Option<f64>,
+    /// Seconds since the last DGPS update.
+    pub age_of_dgps_data: Option<f64>,
+    /// The ID of the DGPS station used in differential correction.
+    pub dgps_id: Option<u16>,
+}
+
+/// The type of GPS fix. 'None' means that the GPS had no fix, which is
+/// different to the <fix> element being absent (the fix is unknown).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixType {
+    None,
+    TwoD,
+    ThreeD,
+    Dgps,
+    Pps,
+}
+
+impl Display for FixType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FixType::None => write!(f, "none"),
+            FixType::TwoD => write!(f, "2d"),
+            FixType::ThreeD => write!(f, "3d"),
+            FixType::Dgps => write!(f, "dgps"),
+            FixType::Pps => write!(f, "pps"),
+        }
+    }
+}
+
+impl FromStr for FixType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(FixType::None),
+            "2d" => Ok(FixType::TwoD),
+            "3d" => Ok(FixType::ThreeD),
+            "dgps" => Ok(FixType::Dgps),
+            "pps" => Ok(FixType::Pps),
+            _ => Err("expected one of 'none', '2d', '3d', 'dgps' or 'pps'".to_string()),
+        }
+    }
 }
 
 /// All the Garmin TrackPoint extensions according to
-/// https://www8.garmin.com/xmlschemas/TrackPointExtensionv1.xsd
-#[derive(Debug, Clone)]
+/// https://www8.garmin.com/xmlschemas/TrackPointExtensionv2.xsd
+/// (v1 is the same minus 'speed' and 'course').
+#[derive(Debug, Clone, Default)]
 pub struct Extensions {
     pub air_temp: Option<f64>,
     pub water_temp: Option<f64>,
     pub depth: Option<f64>,
     pub heart_rate: Option<u16>,
     pub cadence: Option<u16>,
+    /// The speed recorded by the device, in metres per second.
+    pub speed: Option<f64>,
+    /// The direction of travel recorded by the device, in degrees
+    /// clockwise from true north.
+    pub course: Option<f64>,
+    /// Power, in watts, from a power meter. This is not part of the
+    /// Garmin schema but is commonly written alongside it.
+    pub power: Option<u16>,
+    /// Any other extension elements, as raw XML.
+    pub other: Option<String>,
 }
 
 impl Gpx {
+    /// Returns the number of tracks.
+    pub fn num_tracks(&self) -> usize {
+        self.tracks.len()
+    }
+
+    /// Returns the total number of segments across all tracks.
+    pub fn num_segments(&self) -> usize {
+        self.tracks.iter().map(|track| track.segments.len()).sum()
+    }
+
+    /// Returns the number of points in each segment of each track,
+    /// e.g. [[100, 20], [50]] for a file with two tracks, the first
+    /// of which has two segments.
+    pub fn track_point_counts(&self) -> Vec<Vec<usize>> {
+        self.tracks
+            .iter()
+            .map(|track| {
+                track
+                    .segments
+                    .iter()
+                    .map(|segment| segment.points.len())
+                    .collect()
+            })
+            .collect()
+    }
+
     /// Returns the total number of points across all tracks and segments.
     pub fn num_points(&self) -> usize {
         self.tracks
@@ -102,6 +345,272 @@ impl Gpx {
             .sum()
     }
 
+    /// Calculates the bounds of all the points in all the tracks.
+    /// Returns None if there are no points.
+    pub fn compute_bounds(&self) -> Option<Bounds> {
+        self.compute_bounds_with_waypoints(&[])
+    }
+
+    /// Calculates the bounds of all the points in all the tracks and of
+    /// 'waypoints', which are not part of the Gpx but are written with it.
+    /// Returns None if there are no points.
+    pub fn compute_bounds_with_waypoints(&self, waypoints: &[Waypoint]) -> Option<Bounds> {
+        Bounds::enclosing(
+            self.tracks
+                .iter()
+                .flat_map(|track| &track.segments)
+                .flat_map(|segment| &segment.points)
+                .map(|p| (p.lat, p.lon))
+                .chain(waypoints.iter().map(|w| (w.lat, w.lon))),
+        )
+    }
+
+    /// Adds 'delta' (which may be negative) to the time of every point and
+    /// to the metadata time. This is for correcting files recorded by a device
+    /// whose clock was wrong. Because every time moves by the same amount the
+    /// gaps between points do not change, so a uniform shift can never break
+    /// the requirement that times are increasing.
+    pub fn shift_times(&mut self, delta: Duration) {
+        if let Some(time) = &mut self.metadata.time {
+            *time += delta;
+        }
+
+        for point in self
+            .tracks
+            .iter_mut()
+            .flat_map(|track| &mut track.segments)
+            .flat_map(|segment| &mut segment.points)
+        {
+            if let Some(time) = &mut point.time {
+                *time += delta;
+            }
+        }
+    }
+
+    /// Removes all the extensions: those on the <gpx>, the tracks, the
+    /// segments and the trackpoints. This loses the heart rate, cadence
+    /// and temperatures, so it should only be done just before writing.
+    pub fn strip_extensions(&mut self) {
+        self.extensions = None;
+
+        for track in &mut self.tracks {
+            track.extensions = None;
+            for segment in &mut track.segments {
+                segment.extensions = None;
+                for point in &mut segment.points {
+                    point.extensions = None;
+                }
+            }
+        }
+    }
+
+    /// Sorts the points of each segment into time order, for repairing
+    /// files whose points have been shuffled, e.g. by a bad merge. Unlike
+    /// joining, the tracks and segments are left as they are. Segments with
+    /// a point that has no time are left alone, because there is no way to
+    /// tell where that point belongs. Returns the number of segments that
+    /// were out of order.
+    pub fn sort_points_by_time(&mut self) -> usize {
+        let mut num_sorted = 0;
+
+        for segment in self.tracks.iter_mut().flat_map(|track| &mut track.segments) {
+            if segment.points.iter().any(|p| p.time.is_none())
+                || segment.points.is_sorted_by_key(|p| p.time)
+            {
+                continue;
+            }
+
+            // A stable sort, so points with the same time keep their order.
+            segment.points.sort_by_key(|p| p.time);
+            num_sorted += 1;
+        }
+
+        num_sorted
+    }
+
+    /// Removes the points in each segment which have the same time as the
+    /// previous point. Two points recorded at the same time can't both be
+    /// right, so the first is kept. Returns the number of points removed.
+    pub fn remove_duplicate_times(&mut self) -> usize {
+        let start_count = self.num_points();
+
+        for segment in self.tracks.iter_mut().flat_map(|track| &mut track.segments) {
+            segment
+                .points
+                .dedup_by(|p, prev| p.time.is_some() && p.time == prev.time);
+        }
+
+        start_count - self.num_points()
+    }
+
+    /// Reverses the direction of travel, e.g. to make the return leg of an
+    /// out-and-back route. The order of the tracks, the segments and the
+    /// points are all reversed, and the elevations and extensions go with
+    /// their points. The times are rebuilt so that the reversed track starts
+    /// at the original start time and the time between each pair of points is
+    /// unchanged. Points without a time are left without one.
+    pub fn reverse(&mut self) {
+        let mut times = self
+            .tracks
+            .iter()
+            .flat_map(|track| &track.segments)
+            .flat_map(|segment| &segment.points)
+            .filter_map(|p| p.time);
+        let first_time = times.next();
+        let last_time = times.next_back().or(first_time);
+
+        self.tracks.reverse();
+        for track in &mut self.tracks {
+            track.segments.reverse();
+            for segment in &mut track.segments {
+                segment.points.reverse();
+                if let (Some(first_time), Some(last_time)) = (first_time, last_time) {
+                    for p in &mut segment.points {
+                        if let Some(time) = &mut p.time {
+                            *time = first_time + (last_time - *time);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Splits each segment into several wherever the time between consecutive
+    /// points is more than 'gap', such as when the device was paused without
+    /// starting a new segment. Points without a time never cause a split.
+    /// Returns the number of segments that were added.
+    pub fn split_segments_on_time_gap(&mut self, gap: Duration) -> usize {
+        let mut num_added = 0;
+
+        for track in &mut self.tracks {
+            let mut segments = Vec::with_capacity(track.segments.len());
+
+            for segment in track.segments.drain(..) {
+                let mut points = Vec::with_capacity(segment.points.len());
+                let mut prev_time = None;
+
+                for p in segment.points {
+                    if let (Some(t1), Some(t2)) = (prev_time, p.time) {
+                        if t2 - t1 > gap {
+                            segments.push(TrackSegment {
+                                points: std::mem::take(&mut points),
+                                extensions: segment.extensions.clone(),
+                                raw_children: segment.raw_children.clone(),
+                            });
+                            num_added += 1;
+                        }
+                    }
+                    prev_time = p.time.or(prev_time);
+                    points.push(p);
+                }
+
+                segments.push(TrackSegment {
+                    points,
+                    extensions: segment.extensions,
+                    raw_children: segment.raw_children,
+                });
+            }
+
+            track.segments = segments;
+        }
+
+        num_added
+    }
+
+    /// Removes all the points which are outside 'bounds', for sharing just
+    /// part of a ride. Where a segment leaves the box and later re-enters it
+    /// the segment is split, so that there isn't a straight line across the
+    /// gap. Segments and tracks that end up empty are removed, and the
+    /// metadata bounds are updated to match the remaining points. Returns
+    /// the number of points removed.
+    pub fn crop_to_bounds(&mut self, bounds: &Bounds) -> usize {
+        self.retain_points(|p| bounds.contains(p.lat, p.lon))
+    }
+
+    /// Removes all the points whose time is before 'start' or after 'end',
+    /// for extracting part of a long recording. Either end of the window
+    /// may be open. Points without a time are removed, because we can't
+    /// tell whether they are in the window. As for 'crop_to_bounds' the
+    /// metadata bounds are updated. Returns the number of points removed.
+    pub fn filter_time_range(
+        &mut self,
+        start: Option<OffsetDateTime>,
+        end: Option<OffsetDateTime>,
+    ) -> usize {
+        self.retain_points(|p| {
+            p.time.is_some_and(|t| {
+                start.is_none_or(|start| t >= start) && end.is_none_or(|end| t <= end)
+            })
+        })
+    }
+
+    /// Removes all the points within 'radius_metres' of the first or last
+    /// point, so that a ride can be shared without revealing where it
+    /// started and finished, which is usually home. This is purely
+    /// geographic: where the track passes near the start or end in the
+    /// middle of the ride those points are removed too, and the segment
+    /// is split there. As for 'crop_to_bounds' the metadata bounds are
+    /// updated. Returns the number of points removed.
+    pub fn apply_privacy_zone(&mut self, radius_metres: f64) -> usize {
+        let mut points = self
+            .tracks
+            .iter()
+            .flat_map(|t| &t.segments)
+            .flat_map(|s| &s.points);
+        let Some(start) = points.next().map(TrackPoint::as_geo_point) else {
+            return 0;
+        };
+        let end = points.last().map_or(start, TrackPoint::as_geo_point);
+
+        self.retain_points(|p| {
+            let p = p.as_geo_point();
+            p.geodesic_distance(&start) > radius_metres && p.geodesic_distance(&end) > radius_metres
+        })
+    }
+
+    /// Removes all the points for which 'keep' returns false, splitting
+    /// segments where they have points removed from the middle. Segments
+    /// and tracks that end up empty are removed, and the metadata bounds
+    /// are updated. Returns the number of points removed.
+    fn retain_points(&mut self, keep: impl Fn(&TrackPoint) -> bool) -> usize {
+        let start_count = self.num_points();
+
+        for track in &mut self.tracks {
+            let mut segments = Vec::with_capacity(track.segments.len());
+
+            for segment in track.segments.drain(..) {
+                let mut points = Vec::with_capacity(segment.points.len());
+
+                for p in segment.points {
+                    if keep(&p) {
+                        points.push(p);
+                    } else if !points.is_empty() {
+                        segments.push(TrackSegment {
+                            points: std::mem::take(&mut points),
+                            extensions: segment.extensions.clone(),
+                            raw_children: segment.raw_children.clone(),
+                        });
+                    }
+                }
+
+                if !points.is_empty() {
+                    segments.push(TrackSegment {
+                        points,
+                        extensions: segment.extensions,
+                        raw_children: segment.raw_children,
+                    });
+                }
+            }
+
+            track.segments = segments;
+        }
+
+        self.tracks.retain(|track| !track.segments.is_empty());
+        self.metadata.bounds = self.compute_bounds();
+
+        start_count - self.num_points()
+    }
+
     /// Returns true if the GPX consists of a single track with one segment.
     pub fn is_single_track(&self) -> bool {
         self.tracks.len() == 1 && self.tracks[0].segments.len() == 1
@@ -130,12 +639,140 @@ impl Gpx {
             }
         }
 
-        for idx in (1..self.tracks.len() - 1).rev() {
-            self.tracks.remove(idx);
+        self.tracks.truncate(1);
+        self.tracks[0].segments.truncate(1);
+        self.tracks[0].segments[0].points = points;
+
+        self
+    }
+}
+
+/// Builds a Gpx programmatically, e.g. when converting from another file
+/// format. Points are added to the most recently added segment of the
+/// most recently added track; a track and segment are created automatically
+/// if necessary. Empty segments and tracks are removed by 'build'.
+#[derive(Debug)]
+pub struct GpxBuilder {
+    filename: PathBuf,
+    creator: Option<String>,
+    metadata: Metadata,
+    tracks: Vec<Track>,
+}
+
+impl GpxBuilder {
+    pub fn new(filename: impl Into<PathBuf>) -> Self {
+        Self {
+            filename: filename.into(),
+            creator: None,
+            metadata: Metadata::default(),
+            tracks: Vec::new(),
         }
+    }
+
+    /// Sets the 'creator' attribute. Defaults to the name of this program.
+    pub fn creator(&mut self, creator: impl Into<String>) -> &mut Self {
+        self.creator = Some(creator.into());
+        self
+    }
+
+    pub fn metadata(&mut self, metadata: Metadata) -> &mut Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Starts a new track. Subsequent segments are added to it.
+    pub fn add_track(&mut self, name: Option<String>) -> &mut Self {
+        self.tracks.push(Track {
+            name,
+            r#type: None,
+            comment: None,
+            desc: None,
+            extensions: None,
+            raw_children: None,
+            segments: Vec::new(),
+        });
+        self
+    }
+
+    /// Sets the type of the current track, e.g. "cycling".
+    pub fn track_type(&mut self, track_type: Option<String>) -> &mut Self {
+        self.current_track().r#type = track_type;
+        self
+    }
 
+    /// Starts a new segment in the current track. Subsequent
+    /// points are added to it.
+    pub fn add_segment(&mut self) -> &mut Self {
+        self.current_track().segments.push(TrackSegment {
+            points: Vec::new(),
+            extensions: None,
+            raw_children: None,
+        });
         self
     }
+
+    /// Adds a point to the current segment.
+    pub fn push_point(&mut self, point: TrackPoint) -> &mut Self {
+        let track = self.current_track();
+        if track.segments.is_empty() {
+            track.segments.push(TrackSegment {
+                points: Vec::new(),
+                extensions: None,
+                raw_children: None,
+            });
+        }
+        track.segments.last_mut().unwrap().points.push(point);
+        self
+    }
+
+    /// Creates the Gpx. It is always a GPX 1.1 document, since
+    /// that is what we write.
+    pub fn build(mut self) -> Gpx {
+        for track in &mut self.tracks {
+            track.segments.retain(|segment| !segment.points.is_empty());
+        }
+        self.tracks.retain(|track| !track.segments.is_empty());
+
+        let mut attributes = HashMap::new();
+        attributes.insert(
+            "xmlns".to_string(),
+            "http://www.topografix.com/GPX/1/1".to_string(),
+        );
+        attributes.insert(
+            "xmlns:xsi".to_string(),
+            "http://www.w3.org/2001/XMLSchema-instance".to_string(),
+        );
+        attributes.insert(
+            "xsi:schemaLocation".to_string(),
+            "http://www.topografix.com/GPX/1/1 http://www.topografix.com/GPX/1/1/gpx.xsd"
+                .to_string(),
+        );
+
+        Gpx {
+            filename: self.filename,
+            declaration: Declaration {
+                version: "1.0".to_string(),
+                encoding: Some("UTF-8".to_string()),
+                standalone: None,
+            },
+            info: GpxInfo {
+                creator: self.creator.unwrap_or_else(|| PROGRAM_NAME.to_string()),
+                version: "1.1".to_string(),
+                attributes,
+            },
+            metadata: self.metadata,
+            tracks: self.tracks,
+            extensions: None,
+            raw_children: None,
+        }
+    }
+
+    fn current_track(&mut self) -> &mut Track {
+        if self.tracks.is_empty() {
+            self.add_track(None);
+        }
+        self.tracks.last_mut().unwrap()
+    }
 }
 
 /// An EnrichedGpx is one where we flatten the Tracks and Segments into a
@@ -149,7 +786,18 @@ pub struct EnrichedGpx {
     pub metadata: Metadata,
     pub track_name: Option<String>,
     pub track_type: Option<String>,
+    pub track_comment: Option<String>,
+    pub track_desc: Option<String>,
+    pub track_extensions: Option<TrackExtensions>,
+    pub track_raw_children: Option<String>,
     pub points: Vec<EnrichedTrackPoint>,
+    /// The children of the <extensions> of the <gpx>, as raw XML.
+    pub extensions: Option<String>,
+    /// The children of the <gpx> that we do not understand, as raw XML.
+    pub raw_children: Option<String>,
+    /// Waypoints to be written along with the track. We do not read
+    /// waypoints from the input, these are ones we have created.
+    pub waypoints: Vec<Waypoint>,
 }
 
 impl EnrichedGpx {
@@ -159,36 +807,509 @@ impl EnrichedGpx {
         self.points.len() - 1
     }
 
-    /// Returns the average temperature across the entire track.
-    pub fn avg_temperature(&self) -> Option<f64> {
-        let sum: f64 = self
-            .points
+    /// Calculates the bounds of all the points and waypoints.
+    /// Returns None if there are no points.
+    pub fn compute_bounds(&self) -> Option<Bounds> {
+        Bounds::enclosing(
+            self.points
+                .iter()
+                .map(|p| (p.lat, p.lon))
+                .chain(self.waypoints.iter().map(|w| (w.lat, w.lon))),
+        )
+    }
+
+    /// Returns the point nearest to the lat-lon, measured by geodesic
+    /// distance, or None if there are no points. This checks every point,
+    /// so if you need to do a lot of lookups build a 'point_index' and use
+    /// 'nearest_point_indexed' instead.
+    pub fn nearest_point(&self, lat: f64, lon: f64) -> Option<&EnrichedTrackPoint> {
+        let target = point! { x: lon, y: lat };
+        self.points.iter().min_by(|a, b| {
+            let da = a.as_geo_point().geodesic_distance(&target);
+            let db = b.as_geo_point().geodesic_distance(&target);
+            da.total_cmp(&db)
+        })
+    }
+
+    /// Builds a spatial index of the points, for use with 'nearest_point_indexed'.
+    /// The index refers to the points by their position, so it must be rebuilt
+    /// if points are added or removed.
+    pub fn point_index(&self) -> SpatialIndex<usize> {
+        SpatialIndex::new(
+            self.points
+                .iter()
+                .enumerate()
+                .map(|(idx, p)| (idx, p.lat, p.lon))
+                .collect(),
+        )
+    }
+
+    /// Returns the point nearest to the lat-lon in O(log n) time, using
+    /// an index previously built by 'point_index'.
+    pub fn nearest_point_indexed(
+        &self,
+        index: &SpatialIndex<usize>,
+        lat: f64,
+        lon: f64,
+    ) -> Option<&EnrichedTrackPoint> {
+        index.nearest(lat, lon).map(|&idx| &self.points[idx])
+    }
+
+    /// Projects the points onto a flat plane for plotting, returning the
+    /// (x, y) of each point in metres east and north of the centroid of the
+    /// track. This uses an equirectangular projection centred on the
+    /// centroid, which is accurate to well under 1% over the area covered
+    /// by a single ride, but not over hundreds of kilometres.
+    pub fn project_to_local_xy(&self) -> Vec<(f64, f64)> {
+        const MEAN_EARTH_RADIUS_METRES: f64 = 6_371_008.8;
+
+        if self.points.is_empty() {
+            return Vec::new();
+        }
+
+        let n = self.points.len() as f64;
+        let lat0 = self.points.iter().map(|p| p.lat).sum::<f64>() / n;
+        let lon0 = self.points.iter().map(|p| p.lon).sum::<f64>() / n;
+        let cos_lat0 = lat0.to_radians().cos();
+
+        self.points
             .iter()
-            .flat_map(|p| p.extensions.as_ref())
-            .flat_map(|ext| ext.air_temp)
-            .sum();
+            .map(|p| {
+                let x = (p.lon - lon0).to_radians() * cos_lat0 * MEAN_EARTH_RADIUS_METRES;
+                let y = (p.lat - lat0).to_radians() * MEAN_EARTH_RADIUS_METRES;
+                (x, y)
+            })
+            .collect()
+    }
+
+    /// Returns the lat-lon at 'metres' along the track, linearly
+    /// interpolated between the points either side. Returns None if
+    /// 'metres' is before the start or beyond the end of the track.
+    /// The points must have been enriched.
+    pub fn point_at_distance(&self, metres: f64) -> Option<(f64, f64)> {
+        let (p1, p2, fraction) = self.points_around_distance(metres)?;
+        let lerp = |v1: f64, v2: f64| v1 + (v2 - v1) * fraction;
+        Some((lerp(p1.lat, p2.lat), lerp(p1.lon, p2.lon)))
+    }
+
+    /// Returns the elevation at 'metres' along the track, linearly
+    /// interpolated between the points either side. Returns None if
+    /// 'metres' is before the start or beyond the end of the track, or
+    /// if either of the points does not have an elevation.
+    /// The points must have been enriched.
+    pub fn elevation_at_distance(&self, metres: f64) -> Option<f64> {
+        let (p1, p2, fraction) = self.points_around_distance(metres)?;
+        let (ele1, ele2) = (p1.ele?, p2.ele?);
+        Some(ele1 + (ele2 - ele1) * fraction)
+    }
+
+    /// Finds the points either side of 'metres' along the track, and how
+    /// far 'metres' is between them (0 to 1). If 'metres' is exactly at a
+    /// point then both points are that point.
+    fn points_around_distance(
+        &self,
+        metres: f64,
+    ) -> Option<(&EnrichedTrackPoint, &EnrichedTrackPoint, f64)> {
+        let first = self.points.first()?;
+        let last = self.points.last()?;
+        if !(first.running_metres..=last.running_metres).contains(&metres) {
+            return None;
+        }
+
+        // The first point at or beyond 'metres'.
+        let idx = self.points.partition_point(|p| p.running_metres < metres);
+        let p2 = &self.points[idx];
+        if idx == 0 || p2.running_metres == metres {
+            return Some((p2, p2, 0.0));
+        }
+
+        let p1 = &self.points[idx - 1];
+        let fraction = (metres - p1.running_metres) / (p2.running_metres - p1.running_metres);
+        Some((p1, p2, fraction))
+    }
+
+    /// Returns a copy of the points in 'range' as a track of their own,
+    /// called 'track_name', e.g. to analyse one day of a multi-day tour.
+    /// The points are renumbered from 0, and must be enriched again so
+    /// that the running totals start from the beginning of the range.
+    pub fn extract_track(&self, range: Range<usize>, track_name: Option<String>) -> EnrichedGpx {
+        let mut points = self.points[range].to_vec();
+        for (idx, p) in points.iter_mut().enumerate() {
+            p.index = idx;
+        }
+        if let Some(first) = points.first_mut() {
+            first.segment_start = false;
+        }
+
+        EnrichedGpx {
+            filename: self.filename.clone(),
+            declaration: self.declaration.clone(),
+            info: self.info.clone(),
+            metadata: self.metadata.clone(),
+            track_name,
+            track_type: self.track_type.clone(),
+            track_comment: self.track_comment.clone(),
+            track_desc: self.track_desc.clone(),
+            track_extensions: self.track_extensions.clone(),
+            track_raw_children: self.track_raw_children.clone(),
+            points,
+            extensions: self.extensions.clone(),
+            raw_children: self.raw_children.clone(),
+            waypoints: Vec::new(),
+        }
+    }
+
+    /// Adds waypoints, which will be written out before the track.
+    pub fn add_waypoints(&mut self, waypoints: impl IntoIterator<Item = Waypoint>) {
+        self.waypoints.extend(waypoints);
+    }
+
+    /// Converts back to the plain model, as a Gpx with a single track
+    /// containing a single segment. This is the reverse of the 'From<Gpx>'
+    /// conversion, and reflects any changes made to the points since then
+    /// (despiking, smoothing, resampling, simplification etc.) The enriched
+    /// fields are dropped. The waypoints are not part of the Gpx model, so
+    /// the caller must handle them separately.
+    pub fn to_gpx(&self) -> Gpx {
+        let points = self.points.iter().map(TrackPoint::from).collect();
+
+        Gpx {
+            filename: self.filename.clone(),
+            declaration: self.declaration.clone(),
+            info: self.info.clone(),
+            metadata: self.metadata.clone(),
+            tracks: vec![Track {
+                name: self.track_name.clone(),
+                r#type: self.track_type.clone(),
+                comment: self.track_comment.clone(),
+                desc: self.track_desc.clone(),
+                extensions: self.track_extensions.clone(),
+                raw_children: self.track_raw_children.clone(),
+                segments: vec![TrackSegment {
+                    points,
+                    extensions: None,
+                    raw_children: None,
+                }],
+            }],
+            extensions: self.extensions.clone(),
+            raw_children: self.raw_children.clone(),
+        }
+    }
+
+    /// Splits the track into spans of approximately 'km' kilometres each,
+    /// like the auto-lap feature on a watch, and returns the (start, end)
+    /// indexes of each span. Each span ends on the point nearest to the
+    /// boundary and the next span starts on that same point, so that the
+    /// distance, duration and ascent of a span can be calculated by
+    /// subtracting the 'running' values of its end points. The last span
+    /// is usually shorter than 'km'.
+    ///
+    /// Must be called after the points have been enriched.
+    pub fn split_by_distance(&self, km: f64) -> Vec<(usize, usize)> {
+        assert!(km > 0.0);
+
+        let mut splits = Vec::new();
+        if self.points.len() < 2 {
+            return splits;
+        }
+
+        let split_metres = km * 1000.0;
+        let total_metres = self.points[self.last_valid_idx()].running_metres;
+        let mut start_idx = 0;
+        let mut boundary = split_metres;
+
+        while boundary < total_metres {
+            // 'running_metres' is ascending, so we can binary search for the
+            // first point at or after the boundary. The nearest point is
+            // either that one or the one before it.
+            let after_idx = self.points.partition_point(|p| p.running_metres < boundary);
+            let before_idx = after_idx - 1;
+            let end_idx = if self.points[after_idx].running_metres - boundary
+                < boundary - self.points[before_idx].running_metres
+            {
+                after_idx
+            } else {
+                before_idx
+            };
+
+            // Guard against empty spans when the points are sparse.
+            if end_idx > start_idx {
+                splits.push((start_idx, end_idx));
+                start_idx = end_idx;
+            }
+
+            boundary += split_metres;
+        }
+
+        if start_idx < self.last_valid_idx() {
+            splits.push((start_idx, self.last_valid_idx()));
+        }
+
+        splits
+    }
+
+    /// Finds the quickest part of the track that covers at least
+    /// 'distance_metres', such as the "fastest 5km", and returns the
+    /// (start, end) indexes of its points and the time taken. Returns None
+    /// if the track is shorter than 'distance_metres' or has no times.
+    ///
+    /// Must be called after the points have been enriched.
+    pub fn fastest_segment(&self, distance_metres: f64) -> Option<(usize, usize, Duration)> {
+        assert!(distance_metres > 0.0);
+
+        let mut fastest: Option<(usize, usize, Duration)> = None;
+        let mut start_idx = 0;
+
+        for end_idx in 1..self.points.len() {
+            let end = &self.points[end_idx];
+
+            // Move the start of the window forward for as long as the window
+            // still covers the distance. Time only increases along the track,
+            // so the shortest window ending at this point is also the quickest.
+            while end.running_metres - self.points[start_idx + 1].running_metres >= distance_metres
+            {
+                start_idx += 1;
+            }
+
+            let start = &self.points[start_idx];
+            if end.running_metres - start.running_metres < distance_metres {
+                continue;
+            }
 
-        if sum == 0.0 {
-            None
+            if let (Some(t1), Some(t2)) = (end.time, start.time) {
+                let duration = t1 - t2;
+                if fastest.is_none_or(|(_, _, d)| duration < d) {
+                    fastest = Some((start_idx, end_idx, duration));
+                }
+            }
+        }
+
+        fastest
+    }
+
+    /// Resamples the track so that there is a point every 'interval', like a
+    /// device recording at a fixed rate. The positions and elevations are
+    /// linearly interpolated between the original points, and the extensions
+    /// (heart rate, cadence etc.) are taken from the nearest original point.
+    ///
+    /// When stopped, devices often record nothing for minutes at a time, and
+    /// filling such a gap would produce thousands of identical points. So gaps
+    /// longer than 'max_gap' are not filled in: the points either side of the
+    /// gap are kept and the sampling restarts from the point after the gap.
+    /// The last point is always kept so that the track ends in the same place.
+    ///
+    /// If any point does not have a time the track cannot be resampled and
+    /// the points are copied unchanged. Only the recorded fields are set on
+    /// the new points, so they must be enriched again before use.
+    pub fn resample_by_time(&self, interval: Duration, max_gap: Duration) -> EnrichedGpx {
+        assert!(interval.is_positive());
+
+        let points = if self.points.len() < 2 || self.points.iter().any(|p| p.time.is_none()) {
+            self.points.clone()
         } else {
-            Some(sum / self.points.len() as f64)
+            let time = |idx: usize| self.points[idx].time.unwrap();
+            let mut points: Vec<EnrichedTrackPoint> = Vec::new();
+            let mut next_time = time(0);
+            let mut segment_start = false;
+
+            for idx in 1..self.points.len() {
+                let (p1, p2) = (&self.points[idx - 1], &self.points[idx]);
+
+                // Don't fill in the gap between two segments either.
+                if p2.segment_start || time(idx) - time(idx - 1) > max_gap {
+                    if points.last().and_then(|p| p.time) != Some(time(idx - 1)) {
+                        points.push(interpolate_point(points.len(), p1, p2, time(idx - 1)));
+                    }
+                    next_time = time(idx);
+                    segment_start = p2.segment_start;
+                    continue;
+                }
+
+                while next_time <= time(idx) {
+                    points.push(interpolate_point(points.len(), p1, p2, next_time));
+                    points.last_mut().unwrap().segment_start = segment_start;
+                    segment_start = false;
+                    next_time += interval;
+                }
+            }
+
+            let last_idx = self.last_valid_idx();
+            if points.last().and_then(|p| p.time) != Some(time(last_idx)) {
+                points.push(interpolate_point(
+                    points.len(),
+                    &self.points[last_idx - 1],
+                    &self.points[last_idx],
+                    time(last_idx),
+                ));
+            }
+
+            points
+        };
+
+        EnrichedGpx {
+            filename: self.filename.clone(),
+            declaration: self.declaration.clone(),
+            info: self.info.clone(),
+            metadata: self.metadata.clone(),
+            track_name: self.track_name.clone(),
+            track_type: self.track_type.clone(),
+            track_comment: self.track_comment.clone(),
+            track_desc: self.track_desc.clone(),
+            track_extensions: self.track_extensions.clone(),
+            track_raw_children: self.track_raw_children.clone(),
+            points,
+            extensions: self.extensions.clone(),
+            raw_children: self.raw_children.clone(),
+            waypoints: self.waypoints.clone(),
+        }
+    }
+
+    /// Calculates the overall statistics for the ride. The points must have
+    /// been enriched. These cover the whole track, so the distance and ascent
+    /// can be slightly more than the totals of a StageList: the stage totals
+    /// do not include the step from the last point of one stage to the first
+    /// point of the next.
+    pub fn summary(&self) -> RideSummary {
+        let (Some(first), Some(last)) = (self.points.first(), self.points.last()) else {
+            return RideSummary {
+                distance_km: 0.0,
+                duration: None,
+                ascent_metres: None,
+                descent_metres: None,
+                avg_speed_kmh: None,
+                max_speed_kmh: None,
+                avg_heart_rate: None,
+            };
+        };
+
+        let duration = match (last.time, first.start_time()) {
+            (Some(et), Some(st)) => Some(et - st),
+            _ => None,
+        };
+
+        RideSummary {
+            distance_km: last.running_metres / 1000.0,
+            duration,
+            ascent_metres: last.running_ascent_metres,
+            descent_metres: last.running_descent_metres,
+            avg_speed_kmh: duration.map(|dur| speed_kmh_from_duration(last.running_metres, dur)),
+            max_speed_kmh: self
+                .points
+                .iter()
+                .filter_map(|p| p.effective_speed_kmh())
+                .max_by(f64::total_cmp),
+            avg_heart_rate: self.avg_heart_rate(),
         }
     }
 
+    /// Returns the average temperature across the entire track.
+    /// Only points that have a temperature contribute to the average.
+    pub fn avg_temperature(&self) -> Option<f64> {
+        average(self.points.iter().filter_map(|p| p.air_temp()))
+    }
+
+    /// Returns the average water temperature across the entire track.
+    /// Only points that have a water temperature contribute to the average.
+    pub fn avg_water_temperature(&self) -> Option<f64> {
+        average(self.points.iter().filter_map(|p| p.water_temp()))
+    }
+
+    /// Returns the average depth across the entire track.
+    /// Only points that have a depth contribute to the average.
+    pub fn avg_depth(&self) -> Option<f64> {
+        average(self.points.iter().filter_map(|p| p.depth()))
+    }
+
     /// Returns the average heart rate across the entire track.
+    /// Only points that have a heart rate contribute to the average.
     pub fn avg_heart_rate(&self) -> Option<f64> {
-        let sum: f64 = self
-            .points
-            .iter()
-            .flat_map(|p| p.extensions.as_ref())
-            .flat_map(|ext| ext.heart_rate.map(|hr| hr as f64))
-            .sum();
+        average(
+            self.points
+                .iter()
+                .filter_map(|p| p.heart_rate().map(|hr| hr as f64)),
+        )
+    }
 
-        if sum == 0.0 {
-            None
-        } else {
-            Some(sum / self.points.len() as f64)
-        }
+    /// Returns the average power across the entire track.
+    /// Only points that have a power reading contribute to the average.
+    pub fn avg_power(&self) -> Option<f64> {
+        average(
+            self.points
+                .iter()
+                .filter_map(|p| p.power().map(|pwr| pwr as f64)),
+        )
+    }
+}
+
+/// Makes a new point at 'time', which must be between the times of 'p1'
+/// and 'p2', by linear interpolation.
+fn interpolate_point(
+    index: usize,
+    p1: &EnrichedTrackPoint,
+    p2: &EnrichedTrackPoint,
+    time: OffsetDateTime,
+) -> EnrichedTrackPoint {
+    let span = (p2.time.unwrap() - p1.time.unwrap()).as_seconds_f64();
+    let fraction = if span > 0.0 {
+        (time - p1.time.unwrap()).as_seconds_f64() / span
+    } else {
+        0.0
+    };
+
+    let lerp = |v1: f64, v2: f64| v1 + (v2 - v1) * fraction;
+    let nearest = if fraction < 0.5 { p1 } else { p2 };
+
+    let ele = match (p1.ele, p2.ele) {
+        (Some(ele1), Some(ele2)) => Some(lerp(ele1, ele2)),
+        _ => nearest.ele,
+    };
+
+    let tp = TrackPoint {
+        lat: lerp(p1.lat, p2.lat),
+        lon: lerp(p1.lon, p2.lon),
+        ele,
+        time: Some(time),
+        fix_info: nearest.fix_info.clone(),
+        extensions: nearest.extensions.clone(),
+        raw_children: nearest.raw_children.clone(),
+    };
+
+    EnrichedTrackPoint::new(index, &tp)
+}
+
+/// The overall statistics for a ride, calculated directly from the
+/// enriched points without doing stage detection. Values which cannot
+/// be calculated because the data is missing (e.g. no times) are None.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RideSummary {
+    pub distance_km: f64,
+    /// The time from the start of the first point to the end of the last.
+    pub duration: Option<Duration>,
+    pub ascent_metres: Option<f64>,
+    pub descent_metres: Option<f64>,
+    /// The average speed over the whole duration, including any stops.
+    pub avg_speed_kmh: Option<f64>,
+    /// The maximum 'effective_speed_kmh' of any point.
+    pub max_speed_kmh: Option<f64>,
+    pub avg_heart_rate: Option<f64>,
+}
+
+/// Returns the average of the values, or None if there are no values.
+/// Note that we must use the count to decide whether there is any data:
+/// a set of values which sum to zero (e.g. 0°C) is still valid.
+fn average(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let mut sum = 0.0;
+    let mut count = 0;
+
+    for v in values {
+        sum += v;
+        count += 1;
+    }
+
+    if count == 0 {
+        None
+    } else {
+        Some(sum / count as f64)
     }
 }
 
@@ -206,8 +1327,16 @@ pub struct EnrichedTrackPoint {
     pub ele: Option<f64>,
     /// The time as read from the <time> tag.
     pub time: Option<OffsetDateTime>,
+    /// The position and accuracy info, such as <hdop>.
+    pub fix_info: Option<FixInfo>,
     /// The Garmin TrackPoint extensions.
     pub extensions: Option<Extensions>,
+    /// The children of the <trkpt> that we do not understand, as raw XML.
+    pub raw_children: Option<String>,
+    /// True if this point starts a new segment of the original track. There
+    /// is a gap (such as where the GPS signal was lost) between it and the
+    /// previous point, so the distance between them is not counted.
+    pub segment_start: bool,
 
     // All the below fields are the 'enriched' ones.
     /// The amount of time between this trackpoint and the previous one.
@@ -218,6 +1347,9 @@ pub struct EnrichedTrackPoint {
     pub running_metres: f64,
     /// The instantaneous speed at this point.
     pub speed_kmh: Option<f64>,
+    /// The average speed over the smoothing window ending at this point.
+    /// Only calculated if a smoothing window was specified.
+    pub smoothed_speed_kmh: Option<f64>,
     /// The elapsed time between the beginning of the track and this point.
     pub running_delta_time: Option<Duration>,
     /// The change in elevation between this trackpoint and the previous one.
@@ -226,6 +1358,9 @@ pub struct EnrichedTrackPoint {
     pub running_ascent_metres: Option<f64>,
     /// The running descent between the beginning of the track and this point.
     pub running_descent_metres: Option<f64>,
+    /// The gradient between the previous trackpoint and this one, as a
+    /// percentage. Negative means downhill.
+    pub grade_percent: Option<f64>,
     /// The location (reverse geo-coded based on lat-lon)
     pub location: Option<String>,
 }
@@ -238,15 +1373,20 @@ impl EnrichedTrackPoint {
             lon: value.lon,
             ele: value.ele,
             time: value.time,
+            fix_info: value.fix_info.clone(),
             extensions: value.extensions.clone(),
+            raw_children: value.raw_children.clone(),
+            segment_start: false,
             delta_time: None,
             delta_metres: 0.0,
             running_metres: 0.0,
             speed_kmh: None,
+            smoothed_speed_kmh: None,
             running_delta_time: None,
             ele_delta_metres: None,
             running_ascent_metres: None,
             running_descent_metres: None,
+            grade_percent: None,
             location: Default::default(),
         }
     }
@@ -287,6 +1427,18 @@ impl EnrichedTrackPoint {
         self.extensions.as_ref().and_then(|ext| ext.air_temp)
     }
 
+    /// Convenience function to extract the water_temp from
+    /// the Garmin extensions.
+    pub fn water_temp(&self) -> Option<f64> {
+        self.extensions.as_ref().and_then(|ext| ext.water_temp)
+    }
+
+    /// Convenience function to extract the depth (in metres)
+    /// from the Garmin extensions.
+    pub fn depth(&self) -> Option<f64> {
+        self.extensions.as_ref().and_then(|ext| ext.depth)
+    }
+
     /// Convenience function to extract the heart_rate from
     /// the Garmin extensions.
     pub fn heart_rate(&self) -> Option<u16> {
@@ -298,10 +1450,67 @@ impl EnrichedTrackPoint {
     pub fn cadence(&self) -> Option<u16> {
         self.extensions.as_ref().and_then(|ext| ext.cadence)
     }
+
+    /// The speed to use when looking for the maximum speed: the smoothed
+    /// speed if we have calculated it, else the instantaneous speed.
+    pub fn effective_speed_kmh(&self) -> Option<f64> {
+        self.smoothed_speed_kmh.or(self.speed_kmh)
+    }
+
+    /// Convenience function to extract the speed recorded by the
+    /// device from the Garmin extensions, converted to km/h. Compare
+    /// with 'speed_kmh', which we calculate ourselves.
+    pub fn recorded_speed_kmh(&self) -> Option<f64> {
+        self.extensions
+            .as_ref()
+            .and_then(|ext| ext.speed)
+            .map(|mps| mps * 3.6)
+    }
+
+    /// Convenience function to extract the course (in degrees)
+    /// from the Garmin extensions.
+    pub fn course(&self) -> Option<f64> {
+        self.extensions.as_ref().and_then(|ext| ext.course)
+    }
+
+    /// Convenience function to extract the power (in watts)
+    /// from the extensions.
+    pub fn power(&self) -> Option<u16> {
+        self.extensions.as_ref().and_then(|ext| ext.power)
+    }
+
+    /// Convenience function to extract the horizontal dilution
+    /// of precision from the fix info.
+    pub fn hdop(&self) -> Option<f64> {
+        self.fix_info.as_ref().and_then(|fi| fi.hdop)
+    }
+}
+
+impl From<&EnrichedTrackPoint> for TrackPoint {
+    /// Drops all the enriched fields.
+    fn from(value: &EnrichedTrackPoint) -> Self {
+        Self {
+            lat: value.lat,
+            lon: value.lon,
+            ele: value.ele,
+            time: value.time,
+            fix_info: value.fix_info.clone(),
+            extensions: value.extensions.clone(),
+            raw_children: value.raw_children.clone(),
+        }
+    }
 }
 
 impl From<Gpx> for EnrichedGpx {
     fn from(value: Gpx) -> Self {
+        // Remember where each segment starts, since that is lost when
+        // they are joined into one.
+        let mut segment_starts = Vec::with_capacity(value.num_points());
+        for segment in value.tracks.iter().flat_map(|t| &t.segments) {
+            let is_first = segment_starts.is_empty();
+            segment_starts.extend((0..segment.points.len()).map(|idx| idx == 0 && !is_first));
+        }
+
         let value = value.into_single_track();
 
         Self {
@@ -311,12 +1520,625 @@ impl From<Gpx> for EnrichedGpx {
             metadata: value.metadata,
             track_name: value.tracks[0].name.clone(),
             track_type: value.tracks[0].r#type.clone(),
+            track_comment: value.tracks[0].comment.clone(),
+            track_desc: value.tracks[0].desc.clone(),
+            track_extensions: value.tracks[0].extensions.clone(),
+            track_raw_children: value.tracks[0].raw_children.clone(),
             points: value.tracks[0].segments[0]
                 .points
                 .iter()
                 .enumerate()
-                .map(|(idx, tp)| EnrichedTrackPoint::new(idx, tp))
+                .zip(segment_starts)
+                .map(|((idx, tp), segment_start)| EnrichedTrackPoint {
+                    segment_start,
+                    ..EnrichedTrackPoint::new(idx, tp)
+                })
                 .collect(),
+            extensions: value.extensions,
+            raw_children: value.raw_children,
+            waypoints: Vec::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        stage::enrich_trackpoints,
+        test_helpers::{
+            enriched, make_enriched_gpx, make_gpx, ride_points, round_trip, start_time,
+            track_point, xy_point,
+        },
+    };
+
+    /// Makes a track with one point per value, each with that air temperature.
+    fn track_with_temperatures(temps: &[Option<f64>]) -> EnrichedGpx {
+        let points = temps
+            .iter()
+            .enumerate()
+            .map(|(i, &air_temp)| TrackPoint {
+                extensions: Some(Extensions {
+                    air_temp,
+                    heart_rate: air_temp.map(|t| t as u16),
+                    ..Default::default()
+                }),
+                ..xy_point(i as f64 * 10.0, 0.0)
+            })
+            .collect();
+        make_enriched_gpx(points)
+    }
+
+    #[test]
+    fn averages_only_count_points_with_a_value() {
+        let gpx = track_with_temperatures(&[Some(10.0), None, Some(20.0), None]);
+        assert_eq!(gpx.avg_temperature(), Some(15.0));
+        assert_eq!(gpx.avg_heart_rate(), Some(15.0));
+    }
+
+    #[test]
+    fn averages_of_zero_are_not_missing() {
+        let gpx = track_with_temperatures(&[Some(0.0), Some(0.0), Some(0.0)]);
+        assert_eq!(gpx.avg_temperature(), Some(0.0));
+        assert_eq!(gpx.avg_heart_rate(), Some(0.0));
+    }
+
+    #[test]
+    fn averages_are_none_without_any_values() {
+        let gpx = track_with_temperatures(&[None, None]);
+        assert_eq!(gpx.avg_temperature(), None);
+        assert_eq!(gpx.avg_heart_rate(), None);
+    }
+
+    #[test]
+    fn bounds_enclose_the_points_of_every_track() {
+        let mut builder = GpxBuilder::new("test.gpx");
+        builder
+            .add_track(None)
+            .push_point(track_point(51.0, -1.0, None))
+            .push_point(track_point(51.2, -0.5, None))
+            .add_segment()
+            .push_point(track_point(50.9, -0.8, None))
+            .add_track(None)
+            .push_point(track_point(51.5, 0.3, None));
+        let gpx = builder.build();
+
+        let bounds = gpx.compute_bounds().unwrap();
+        assert_eq!(
+            (
+                bounds.min_lat,
+                bounds.min_lon,
+                bounds.max_lat,
+                bounds.max_lon
+            ),
+            (50.9, -1.0, 51.5, 0.3)
+        );
+    }
+
+    #[test]
+    fn bounds_enclose_the_waypoints() {
+        let mut gpx = make_enriched_gpx(vec![
+            track_point(51.0, -1.0, None),
+            track_point(51.2, -0.5, None),
+        ]);
+        let waypoint = Waypoint {
+            lat: 52.0,
+            lon: -2.0,
+            ele: None,
+            time: None,
+            name: None,
+            desc: None,
+        };
+        gpx.waypoints.push(waypoint.clone());
+
+        let corners = |bounds: Bounds| {
+            (
+                bounds.min_lat,
+                bounds.min_lon,
+                bounds.max_lat,
+                bounds.max_lon,
+            )
+        };
+        assert_eq!(
+            corners(gpx.compute_bounds().unwrap()),
+            (51.0, -2.0, 52.0, -0.5)
+        );
+
+        // The plain Gpx does not hold the waypoints, they are written
+        // alongside it.
+        let gpx = gpx.to_gpx();
+        assert_eq!(
+            corners(gpx.compute_bounds().unwrap()),
+            (51.0, -1.0, 51.2, -0.5)
+        );
+        assert_eq!(
+            corners(gpx.compute_bounds_with_waypoints(&[waypoint]).unwrap()),
+            (51.0, -2.0, 52.0, -0.5)
+        );
+    }
+
+    #[test]
+    fn an_empty_file_has_no_bounds() {
+        let gpx = GpxBuilder::new("test.gpx").build();
+        assert!(gpx.compute_bounds().is_none());
+    }
+
+    /// Makes an enriched track heading east with a point every 'step' metres.
+    fn straight_line(step: f64, count: usize) -> EnrichedGpx {
+        enriched((0..count).map(|i| xy_point(i as f64 * step, 0.0)).collect())
+    }
+
+    #[test]
+    fn a_track_shorter_than_one_split_is_a_single_split() {
+        let gpx = straight_line(100.0, 6);
+        assert_eq!(gpx.split_by_distance(1.0), vec![(0, 5)]);
+    }
+
+    #[test]
+    fn a_boundary_on_a_point_ends_the_split_there() {
+        // Points every 250m, so every 4th point is on a kilometre boundary.
+        let gpx = straight_line(250.0, 11);
+        assert_eq!(gpx.split_by_distance(1.0), vec![(0, 4), (4, 8), (8, 10)]);
+    }
+
+    #[test]
+    fn a_built_two_point_track_round_trips() {
+        let mut builder = GpxBuilder::new("built.gpx");
+        builder
+            .creator("builder test")
+            .metadata(Metadata {
+                name: Some("Two points".to_string()),
+                time: Some(start_time()),
+                ..Default::default()
+            })
+            .add_track(Some("Track".to_string()))
+            .push_point(TrackPoint {
+                time: Some(start_time()),
+                ..track_point(51.5, -0.1, Some(10.0))
+            })
+            .push_point(TrackPoint {
+                time: Some(start_time() + Duration::seconds(10)),
+                ..track_point(51.501, -0.1, Some(12.0))
+            });
+        let gpx = builder.build();
+        assert_eq!(gpx.info.version, "1.1");
+        assert_eq!(gpx.info.creator, "builder test");
+
+        let read = round_trip(&gpx);
+        assert_eq!(read.info.creator, gpx.info.creator);
+        assert_eq!(read.metadata.name, gpx.metadata.name);
+        assert_eq!(read.metadata.time, gpx.metadata.time);
+        assert_eq!(format!("{:?}", read.tracks), format!("{:?}", gpx.tracks));
+    }
+
+    #[test]
+    fn shifting_the_times_moves_every_time_and_keeps_them_increasing() {
+        let mut gpx = make_gpx(ride_points(&[(20.0, 600)]));
+        gpx.metadata.time = Some(start_time());
+        let before: Vec<_> = gpx.tracks[0].segments[0]
+            .points
+            .iter()
+            .map(|p| p.time.unwrap())
+            .collect();
+
+        let delta = Duration::hours(-1);
+        gpx.shift_times(delta);
+
+        assert_eq!(gpx.metadata.time, Some(start_time() + delta));
+        for (p, t) in gpx.tracks[0].segments[0].points.iter().zip(&before) {
+            assert_eq!(p.time, Some(*t + delta));
+        }
+
+        let mut enriched = EnrichedGpx::from(gpx);
+        enrich_trackpoints(&mut enriched, 0.0, None, None).unwrap();
+    }
+
+    #[test]
+    fn the_nearest_point_is_found_with_and_without_an_index() {
+        let gpx = make_enriched_gpx((0..50).map(|i| xy_point(i as f64 * 100.0, 0.0)).collect());
+        // 10m north of, and 20m beyond, point 7.
+        let target = xy_point(720.0, 10.0);
+
+        let nearest = gpx.nearest_point(target.lat, target.lon).unwrap();
+        assert_eq!(nearest.index, 7);
+
+        let index = gpx.point_index();
+        let indexed = gpx
+            .nearest_point_indexed(&index, target.lat, target.lon)
+            .unwrap();
+        assert_eq!(indexed.index, 7);
+    }
+
+    #[test]
+    fn an_empty_track_has_no_nearest_point() {
+        let mut gpx = make_enriched_gpx(vec![xy_point(0.0, 0.0)]);
+        gpx.points.clear();
+        assert!(gpx.nearest_point(51.5, 0.0).is_none());
+    }
+
+    #[test]
+    fn resampling_keeps_the_distance_and_does_not_fill_long_gaps() {
+        // Two 20 minute rides with a 15 minute gap, during which nothing
+        // was recorded, between them.
+        let mut points = ride_points(&[(20.0, 1200), (0.0, 900), (20.0, 1200)]);
+        points.drain(121..210);
+        let gpx = enriched(points);
+
+        let mut resampled = gpx.resample_by_time(Duration::seconds(3), Duration::minutes(1));
+        enrich_trackpoints(&mut resampled, 0.0, None, None).unwrap();
+
+        let metres = gpx.points.last().unwrap().running_metres;
+        let resampled_metres = resampled.points.last().unwrap().running_metres;
+        assert!(
+            (resampled_metres - metres).abs() < metres * 0.001,
+            "{resampled_metres} vs {metres}"
+        );
+
+        // 40 minutes of riding at one point every 3 seconds.
+        let count = resampled.points.len();
+        assert!((800..=805).contains(&count), "{count}");
+        let long_gaps = resampled
+            .points
+            .iter()
+            .filter(|p| p.delta_time.is_some_and(|dt| dt > Duration::seconds(3)))
+            .count();
+        assert_eq!(long_gaps, 1);
+    }
+
+    #[test]
+    fn a_long_time_gap_splits_the_segment() {
+        // 10 minutes of riding, a 30 minute gap, then 10 more minutes.
+        let mut points = ride_points(&[(20.0, 600), (0.0, 1800), (20.0, 600)]);
+        points.drain(61..240);
+        let mut gpx = make_gpx(points.clone());
+
+        assert_eq!(gpx.split_segments_on_time_gap(Duration::minutes(5)), 1);
+
+        let segments = &gpx.tracks[0].segments;
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].points.len(), 61);
+        assert_eq!(segments[1].points.len(), 61);
+        let split_times: Vec<_> = segments
+            .iter()
+            .flat_map(|s| &s.points)
+            .map(|p| p.time)
+            .collect();
+        let original_times: Vec<_> = points.iter().map(|p| p.time).collect();
+        assert_eq!(split_times, original_times);
+    }
+
+    #[test]
+    fn reversing_a_track_rebuilds_the_times() {
+        // Ride, stop for 5 minutes, then ride again.
+        let points: Vec<_> = ride_points(&[(20.0, 600), (0.0, 300), (10.0, 600)])
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| TrackPoint {
+                ele: Some(i as f64),
+                ..p
+            })
+            .collect();
+        let mut gpx = make_gpx(points.clone());
+
+        gpx.reverse();
+
+        let reversed = &gpx.tracks[0].segments[0].points;
+        let first = reversed.first().unwrap();
+        let last = points.last().unwrap();
+        assert_eq!(
+            (first.lat, first.lon, first.ele),
+            (last.lat, last.lon, last.ele)
+        );
+        assert_eq!(first.time, Some(start_time()));
+        assert_eq!(reversed.last().unwrap().time, last.time);
+
+        // The time between each pair of points is the same, in reverse.
+        let gaps = |points: &[TrackPoint]| -> Vec<_> {
+            points
+                .windows(2)
+                .map(|w| w[1].time.unwrap() - w[0].time.unwrap())
+                .collect()
+        };
+        let mut original_gaps = gaps(&points);
+        original_gaps.reverse();
+        assert_eq!(gaps(reversed), original_gaps);
+
+        let mut gpx = EnrichedGpx::from(gpx);
+        enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap();
+        assert_eq!(gpx.points.len(), points.len());
+    }
+
+    #[test]
+    fn to_gpx_round_trips_the_enriched_points() {
+        let points = ride_points(&[(20.0, 600)])
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| TrackPoint {
+                ele: Some(100.0 + i as f64 / 2.0),
+                ..p
+            })
+            .collect();
+        let gpx = round_trip(&make_gpx(points));
+        let mut enriched = EnrichedGpx::from(gpx);
+        enrich_trackpoints(&mut enriched, 0.0, None, None).unwrap();
+        // Edit the enriched points, as simplification would.
+        enriched.points.retain(|p| p.index % 2 == 0);
+
+        let gpx = round_trip(&enriched.to_gpx());
+
+        assert_eq!(gpx.tracks.len(), 1);
+        let coords: Vec<_> = gpx.tracks[0].segments[0]
+            .points
+            .iter()
+            .map(|p| (p.lat, p.lon, p.ele, p.time))
+            .collect();
+        let expected: Vec<_> = enriched
+            .points
+            .iter()
+            .map(|p| (p.lat, p.lon, p.ele, p.time))
+            .collect();
+        assert_eq!(coords, expected);
+    }
+
+    #[test]
+    fn the_fastest_segment_is_in_the_fast_section() {
+        // 10 minutes at 20km/h, 5 minutes at 40km/h, then 10 at 20km/h.
+        // The fast section is points 60 to 90.
+        let gpx = enriched(ride_points(&[(20.0, 600), (40.0, 300), (20.0, 600)]));
+
+        let (start, end, duration) = gpx.fastest_segment(2000.0).unwrap();
+
+        assert!(start >= 60 && end <= 90, "{start}..{end}");
+        let metres = gpx.points[end].running_metres - gpx.points[start].running_metres;
+        assert!(metres >= 2000.0, "{metres}");
+        // 2km at 40km/h is 3 minutes, plus up to one point of slack.
+        assert!(
+            duration >= Duration::minutes(3) && duration <= Duration::seconds(190),
+            "{duration}"
+        );
+    }
+
+    #[test]
+    fn there_is_no_fastest_segment_longer_than_the_track() {
+        let gpx = enriched(ride_points(&[(20.0, 600)]));
+
+        assert!(gpx.fastest_segment(1000.0).is_some());
+        assert!(gpx.fastest_segment(10_000.0).is_none());
+    }
+
+    #[test]
+    fn cropping_splits_the_segment_where_it_leaves_the_box() {
+        // Heading east, with a detour 500m north in the middle.
+        let points = (0..=10)
+            .map(|i| {
+                let y = if (4..=6).contains(&i) { 500.0 } else { 0.0 };
+                xy_point(i as f64 * 100.0, y)
+            })
+            .collect();
+        let mut gpx = make_gpx(points);
+        let mut bounds = gpx.compute_bounds().unwrap();
+        let detour_lat = gpx.tracks[0].segments[0].points[5].lat;
+        bounds.max_lat = (bounds.min_lat + detour_lat) / 2.0;
+
+        assert_eq!(gpx.crop_to_bounds(&bounds), 3);
+
+        let segments = &gpx.tracks[0].segments;
+        let lengths: Vec<_> = segments.iter().map(|s| s.points.len()).collect();
+        assert_eq!(lengths, [4, 4]);
+        let new_bounds = gpx.metadata.bounds.unwrap();
+        assert_eq!(new_bounds.max_lat, new_bounds.min_lat);
+    }
+
+    #[test]
+    fn the_privacy_zone_removes_the_points_near_both_ends() {
+        // Out 2km east, then back past the start to finish 1km west of it,
+        // so the ride goes through the start zone in the middle too.
+        let points = (0..=20)
+            .map(|i| xy_point(i as f64 * 100.0, 0.0))
+            .chain((-10..=19).rev().map(|i| xy_point(i as f64 * 100.0, 50.0)))
+            .collect();
+        let mut gpx = make_gpx(points);
+        let start = gpx.tracks[0].segments[0].points[0].as_geo_point();
+        let end = gpx.tracks[0].segments[0].points[50].as_geo_point();
+
+        assert_eq!(gpx.apply_privacy_zone(250.0), 11);
+
+        let segments = &gpx.tracks[0].segments;
+        let lengths: Vec<_> = segments.iter().map(|s| s.points.len()).collect();
+        assert_eq!(lengths, [35, 5]);
+        for p in segments.iter().flat_map(|s| &s.points) {
+            let p = p.as_geo_point();
+            assert!(p.geodesic_distance(&start) > 250.0);
+            assert!(p.geodesic_distance(&end) > 250.0);
+        }
+    }
+
+    #[test]
+    fn counts_the_tracks_segments_and_points() {
+        let mut builder = GpxBuilder::new("test.gpx");
+        builder
+            .add_track(None)
+            .push_point(track_point(51.0, -1.0, None))
+            .push_point(track_point(51.1, -1.0, None))
+            .push_point(track_point(51.2, -1.0, None))
+            .add_segment()
+            .push_point(track_point(51.3, -1.0, None))
+            .push_point(track_point(51.4, -1.0, None))
+            .add_track(None)
+            .push_point(track_point(52.0, -1.0, None));
+        let gpx = builder.build();
+
+        assert_eq!(gpx.num_tracks(), 2);
+        assert_eq!(gpx.num_segments(), 3);
+        assert_eq!(gpx.num_points(), 6);
+        assert_eq!(gpx.track_point_counts(), [vec![3, 2], vec![1]]);
+    }
+
+    #[test]
+    fn converting_to_enriched_merges_the_tracks_and_back_again() {
+        let mut builder = GpxBuilder::new("test.gpx");
+        builder
+            .add_track(Some("Morning".to_string()))
+            .push_point(track_point(51.0, -1.0, Some(10.0)))
+            .push_point(track_point(51.1, -1.0, Some(20.0)))
+            .add_segment()
+            .push_point(track_point(51.2, -1.0, None))
+            .add_track(Some("Afternoon".to_string()))
+            .push_point(track_point(51.3, -1.0, Some(30.0)));
+        let gpx = builder.build();
+        let original: Vec<_> = gpx
+            .tracks
+            .iter()
+            .flat_map(|t| &t.segments)
+            .flat_map(|s| &s.points)
+            .map(|p| (p.lat, p.lon, p.ele))
+            .collect();
+
+        let enriched = EnrichedGpx::from(gpx);
+
+        assert_eq!(enriched.track_name.as_deref(), Some("Morning"));
+        let merged: Vec<_> = enriched
+            .points
+            .iter()
+            .map(|p| (p.lat, p.lon, p.ele))
+            .collect();
+        assert_eq!(merged, original);
+        let segment_starts: Vec<_> = enriched.points.iter().map(|p| p.segment_start).collect();
+        assert_eq!(segment_starts, [false, false, true, true]);
+
+        let gpx = enriched.to_gpx();
+        assert_eq!(gpx.track_point_counts(), [vec![4]]);
+        let converted: Vec<_> = gpx.tracks[0].segments[0]
+            .points
+            .iter()
+            .map(|p| (p.lat, p.lon, p.ele))
+            .collect();
+        assert_eq!(converted, original);
+    }
+
+    #[test]
+    fn elevation_and_position_at_a_distance() {
+        let points = (0..=10)
+            .map(|i| TrackPoint {
+                ele: Some(100.0 + i as f64 * 10.0),
+                ..xy_point(i as f64 * 100.0, 0.0)
+            })
+            .collect();
+        let gpx = enriched(points);
+
+        // Exactly on point 4.
+        let p4 = &gpx.points[4];
+        assert_eq!(gpx.elevation_at_distance(p4.running_metres), Some(140.0));
+        assert_eq!(
+            gpx.point_at_distance(p4.running_metres),
+            Some((p4.lat, p4.lon))
+        );
+
+        // A quarter of the way from point 6 to point 7.
+        let (p6, p7) = (&gpx.points[6], &gpx.points[7]);
+        let metres = p6.running_metres + (p7.running_metres - p6.running_metres) / 4.0;
+        let ele = gpx.elevation_at_distance(metres).unwrap();
+        assert!((ele - 162.5).abs() < 1e-9, "{ele}");
+        let (lat, lon) = gpx.point_at_distance(metres).unwrap();
+        assert!((lon - (p6.lon + (p7.lon - p6.lon) / 4.0)).abs() < 1e-12);
+        assert!((lat - p6.lat).abs() < 1e-12);
+
+        let end = gpx.points.last().unwrap().running_metres;
+        assert_eq!(gpx.elevation_at_distance(end), Some(200.0));
+        assert_eq!(gpx.elevation_at_distance(end + 1.0), None);
+        assert_eq!(gpx.point_at_distance(-1.0), None);
+    }
+
+    #[test]
+    fn filtering_by_time_keeps_the_middle_20_minutes() {
+        let mut gpx = make_gpx(ride_points(&[(20.0, 3600)]));
+        let start = start_time() + Duration::minutes(20);
+        let end = start_time() + Duration::minutes(40);
+
+        assert_eq!(gpx.filter_time_range(Some(start), Some(end)), 240);
+
+        let points = &gpx.tracks[0].segments[0].points;
+        assert_eq!(points.len(), 121);
+        assert_eq!(points.first().unwrap().time, Some(start));
+        assert_eq!(points.last().unwrap().time, Some(end));
+        let bounds = gpx.metadata.bounds.unwrap();
+        assert_eq!(bounds.min_lon, points.first().unwrap().lon);
+        assert_eq!(bounds.max_lon, points.last().unwrap().lon);
+    }
+
+    #[test]
+    fn either_end_of_the_time_window_may_be_open() {
+        let mut gpx = make_gpx(ride_points(&[(20.0, 3600)]));
+        let end = start_time() + Duration::minutes(10);
+        gpx.filter_time_range(None, Some(end));
+        assert_eq!(gpx.num_points(), 61);
+
+        let mut gpx = make_gpx(ride_points(&[(20.0, 3600)]));
+        let start = start_time() + Duration::minutes(50);
+        gpx.filter_time_range(Some(start), None);
+        assert_eq!(gpx.num_points(), 61);
+    }
+
+    #[test]
+    fn projected_distances_match_geodesic_distances() {
+        let gpx = make_enriched_gpx(vec![
+            track_point(51.500, -0.120, None),
+            track_point(51.510, -0.100, None),
+            track_point(51.495, -0.090, None),
+        ]);
+
+        let xy = gpx.project_to_local_xy();
+
+        assert_eq!(xy.len(), 3);
+        // The origin is the centroid.
+        let sum_x: f64 = xy.iter().map(|(x, _)| x).sum();
+        let sum_y: f64 = xy.iter().map(|(_, y)| y).sum();
+        assert!(sum_x.abs() < 1e-6 && sum_y.abs() < 1e-6, "{sum_x}, {sum_y}");
+
+        for (i, j) in [(0, 1), (1, 2), (0, 2)] {
+            let projected = (xy[i].0 - xy[j].0).hypot(xy[i].1 - xy[j].1);
+            let geodesic = gpx.points[i]
+                .as_geo_point()
+                .geodesic_distance(&gpx.points[j].as_geo_point());
+            assert!(
+                (projected - geodesic).abs() < geodesic * 0.005,
+                "{projected} vs {geodesic}"
+            );
+        }
+    }
+
+    #[test]
+    fn repairing_a_shuffled_segment_sorts_it_by_time() {
+        let points = ride_points(&[(20.0, 200)]);
+        let mut builder = GpxBuilder::new("test.gpx");
+        // Shuffle the first segment; the second is already in order.
+        for i in 0..points.len() {
+            builder.push_point(points[i * 5 % points.len()].clone());
+        }
+        // A point recorded twice.
+        builder.push_point(points[20].clone());
+        builder.add_segment();
+        for p in ride_points(&[(20.0, 100)]) {
+            builder.push_point(TrackPoint {
+                time: p.time.map(|t| t + Duration::hours(1)),
+                ..p
+            });
+        }
+        let mut gpx = builder.build();
+
+        assert_eq!(gpx.sort_points_by_time(), 1);
+        assert_eq!(gpx.remove_duplicate_times(), 1);
+
+        assert_eq!(gpx.track_point_counts(), [vec![21, 11]]);
+        let times: Vec<_> = gpx.tracks[0].segments[0]
+            .points
+            .iter()
+            .map(|p| p.time)
+            .collect();
+        let expected: Vec<_> = points.iter().map(|p| p.time).collect();
+        assert_eq!(times, expected);
+
+        let mut gpx = EnrichedGpx::from(gpx);
+        enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap();
+    }
+}
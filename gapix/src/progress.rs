@@ -0,0 +1,61 @@
+//! Contains the functionality relating to reporting the progress of long
+//! operations. Enriching or simplifying a multi-day track with millions of
+//! points can take a while, and without this there is no feedback at all.
+
+use std::{path::Path, sync::LazyLock, time::Duration};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+/// Receives reports of how far an operation has got. The operations only
+/// know about this trait, so how (or whether) the progress is shown is up
+/// to the caller.
+pub trait Progress: Sync {
+    /// Called with the number of points processed so far and the total.
+    fn update(&self, done: usize, total: usize);
+}
+
+/// Allows a simple closure to be used to receive the progress.
+impl<F: Fn(usize, usize) + Sync> Progress for F {
+    fn update(&self, done: usize, total: usize) {
+        self(done, total)
+    }
+}
+
+/// All the bars are drawn together, because several files may be
+/// processed in parallel and separate bars would overwrite each other.
+static BARS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+/// Shows the progress of one operation on one file as a bar on stderr.
+/// The bar is removed when the reporter is dropped.
+pub struct ProgressBarReporter(ProgressBar);
+
+impl ProgressBarReporter {
+    /// Creates a bar labelled with the operation and the name of the file.
+    pub fn new(operation: &str, filename: &Path) -> Self {
+        let style = ProgressStyle::with_template("{msg} [{bar:40}] {pos}/{len} points")
+            .unwrap()
+            .progress_chars("=> ");
+
+        let bar = BARS.add(ProgressBar::no_length());
+        bar.set_style(style);
+        bar.set_message(format!(
+            "{operation} {:?}",
+            filename.file_name().unwrap_or(filename.as_os_str())
+        ));
+        bar.enable_steady_tick(Duration::from_millis(250));
+        Self(bar)
+    }
+}
+
+impl Progress for ProgressBarReporter {
+    fn update(&self, done: usize, total: usize) {
+        self.0.set_length(total as u64);
+        self.0.set_position(done as u64);
+    }
+}
+
+impl Drop for ProgressBarReporter {
+    fn drop(&mut self) {
+        self.0.finish_and_clear();
+    }
+}
@@ -1,19 +1,76 @@
 use std::{
     collections::HashSet,
-    error::Error,
-    fs::File,
-    io::{BufWriter, Write},
+    fmt, fs,
+    io::{self, Write},
     path::Path,
 };
 
-use geo::{coord, LineString, SimplifyIdx};
+use clap::ValueEnum;
+use geo::{coord, GeodesicDistance, LineString, SimplifyIdx, SimplifyVwIdx};
 use logging_timer::time;
+use quick_xml::escape::escape;
+use time::OffsetDateTime;
 
 use crate::{
-    formatting::format_utc_date,
-    model::{Declaration, EnrichedGpx, EnrichedTrackPoint, GpxInfo, Metadata},
+    error::GapixError,
+    formatting::{format_utc_date, format_utc_date_with_decimals},
+    model::{
+        Bounds, Copyright, Declaration, EnrichedGpx, EnrichedTrackPoint, FixInfo, Gpx, GpxInfo,
+        Link, Metadata, Person, Track, TrackExtensions, TrackPoint, Waypoint,
+    },
+    progress::Progress,
 };
 
+/// Writing to a file with this name writes to stdout instead.
+pub const STDOUT_FILENAME: &str = "-";
+
+/// Controls how the GPX file is written.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteOptions {
+    /// The number of decimal places for lat and lon. 6 places is about 11cm.
+    pub coord_decimals: usize,
+    /// The number of decimal places for elevations (which are in metres).
+    pub ele_decimals: usize,
+    /// Whether to write each element on its own indented line. If false
+    /// the whitespace between tags is omitted, which makes the file
+    /// noticeably smaller.
+    pub pretty: bool,
+    /// The number of decimal places for the seconds of times. The default,
+    /// None, writes as many as are needed: none for a whole second, and
+    /// without trailing zeros otherwise, so no precision is lost.
+    pub time_decimals: Option<usize>,
+    /// Whether to write the elements, and the extension elements, that we
+    /// read but do not understand. They are dropped by default, which keeps
+    /// the files small.
+    pub preserve_unknown: bool,
+    /// Whether to drop every extension, including the heart rate, cadence
+    /// and temperatures, to make the file as small and as private as possible.
+    pub strip_extensions: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            coord_decimals: 6,
+            ele_decimals: 1,
+            pretty: true,
+            time_decimals: None,
+            preserve_unknown: false,
+            strip_extensions: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, ValueEnum)]
+/// The algorithm used to reduce the number of trackpoints.
+pub enum SimplifyMethod {
+    /// Ramer-Douglas-Peucker.
+    #[default]
+    Rdp,
+    /// Visvalingam-Whyatt.
+    Vw,
+}
+
 /// We take input from the user in "metres of accuracy".
 /// The 'geo' implementation of RDP requires an epsilon
 /// which is relative to the coordinate scale in use.
@@ -41,13 +98,355 @@ pub fn metres_to_epsilon(metres: u16) -> f64 {
 /// 31358           50      387 (1.2%, 51Kb)    Poor - cuts off a lot of corners
 /// 31358           100     236 (0.8%, 31Kb)    Very poor - significant corner truncation
 #[time]
-pub fn reduce_trackpoints_by_rdp(points: &mut Vec<EnrichedTrackPoint>, epsilon: f64) {
-    let line_string: LineString<_> = points
+pub fn reduce_trackpoints_by_rdp(
+    points: &mut Vec<EnrichedTrackPoint>,
+    epsilon: f64,
+    progress: Option<&dyn Progress>,
+) {
+    // RDP is a single call into the GEO crate, so we can only report
+    // the start and the end.
+    let num_points = points.len();
+    if let Some(progress) = progress {
+        progress.update(0, num_points);
+    }
+
+    let line_string = make_line_string(points);
+    let indices_to_keep: HashSet<usize> = HashSet::from_iter(line_string.simplify_idx(&epsilon));
+    retain_indices(points, &indices_to_keep);
+
+    if let Some(progress) = progress {
+        progress.update(num_points, num_points);
+    }
+}
+
+/// A variant of 'reduce_trackpoints_by_rdp' that never removes the points
+/// whose 'index' is in 'keep', whatever RDP thinks of them. This is used to
+/// retain the start and end of each stage (and the other highlighted points)
+/// so that the simplified track still lines up with the analysis; otherwise
+/// RDP happily throws away the point where a Control stop began.
+///
+/// Note that 'keep' contains 'EnrichedTrackPoint::index' values, not
+/// positions in 'points', because points may already have been removed.
+#[time]
+pub fn reduce_trackpoints_by_rdp_keeping(
+    points: &mut Vec<EnrichedTrackPoint>,
+    epsilon: f64,
+    keep: &HashSet<usize>,
+) {
+    let line_string = make_line_string(points);
+    let mut indices_to_keep: HashSet<usize> =
+        HashSet::from_iter(line_string.simplify_idx(&epsilon));
+    indices_to_keep.extend(
+        points
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| keep.contains(&p.index))
+            .map(|(idx, _)| idx),
+    );
+    retain_indices(points, &indices_to_keep);
+}
+
+/// Runs 'reduce', which can be any of the simplification methods, then
+/// puts back the points whose 'index' is in 'keep' that it removed. This
+/// is the general version of 'reduce_trackpoints_by_rdp_keeping'. Note that
+/// when reducing to a count of points, the kept points are extra.
+pub fn reduce_trackpoints_keeping<F>(
+    points: &mut Vec<EnrichedTrackPoint>,
+    keep: &HashSet<usize>,
+    reduce: F,
+) where
+    F: FnOnce(&mut Vec<EnrichedTrackPoint>),
+{
+    let mut reduced = points.clone();
+    reduce(&mut reduced);
+    let retained: HashSet<usize> = reduced
         .iter()
-        .map(|p| coord! { x: p.lon, y: p.lat })
+        .map(|p| p.index)
+        .chain(keep.iter().copied())
         .collect();
-    let indices_to_keep: HashSet<usize> = HashSet::from_iter(line_string.simplify_idx(&epsilon));
+    points.retain(|p| retained.contains(&p.index));
+}
+
+/// The number of points either side of a point used to
+/// calculate the local spacing in 'reduce_trackpoints_adaptive'.
+const ADAPTIVE_HALF_WINDOW: usize = 15;
+
+/// A variant of 'reduce_trackpoints_by_rdp' which varies epsilon along the
+/// track according to the local point density. With a single epsilon, sparse
+/// sections (long straight roads, where the device records fewer points, or
+/// files that have already been thinned) lose points that matter, while dense
+/// sections (towns, with lots of junctions and bends) keep many points that
+/// do not.
+///
+/// For each point we take the median spacing of the points around it and
+/// compare it with the median spacing of the whole track. Epsilon is scaled
+/// by the inverse of that ratio, in powers of 2 between 1/4 and 4 times
+/// 'base_metres', so dense sections are simplified more aggressively and
+/// sparse ones more gently. The track is then split into bands of points
+/// with the same epsilon and RDP is run on each band separately; the point
+/// where two bands meet is kept. The points must have been enriched,
+/// because we need 'delta_metres'.
+#[time]
+pub fn reduce_trackpoints_adaptive(points: &mut Vec<EnrichedTrackPoint>, base_metres: u16) {
+    if points.len() < 3 {
+        return;
+    }
+
+    let spacings: Vec<f64> = points.iter().skip(1).map(|p| p.delta_metres).collect();
+    let overall = median(spacings.clone());
+    if overall <= 0.0 {
+        reduce_trackpoints_by_rdp(points, metres_to_epsilon(base_metres), None);
+        return;
+    }
+
+    // The scale for each point, as a power of 2. Point 0 has no spacing
+    // of its own so it uses the spacing to point 1.
+    let scales: Vec<i32> = (0..points.len())
+        .map(|idx| {
+            let idx = idx.saturating_sub(1);
+            let start = idx.saturating_sub(ADAPTIVE_HALF_WINDOW);
+            let end = (idx + ADAPTIVE_HALF_WINDOW + 1).min(spacings.len());
+            let local = median(spacings[start..end].to_vec());
+            if local <= 0.0 {
+                2
+            } else {
+                (overall / local).log2().round().clamp(-2.0, 2.0) as i32
+            }
+        })
+        .collect();
+
+    let base_epsilon = metres_to_epsilon(base_metres);
+    let mut indices_to_keep = HashSet::new();
+    let mut start = 0;
+    while start < points.len() - 1 {
+        let mut end = start + 1;
+        while end < points.len() - 1 && scales[end] == scales[start] {
+            end += 1;
+        }
+
+        let epsilon = base_epsilon * 2f64.powi(scales[start]);
+        let line_string = make_line_string(&points[start..=end]);
+        indices_to_keep.extend(
+            line_string
+                .simplify_idx(&epsilon)
+                .into_iter()
+                .map(|idx| idx + start),
+        );
+        start = end;
+    }
+
+    retain_indices(points, &indices_to_keep);
+}
+
+/// Returns the median of 'values', or 0 if there are none.
+fn median(mut values: Vec<f64>) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+/// When stationary, devices keep writing points which are all in nearly the
+/// same place. RDP does not remove them all, so this is a pre-pass which
+/// replaces each run of points within 'radius_metres' of the first point of
+/// the run with a single point: the last one, so that its time (when we
+/// started moving again) is kept. Its 'delta_time' and 'delta_metres' are
+/// set to the totals for the run, so it carries the whole dwell time.
+/// The first point of the track is always kept, so that the track still
+/// starts at the same place and time. Returns the number of points removed.
+///
+/// This must be called before simplifying with RDP or VW.
+#[time]
+pub fn collapse_stationary_clusters(
+    points: &mut Vec<EnrichedTrackPoint>,
+    radius_metres: f64,
+) -> usize {
+    let start_count = points.len();
+    let mut indices_to_keep = HashSet::from([0]);
+    let mut idx = 1;
+
+    while idx < points.len() {
+        let anchor = points[idx].as_geo_point();
+        let mut end = idx;
+        while end + 1 < points.len()
+            && anchor.geodesic_distance(&points[end + 1].as_geo_point()) <= radius_metres
+        {
+            end += 1;
+        }
+
+        if end > idx {
+            let delta_time = points[idx..=end].iter().map(|p| p.delta_time).sum();
+            let delta_metres = points[idx..=end].iter().map(|p| p.delta_metres).sum();
+            points[end].delta_time = delta_time;
+            points[end].delta_metres = delta_metres;
+        }
+
+        indices_to_keep.insert(end);
+        idx = end + 1;
+    }
+
+    retain_indices(points, &indices_to_keep);
+    start_count - points.len()
+}
+
+/// Runs RDP independently on each segment of each track, so that the tracks
+/// and segments are preserved. Segments usually mean that there is a real
+/// discontinuity in the recording, such as a loss of GPS signal or the device
+/// being turned off, and simplifying across the boundary would join the end
+/// of one segment to the start of the next with a straight line. The first
+/// and last points of every segment are always kept, as are the points for
+/// which 'keep' returns true. The points of a Gpx have no index, so unlike
+/// 'reduce_trackpoints_keeping' the caller has to identify them some other way.
+#[time]
+pub fn simplify_each_segment(gpx: &mut Gpx, epsilon: f64, keep: impl Fn(&TrackPoint) -> bool) {
+    for segment in gpx.tracks.iter_mut().flat_map(|t| &mut t.segments) {
+        let line_string: LineString = segment
+            .points
+            .iter()
+            .map(|p| coord! { x: p.lon, y: p.lat })
+            .collect();
+        let indices_to_keep: HashSet<usize> =
+            HashSet::from_iter(line_string.simplify_idx(&epsilon));
+
+        let mut n = 0;
+        segment.points.retain(|p| {
+            let retain = indices_to_keep.contains(&n) || keep(p);
+            n += 1;
+            retain
+        });
+    }
+}
+
+/// A variant of 'reduce_trackpoints_by_rdp' that also takes elevation into
+/// account. The normal RDP only looks at lat-lon, so a climb that is straight
+/// on the map but has significant changes in gradient loses all its
+/// interesting elevation points. Here we run RDP twice, once on the map and
+/// once on the elevation profile (elevation against distance travelled, both
+/// in metres), and keep any point that either pass thinks is significant.
+///
+/// 'elevation_metres' is the vertical accuracy to use for the profile pass.
+/// The points must have been enriched, because we need 'running_metres'.
+#[time]
+pub fn reduce_trackpoints_by_rdp_preserving_elevation(
+    points: &mut Vec<EnrichedTrackPoint>,
+    epsilon: f64,
+    elevation_metres: f64,
+) {
+    let line_string = make_line_string(points);
+    let mut indices_to_keep: HashSet<usize> =
+        HashSet::from_iter(line_string.simplify_idx(&epsilon));
+    indices_to_keep.extend(simplify_elevation_profile_idx(points, elevation_metres));
+    retain_indices(points, &indices_to_keep);
+}
+
+/// Runs RDP over the elevation profile of the track and returns the
+/// indexes (into 'points') of the points to keep. Points which do not
+/// have an elevation cannot take part in the profile, so are never
+/// returned from here.
+fn simplify_elevation_profile_idx(points: &[EnrichedTrackPoint], epsilon: f64) -> Vec<usize> {
+    let mut positions = Vec::with_capacity(points.len());
+    let mut coords = Vec::with_capacity(points.len());
+
+    for (idx, p) in points.iter().enumerate() {
+        if let Some(ele) = p.ele {
+            positions.push(idx);
+            coords.push(coord! { x: p.running_metres, y: ele });
+        }
+    }
+
+    LineString::new(coords)
+        .simplify_idx(&epsilon)
+        .into_iter()
+        .map(|idx| positions[idx])
+        .collect()
+}
+
+/// Simplifies the track using RDP such that it contains no more than
+/// 'target' points. This is useful when you have a point-count (and hence
+/// file-size) budget, such as the Audax UK DIY upload limit, rather than
+/// a desired accuracy.
+///
+/// RDP retains fewer points as epsilon increases, so we binary search
+/// for the smallest epsilon that gets us under the target, which gives
+/// us the largest point count not exceeding it. The search is bounded
+/// to a fixed number of iterations. Returns the epsilon that was used.
+#[time]
+pub fn reduce_trackpoints_to_count(points: &mut Vec<EnrichedTrackPoint>, target: usize) -> f64 {
+    const MAX_ITERATIONS: usize = 64;
+
+    if points.len() <= target {
+        return 0.0;
+    }
+
+    let line_string = make_line_string(points);
+
+    // Find an upper bound. RDP always keeps the first and last points,
+    // so for any target >= 2 a large enough epsilon will get there.
+    let mut high = metres_to_epsilon(1000);
+    let mut indices_to_keep = line_string.simplify_idx(&high);
+    for _ in 0..MAX_ITERATIONS {
+        if indices_to_keep.len() <= target {
+            break;
+        }
+        high *= 2.0;
+        indices_to_keep = line_string.simplify_idx(&high);
+    }
+
+    let mut low = 0.0;
+    for _ in 0..MAX_ITERATIONS {
+        let mid = (low + high) / 2.0;
+        let candidate = line_string.simplify_idx(&mid);
+        if candidate.len() <= target {
+            high = mid;
+            indices_to_keep = candidate;
+        } else {
+            low = mid;
+        }
+    }
+
+    retain_indices(points, &HashSet::from_iter(indices_to_keep));
+    high
+}
 
+/// The Visvalingam-Whyatt algorithm requires an area threshold rather
+/// than a distance. We derive it from the same "metres of accuracy" input
+/// as RDP by squaring the equivalent epsilon, i.e. we remove points whose
+/// effective triangle is smaller than a square with sides of 'metres'.
+pub fn metres_to_vw_area(metres: u16) -> f64 {
+    let epsilon = metres_to_epsilon(metres);
+    epsilon * epsilon
+}
+
+/// Feed the points into the GEO crate so we can use its implementation
+/// of https://en.wikipedia.org/wiki/Visvalingam%E2%80%93Whyatt_algorithm
+///
+/// VW removes the points which contribute the least area to the line,
+/// which tends to preserve the overall shape of the track better than
+/// RDP at aggressive settings (RDP cuts corners off).
+#[time]
+pub fn reduce_trackpoints_by_vw(points: &mut Vec<EnrichedTrackPoint>, area_threshold: f64) {
+    let line_string = make_line_string(points);
+    let indices_to_keep: HashSet<usize> =
+        HashSet::from_iter(line_string.simplify_vw_idx(&area_threshold));
+    retain_indices(points, &indices_to_keep);
+}
+
+fn make_line_string(points: &[EnrichedTrackPoint]) -> LineString {
+    points
+        .iter()
+        .map(|p| coord! { x: p.lon, y: p.lat })
+        .collect()
+}
+
+/// Removes all points whose position in 'points' is not in 'indices_to_keep'.
+fn retain_indices(points: &mut Vec<EnrichedTrackPoint>, indices_to_keep: &HashSet<usize>) {
     let mut n = 0;
     points.retain(|_| {
         let keep = indices_to_keep.contains(&n);
@@ -56,31 +455,128 @@ pub fn reduce_trackpoints_by_rdp(points: &mut Vec<EnrichedTrackPoint>, epsilon:
     });
 }
 
-#[time]
+/// Writes the (single track) simplified file, by converting 'gpx'
+/// back to the plain model.
 pub fn write_simplified_gpx_file(
     output_file: &Path,
     gpx: &EnrichedGpx,
-) -> Result<(), Box<dyn Error>> {
-    print!("Writing file {:?}", &output_file);
-    let mut w = BufWriter::new(File::create(output_file)?);
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    write_segmented_gpx_file(output_file, &gpx.to_gpx(), &gpx.waypoints, options)
+}
+
+/// Writes a simplified file which keeps all the tracks and segments
+/// of 'gpx', as simplified by 'simplify_each_segment'. The waypoints
+/// are written before the tracks. If 'output_file' is STDOUT_FILENAME
+/// the file is written to stdout.
+#[time]
+pub fn write_segmented_gpx_file(
+    output_file: &Path,
+    gpx: &Gpx,
+    waypoints: &[Waypoint],
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    let bytes = if options.strip_extensions {
+        let mut gpx = gpx.clone();
+        gpx.strip_extensions();
+        write_gpx_to_bytes(&gpx, waypoints, options)?
+    } else {
+        write_gpx_to_bytes(gpx, waypoints, options)?
+    };
+
+    if output_file == Path::new(STDOUT_FILENAME) {
+        io::stdout().lock().write_all(&bytes)?;
+    } else {
+        fs::write(output_file, &bytes)?;
+        eprintln!("Wrote file {:?}, {} Kb", &output_file, bytes.len() / 1024);
+    }
+    Ok(())
+}
+
+/// Writes the GPX to memory rather than to a file, for when the
+/// caller wants to do something else with it, such as send it over
+/// the network. The default options are used, so the result is
+/// exactly what would be written to a file.
+// The program itself always writes with the options from the command line.
+#[allow(dead_code)]
+pub fn gpx_to_bytes(gpx: &Gpx) -> Result<Vec<u8>, GapixError> {
+    write_gpx_to_bytes(gpx, &[], &WriteOptions::default())
+}
+
+/// Like 'gpx_to_bytes', but returns the GPX as a String.
+#[allow(dead_code)]
+pub fn gpx_to_string(gpx: &Gpx) -> Result<String, GapixError> {
+    String::from_utf8(gpx_to_bytes(gpx)?).map_err(|err| GapixError::Encoding(err.utf8_error()))
+}
 
-    write_declaration_tag(&mut w, &gpx.declaration)?;
-    write_gpx_tag_open(&mut w, &gpx.info)?;
-    write_metadata_tag(&mut w, &gpx.metadata)?;
-    write_track(&mut w, &gpx.track_name, &gpx.track_type, &gpx.points)?;
-    write_gpx_tag_close(&mut w)?;
+/// Writes the GPX, followed by the 'waypoints', to memory.
+pub(crate) fn write_gpx_to_bytes(
+    gpx: &Gpx,
+    waypoints: &[Waypoint],
+    options: &WriteOptions,
+) -> Result<Vec<u8>, GapixError> {
+    let mut bytes = Vec::new();
+    write_gpx(&mut bytes, gpx, waypoints, options)?;
+    Ok(bytes)
+}
 
-    w.flush().unwrap();
-    let metadata = std::fs::metadata(output_file)?;
-    println!(", {} Kb", metadata.len() / 1024);
+/// Writes the GPX to any Write, such as a file or a Vec.
+fn write_gpx<W: Write>(
+    mut w: W,
+    gpx: &Gpx,
+    waypoints: &[Waypoint],
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    write_declaration_tag(&mut w, &gpx.declaration, options)?;
+    write_gpx_tag_open(&mut w, &gpx.info, options)?;
+    // If the input did not specify any bounds, fill them in from the
+    // points we are actually writing.
+    let bounds = gpx
+        .metadata
+        .bounds
+        .clone()
+        .or_else(|| gpx.compute_bounds_with_waypoints(waypoints));
+    write_metadata_tag(&mut w, &gpx.metadata, bounds.as_ref(), options)?;
+    for waypoint in waypoints {
+        write_waypoint(&mut w, waypoint, options)?;
+    }
+    for track in &gpx.tracks {
+        write_segmented_track(&mut w, track, options)?;
+    }
+    write_raw_children(&mut w, gpx.raw_children.as_ref(), 1, options)?;
+    if let Some(extensions) = gpx.extensions.as_ref().filter(|_| options.preserve_unknown) {
+        write_line(&mut w, options, 1, format_args!("<extensions>"))?;
+        write_line(&mut w, options, 2, format_args!("{}", extensions))?;
+        write_line(&mut w, options, 1, format_args!("</extensions>"))?;
+    }
+    write_gpx_tag_close(&mut w, options)?;
+    w.flush()?;
 
     Ok(())
 }
 
+/// Writes one line of the file, indented by 'depth' levels. When not
+/// pretty printing the indentation and the newline are left out, which
+/// removes all the whitespace between the tags without changing the text
+/// inside them.
+fn write_line<W: Write>(
+    w: &mut W,
+    options: &WriteOptions,
+    depth: usize,
+    line: fmt::Arguments<'_>,
+) -> io::Result<()> {
+    if options.pretty {
+        writeln!(w, "{:width$}{line}", "", width = depth * 2)
+    } else {
+        write!(w, "{line}")
+    }
+}
+
 fn write_declaration_tag<W: Write>(
     w: &mut W,
     declaration: &Declaration,
-) -> Result<(), Box<dyn Error>> {
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
     write!(w, "<?xml version=\"{}\"", declaration.version)?;
     if let Some(encoding) = &declaration.encoding {
         write!(w, " encoding=\"{}\"", encoding)?;
@@ -88,85 +584,1184 @@ fn write_declaration_tag<W: Write>(
     if let Some(standalone) = &declaration.standalone {
         write!(w, " standalone=\"{}\"", standalone)?;
     }
-    writeln!(w, "?>")?;
+    write_line(w, options, 0, format_args!("?>"))?;
     Ok(())
 }
 
-fn write_gpx_tag_open<W: Write>(w: &mut W, info: &GpxInfo) -> Result<(), Box<dyn Error>> {
-    writeln!(
+fn write_gpx_tag_open<W: Write>(
+    w: &mut W,
+    info: &GpxInfo,
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    write!(
         w,
         "<gpx creator=\"{}\" version=\"{}\"",
-        info.creator, info.version
+        escape(&info.creator),
+        escape(&info.version)
     )?;
+    // The attributes need a separator even when not pretty printing.
+    let separator = if options.pretty { "\n  " } else { " " };
     for (key, value) in &info.attributes {
-        writeln!(w, "  {}=\"{}\"", key, value)?;
+        write!(w, "{separator}{}=\"{}\"", key, escape(value))?;
+    }
+    if options.pretty {
+        writeln!(w)?;
     }
-    writeln!(w, ">")?;
+    write_line(w, options, 0, format_args!(">"))?;
     Ok(())
 }
 
-fn write_gpx_tag_close<W: Write>(w: &mut W) -> Result<(), Box<dyn Error>> {
-    writeln!(w, "</gpx>")?;
+fn write_gpx_tag_close<W: Write>(w: &mut W, options: &WriteOptions) -> Result<(), GapixError> {
+    write_line(w, options, 0, format_args!("</gpx>"))?;
     Ok(())
 }
 
-fn write_metadata_tag<W: Write>(w: &mut W, metadata: &Metadata) -> Result<(), Box<dyn Error>> {
-    writeln!(w, "  <metadata>")?;
-    writeln!(w, "    <link href=\"{}\">", metadata.link.href)?;
-    if let Some(text) = &metadata.link.text {
-        writeln!(w, "      <text>{}</text>", text)?;
+/// Writes the <metadata> tag. The child elements are written
+/// in the order defined by the XSD. 'bounds' is written in
+/// place of 'metadata.bounds'.
+fn write_metadata_tag<W: Write>(
+    w: &mut W,
+    metadata: &Metadata,
+    bounds: Option<&Bounds>,
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    write_line(w, options, 1, format_args!("<metadata>"))?;
+    if let Some(name) = &metadata.name {
+        write_line(w, options, 2, format_args!("<name>{}</name>", escape(name)))?;
+    }
+    if let Some(desc) = &metadata.desc {
+        write_line(w, options, 2, format_args!("<desc>{}</desc>", escape(desc)))?;
+    }
+    if let Some(author) = &metadata.author {
+        write_person_tag(w, author, options)?;
+    }
+    if let Some(copyright) = &metadata.copyright {
+        write_copyright_tag(w, copyright, options)?;
     }
-    if let Some(r#type) = &metadata.link.r#type {
-        writeln!(w, "      <type>{}</type>", r#type)?;
+    for link in &metadata.links {
+        write_link_tag(w, link, 2, options)?;
     }
-    writeln!(w, "    </link>")?;
     if let Some(time) = &metadata.time {
-        writeln!(w, "    <time>{}</time>", format_utc_date(time))?;
+        write_line(
+            w,
+            options,
+            2,
+            format_args!("<time>{}</time>", format_time(time, options)),
+        )?;
     }
-    writeln!(w, "  </metadata>")?;
+    if let Some(keywords) = &metadata.keywords {
+        write_line(
+            w,
+            options,
+            2,
+            format_args!("<keywords>{}</keywords>", escape(keywords)),
+        )?;
+    }
+    if let Some(bounds) = bounds {
+        write_bounds_tag(w, bounds, options)?;
+    }
+    write_line(w, options, 1, format_args!("</metadata>"))?;
     Ok(())
 }
 
-fn write_track<W: Write>(
+/// Writes a <link> tag. Links can appear at several different
+/// nesting levels, so the caller specifies the depth.
+fn write_link_tag<W: Write>(
     w: &mut W,
-    track_name: &Option<String>,
-    track_type: &Option<String>,
-    points: &[EnrichedTrackPoint],
-) -> Result<(), Box<dyn Error>> {
-    writeln!(w, "  <trk>")?;
-    if let Some(track_name) = track_name {
-        writeln!(w, "    <name>{}</name>", track_name)?;
+    link: &Link,
+    depth: usize,
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    write_line(
+        w,
+        options,
+        depth,
+        format_args!("<link href=\"{}\">", escape(&link.href)),
+    )?;
+    if let Some(text) = &link.text {
+        write_line(
+            w,
+            options,
+            depth + 1,
+            format_args!("<text>{}</text>", escape(text)),
+        )?;
     }
-    if let Some(track_type) = track_type {
-        writeln!(w, "    <type>{}</type>", track_type)?;
+    if let Some(r#type) = &link.r#type {
+        write_line(
+            w,
+            options,
+            depth + 1,
+            format_args!("<type>{}</type>", escape(r#type)),
+        )?;
     }
+    write_line(w, options, depth, format_args!("</link>"))?;
+    Ok(())
+}
 
-    writeln!(w, "    <trkseg>")?;
-    for p in points {
-        write_trackpoint(w, p)?;
+fn write_person_tag<W: Write>(
+    w: &mut W,
+    person: &Person,
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    write_line(w, options, 2, format_args!("<author>"))?;
+    if let Some(name) = &person.name {
+        write_line(w, options, 3, format_args!("<name>{}</name>", escape(name)))?;
+    }
+    if let Some(email) = &person.email {
+        write_line(
+            w,
+            options,
+            3,
+            format_args!(
+                "<email id=\"{}\" domain=\"{}\" />",
+                escape(&email.id),
+                escape(&email.domain)
+            ),
+        )?;
     }
-    writeln!(w, "    </trkseg>")?;
+    if let Some(link) = &person.link {
+        write_link_tag(w, link, 3, options)?;
+    }
+    write_line(w, options, 2, format_args!("</author>"))?;
+    Ok(())
+}
 
-    writeln!(w, "  </trk>")?;
+fn write_copyright_tag<W: Write>(
+    w: &mut W,
+    copyright: &Copyright,
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    write_line(
+        w,
+        options,
+        2,
+        format_args!("<copyright author=\"{}\">", escape(&copyright.author)),
+    )?;
+    if let Some(year) = &copyright.year {
+        write_line(w, options, 3, format_args!("<year>{}</year>", year))?;
+    }
+    if let Some(license) = &copyright.license {
+        write_line(
+            w,
+            options,
+            3,
+            format_args!("<license>{}</license>", escape(license)),
+        )?;
+    }
+    write_line(w, options, 2, format_args!("</copyright>"))?;
     Ok(())
 }
 
-fn write_trackpoint<W: Write>(w: &mut W, point: &EnrichedTrackPoint) -> Result<(), Box<dyn Error>> {
-    writeln!(
+fn write_bounds_tag<W: Write>(
+    w: &mut W,
+    bounds: &Bounds,
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    let dp = options.coord_decimals;
+    write_line(
         w,
-        "      <trkpt lat=\"{:.6}\" lon=\"{:.6}\">",
-        point.lat, point.lon
+        options,
+        2,
+        format_args!(
+            "<bounds minlat=\"{:.*}\" minlon=\"{:.*}\" maxlat=\"{:.*}\" maxlon=\"{:.*}\" />",
+            dp, bounds.min_lat, dp, bounds.min_lon, dp, bounds.max_lat, dp, bounds.max_lon
+        ),
     )?;
+    Ok(())
+}
 
-    if let Some(ele) = point.ele {
-        writeln!(w, "        <ele>{:.1}</ele>", ele)?;
+/// Writes a <wpt> tag. The child elements are written
+/// in the order defined by the XSD.
+fn write_waypoint<W: Write>(
+    w: &mut W,
+    waypoint: &Waypoint,
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    let dp = options.coord_decimals;
+    write_line(
+        w,
+        options,
+        1,
+        format_args!(
+            "<wpt lat=\"{:.*}\" lon=\"{:.*}\">",
+            dp, waypoint.lat, dp, waypoint.lon
+        ),
+    )?;
+    if let Some(ele) = waypoint.ele {
+        write_line(
+            w,
+            options,
+            2,
+            format_args!("<ele>{:.*}</ele>", options.ele_decimals, ele),
+        )?;
+    }
+    if let Some(t) = waypoint.time {
+        write_line(
+            w,
+            options,
+            2,
+            format_args!("<time>{}</time>", format_time(&t, options)),
+        )?;
+    }
+    if let Some(name) = &waypoint.name {
+        write_line(w, options, 2, format_args!("<name>{}</name>", escape(name)))?;
+    }
+    if let Some(desc) = &waypoint.desc {
+        write_line(w, options, 2, format_args!("<desc>{}</desc>", escape(desc)))?;
+    }
+    write_line(w, options, 1, format_args!("</wpt>"))?;
+    Ok(())
+}
+
+fn write_segmented_track<W: Write>(
+    w: &mut W,
+    track: &Track,
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    write_line(w, options, 1, format_args!("<trk>"))?;
+    if let Some(track_name) = &track.name {
+        write_line(
+            w,
+            options,
+            2,
+            format_args!("<name>{}</name>", escape(track_name)),
+        )?;
+    }
+    if let Some(comment) = &track.comment {
+        write_line(
+            w,
+            options,
+            2,
+            format_args!("<cmt>{}</cmt>", escape(comment)),
+        )?;
+    }
+    if let Some(desc) = &track.desc {
+        write_line(w, options, 2, format_args!("<desc>{}</desc>", escape(desc)))?;
+    }
+    if let Some(track_type) = &track.r#type {
+        write_line(
+            w,
+            options,
+            2,
+            format_args!("<type>{}</type>", escape(track_type)),
+        )?;
     }
 
-    if let Some(t) = point.time {
-        writeln!(w, "        <time>{}</time>", format_utc_date(&t))?;
+    write_raw_children(w, track.raw_children.as_ref(), 2, options)?;
+    if let Some(extensions) = &track.extensions {
+        write_track_extensions(w, extensions, options)?;
     }
 
-    writeln!(w, "      </trkpt>")?;
+    for segment in &track.segments {
+        write_line(w, options, 2, format_args!("<trkseg>"))?;
+        for p in &segment.points {
+            write_trackpoint(w, p, options)?;
+        }
+        write_raw_children(w, segment.raw_children.as_ref(), 3, options)?;
+        if let Some(extensions) = segment
+            .extensions
+            .as_ref()
+            .filter(|_| options.preserve_unknown)
+        {
+            write_line(w, options, 3, format_args!("<extensions>"))?;
+            write_line(w, options, 4, format_args!("{}", extensions))?;
+            write_line(w, options, 3, format_args!("</extensions>"))?;
+        }
+        write_line(w, options, 2, format_args!("</trkseg>"))?;
+    }
 
+    write_line(w, options, 1, format_args!("</trk>"))?;
     Ok(())
 }
+
+fn write_track_extensions<W: Write>(
+    w: &mut W,
+    extensions: &TrackExtensions,
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    let other = extensions
+        .other
+        .as_ref()
+        .filter(|_| options.preserve_unknown);
+    if extensions.display_color.is_none() && other.is_none() {
+        return Ok(());
+    }
+
+    write_line(w, options, 2, format_args!("<extensions>"))?;
+    if let Some(display_color) = &extensions.display_color {
+        // Declare the namespace here, since the <gpx> tag of the input
+        // file may not have done so (or may use a different prefix).
+        write_line(
+            w,
+            options,
+            3,
+            format_args!(
+                "<gpxx:TrackExtension xmlns:gpxx=\"http://www.garmin.com/xmlschemas/GpxExtensions/v3\">"
+            ),
+        )?;
+        write_line(
+            w,
+            options,
+            4,
+            format_args!(
+                "<gpxx:DisplayColor>{}</gpxx:DisplayColor>",
+                escape(display_color)
+            ),
+        )?;
+        write_line(w, options, 3, format_args!("</gpxx:TrackExtension>"))?;
+    }
+    if let Some(other) = other {
+        write_line(w, options, 3, format_args!("{}", other))?;
+    }
+    write_line(w, options, 2, format_args!("</extensions>"))?;
+    Ok(())
+}
+
+fn write_trackpoint<W: Write>(
+    w: &mut W,
+    p: &TrackPoint,
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    let dp = options.coord_decimals;
+    write_line(
+        w,
+        options,
+        3,
+        format_args!("<trkpt lat=\"{:.*}\" lon=\"{:.*}\">", dp, p.lat, dp, p.lon),
+    )?;
+
+    if let Some(ele) = p.ele {
+        write_line(
+            w,
+            options,
+            4,
+            format_args!("<ele>{:.*}</ele>", options.ele_decimals, ele),
+        )?;
+    }
+
+    if let Some(t) = p.time {
+        write_line(
+            w,
+            options,
+            4,
+            format_args!("<time>{}</time>", format_time(&t, options)),
+        )?;
+    }
+
+    if let Some(fix_info) = &p.fix_info {
+        write_fix_info(w, fix_info, options)?;
+    }
+
+    write_raw_children(w, p.raw_children.as_ref(), 4, options)?;
+
+    // We only write the extensions we do not understand. The
+    // Garmin ones are deliberately stripped to save space.
+    if let Some(other) = p
+        .extensions
+        .as_ref()
+        .and_then(|ext| ext.other.as_ref())
+        .filter(|_| options.preserve_unknown)
+    {
+        write_line(w, options, 4, format_args!("<extensions>"))?;
+        write_line(w, options, 5, format_args!("{}", other))?;
+        write_line(w, options, 4, format_args!("</extensions>"))?;
+    }
+
+    write_line(w, options, 3, format_args!("</trkpt>"))?;
+
+    Ok(())
+}
+
+/// Writes the child elements that we read but did not understand, if
+/// they are being preserved. They come after the elements we do
+/// understand and before the <extensions>.
+fn write_raw_children<W: Write>(
+    w: &mut W,
+    raw_children: Option<&String>,
+    depth: usize,
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    if let Some(raw_children) = raw_children.filter(|_| options.preserve_unknown) {
+        write_line(w, options, depth, format_args!("{}", raw_children))?;
+    }
+    Ok(())
+}
+
+fn format_time(time: &OffsetDateTime, options: &WriteOptions) -> String {
+    match options.time_decimals {
+        Some(decimals) => format_utc_date_with_decimals(time, decimals),
+        None => format_utc_date(time),
+    }
+}
+
+/// Writes the position and accuracy elements of a <trkpt>. These are
+/// split by <name>, <desc> etc. in the XSD, but we don't write those.
+fn write_fix_info<W: Write>(
+    w: &mut W,
+    fix_info: &FixInfo,
+    options: &WriteOptions,
+) -> Result<(), GapixError> {
+    if let Some(magvar) = fix_info.magvar {
+        write_line(w, options, 4, format_args!("<magvar>{}</magvar>", magvar))?;
+    }
+    if let Some(geoid_height) = fix_info.geoid_height {
+        write_line(
+            w,
+            options,
+            4,
+            format_args!("<geoidheight>{}</geoidheight>", geoid_height),
+        )?;
+    }
+    if let Some(fix) = fix_info.fix {
+        write_line(w, options, 4, format_args!("<fix>{}</fix>", fix))?;
+    }
+    if let Some(sat) = fix_info.sat {
+        write_line(w, options, 4, format_args!("<sat>{}</sat>", sat))?;
+    }
+    if let Some(hdop) = fix_info.hdop {
+        write_line(w, options, 4, format_args!("<hdop>{}</hdop>", hdop))?;
+    }
+    if let Some(vdop) = fix_info.vdop {
+        write_line(w, options, 4, format_args!("<vdop>{}</vdop>", vdop))?;
+    }
+    if let Some(pdop) = fix_info.pdop {
+        write_line(w, options, 4, format_args!("<pdop>{}</pdop>", pdop))?;
+    }
+    if let Some(age) = fix_info.age_of_dgps_data {
+        write_line(
+            w,
+            options,
+            4,
+            format_args!("<ageofdgpsdata>{}</ageofdgpsdata>", age),
+        )?;
+    }
+    if let Some(dgps_id) = fix_info.dgps_id {
+        write_line(w, options, 4, format_args!("<dgpsid>{}</dgpsid>", dgps_id))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use time::Duration;
+
+    use super::*;
+    use crate::{
+        args::Args,
+        model::{Extensions, FixType, GpxBuilder, TrackExtensions},
+        stage::{detect_stages, enrich_trackpoints, StageDetectionParameters},
+        test_helpers::{
+            make_enriched_gpx, make_gpx, read_gpx_str, ride, ride_points, round_trip, track_point,
+            write_gpx_str, xy_point,
+        },
+    };
+
+    const FULL_METADATA_GPX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+  <metadata>
+    <name>Morning Ride</name>
+    <desc>A loop</desc>
+    <author>
+      <name>Phil</name>
+      <email id="phil" domain="example.com" />
+      <link href="https://example.com/phil">
+        <text>Home page</text>
+      </link>
+    </author>
+    <copyright author="Phil">
+      <year>2024</year>
+      <license>https://creativecommons.org/licenses/by/4.0/</license>
+    </copyright>
+    <link href="https://example.com/ride">
+      <text>The ride</text>
+      <type>text/html</type>
+    </link>
+    <time>2024-06-01T08:00:00Z</time>
+    <keywords>audax, cycling</keywords>
+    <bounds minlat="51.000000" minlon="-1.000000" maxlat="52.000000" maxlon="1.000000" />
+  </metadata>
+  <trk>
+    <trkseg>
+      <trkpt lat="51.5" lon="0.0"><ele>10</ele><time>2024-06-01T08:00:00Z</time></trkpt>
+      <trkpt lat="51.6" lon="0.1"><ele>12</ele><time>2024-06-01T08:10:00Z</time></trkpt>
+    </trkseg>
+  </trk>
+</gpx>
+"#;
+
+    #[test]
+    fn full_metadata_round_trips() {
+        let gpx = round_trip(&read_gpx_str(FULL_METADATA_GPX));
+        let md = &gpx.metadata;
+
+        assert_eq!(md.name.as_deref(), Some("Morning Ride"));
+        assert_eq!(md.desc.as_deref(), Some("A loop"));
+
+        let author = md.author.as_ref().unwrap();
+        assert_eq!(author.name.as_deref(), Some("Phil"));
+        let email = author.email.as_ref().unwrap();
+        assert_eq!(
+            (email.id.as_str(), email.domain.as_str()),
+            ("phil", "example.com")
+        );
+        let author_link = author.link.as_ref().unwrap();
+        assert_eq!(author_link.href, "https://example.com/phil");
+        assert_eq!(author_link.text.as_deref(), Some("Home page"));
+
+        let copyright = md.copyright.as_ref().unwrap();
+        assert_eq!(copyright.author, "Phil");
+        assert_eq!(copyright.year, Some(2024));
+        assert_eq!(
+            copyright.license.as_deref(),
+            Some("https://creativecommons.org/licenses/by/4.0/")
+        );
+
+        assert_eq!(md.links.len(), 1);
+        assert_eq!(md.links[0].href, "https://example.com/ride");
+        assert_eq!(md.links[0].text.as_deref(), Some("The ride"));
+        assert_eq!(md.links[0].r#type.as_deref(), Some("text/html"));
+
+        assert_eq!(md.time.unwrap().unix_timestamp(), 1_717_228_800);
+        assert_eq!(md.keywords.as_deref(), Some("audax, cycling"));
+
+        let bounds = md.bounds.as_ref().unwrap();
+        assert_eq!(
+            (
+                bounds.min_lat,
+                bounds.min_lon,
+                bounds.max_lat,
+                bounds.max_lon
+            ),
+            (51.0, -1.0, 52.0, 1.0)
+        );
+    }
+
+    #[test]
+    fn text_is_escaped_when_it_is_written() {
+        let mut gpx = make_gpx(vec![track_point(51.5, -0.1, None)]);
+        gpx.metadata.name = Some("Fish & Chips <Friday>".to_string());
+        gpx.tracks[0].name = Some("Tom & Jerry's \"ride\"".to_string());
+        gpx.metadata.links.push(Link {
+            href: "https://example.com/?a=1&b=2".to_string(),
+            text: None,
+            r#type: None,
+        });
+
+        let xml = write_gpx_str(&gpx, &WriteOptions::default());
+        assert!(
+            xml.contains("<name>Fish &amp; Chips &lt;Friday&gt;</name>"),
+            "{xml}"
+        );
+        assert!(
+            xml.contains(r#"href="https://example.com/?a=1&amp;b=2""#),
+            "{xml}"
+        );
+
+        let gpx = read_gpx_str(&xml);
+        assert_eq!(gpx.metadata.name.as_deref(), Some("Fish & Chips <Friday>"));
+        assert_eq!(
+            gpx.tracks[0].name.as_deref(),
+            Some("Tom & Jerry's \"ride\"")
+        );
+        assert_eq!(gpx.metadata.links[0].href, "https://example.com/?a=1&b=2");
+    }
+
+    /// A zig-zag of 'legs' legs, each 200m along and 100m across,
+    /// with a point every 10m.
+    fn zigzag(legs: usize) -> Vec<EnrichedTrackPoint> {
+        let points = (0..=legs * 20)
+            .map(|i| {
+                let leg = i / 20;
+                let along = (i % 20) as f64 * 10.0;
+                let across = if leg % 2 == 0 { along } else { 200.0 - along };
+                xy_point(i as f64 * 10.0, across / 2.0)
+            })
+            .collect();
+        make_enriched_gpx(points).points
+    }
+
+    /// A 2km straight line with a point every 10m, each of which is
+    /// 0.5m either side of the line.
+    fn noisy_line() -> Vec<EnrichedTrackPoint> {
+        let points = (0..=200)
+            .map(|i| xy_point(i as f64 * 10.0, if i % 2 == 0 { 0.5 } else { -0.5 }))
+            .collect();
+        make_enriched_gpx(points).points
+    }
+
+    #[test]
+    fn rdp_and_vw_keep_the_corners_of_a_zigzag() {
+        let mut rdp = zigzag(5);
+        let mut vw = zigzag(5);
+
+        reduce_trackpoints_by_rdp(&mut rdp, metres_to_epsilon(5), None);
+        reduce_trackpoints_by_vw(&mut vw, metres_to_vw_area(5));
+
+        // The 2 ends and the 4 corners.
+        assert_eq!(rdp.len(), 6);
+        assert_eq!(vw.len(), rdp.len());
+        let corners: Vec<_> = rdp.iter().map(|p| p.index).collect();
+        assert_eq!(corners, [0, 20, 40, 60, 80, 100]);
+        assert_eq!(vw.iter().map(|p| p.index).collect::<Vec<_>>(), corners);
+    }
+
+    #[test]
+    fn epsilon_simplifies_to_the_corners_of_a_zigzag() {
+        let simplify = |epsilon: &str| {
+            let args = Args::try_parse_from(["gapix", "--epsilon", epsilon]).unwrap();
+            let mut points = zigzag(5);
+            reduce_trackpoints_by_rdp(&mut points, args.rdp_epsilon().unwrap(), None);
+            points.iter().map(|p| p.index).collect::<Vec<_>>()
+        };
+
+        // About 5m, so the corners are kept but not the points between them.
+        assert_eq!(simplify("0.000045"), [0, 20, 40, 60, 80, 100]);
+        // About 1km, so only the ends are kept.
+        assert_eq!(simplify("0.01"), [0, 100]);
+    }
+
+    #[test]
+    fn rdp_removes_more_noise_than_vw() {
+        let mut rdp = noisy_line();
+        let mut vw = noisy_line();
+        let original_len = rdp.len();
+
+        reduce_trackpoints_by_rdp(&mut rdp, metres_to_epsilon(5), None);
+        reduce_trackpoints_by_vw(&mut vw, metres_to_vw_area(5));
+
+        // Every point is within 5m of the line, so RDP keeps only the ends.
+        // VW removes the smallest triangles first, and once some points have
+        // gone the triangles formed by the rest get bigger, so it keeps more.
+        assert_eq!(rdp.len(), 2);
+        assert!(vw.len() > rdp.len());
+        assert!(vw.len() < original_len / 2, "VW kept {} points", vw.len());
+        assert_eq!(vw.first().unwrap().index, 0);
+        assert_eq!(vw.last().unwrap().index, original_len - 1);
+    }
+
+    /// A 5km sine wave with a point every 5m, so that there are
+    /// plenty of significant points for RDP to choose from.
+    fn sine_wave() -> Vec<EnrichedTrackPoint> {
+        let points = (0..=1000)
+            .map(|i| xy_point(i as f64 * 5.0, 50.0 * (i as f64 / 20.0).sin()))
+            .collect();
+        make_enriched_gpx(points).points
+    }
+
+    #[test]
+    fn reduce_to_count_gets_close_to_the_target_without_exceeding_it() {
+        for target in [50, 100, 200] {
+            let mut points = sine_wave();
+            reduce_trackpoints_to_count(&mut points, target);
+            assert!(points.len() <= target);
+            assert!(
+                points.len() + 3 >= target,
+                "Target {target}, kept {}",
+                points.len()
+            );
+        }
+    }
+
+    #[test]
+    fn reduce_to_count_leaves_a_short_track_alone() {
+        let mut points = sine_wave();
+        let original_len = points.len();
+        let epsilon = reduce_trackpoints_to_count(&mut points, original_len);
+        assert_eq!(epsilon, 0.0);
+        assert_eq!(points.len(), original_len);
+    }
+
+    #[test]
+    fn preserving_elevation_keeps_the_apex_of_a_spike() {
+        // Straight on the map, flat apart from a sharp peak at point 50.
+        let points = (0..=100)
+            .map(|i| {
+                let mut p = xy_point(i as f64 * 10.0, 0.0);
+                p.ele = Some(if i == 50 { 150.0 } else { 100.0 });
+                p
+            })
+            .collect();
+        let mut gpx = make_enriched_gpx(points);
+        enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap();
+
+        let mut flat = gpx.points.clone();
+        reduce_trackpoints_by_rdp(&mut flat, metres_to_epsilon(5), None);
+        assert!(flat.iter().all(|p| p.index != 50));
+
+        let mut points = gpx.points;
+        reduce_trackpoints_by_rdp_preserving_elevation(&mut points, metres_to_epsilon(5), 5.0);
+        assert!(points.iter().any(|p| p.index == 50));
+        assert_eq!(points.first().unwrap().index, 0);
+        assert_eq!(points.last().unwrap().index, 100);
+    }
+
+    #[test]
+    fn coordinates_and_elevations_are_written_to_the_requested_precision() {
+        let gpx = make_gpx(vec![track_point(51.123456789, -0.987654321, Some(12.3456))]);
+
+        let coarse = write_gpx_str(
+            &gpx,
+            &WriteOptions {
+                coord_decimals: 4,
+                ele_decimals: 0,
+                ..Default::default()
+            },
+        );
+        assert!(
+            coarse.contains(r#"<trkpt lat="51.1235" lon="-0.9877">"#),
+            "{coarse}"
+        );
+        assert!(coarse.contains("<ele>12</ele>"), "{coarse}");
+
+        let fine = write_gpx_str(
+            &gpx,
+            &WriteOptions {
+                coord_decimals: 7,
+                ele_decimals: 3,
+                ..Default::default()
+            },
+        );
+        assert!(
+            fine.contains(r#"<trkpt lat="51.1234568" lon="-0.9876543">"#),
+            "{fine}"
+        );
+        assert!(fine.contains("<ele>12.346</ele>"), "{fine}");
+    }
+
+    #[test]
+    fn compact_output_is_smaller_and_reads_back_the_same() {
+        let gpx = make_gpx((0..20).map(|i| xy_point(i as f64 * 100.0, 0.0)).collect());
+
+        let pretty = write_gpx_str(&gpx, &WriteOptions::default());
+        let compact = write_gpx_str(
+            &gpx,
+            &WriteOptions {
+                pretty: false,
+                ..Default::default()
+            },
+        );
+        assert!(compact.len() < pretty.len());
+        assert!(!compact.trim_end().contains('\n'), "{compact}");
+
+        let from_pretty = read_gpx_str(&pretty);
+        let from_compact = read_gpx_str(&compact);
+        assert_eq!(
+            format!("{:?}", from_compact.metadata),
+            format!("{:?}", from_pretty.metadata)
+        );
+        assert_eq!(
+            format!("{:?}", from_compact.tracks),
+            format!("{:?}", from_pretty.tracks)
+        );
+    }
+
+    #[test]
+    fn compact_output_keeps_the_whitespace_inside_text_and_extensions() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.1" xmlns:foo="http://example.com/foo">
+  <metadata>
+    <name>Multi-line</name>
+  </metadata>
+  <trk>
+    <desc>Line one
+  Line two</desc>
+    <extensions>
+      <foo:a>
+        <foo:b>x</foo:b>
+      </foo:a>
+    </extensions>
+    <trkseg>
+      <trkpt lat="53.0" lon="-2.0"></trkpt>
+      <trkpt lat="53.1" lon="-2.1"></trkpt>
+    </trkseg>
+  </trk>
+</gpx>"#;
+        let gpx = read_gpx_str(xml);
+        let track = &gpx.tracks[0];
+        assert_eq!(track.desc.as_deref(), Some("Line one\n  Line two"));
+        let extension = track.extensions.as_ref().unwrap().other.clone();
+        assert!(extension.as_deref().unwrap().contains("\n        <foo:b>"));
+
+        let compact = write_gpx_str(
+            &gpx,
+            &WriteOptions {
+                pretty: false,
+                preserve_unknown: true,
+                ..Default::default()
+            },
+        );
+        let reread = read_gpx_str(&compact);
+        let track = &reread.tracks[0];
+        assert_eq!(track.desc.as_deref(), Some("Line one\n  Line two"));
+        assert_eq!(track.extensions.as_ref().unwrap().other, extension);
+    }
+
+    #[test]
+    fn simplifying_each_segment_keeps_the_segments_and_their_ends() {
+        // Two 2km segments, each a straight line with a little noise, with
+        // a 1km gap between them.
+        let segment = |start_x: f64| {
+            (0..=40).map(move |i| xy_point(start_x + i as f64 * 50.0, (i % 2) as f64))
+        };
+        let mut builder = GpxBuilder::new("segments.gpx");
+        builder.add_track(None);
+        for p in segment(0.0) {
+            builder.push_point(p);
+        }
+        builder.add_segment();
+        for p in segment(3000.0) {
+            builder.push_point(p);
+        }
+        let original = builder.build();
+
+        let mut gpx = original.clone();
+        simplify_each_segment(&mut gpx, metres_to_epsilon(10), |_| false);
+
+        assert_eq!(gpx.tracks.len(), 1);
+        let (segments, original_segments) = (&gpx.tracks[0].segments, &original.tracks[0].segments);
+        assert_eq!(segments.len(), 2);
+        for (seg, orig) in segments.iter().zip(original_segments) {
+            assert_eq!(seg.points.len(), 2);
+            assert_eq!(seg.points[0].lon, orig.points[0].lon);
+            assert_eq!(seg.points[1].lon, orig.points.last().unwrap().lon);
+        }
+    }
+
+    #[test]
+    fn fix_information_round_trips() {
+        let fix_info = FixInfo {
+            magvar: Some(1.5),
+            geoid_height: Some(47.25),
+            fix: Some(FixType::ThreeD),
+            sat: Some(9),
+            hdop: Some(0.8),
+            vdop: Some(1.2),
+            pdop: Some(1.4),
+            age_of_dgps_data: Some(3.0),
+            dgps_id: Some(42),
+        };
+        let gpx = make_gpx(vec![TrackPoint {
+            fix_info: Some(fix_info.clone()),
+            ..track_point(51.5, -0.1, Some(10.0))
+        }]);
+
+        let written = write_gpx_str(&gpx, &WriteOptions::default());
+        assert!(written.contains("<fix>3d</fix>"), "{written}");
+
+        let read = read_gpx_str(&written);
+        assert_eq!(
+            read.tracks[0].segments[0].points[0].fix_info,
+            Some(fix_info)
+        );
+    }
+
+    #[test]
+    fn a_stationary_cluster_is_collapsed_to_one_point() {
+        // Points 60 to 120 are all in the same place.
+        let mut gpx = make_enriched_gpx(ride_points(&[(20.0, 600), (0.0, 600), (20.0, 600)]));
+        enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap();
+        let stop_time = gpx.points[120].time;
+        let end_time = gpx.points.last().unwrap().time;
+
+        let removed = collapse_stationary_clusters(&mut gpx.points, 5.0);
+
+        assert_eq!(removed, 60);
+        assert_eq!(gpx.points.len(), 121);
+        let stop = &gpx.points[60];
+        assert_eq!(stop.time, stop_time);
+        assert_eq!(stop.delta_time, Some(Duration::seconds(610)));
+        assert_eq!(gpx.points.last().unwrap().time, end_time);
+
+        let total: Duration = gpx.points.iter().filter_map(|p| p.delta_time).sum();
+        assert_eq!(total, Duration::minutes(30));
+    }
+
+    #[test]
+    fn fractional_seconds_round_trip() {
+        let gpx = read_gpx_str(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+  <metadata>
+    <time>2024-06-01T08:00:00Z</time>
+  </metadata>
+  <trk>
+    <trkseg>
+      <trkpt lat="51.5" lon="-0.1">
+        <time>2024-06-01T08:00:01.123Z</time>
+      </trkpt>
+      <trkpt lat="51.5" lon="-0.1001">
+        <time>2024-06-01T08:00:02.000Z</time>
+      </trkpt>
+    </trkseg>
+  </trk>
+</gpx>
+"#,
+        );
+
+        let written = write_gpx_str(&gpx, &WriteOptions::default());
+        assert!(
+            written.contains("<time>2024-06-01T08:00:01.123Z</time>"),
+            "{written}"
+        );
+        assert!(
+            written.contains("<time>2024-06-01T08:00:02Z</time>"),
+            "{written}"
+        );
+
+        let read = read_gpx_str(&written);
+        let times: Vec<_> = read.tracks[0].segments[0]
+            .points
+            .iter()
+            .map(|p| p.time.unwrap().millisecond())
+            .collect();
+        assert_eq!(times, [123, 0]);
+
+        let millis = write_gpx_str(
+            &gpx,
+            &WriteOptions {
+                time_decimals: Some(3),
+                ..Default::default()
+            },
+        );
+        assert!(
+            millis.contains("<time>2024-06-01T08:00:02.000Z</time>"),
+            "{millis}"
+        );
+    }
+
+    #[test]
+    fn forced_indexes_survive_simplification() {
+        // A straight line with a stop in the middle, which RDP alone
+        // reduces to its two end points.
+        let gpx = ride(&[(20.0, 600), (0.0, 900), (20.0, 600)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        let keep = stages.highlighted_trackpoints();
+        assert!(keep.len() > 2, "{keep:?}");
+
+        let mut points = gpx.points.clone();
+        reduce_trackpoints_by_rdp(&mut points, 10.0, None);
+        assert_eq!(points.len(), 2);
+
+        let mut points = gpx.points.clone();
+        reduce_trackpoints_by_rdp_keeping(&mut points, 10.0, &keep);
+        let kept: HashSet<usize> = points.iter().map(|p| p.index).collect();
+        assert!(keep.is_subset(&kept), "{keep:?} vs {kept:?}");
+        assert!(kept.contains(&0) && kept.contains(&(gpx.points.len() - 1)));
+    }
+
+    #[test]
+    fn forced_indexes_survive_every_simplification_method() {
+        let gpx = ride(&[(20.0, 600), (0.0, 900), (20.0, 600)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        let keep = stages.highlighted_trackpoints();
+
+        let check = |name: &str, reduce: fn(&mut Vec<EnrichedTrackPoint>)| {
+            let mut points = gpx.points.clone();
+            reduce_trackpoints_keeping(&mut points, &keep, reduce);
+            let kept: HashSet<usize> = points.iter().map(|p| p.index).collect();
+            assert!(keep.is_subset(&kept), "{name}: {keep:?} vs {kept:?}");
+            assert!(kept.len() < gpx.points.len(), "{name}");
+        };
+
+        check("vw", |points| {
+            reduce_trackpoints_by_vw(points, metres_to_vw_area(10))
+        });
+        check("elevation", |points| {
+            reduce_trackpoints_by_rdp_preserving_elevation(points, 10.0, 10.0)
+        });
+        check("adaptive", |points| reduce_trackpoints_adaptive(points, 10));
+        check("count", |points| {
+            reduce_trackpoints_to_count(points, 2);
+        });
+    }
+
+    #[test]
+    fn simplifying_each_segment_keeps_the_requested_points() {
+        let points: Vec<_> = (0..=40).map(|i| xy_point(i as f64 * 50.0, 0.0)).collect();
+        let forced = points[17].clone();
+        let mut gpx = make_gpx(points);
+
+        simplify_each_segment(&mut gpx, metres_to_epsilon(10), |p| {
+            p.lat == forced.lat && p.lon == forced.lon
+        });
+
+        let kept = &gpx.tracks[0].segments[0].points;
+        assert_eq!(kept.len(), 3);
+        assert_eq!(kept[1].lon, forced.lon);
+    }
+
+    #[test]
+    fn the_track_comment_is_written_between_the_name_and_the_description() {
+        let mut gpx = make_gpx(vec![track_point(51.5, -0.1, None)]);
+        let track = &mut gpx.tracks[0];
+        track.name = Some("Audax".to_string());
+        track.comment = Some("Windy".to_string());
+        track.desc = Some("200km".to_string());
+
+        let xml = write_gpx_str(&gpx, &WriteOptions::default());
+        let name = xml.find("<name>Audax</name>").unwrap();
+        let comment = xml.find("<cmt>Windy</cmt>").unwrap();
+        let desc = xml.find("<desc>200km</desc>").unwrap();
+        assert!(name < comment && comment < desc, "{xml}");
+
+        let gpx = round_trip(&gpx);
+        assert_eq!(gpx.tracks[0].comment.as_deref(), Some("Windy"));
+    }
+
+    #[test]
+    fn adaptive_simplification_reduces_dense_sections_more() {
+        // A gentle S-bend road: the first 1km has a point every 5m, the
+        // next 10km a point every 50m.
+        let xs = (0..200)
+            .map(|i| i as f64 * 5.0)
+            .chain((1..200).map(|i| 1000.0 + i as f64 * 50.0));
+        let points = xs.map(|x| xy_point(x, 20.0 * (x / 200.0).sin())).collect();
+        let mut gpx = make_enriched_gpx(points);
+        enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap();
+
+        let kept = |points: &[EnrichedTrackPoint]| {
+            let dense = points.iter().filter(|p| p.index < 200).count();
+            (dense, points.len() - dense)
+        };
+        let mut points = gpx.points.clone();
+        reduce_trackpoints_by_rdp(&mut points, metres_to_epsilon(5), None);
+        let (rdp_dense, rdp_sparse) = kept(&points);
+        let mut points = gpx.points.clone();
+        reduce_trackpoints_adaptive(&mut points, 5);
+        let (adaptive_dense, adaptive_sparse) = kept(&points);
+
+        // Compared with plain RDP, the dense half loses more points and
+        // the sparse half keeps more.
+        assert!(
+            adaptive_dense < rdp_dense && adaptive_sparse > rdp_sparse,
+            "RDP {rdp_dense}/{rdp_sparse}, adaptive {adaptive_dense}/{adaptive_sparse}"
+        );
+    }
+
+    #[test]
+    fn unknown_extensions_survive_a_round_trip_when_preserved() {
+        let gpx = read_gpx_str(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.1" xmlns="http://www.topografix.com/GPX/1/1" xmlns:foo="http://example.com/foo">
+  <metadata>
+    <time>2024-06-01T08:00:00Z</time>
+  </metadata>
+  <trk>
+    <trkseg>
+      <trkpt lat="51.5" lon="0.0">
+        <time>2024-06-01T08:00:00Z</time>
+        <extensions>
+          <foo:bar baz="1">qux</foo:bar>
+        </extensions>
+      </trkpt>
+    </trkseg>
+  </trk>
+  <extensions>
+    <foo:summary>ride</foo:summary>
+  </extensions>
+</gpx>
+"#,
+        );
+        let options = WriteOptions {
+            preserve_unknown: true,
+            ..Default::default()
+        };
+
+        let xml = write_gpx_str(&gpx, &options);
+        assert!(xml.contains(r#"<foo:bar baz="1">qux</foo:bar>"#), "{xml}");
+        assert!(xml.contains("<foo:summary>ride</foo:summary>"), "{xml}");
+
+        let gpx = read_gpx_str(&xml);
+        let point = &gpx.tracks[0].segments[0].points[0];
+        let other = point.extensions.as_ref().unwrap().other.as_deref();
+        assert_eq!(other, Some(r#"<foo:bar baz="1">qux</foo:bar>"#));
+        assert_eq!(
+            gpx.extensions.as_deref(),
+            Some("<foo:summary>ride</foo:summary>")
+        );
+
+        let xml = write_gpx_str(&gpx, &WriteOptions::default());
+        assert!(!xml.contains("foo:"), "{xml}");
+    }
+
+    #[test]
+    fn gpx_to_string_reparses_to_the_same_model() {
+        let points = ride_points(&[(20.0, 600)])
+            .into_iter()
+            .map(|p| TrackPoint {
+                ele: Some(42.5),
+                ..p
+            })
+            .collect();
+        // Round trip once first, so that the coordinates are rounded and
+        // the bounds are filled in as they will be when written.
+        let gpx = round_trip(&make_gpx(points));
+
+        let xml = gpx_to_string(&gpx).unwrap();
+        assert_eq!(gpx_to_bytes(&gpx).unwrap(), xml.as_bytes());
+        let reparsed = read_gpx_str(&xml);
+
+        // The model has no PartialEq, so compare the Debug output. The
+        // attributes are a HashMap, which has no fixed order.
+        assert_eq!(reparsed.info.attributes, gpx.info.attributes);
+        assert_eq!(
+            format!("{:?}", reparsed.metadata),
+            format!("{:?}", gpx.metadata)
+        );
+        assert_eq!(
+            format!("{:?}", reparsed.tracks),
+            format!("{:?}", gpx.tracks)
+        );
+    }
+
+    #[test]
+    fn stripping_the_extensions_removes_them_from_the_file() {
+        let points = ride_points(&[(20.0, 600)])
+            .into_iter()
+            .map(|p| TrackPoint {
+                extensions: Some(Extensions {
+                    heart_rate: Some(130),
+                    other: Some("<foo:bar>1</foo:bar>".to_string()),
+                    ..Default::default()
+                }),
+                ..p
+            })
+            .collect();
+        let mut gpx = make_gpx(points);
+        gpx.extensions = Some("<foo:summary>ride</foo:summary>".to_string());
+        gpx.tracks[0].extensions = Some(TrackExtensions {
+            display_color: Some("Blue".to_string()),
+            other: None,
+        });
+        gpx.tracks[0].segments[0].extensions = Some("<foo:colour>red</foo:colour>".to_string());
+        let options = WriteOptions {
+            preserve_unknown: true,
+            ..Default::default()
+        };
+        let unstripped = write_gpx_str(&gpx, &options);
+        assert!(unstripped.contains("<extensions>"));
+
+        gpx.strip_extensions();
+
+        let stripped = write_gpx_str(&gpx, &options);
+        assert!(!stripped.contains("<extensions>"), "{stripped}");
+        assert!(stripped.len() < unstripped.len());
+    }
+
+    #[test]
+    fn rdp_reports_the_start_and_the_end() {
+        let mut gpx = make_enriched_gpx(ride_points(&[(20.0, 600)]));
+        enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap();
+        let num_points = gpx.points.len();
+        let updates = std::sync::Mutex::new(Vec::new());
+        let progress = |done: usize, total: usize| updates.lock().unwrap().push((done, total));
+
+        reduce_trackpoints_by_rdp(&mut gpx.points, metres_to_epsilon(10), Some(&progress));
+
+        assert_eq!(
+            updates.into_inner().unwrap(),
+            [(0, num_points), (num_points, num_points)]
+        );
+    }
+}
@@ -4,14 +4,30 @@
 //! other metrics fairly easily.
 
 use core::{fmt, slice};
-use std::{collections::HashSet, ops::Index};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    ops::Index,
+    vec,
+};
 
 use geo::{GeodesicDistance, Point};
 use log::{debug, info, warn};
 use logging_timer::time;
 use time::{Duration, OffsetDateTime};
 
-use crate::model::{EnrichedGpx, EnrichedTrackPoint};
+use crate::{
+    args::Sex,
+    error::GapixError,
+    model::{
+        EnrichedGpx, EnrichedTrackPoint, Gpx, Metadata, Track, TrackPoint, TrackSegment, Waypoint,
+    },
+    progress::Progress,
+};
+
+/// Below this horizontal distance between two points we do not calculate
+/// a gradient, because small errors in the elevation would produce absurd
+/// values (and zero would produce infinity).
+const MIN_GRADE_DISTANCE_METRES: f64 = 1.0;
 
 /// Calculates speed in km/h from metres and seconds.
 pub fn speed_kmh(metres: f64, seconds: f64) -> f64 {
@@ -23,9 +39,30 @@ pub fn speed_kmh_from_duration(metres: f64, time: Duration) -> f64 {
     speed_kmh(metres, time.as_seconds_f64())
 }
 
+/// Calculates an average speed in km/h, returning None if the duration
+/// is missing or is not positive, rather than dividing by it and getting
+/// infinity (or a negative speed).
+fn average_speed_kmh(metres: f64, duration: Option<Duration>) -> Option<f64> {
+    duration
+        .filter(|dur| dur.is_positive())
+        .map(|dur| speed_kmh_from_duration(metres, dur))
+}
+
+/// The stages of one track of a file that contains several tracks,
+/// such as a multi-day tour.
+pub struct TrackStages {
+    /// The number of the track in the file, starting from 1.
+    pub number: usize,
+    /// The index of the first point of the track in the whole file.
+    pub first_index: usize,
+    /// Just the points of the track.
+    pub gpx: EnrichedGpx,
+    pub stages: StageList,
+}
+
 /// These are the parameters that control the 'Stage-finding'
 /// algorithm.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct StageDetectionParameters {
     /// You are considered "Stopped" if your speed drops below this.
     pub stopped_speed_kmh: f64,
@@ -38,6 +75,137 @@ pub struct StageDetectionParameters {
     /// example these can occur when just starting off again.
     /// So set the minimum length of a stage, in seconds.
     pub min_duration_seconds: f64,
+
+    /// To decide whether the track starts with a Moving or a Control
+    /// stage we look at the average speed over the start of the track.
+    /// If it is below this speed we assume you are stopped.
+    pub initial_classification_speed_kmh: f64,
+
+    /// The length of the period at the start of the track over which
+    /// the average speed is calculated to decide the type of the first stage.
+    pub initial_classification_window_seconds: f64,
+
+    /// When the track has no times we cannot tell when you stopped, so
+    /// instead we split it into Moving stages. A stage ends when it is at
+    /// least this long...
+    pub no_time_stage_metres: f64,
+
+    /// ...or when it has this much ascent plus descent, so that hilly
+    /// sections are split into shorter stages.
+    pub no_time_stage_climb_metres: f64,
+
+    /// If set, you are only considered "Stopped" if your cadence is also
+    /// at or below this, like Garmin's auto-pause. This stops a slow,
+    /// pedalling crawl up a steep hill (or a GPS glitch) from being taken
+    /// for a stop. Points without a cadence are judged on speed alone,
+    /// so this does nothing for tracks without cadence data.
+    pub stopped_cadence_rpm: Option<u16>,
+}
+
+impl Default for StageDetectionParameters {
+    fn default() -> Self {
+        Self {
+            stopped_speed_kmh: 0.15,
+            min_metres_to_resume: 100.0,
+            min_duration_seconds: 300.0,
+            // Less than walking pace? Assume you're stopped.
+            initial_classification_speed_kmh: 5.0,
+            initial_classification_window_seconds: 180.0,
+            no_time_stage_metres: 10_000.0,
+            no_time_stage_climb_metres: 250.0,
+            stopped_cadence_rpm: None,
+        }
+    }
+}
+
+impl StageDetectionParameters {
+    /// Returns true if we consider you to be stopped at 'point'.
+    fn is_stopped(&self, point: &EnrichedTrackPoint) -> bool {
+        point
+            .speed_kmh
+            .is_some_and(|speed| speed <= self.stopped_speed_kmh)
+            && self
+                .stopped_cadence_rpm
+                .is_none_or(|max| point.cadence().is_none_or(|cadence| cadence <= max))
+    }
+}
+
+/// Describes the rider, so that we can estimate the calories they used.
+#[derive(Debug, Clone, Copy)]
+pub struct AthleteProfile {
+    pub weight_kg: f64,
+    pub max_hr: u16,
+    pub resting_hr: u16,
+    pub sex: Sex,
+}
+
+impl AthleteProfile {
+    /// Returns the estimated calories (kcal) used per minute at 'heart_rate'.
+    /// This is the formula from Keytel et al. (2005), "Prediction of energy
+    /// expenditure from heart rate monitoring during submaximal exercise".
+    /// It needs an age, which we estimate from the max heart rate using
+    /// Tanaka's formula of max_hr = 208 - 0.7 * age. Time spent at or below
+    /// the resting heart rate does not count.
+    pub fn kcal_per_minute(&self, heart_rate: u16) -> f64 {
+        if heart_rate <= self.resting_hr {
+            return 0.0;
+        }
+
+        let hr = heart_rate as f64;
+        let age = ((208.0 - self.max_hr as f64) / 0.7).clamp(10.0, 100.0);
+        let kj = match self.sex {
+            Sex::Male => -55.0969 + 0.6309 * hr + 0.1988 * self.weight_kg + 0.2017 * age,
+            Sex::Female => -20.4022 + 0.4472 * hr - 0.1263 * self.weight_kg + 0.074 * age,
+        };
+
+        (kj / 4.184).max(0.0)
+    }
+}
+
+/// The upper limits (inclusive) of heart rate zones 1 to 4, in bpm.
+/// Anything above 'z4_max' is in zone 5.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HrZones {
+    pub z1_max: u16,
+    pub z2_max: u16,
+    pub z3_max: u16,
+    pub z4_max: u16,
+}
+
+impl HrZones {
+    /// The number of zones.
+    pub const COUNT: usize = 5;
+
+    /// Makes the common zones of 60%, 70%, 80% and 90% of max heart rate.
+    pub fn from_max_hr(max_hr: u16) -> Self {
+        let pct = |p: u32| (max_hr as u32 * p / 100) as u16;
+        Self {
+            z1_max: pct(60),
+            z2_max: pct(70),
+            z3_max: pct(80),
+            z4_max: pct(90),
+        }
+    }
+
+    /// Returns the zone of the heart rate, as an index from 0 (zone 1)
+    /// to 4 (zone 5).
+    pub fn zone_index(&self, heart_rate: u16) -> usize {
+        [self.z1_max, self.z2_max, self.z3_max, self.z4_max]
+            .iter()
+            .position(|&max| heart_rate <= max)
+            .unwrap_or(Self::COUNT - 1)
+    }
+
+    /// Returns a description of the range of each zone, e.g. "121-140".
+    pub fn descriptions(&self) -> [String; Self::COUNT] {
+        [
+            format!("<={}", self.z1_max),
+            format!("{}-{}", self.z1_max + 1, self.z2_max),
+            format!("{}-{}", self.z2_max + 1, self.z3_max),
+            format!("{}-{}", self.z3_max + 1, self.z4_max),
+            format!(">{}", self.z4_max),
+        ]
+    }
 }
 
 /// Represents a stage from a GPX track. The stage can represent
@@ -54,11 +222,22 @@ pub struct Stage {
     pub min_elevation: Option<EnrichedTrackPoint>,
     pub max_elevation: Option<EnrichedTrackPoint>,
     pub max_speed: Option<EnrichedTrackPoint>,
+    pub max_grade: Option<EnrichedTrackPoint>,
     pub avg_heart_rate: Option<f64>,
     pub max_heart_rate: Option<EnrichedTrackPoint>,
+    /// The time spent at each heart rate, in seconds.
+    pub heart_rate_seconds: BTreeMap<u16, f64>,
+    pub avg_power: Option<f64>,
+    pub normalized_power: Option<f64>,
     pub avg_air_temp: Option<f64>,
     pub min_air_temp: Option<EnrichedTrackPoint>,
     pub max_air_temp: Option<EnrichedTrackPoint>,
+    pub avg_water_temp: Option<f64>,
+    pub min_water_temp: Option<EnrichedTrackPoint>,
+    pub max_water_temp: Option<EnrichedTrackPoint>,
+    pub avg_depth: Option<f64>,
+    pub min_depth: Option<EnrichedTrackPoint>,
+    pub max_depth: Option<EnrichedTrackPoint>,
 }
 
 /// The type of a Stage.
@@ -90,7 +269,7 @@ impl Stage {
     /// Returns the indexes of all the TrackPoints that have been
     /// highlighted as 'special' in some way, e.g. the point
     /// of min elevation. This is so we can force a hyperlink
-    /// to Google Maps for the special points.
+    /// to the map for the special points.
     pub fn highlighted_trackpoints(&self) -> Vec<usize> {
         let mut idxs = vec![
             self.track_start_point.index,
@@ -110,6 +289,10 @@ impl Stage {
             idxs.push(p.index);
         }
 
+        if let Some(p) = &self.max_grade {
+            idxs.push(p.index);
+        }
+
         if let Some(p) = &self.max_heart_rate {
             idxs.push(p.index);
         }
@@ -118,11 +301,45 @@ impl Stage {
             idxs.push(p.index);
         }
 
+        if let Some(p) = &self.max_water_temp {
+            idxs.push(p.index);
+        }
+
+        if let Some(p) = &self.max_depth {
+            idxs.push(p.index);
+        }
+
         idxs.sort();
 
         idxs
     }
 
+    /// Returns the 'special' points other than the start and end of the
+    /// stage, most significant first. Maxima are considered more
+    /// interesting than the min elevation.
+    fn special_points(&self) -> Vec<&EnrichedTrackPoint> {
+        [
+            &self.max_elevation,
+            &self.max_speed,
+            &self.max_heart_rate,
+            &self.max_grade,
+            &self.max_air_temp,
+            &self.max_water_temp,
+            &self.max_depth,
+            &self.min_elevation,
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Returns the points of the stage, from 'start' to 'end' inclusive.
+    /// The stage only keeps copies of a few points, so 'gpx' must be the
+    /// track the stage was detected in.
+    pub fn points<'gpx>(&self, gpx: &'gpx EnrichedGpx) -> &'gpx [EnrichedTrackPoint] {
+        &gpx.points[self.start.index..=self.end.index]
+    }
+
     /// Returns the duration of the stage.
     pub fn duration(&self) -> Option<Duration> {
         // Be careful to use the time that the 'start' TrackPoint
@@ -162,15 +379,32 @@ impl Stage {
 
     /// Returns the average speed of the stage, in kmh.
     pub fn average_speed_kmh(&self) -> Option<f64> {
-        self.duration()
-            .map(|dur| speed_kmh_from_duration(self.distance_metres(), dur))
+        average_speed_kmh(self.distance_metres(), self.duration())
+    }
+
+    /// Returns the average speed of the stage, in kmh, calculated from the
+    /// speed of each point weighted by the time taken to reach it. This
+    /// should agree with 'average_speed_kmh', which is calculated from the
+    /// end points only, so it is used as a cross-check. Points without a
+    /// speed are ignored. The start point is included because the time taken
+    /// to reach it is part of the 'duration'. Returns None if there is no
+    /// time to weight by.
+    pub fn average_speed_over_moving_points(&self, gpx: &EnrichedGpx) -> Option<f64> {
+        let (weighted_speed, seconds) = self
+            .points(gpx)
+            .iter()
+            .filter_map(|p| Some((p.speed_kmh?, p.delta_time?.as_seconds_f64())))
+            .fold((0.0, 0.0), |(ws, s), (speed, secs)| {
+                (ws + speed * secs, s + secs)
+            });
+
+        (seconds > 0.0).then(|| weighted_speed / seconds)
     }
 
     /// Returns the average speed, calculated over the distance from
     /// the start of the track to the end of the stage.
     pub fn running_average_speed_kmh(&self) -> Option<f64> {
-        self.running_duration()
-            .map(|dur| speed_kmh_from_duration(self.end.running_metres, dur))
+        average_speed_kmh(self.end.running_metres, self.running_duration())
     }
 
     /// Returns the total ascent in metres over the stage.
@@ -216,6 +450,58 @@ impl Stage {
     pub fn descent_rate_per_km(&self) -> Option<f64> {
         self.descent_metres().map(|a| a / self.distance_km())
     }
+
+    /// Returns the steepest gradient, as a percentage, of any
+    /// point within the stage.
+    pub fn max_grade(&self) -> Option<f64> {
+        self.max_grade.as_ref().and_then(|p| p.grade_percent)
+    }
+
+    /// Returns the average gradient, as a percentage, over the stage.
+    /// This is the net change in elevation divided by the distance.
+    pub fn avg_grade(&self) -> Option<f64> {
+        average_grade(&self.start, &self.end)
+    }
+
+    /// Returns the estimated calories (kcal) used during the stage,
+    /// based on the heart rate. Returns None if there is no heart
+    /// rate data.
+    pub fn estimated_calories(&self, profile: &AthleteProfile) -> Option<f64> {
+        if self.heart_rate_seconds.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.heart_rate_seconds
+                .iter()
+                .map(|(&hr, &secs)| profile.kcal_per_minute(hr) * secs / 60.0)
+                .sum(),
+        )
+    }
+
+    /// Returns the Intensity Factor of the stage for a rider with a
+    /// Functional Threshold Power of 'ftp' watts.
+    pub fn intensity_factor(&self, ftp: f64) -> Option<f64> {
+        intensity_factor(self.normalized_power, ftp)
+    }
+
+    /// Returns the Training Stress Score of the stage for a rider with a
+    /// Functional Threshold Power of 'ftp' watts.
+    pub fn training_stress_score(&self, ftp: f64) -> Option<f64> {
+        training_stress_score(self.normalized_power, self.duration(), ftp)
+    }
+
+    /// Returns the time spent in each heart rate zone. The time between
+    /// two points counts towards the zone of the heart rate at the second
+    /// point. Points without a heart rate are skipped, so the total can be
+    /// less than the duration of the stage.
+    pub fn time_in_zones(&self, zones: &HrZones) -> [Duration; HrZones::COUNT] {
+        let mut seconds = [0.0; HrZones::COUNT];
+        for (&hr, &secs) in &self.heart_rate_seconds {
+            seconds[zones.zone_index(hr)] += secs;
+        }
+        seconds.map(Duration::seconds_f64)
+    }
 }
 
 #[derive(Default)]
@@ -238,11 +524,20 @@ impl<'sl> IntoIterator for &'sl StageList {
     }
 }
 
+impl IntoIterator for StageList {
+    type Item = Stage;
+    type IntoIter = vec::IntoIter<Stage>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
 impl StageList {
     /// Returns the indexes of all the TrackPoints that have been
     /// highlighted as 'special' in some way, e.g. the point
     /// of min elevation. This is so we can force a hyperlink
-    /// to Google Maps for the special points.
+    /// to the map for the special points.
     pub fn highlighted_trackpoints(&self) -> HashSet<usize> {
         let mut idxs = HashSet::new();
 
@@ -255,36 +550,124 @@ impl StageList {
         idxs
     }
 
-    pub fn iter(&self) -> slice::Iter<Stage> {
+    /// A variant of 'highlighted_trackpoints' for use when forcing
+    /// hyperlinks, which drops special points that are within 'min_metres'
+    /// (along the track) of a more significant highlighted point, so that
+    /// a dense track does not end up with a cluster of hyperlinks a few
+    /// metres apart. The start and end of each stage are always kept.
+    pub fn highlighted_trackpoints_spaced(&self, min_metres: f64) -> HashSet<usize> {
+        let mut kept: Vec<&EnrichedTrackPoint> = Vec::new();
+        for s in self.iter() {
+            for p in [&s.track_start_point, &s.start, &s.end] {
+                kept.push(p);
+            }
+        }
+
+        let mut specials: Vec<(usize, &EnrichedTrackPoint)> = self
+            .iter()
+            .flat_map(|s| s.special_points().into_iter().enumerate())
+            .collect();
+        specials.sort_by_key(|(rank, _)| *rank);
+
+        for (_, p) in specials {
+            if kept
+                .iter()
+                .all(|k| (k.running_metres - p.running_metres).abs() >= min_metres)
+            {
+                kept.push(p);
+            }
+        }
+
+        kept.into_iter().map(|p| p.index).collect()
+    }
+
+    /// Returns an iterator over the stages, in track order.
+    pub fn iter(&self) -> slice::Iter<'_, Stage> {
         self.0.iter()
     }
 
-    /// Returns the first point in the first stage.
-    pub fn first_point(&self) -> &EnrichedTrackPoint {
-        &self.0[0].start
+    /// Returns the first point in the first stage, or None
+    /// if there are no stages.
+    pub fn first_point(&self) -> Option<&EnrichedTrackPoint> {
+        self.0.first().map(|stage| &stage.start)
     }
 
-    /// Returns the last point in the last stage.
-    pub fn last_point(&self) -> &EnrichedTrackPoint {
-        &self.0[self.len() - 1].end
+    /// Returns the last point in the last stage, or None
+    /// if there are no stages.
+    pub fn last_point(&self) -> Option<&EnrichedTrackPoint> {
+        self.0.last().map(|stage| &stage.end)
     }
 
     pub fn push(&mut self, stage: Stage) {
         self.0.push(stage);
     }
 
+    /// Absorbs any stage shorter than 'min_duration_seconds' into its
+    /// neighbours. Since stages alternate between Moving and Control, a short
+    /// stage in the middle of the list is merged with the stages either side
+    /// of it (which are of the same type as each other) to make one stage.
+    /// A short stage at the start or end of the list is merged into its only
+    /// neighbour. The shortest stages are merged first.
+    ///
+    /// The merged stages are rebuilt from 'gpx', so all the min/max fields
+    /// are correct for the new, longer stage.
+    pub fn merge_short_stages(&mut self, gpx: &EnrichedGpx, min_duration_seconds: f64) {
+        while self.len() > 1 {
+            let shortest = self
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, stage)| stage.duration().map(|d| (idx, d.as_seconds_f64())))
+                .filter(|(_, secs)| *secs < min_duration_seconds)
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+
+            let Some((idx, _)) = shortest else {
+                break;
+            };
+
+            let (first, last, stage_type) = if idx == 0 {
+                (0, 1, self[1].stage_type)
+            } else if idx == self.len() - 1 {
+                (idx - 1, idx, self[idx - 1].stage_type)
+            } else {
+                (idx - 1, idx + 1, self[idx - 1].stage_type)
+            };
+
+            let merged = make_stage(
+                gpx,
+                stage_type,
+                self[first].start.index,
+                self[last].end.index,
+            );
+
+            info!(
+                "Merging short {} stage at index {} into a {} stage from point {} to {}",
+                self[idx].stage_type, idx, merged.stage_type, merged.start.index, merged.end.index
+            );
+
+            self.0.splice(first..=last, [merged]);
+        }
+
+        assert_stages_cover_track(self, gpx);
+    }
+
+    /// Returns the number of stages.
     pub fn len(&self) -> usize {
         self.0.len()
     }
 
+    /// Returns true if there are no stages.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     /// Returns the start time of the first Stage.
     pub fn start_time(&self) -> Option<OffsetDateTime> {
-        self.first_point().start_time()
+        self.first_point().and_then(|p| p.start_time())
     }
 
     /// Returns the end time of the last Stage.
     pub fn end_time(&self) -> Option<OffsetDateTime> {
-        self.last_point().time
+        self.last_point().and_then(|p| p.time)
     }
 
     /// Returns the total duration between the start of the first
@@ -328,20 +711,18 @@ impl StageList {
     /// Returns the average moving speed over the whole track,
     /// this excludes stopped time.
     pub fn average_moving_speed(&self) -> Option<f64> {
-        self.total_moving_time()
-            .map(|tmt| speed_kmh_from_duration(self.distance_metres(), tmt))
+        average_speed_kmh(self.distance_metres(), self.total_moving_time())
     }
 
     /// Returns the overall average moving speed over the whole track,
     /// this includes stopped time.
     pub fn average_overall_speed(&self) -> Option<f64> {
-        self.duration()
-            .map(|dur| speed_kmh_from_duration(self.distance_metres(), dur))
+        average_speed_kmh(self.distance_metres(), self.duration())
     }
 
     /// Returns the point of minimum elevation across all the stages.
     pub fn min_elevation(&self) -> Option<&EnrichedTrackPoint> {
-        let mut min_stage = &self.0[0];
+        let mut min_stage = self.0.first()?;
         for stage in self.iter() {
             if stage.min_elevation.is_none() {
                 return None;
@@ -359,7 +740,7 @@ impl StageList {
 
     /// Returns the point of maximum elevation across all the stages.
     pub fn max_elevation(&self) -> Option<&EnrichedTrackPoint> {
-        let mut max_stage = &self.0[0];
+        let mut max_stage = self.0.first()?;
         for stage in self.iter() {
             if stage.max_elevation.is_none() {
                 return None;
@@ -395,12 +776,12 @@ impl StageList {
 
     /// Returns the point of maximum speed across all the stages.
     pub fn max_speed(&self) -> Option<&EnrichedTrackPoint> {
-        let mut max_stage = &self.0[0];
+        let mut max_stage = self.0.first()?;
         for stage in self.iter() {
             if stage.max_speed.is_none() {
                 return None;
-            } else if stage.max_speed.as_ref().unwrap().speed_kmh
-                > max_stage.max_speed.as_ref().unwrap().speed_kmh
+            } else if stage.max_speed.as_ref().unwrap().effective_speed_kmh()
+                > max_stage.max_speed.as_ref().unwrap().effective_speed_kmh()
             {
                 // The unwraps are safe because we are iterating across all
                 // stages, so max being None will be trapped.
@@ -435,26 +816,333 @@ impl StageList {
             .max_by(|a, b| a.air_temp().unwrap().total_cmp(&b.air_temp().unwrap()))
     }
 
-    /// Returns the total moving time as a percentage of the total duration
-    /// across all the stages.
-    pub fn moving_percent(&self) -> Option<f64> {
-        self.total_moving_time()
-            .map(|tmt| tmt.as_seconds_f64() / self.duration().unwrap().as_seconds_f64())
+    /// Returns the point of minimum water temperature across all the stages.
+    pub fn min_water_temperature(&self) -> Option<&EnrichedTrackPoint> {
+        self.0
+            .iter()
+            .filter_map(|s| s.min_water_temp.as_ref())
+            .min_by(|a, b| a.water_temp().unwrap().total_cmp(&b.water_temp().unwrap()))
+    }
+
+    /// Returns the point of maximum water temperature across all the stages.
+    pub fn max_water_temperature(&self) -> Option<&EnrichedTrackPoint> {
+        self.0
+            .iter()
+            .filter_map(|s| s.max_water_temp.as_ref())
+            .max_by(|a, b| a.water_temp().unwrap().total_cmp(&b.water_temp().unwrap()))
+    }
+
+    /// Returns the point of minimum depth across all the stages.
+    pub fn min_depth(&self) -> Option<&EnrichedTrackPoint> {
+        self.0
+            .iter()
+            .filter_map(|s| s.min_depth.as_ref())
+            .min_by(|a, b| a.depth().unwrap().total_cmp(&b.depth().unwrap()))
+    }
+
+    /// Returns the point of maximum depth across all the stages.
+    pub fn max_depth(&self) -> Option<&EnrichedTrackPoint> {
+        self.0
+            .iter()
+            .filter_map(|s| s.max_depth.as_ref())
+            .max_by(|a, b| a.depth().unwrap().total_cmp(&b.depth().unwrap()))
+    }
+
+    /// Returns the estimated calories (kcal) used across all the stages
+    /// which have heart rate data.
+    pub fn estimated_calories(&self, profile: &AthleteProfile) -> Option<f64> {
+        self.0
+            .iter()
+            .filter_map(|s| s.estimated_calories(profile))
+            .reduce(|a, b| a + b)
+    }
+
+    /// Returns the Normalized Power across all the stages. This can't be
+    /// worked out from the Normalized Power of each stage because the
+    /// rolling average runs across the stage boundaries, so it is
+    /// calculated from the points.
+    pub fn normalized_power(&self, gpx: &EnrichedGpx) -> Option<f64> {
+        let first = self.first_point()?.index;
+        let last = self.last_point()?.index;
+        normalized_power(&gpx.points[first..=last])
+    }
+
+    /// Returns the Intensity Factor across all the stages for a rider
+    /// with a Functional Threshold Power of 'ftp' watts.
+    pub fn intensity_factor(&self, gpx: &EnrichedGpx, ftp: f64) -> Option<f64> {
+        intensity_factor(self.normalized_power(gpx), ftp)
+    }
+
+    /// Returns the Training Stress Score across all the stages for a rider
+    /// with a Functional Threshold Power of 'ftp' watts.
+    pub fn training_stress_score(&self, gpx: &EnrichedGpx, ftp: f64) -> Option<f64> {
+        training_stress_score(self.normalized_power(gpx), self.duration(), ftp)
+    }
+
+    /// Returns the time spent in each heart rate zone across all the stages.
+    pub fn time_in_zones(&self, zones: &HrZones) -> [Duration; HrZones::COUNT] {
+        let mut total = [Duration::ZERO; HrZones::COUNT];
+        for stage in &self.0 {
+            for (t, d) in total.iter_mut().zip(stage.time_in_zones(zones)) {
+                *t += d;
+            }
+        }
+        total
+    }
+
+    /// Returns the point with the steepest gradient across all the stages.
+    pub fn max_grade(&self) -> Option<&EnrichedTrackPoint> {
+        self.0
+            .iter()
+            .filter_map(|s| s.max_grade.as_ref())
+            .max_by(|a, b| {
+                a.grade_percent
+                    .unwrap()
+                    .total_cmp(&b.grade_percent.unwrap())
+            })
     }
 
-    /// Returns the total controlling time as a percentage of the total duration
+    /// Returns the average gradient, as a percentage, across all the stages.
+    pub fn avg_grade(&self) -> Option<f64> {
+        average_grade(self.first_point()?, self.last_point()?)
+    }
+
+    /// Returns the total moving time as a fraction of the total duration
+    /// across all the stages, e.g. 0.5 for a ride that was stopped for half
+    /// the time. Riders use it to judge how much time they spent faffing.
+    /// Returns None if there are no times or the duration is zero.
+    pub fn moving_ratio(&self) -> Option<f64> {
+        let duration = self.duration()?.as_seconds_f64();
+        if duration <= 0.0 {
+            return None;
+        }
+        Some(self.total_moving_time()?.as_seconds_f64() / duration)
+    }
+
+    /// Returns the total controlling time as a fraction of the total duration
     /// across all the stages.
-    pub fn controlling_percent(&self) -> Option<f64> {
-        self.moving_percent().map(|mp| 1.0 - mp)
+    pub fn controlling_ratio(&self) -> Option<f64> {
+        self.moving_ratio().map(|mr| 1.0 - mr)
+    }
+}
+
+/// Removes the points whose horizontal dilution of precision is greater
+/// than 'max_hdop', since their positions are probably garbage. Points
+/// without an HDOP are kept. The remaining points are renumbered, and if
+/// the first point of a segment is removed the next point starts the
+/// segment instead.
+///
+/// This must be called before 'enrich_trackpoints', which calculates the
+/// distances between the remaining points. Returns the number of points
+/// that were removed.
+#[time]
+pub fn filter_by_hdop(gpx: &mut EnrichedGpx, max_hdop: f64) -> usize {
+    let start_count = gpx.points.len();
+    let mut segment_start = false;
+
+    gpx.points.retain_mut(|p| {
+        segment_start |= p.segment_start;
+        if p.hdop().is_some_and(|hdop| hdop > max_hdop) {
+            return false;
+        }
+        p.segment_start = segment_start;
+        segment_start = false;
+        true
+    });
+
+    for (idx, p) in gpx.points.iter_mut().enumerate() {
+        p.index = idx;
+    }
+
+    start_count - gpx.points.len()
+}
+
+/// Removes GPS spikes, i.e. single points that are wildly off the true path.
+/// A point is considered to be a spike if the speed implied by travelling
+/// to it from the previous point is greater than 'max_speed_kmh'. Rather
+/// than removing the point (which would invalidate the 'index' of all
+/// subsequent points) its lat, lon and elevation are interpolated from its
+/// neighbours, according to its time. A spike at the end of the track is
+/// moved onto the previous point.
+///
+/// This must be called before 'enrich_trackpoints', since it moves points.
+/// Points without a time, and the first point of each segment, are left
+/// alone. Returns the number of points
+/// that were moved.
+#[time]
+pub fn despike(gpx: &mut EnrichedGpx, max_speed_kmh: f64) -> usize {
+    let mut num_moved = 0;
+
+    for idx in 1..gpx.points.len() {
+        let prev = &gpx.points[idx - 1];
+        let curr = &gpx.points[idx];
+        let (Some(t1), Some(t2)) = (prev.time, curr.time) else {
+            continue;
+        };
+        if curr.segment_start {
+            continue;
+        }
+
+        let metres = distance_between_points_metres(prev.as_geo_point(), curr.as_geo_point());
+        let speed = speed_kmh_from_duration(metres, t2 - t1);
+        if speed <= max_speed_kmh {
+            continue;
+        }
+
+        let (lat, lon, ele) = match gpx.points.get(idx + 1) {
+            Some(next) if next.time.is_some_and(|t3| t3 > t1) => {
+                let t3 = next.time.unwrap();
+                let fraction = (t2 - t1).as_seconds_f64() / (t3 - t1).as_seconds_f64();
+                let ele = match (prev.ele, next.ele) {
+                    (Some(e1), Some(e3)) => Some(e1 + (e3 - e1) * fraction),
+                    _ => curr.ele,
+                };
+                (
+                    prev.lat + (next.lat - prev.lat) * fraction,
+                    prev.lon + (next.lon - prev.lon) * fraction,
+                    ele,
+                )
+            }
+            _ => (prev.lat, prev.lon, prev.ele.or(curr.ele)),
+        };
+
+        debug!(
+            "Despiking point {} which implies a speed of {:.1} km/h",
+            gpx.points[idx].index, speed
+        );
+        let curr = &mut gpx.points[idx];
+        curr.lat = lat;
+        curr.lon = lon;
+        curr.ele = ele;
+        num_moved += 1;
+    }
+
+    info!("Despiking moved {} points", num_moved);
+    num_moved
+}
+
+/// Fills in the elevation of points which do not have one. Between two points
+/// that have an elevation the missing values are linearly interpolated by
+/// the distance along the track, and before the first (or after the last)
+/// known elevation the nearest known value is copied. If no point has an
+/// elevation there is nothing to interpolate from, and nothing is changed.
+///
+/// This must be called before 'enrich_trackpoints', since it changes the
+/// elevations that the ascent and descent are calculated from. Returns the
+/// number of points that were filled.
+#[time]
+pub fn fill_missing_elevation(gpx: &mut EnrichedGpx) -> usize {
+    let known: Vec<usize> = (0..gpx.points.len())
+        .filter(|&idx| gpx.points[idx].ele.is_some())
+        .collect();
+    let (Some(&first), Some(&last)) = (known.first(), known.last()) else {
+        return 0;
+    };
+
+    let mut num_filled = first + (gpx.last_valid_idx() - last);
+    let first_ele = gpx.points[first].ele;
+    for p in &mut gpx.points[..first] {
+        p.ele = first_ele;
+    }
+    let last_ele = gpx.points[last].ele;
+    for p in &mut gpx.points[last + 1..] {
+        p.ele = last_ele;
+    }
+
+    for pair in known.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        if end - start < 2 {
+            continue;
+        }
+
+        // Distances are not calculated until the points are enriched.
+        let mut cum_metres = vec![0.0; end - start + 1];
+        for idx in start + 1..=end {
+            cum_metres[idx - start] = cum_metres[idx - start - 1]
+                + distance_between_points_metres(
+                    gpx.points[idx - 1].as_geo_point(),
+                    gpx.points[idx].as_geo_point(),
+                );
+        }
+
+        let e1 = gpx.points[start].ele.unwrap();
+        let e2 = gpx.points[end].ele.unwrap();
+        let total_metres = cum_metres[end - start];
+        for idx in start + 1..end {
+            // If we have not moved there is no distance to interpolate
+            // by, so fall back to the position in the gap.
+            let fraction = if total_metres > 0.0 {
+                cum_metres[idx - start] / total_metres
+            } else {
+                (idx - start) as f64 / (end - start) as f64
+            };
+            gpx.points[idx].ele = Some(e1 + (e2 - e1) * fraction);
+            num_filled += 1;
+        }
+    }
+
+    info!("Filled in the elevation of {} points", num_filled);
+    num_filled
+}
+
+/// Smooths the elevations of the points by replacing each one with the
+/// average of the 'window' points centred on it. Barometric and GPS
+/// elevations are noisy, and the noise inflates the total ascent and descent.
+/// Points without an elevation are left alone, and do not contribute to the
+/// averages of their neighbours.
+///
+/// This must be called before 'enrich_trackpoints', since it changes the
+/// elevations that the ascent and descent are calculated from.
+#[time]
+pub fn smooth_elevation(gpx: &mut EnrichedGpx, window: usize) {
+    let half_window = window / 2;
+    let original: Vec<_> = gpx.points.iter().map(|p| p.ele).collect();
+
+    for (idx, p) in gpx.points.iter_mut().enumerate() {
+        if p.ele.is_none() {
+            continue;
+        }
+
+        let start = idx.saturating_sub(half_window);
+        let end = (idx + half_window).min(original.len() - 1);
+
+        let mut sum = 0.0;
+        let mut count = 0;
+        for ele in original[start..=end].iter().flatten() {
+            sum += ele;
+            count += 1;
+        }
+
+        p.ele = Some(sum / count as f64);
     }
 }
 
 /// Calculate a set of enriched TrackPoint information (distances, speed, climb).
+/// Changes in elevation are accumulated until they reach
+/// 'min_ascent_threshold_metres' before they are counted as ascent or descent,
+/// which stops small wobbles in the elevation adding up to a lot of climbing.
+/// A threshold of 0 counts every change.
+/// If 'speed_smoothing_window_seconds' is specified then 'smoothed_speed_kmh'
+/// is also calculated. The first point is given the speed of the second.
+/// The distance across the gap before the first point of each segment is
+/// not counted, so that point has a speed of 0.
+///
+/// If 'progress' is specified it is told about each point as it is done.
+///
+/// Returns an error if the times of the points do not increase, because
+/// none of the speed or stage calculations make sense if they don't.
 #[time]
-pub fn enrich_trackpoints(gpx: &mut EnrichedGpx) {
+pub fn enrich_trackpoints(
+    gpx: &mut EnrichedGpx,
+    min_ascent_threshold_metres: f64,
+    speed_smoothing_window_seconds: Option<f64>,
+    progress: Option<&dyn Progress>,
+) -> Result<(), GapixError> {
+    let num_points = gpx.points.len();
     let start_time = gpx.points[0].time;
     let mut cum_ascent_metres = None;
     let mut cum_descent_metres = None;
+    let mut pending_ele_delta_metres = 0.0;
 
     let mut p1 = gpx.points[0].as_geo_point();
 
@@ -465,7 +1153,6 @@ pub fn enrich_trackpoints(gpx: &mut EnrichedGpx) {
     if gpx.points[0].time.is_some() {
         gpx.points[0].delta_time = Some(Duration::ZERO);
         gpx.points[0].running_delta_time = Some(Duration::ZERO);
-        gpx.points[0].speed_kmh = Some(0.0);
     }
     if gpx.points[0].ele.is_some() {
         gpx.points[0].ele_delta_metres = Some(0.0);
@@ -474,11 +1161,19 @@ pub fn enrich_trackpoints(gpx: &mut EnrichedGpx) {
         cum_ascent_metres = Some(0.0);
         cum_descent_metres = Some(0.0);
     }
+    if let Some(progress) = progress {
+        progress.update(1, num_points);
+    }
 
     // Note we are iterating all points EXCEPT the first one.
     for idx in 1..gpx.points.len() {
         let p2 = gpx.points[idx].as_geo_point();
-        gpx.points[idx].delta_metres = distance_between_points_metres(p1, p2);
+        // The jump across the gap between two segments was not travelled.
+        gpx.points[idx].delta_metres = if gpx.points[idx].segment_start {
+            0.0
+        } else {
+            distance_between_points_metres(p1, p2)
+        };
         assert!(gpx.points[idx].delta_metres >= 0.0);
 
         gpx.points[idx].running_metres =
@@ -490,27 +1185,36 @@ pub fn enrich_trackpoints(gpx: &mut EnrichedGpx) {
         gpx.points[idx].delta_time = match (gpx.points[idx].time, gpx.points[idx - 1].time) {
             (Some(t1), Some(t2)) => {
                 let dt = t1 - t2;
-                assert!(dt.is_positive());
+                if !dt.is_positive() {
+                    return Err(GapixError::TimeNotIncreasing {
+                        index: gpx.points[idx].index,
+                        earlier: "the time of the previous trackpoint",
+                        filename: gpx.filename.clone(),
+                    });
+                }
                 Some(dt)
             }
             _ => None,
         };
 
         // Speed. Based on the distance we just calculated.
-        gpx.points[idx].speed_kmh = match gpx.points[idx].delta_time {
-            Some(t) => {
-                let speed = speed_kmh_from_duration(gpx.points[idx].delta_metres, t);
-                assert!(speed >= 0.0);
-                Some(speed)
-            }
-            None => todo!(),
-        };
+        gpx.points[idx].speed_kmh = gpx.points[idx].delta_time.map(|t| {
+            let speed = speed_kmh_from_duration(gpx.points[idx].delta_metres, t);
+            assert!(speed >= 0.0);
+            speed
+        });
 
         // How long it took to get here.
         gpx.points[idx].running_delta_time = match (gpx.points[idx].time, start_time) {
             (Some(t1), Some(t2)) => {
                 let dt = t1 - t2;
-                assert!(dt.is_positive());
+                if !dt.is_positive() {
+                    return Err(GapixError::TimeNotIncreasing {
+                        index: gpx.points[idx].index,
+                        earlier: "the start of the track",
+                        filename: gpx.filename.clone(),
+                    });
+                }
                 Some(dt)
             }
             _ => None,
@@ -524,15 +1228,28 @@ pub fn enrich_trackpoints(gpx: &mut EnrichedGpx) {
 
         gpx.points[idx].ele_delta_metres = ele_delta_metres;
 
+        // Gradient, guarding against (nearly) zero horizontal distances.
+        if gpx.points[idx].delta_metres >= MIN_GRADE_DISTANCE_METRES {
+            gpx.points[idx].grade_percent =
+                ele_delta_metres.map(|edm| edm / gpx.points[idx].delta_metres * 100.0);
+        }
+
         if let Some(edm) = ele_delta_metres {
-            if edm > 0.0 {
-                let cam = cum_ascent_metres.unwrap_or_default() + edm;
+            pending_ele_delta_metres += edm;
+            if pending_ele_delta_metres > 0.0
+                && pending_ele_delta_metres >= min_ascent_threshold_metres
+            {
+                let cam = cum_ascent_metres.unwrap_or_default() + pending_ele_delta_metres;
                 assert!(cam >= 0.0);
                 cum_ascent_metres = Some(cam);
-            } else {
-                let cdm = cum_descent_metres.unwrap_or_default() + edm.abs();
+                pending_ele_delta_metres = 0.0;
+            } else if pending_ele_delta_metres <= 0.0
+                && pending_ele_delta_metres.abs() >= min_ascent_threshold_metres
+            {
+                let cdm = cum_descent_metres.unwrap_or_default() + pending_ele_delta_metres.abs();
                 assert!(cdm >= 0.0);
                 cum_descent_metres = Some(cdm);
+                pending_ele_delta_metres = 0.0;
             }
         }
 
@@ -540,6 +1257,58 @@ pub fn enrich_trackpoints(gpx: &mut EnrichedGpx) {
         gpx.points[idx].running_descent_metres = cum_descent_metres;
 
         p1 = p2;
+
+        if let Some(progress) = progress {
+            progress.update(idx + 1, num_points);
+        }
+    }
+
+    // The first point has no previous point, so it has no speed of its own.
+    // A speed of 0 would put a spurious stop at the start of the speeds we
+    // export and smooth, so use the speed over the first interval instead.
+    if gpx.points[0].time.is_some() {
+        gpx.points[0].speed_kmh = gpx.points.get(1).and_then(|p| p.speed_kmh).or(Some(0.0));
+    }
+
+    if let Some(window_seconds) = speed_smoothing_window_seconds {
+        smooth_speed(gpx, window_seconds);
+    }
+
+    Ok(())
+}
+
+/// Calculates 'smoothed_speed_kmh' for each point as the distance travelled
+/// over the preceding 'window_seconds' divided by the time taken. This is
+/// a time-weighted average of the instantaneous speeds, so irregularly
+/// spaced points are handled correctly. Must be called after the running
+/// distances have been calculated.
+fn smooth_speed(gpx: &mut EnrichedGpx, window_seconds: f64) {
+    let mut window_start = 0;
+
+    for idx in 0..gpx.points.len() {
+        let Some(t2) = gpx.points[idx].time else {
+            continue;
+        };
+
+        // Move the start of the window forward until it is within range.
+        while window_start < idx {
+            match gpx.points[window_start].time {
+                Some(t1) if (t2 - t1).as_seconds_f64() <= window_seconds => break,
+                _ => window_start += 1,
+            }
+        }
+
+        gpx.points[idx].smoothed_speed_kmh = if window_start == idx {
+            // The window does not span any points (it is shorter than the
+            // gap to the previous point) so just use the instantaneous speed.
+            gpx.points[idx].speed_kmh
+        } else {
+            let start = &gpx.points[window_start];
+            let metres = gpx.points[idx].running_metres - start.running_metres;
+            start
+                .time
+                .map(|t1| speed_kmh_from_duration(metres, t2 - t1))
+        };
     }
 }
 
@@ -572,16 +1341,17 @@ pub fn detect_stages(gpx: &EnrichedGpx, params: StageDetectionParameters) -> Sta
         params.min_metres_to_resume
     );
 
-    let mut stages = StageList::default();
-
-    // If we don't have time there is nothing we can do.
+    // If we don't have time we can't detect stops, fall back to
+    // splitting the track by distance and climbing.
     if gpx.points.iter().any(|p| p.time.is_none()) {
-        return stages;
+        return detect_stages_without_time(gpx, &params);
     }
 
-    // Note 1: The first TrackPoint always has a speed of 0, but it is unlikely
-    // that you are actually in a Control stage. However, it's not impossible,
-    // see Note 2 for why.
+    let mut stages = StageList::default();
+
+    // Note 1: The first TrackPoint has no speed of its own, it is given the
+    // speed of the second one (see enrich_trackpoints). However, you may
+    // really be stopped at the start, see Note 2 for why.
 
     // Note 2: We need to deal with the slightly bizarre situation where you turn
     // the GPS on and then don't go anywhere for a while - so your first stage
@@ -623,7 +1393,36 @@ pub fn detect_stages(gpx: &EnrichedGpx, params: StageDetectionParameters) -> Sta
 
     info!("Detection finished, found {} stages", stages.len());
 
-    // Should include all TrackPoints and start/end indexes overlap.
+    assert_stages_cover_track(&stages, gpx);
+    if cfg!(debug_assertions) {
+        check_stage_speeds(&stages, gpx);
+    }
+    stages
+}
+
+/// Cross-checks the average speed of each stage against the speeds of its
+/// points. They should agree, so a difference means that the enrichment
+/// and the stage calculations have got out of step. This is a developer
+/// check, so it only runs in debug builds and only logs at debug level.
+fn check_stage_speeds(stages: &StageList, gpx: &EnrichedGpx) {
+    for stage in stages {
+        if let (Some(s1), Some(s2)) = (
+            stage.average_speed_kmh(),
+            stage.average_speed_over_moving_points(gpx),
+        ) {
+            if (s1 - s2).abs() > 0.01 * s1.max(s2) {
+                debug!(
+                    "The average speed of the {} stage from point {} to {} is {s1:.2}km/h, but the average of the speeds of its points is {s2:.2}km/h",
+                    stage.stage_type, stage.start.index, stage.end.index
+                );
+            }
+        }
+    }
+}
+
+/// Checks that the stages include all the TrackPoints and that the
+/// start/end indexes do not overlap or leave gaps.
+fn assert_stages_cover_track(stages: &StageList, gpx: &EnrichedGpx) {
     assert_eq!(
         stages[0].start.index, 0,
         "Should always start with the first point"
@@ -642,8 +1441,6 @@ pub fn detect_stages(gpx: &EnrichedGpx, params: StageDetectionParameters) -> Sta
             "The next stage should always start on the next index"
         );
     }
-
-    stages
 }
 
 fn get_next_stage(
@@ -681,33 +1478,110 @@ fn get_next_stage(
         "A stage must contain at least 1 TrackPoint"
     );
 
-    let (min_elevation, max_elevation) = find_min_and_max_elevation_points(gpx, start_idx, end_idx);
-    let (max_heart_rate, avg_heart_rate) = find_heart_rates(gpx, start_idx, end_idx);
-    let (min_air_temp, max_air_temp, avg_air_temp) = find_air_temps(gpx, start_idx, end_idx);
+    Some(make_stage(gpx, stage_type, start_idx, end_idx))
+}
 
-    let stage = Stage {
-        stage_type,
-        track_start_point: gpx.points[0].clone(),
-        start: gpx.points[start_idx].clone(),
-        end: gpx.points[end_idx].clone(),
-        min_elevation,
-        max_elevation,
-        max_speed: find_max_speed(gpx, start_idx, end_idx),
-        avg_heart_rate,
-        max_heart_rate,
-        min_air_temp,
-        max_air_temp,
-        avg_air_temp,
-    };
+/// Splits a track which does not have times into Moving stages. Each
+/// stage ends on the first point at which either the distance or the
+/// ascent plus descent since the start of the stage reaches the limits in
+/// 'params'. The stages will have no duration or speeds.
+fn detect_stages_without_time(gpx: &EnrichedGpx, params: &StageDetectionParameters) -> StageList {
+    info!(
+        "Track {:?} does not have times, splitting into stages using no_time_stage_metres={}, no_time_stage_climb_metres={}",
+        gpx.filename, params.no_time_stage_metres, params.no_time_stage_climb_metres
+    );
 
-    // Just check we created everything correctly.
-    assert_eq!(stage.start.index, start_idx);
+    let mut stages = StageList::default();
+    let mut start_idx = 0;
+    let last_idx = gpx.last_valid_idx();
+
+    while start_idx <= last_idx {
+        let start = &gpx.points[start_idx];
+        let mut climb_metres = 0.0;
+        let mut end_idx = start_idx;
+
+        while end_idx < last_idx {
+            end_idx += 1;
+            let p = &gpx.points[end_idx];
+            climb_metres += p.ele_delta_metres.unwrap_or_default().abs();
+            if p.running_metres - start.running_metres >= params.no_time_stage_metres
+                || climb_metres >= params.no_time_stage_climb_metres
+            {
+                break;
+            }
+        }
+
+        // Don't leave a single point on its own at the end.
+        if end_idx + 1 == last_idx {
+            end_idx = last_idx;
+        }
+
+        let stage = make_stage(gpx, StageType::Moving, start_idx, end_idx);
+        info!(
+            "Adding {} stage from point {} to {}, length={:.3}km, duration=unknown",
+            stage.stage_type,
+            stage.start.index,
+            stage.end.index,
+            stage.distance_km()
+        );
+        stages.push(stage);
+
+        // Stages do not share points, the next stage starts on the next point.
+        start_idx = end_idx + 1;
+    }
+
+    info!("Detection finished, found {} stages", stages.len());
+
+    assert_stages_cover_track(&stages, gpx);
+    stages
+}
+
+/// Builds a Stage from the TrackPoints between 'start_idx' and 'end_idx'
+/// inclusive, calculating all the min/max fields.
+fn make_stage(gpx: &EnrichedGpx, stage_type: StageType, start_idx: usize, end_idx: usize) -> Stage {
+    let (min_elevation, max_elevation) = find_min_and_max_elevation_points(gpx, start_idx, end_idx);
+    let (max_heart_rate, avg_heart_rate) = find_heart_rates(gpx, start_idx, end_idx);
+    let (avg_power, normalized_power) = find_power(&gpx.points[start_idx..=end_idx]);
+    let (min_air_temp, max_air_temp, avg_air_temp) =
+        find_min_max_avg(gpx, start_idx, end_idx, EnrichedTrackPoint::air_temp);
+    let (min_water_temp, max_water_temp, avg_water_temp) =
+        find_min_max_avg(gpx, start_idx, end_idx, EnrichedTrackPoint::water_temp);
+    let (min_depth, max_depth, avg_depth) =
+        find_min_max_avg(gpx, start_idx, end_idx, EnrichedTrackPoint::depth);
+
+    let stage = Stage {
+        stage_type,
+        track_start_point: gpx.points[0].clone(),
+        start: gpx.points[start_idx].clone(),
+        end: gpx.points[end_idx].clone(),
+        min_elevation,
+        max_elevation,
+        max_speed: find_max_speed(gpx, start_idx, end_idx),
+        max_grade: find_max_grade(gpx, start_idx, end_idx),
+        avg_heart_rate,
+        max_heart_rate,
+        heart_rate_seconds: find_heart_rate_seconds(&gpx.points[start_idx..=end_idx]),
+        avg_power,
+        normalized_power,
+        min_air_temp,
+        max_air_temp,
+        avg_air_temp,
+        min_water_temp,
+        max_water_temp,
+        avg_water_temp,
+        min_depth,
+        max_depth,
+        avg_depth,
+    };
+
+    // Just check we created everything correctly.
+    assert_eq!(stage.start.index, start_idx);
     assert_eq!(stage.end.index, end_idx);
     assert!(stage.end.index >= stage.start.index);
     assert!(stage.end.time >= stage.start.time);
     assert!(stage.start.index >= stage.track_start_point.index);
 
-    Some(stage)
+    stage
 }
 
 /// A Moving stage is ended when we stop. This occurs when we drop below the
@@ -723,12 +1597,8 @@ fn find_stop_index(
 
     while end_idx <= last_valid_idx {
         // Find the first time we drop below 'stopped_speed_kmh'
-        while end_idx <= last_valid_idx
-            && gpx.points[end_idx]
-                .speed_kmh
-                .expect("speed exists due to check in detect_stages")
-                > params.stopped_speed_kmh
-        {
+        // (and 'stopped_cadence_rpm', if set).
+        while end_idx <= last_valid_idx && !params.is_stopped(&gpx.points[end_idx]) {
             end_idx += 1;
         }
 
@@ -769,7 +1639,7 @@ fn find_stop_index(
             );
             return last_valid_idx;
         }
-        
+
         debug!(
             "find_stop_index(start_idx={start_idx}) Scanned forward to index {}, which is {:.2} metres from the possible stop",
             end_idx,
@@ -880,7 +1750,7 @@ fn find_min_and_max_elevation_points(
 }
 
 /// Within a given range of trackpoints, finds the one with the
-/// maximum speed.
+/// maximum speed. Uses the smoothed speed if it has been calculated.
 fn find_max_speed(
     gpx: &EnrichedGpx,
     start_idx: usize,
@@ -890,9 +1760,9 @@ fn find_max_speed(
 
     for tp in &gpx.points[start_idx..=end_idx] {
         // Any missing speed invalidates the calculation.
-        tp.speed_kmh?;
+        tp.effective_speed_kmh()?;
 
-        if tp.speed_kmh > max.speed_kmh {
+        if tp.effective_speed_kmh() > max.effective_speed_kmh() {
             max = tp;
         }
     }
@@ -900,6 +1770,37 @@ fn find_max_speed(
     Some(max.clone())
 }
 
+/// Within a given range of trackpoints, finds the point with the steepest
+/// gradient. Points without a gradient are ignored.
+fn find_max_grade(
+    gpx: &EnrichedGpx,
+    start_idx: usize,
+    end_idx: usize,
+) -> Option<EnrichedTrackPoint> {
+    gpx.points[start_idx..=end_idx]
+        .iter()
+        .filter(|tp| tp.grade_percent.is_some())
+        .max_by(|a, b| {
+            a.grade_percent
+                .unwrap()
+                .total_cmp(&b.grade_percent.unwrap())
+        })
+        .cloned()
+}
+
+/// Calculates the average gradient, as a percentage, between two points.
+fn average_grade(start: &EnrichedTrackPoint, end: &EnrichedTrackPoint) -> Option<f64> {
+    let distance_metres = end.running_metres - start.running_metres;
+    if distance_metres < MIN_GRADE_DISTANCE_METRES {
+        return None;
+    }
+
+    match (end.ele, start.ele) {
+        (Some(e1), Some(e2)) => Some((e1 - e2) / distance_metres * 100.0),
+        _ => None,
+    }
+}
+
 /// Within a given range of trackpoints, finds the point of
 /// maximum heart rate and the average heart rate.
 fn find_heart_rates(
@@ -922,7 +1823,7 @@ fn find_heart_rates(
         }
     }
 
-    let avg = if sum == 0.0 {
+    let avg = if count == 0 {
         None
     } else {
         Some(sum / count as f64)
@@ -931,31 +1832,287 @@ fn find_heart_rates(
     (max, avg)
 }
 
-/// Finds the min, max and avg air temp over the stage.
-fn find_air_temps(
+/// Totals the time spent at each heart rate. Each point's 'delta_time'
+/// is attributed to its heart rate; points without a heart rate or
+/// time are ignored.
+fn find_heart_rate_seconds(points: &[EnrichedTrackPoint]) -> BTreeMap<u16, f64> {
+    let mut result = BTreeMap::new();
+
+    for p in points {
+        if let (Some(hr), Some(dt)) = (p.heart_rate(), p.delta_time) {
+            *result.entry(hr).or_default() += dt.as_seconds_f64();
+        }
+    }
+
+    result
+}
+
+/// Finds the average and normalized power over a range of trackpoints.
+/// Only points with a power reading contribute.
+fn find_power(points: &[EnrichedTrackPoint]) -> (Option<f64>, Option<f64>) {
+    let mut sum: f64 = 0.0;
+    let mut count = 0;
+
+    for pwr in points.iter().filter_map(|p| p.power()) {
+        sum += pwr as f64;
+        count += 1;
+    }
+
+    let avg = if count == 0 {
+        None
+    } else {
+        Some(sum / count as f64)
+    };
+
+    (avg, normalized_power(points))
+}
+
+impl EnrichedGpx {
+    /// Removes the Control stages (if any) at the start and end of the track,
+    /// which are usually caused by forgetting to start or stop recording at
+    /// the right time. The remaining points are renumbered so that 'index'
+    /// is still the position in 'points', but the caller must enrich the
+    /// points again to recalculate the running values. Does nothing if the
+    /// track starts and ends Moving, or if there are no Moving stages at all.
+    /// Returns the number of points removed.
+    pub fn trim_stationary_ends(&mut self, params: &StageDetectionParameters) -> usize {
+        let stages = detect_stages(self, params.clone());
+        let mut moving = stages
+            .iter()
+            .filter(|stage| stage.stage_type == StageType::Moving);
+
+        let Some(first) = moving.next() else {
+            return 0;
+        };
+        let mut start_idx = first.start.index;
+        let mut end_idx = moving.next_back().unwrap_or(first).end.index;
+
+        // A Control stage ends once we have moved 'min_metres_to_resume', so
+        // the Moving stage starts a little after we actually set off. Go back
+        // to the last stopped point so that we don't lose that distance.
+        while start_idx > 0 && !params.is_stopped(&self.points[start_idx]) {
+            start_idx -= 1;
+        }
+
+        // A stop at the very end of the track is not detected as a Control
+        // stage because we never resume, so look for it separately.
+        if end_idx == self.last_valid_idx() {
+            if let Some(idx) = find_trailing_stop_index(self, params) {
+                end_idx = idx.max(start_idx);
+            }
+        }
+
+        let original_len = self.points.len();
+        self.points.truncate(end_idx + 1);
+        self.points.drain(..start_idx);
+
+        for (idx, p) in self.points.iter_mut().enumerate() {
+            p.index = idx;
+        }
+        self.points[0].delta_metres = 0.0;
+        self.points[0].running_metres = 0.0;
+
+        original_len - self.points.len()
+    }
+}
+
+/// Returns the index of the last point before a stop at the end of the
+/// track, or None if the track does not end with a stop. We are stopped
+/// if we moved less than 'min_metres_to_resume' in the last
+/// 'min_duration_seconds' of the track.
+fn find_trailing_stop_index(gpx: &EnrichedGpx, params: &StageDetectionParameters) -> Option<usize> {
+    let last = &gpx.points[gpx.last_valid_idx()];
+
+    // Find the first point of the tail which is within 'min_metres_to_resume'
+    // of the end. As in 'find_stop_index' the stop itself starts when the
+    // speed drops below 'stopped_speed_kmh'.
+    let tail_idx = gpx
+        .points
+        .partition_point(|p| last.running_metres - p.running_metres >= params.min_metres_to_resume);
+    let tail_duration = (last.time? - gpx.points[tail_idx].time?).as_seconds_f64();
+    if tail_duration < params.min_duration_seconds {
+        return None;
+    }
+
+    let stop_idx = (tail_idx..gpx.points.len()).find(|&idx| params.is_stopped(&gpx.points[idx]))?;
+    Some(stop_idx.saturating_sub(1))
+}
+
+/// Creates a waypoint at the start of each Control stage, so that the
+/// stops (cafés, Audax controls etc.) can be loaded back onto a device.
+/// The waypoints are named like "Stop 1 — 12.3km, 18min".
+pub fn stages_to_waypoints(stages: &StageList) -> Vec<Waypoint> {
+    stages
+        .iter()
+        .filter(|stage| stage.stage_type == StageType::Control)
+        .enumerate()
+        .map(|(idx, stage)| {
+            let mut name = format!(
+                "Stop {} — {:.1}km",
+                idx + 1,
+                stage.start.running_metres / 1000.0
+            );
+            if let Some(duration) = stage.duration() {
+                name.push_str(&format!(", {:.0}min", duration.as_seconds_f64() / 60.0));
+            }
+
+            Waypoint {
+                lat: stage.start.lat,
+                lon: stage.start.lon,
+                ele: stage.start.ele,
+                time: stage.start.time,
+                name: Some(name),
+                desc: stage.start.location.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Makes a separate Gpx for each Moving stage (e.g. each leg between
+/// controls) containing just the points of that stage. The files are named
+/// after the stage number as shown on the Stages sheet, e.g. for stage 3 of
+/// "ride.gpx" the filename is "ride.stage03.gpx".
+pub fn split_into_stage_gpx(gpx: &EnrichedGpx, stages: &StageList) -> Vec<Gpx> {
+    stages
+        .iter()
+        .enumerate()
+        .filter(|(_, stage)| stage.stage_type == StageType::Moving)
+        .map(|(idx, stage)| {
+            let number = idx + 1;
+            let points = stage.points(gpx).iter().map(TrackPoint::from).collect();
+            let name = match &gpx.track_name {
+                Some(name) => format!("{name} - Stage {number}"),
+                None => format!("Stage {number}"),
+            };
+
+            Gpx {
+                filename: gpx.filename.with_extension(format!("stage{number:02}.gpx")),
+                declaration: gpx.declaration.clone(),
+                info: gpx.info.clone(),
+                // The bounds are recalculated when the file is written.
+                metadata: Metadata {
+                    bounds: None,
+                    ..gpx.metadata.clone()
+                },
+                tracks: vec![Track {
+                    name: Some(name),
+                    r#type: gpx.track_type.clone(),
+                    comment: gpx.track_comment.clone(),
+                    desc: gpx.track_desc.clone(),
+                    extensions: gpx.track_extensions.clone(),
+                    raw_children: gpx.track_raw_children.clone(),
+                    segments: vec![TrackSegment {
+                        points,
+                        extensions: None,
+                        raw_children: None,
+                    }],
+                }],
+                extensions: gpx.extensions.clone(),
+                raw_children: gpx.raw_children.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Calculates the Normalized Power over a range of trackpoints. This is
+/// the 4th root of the mean of the 4th powers of the 30 second rolling
+/// average power. It better reflects the physiological cost of a ride with
+/// variable power than the simple average. Returns None if there are no
+/// points with power and time, or they cover less than 30 seconds.
+pub fn normalized_power(points: &[EnrichedTrackPoint]) -> Option<f64> {
+    const WINDOW_SECONDS: f64 = 30.0;
+
+    let mut window = VecDeque::new();
+    let mut window_sum: f64 = 0.0;
+    let mut sum_of_4th_powers: f64 = 0.0;
+    let mut count = 0;
+    let mut first_time = None;
+
+    for p in points {
+        let (Some(pwr), Some(time)) = (p.power(), p.time) else {
+            continue;
+        };
+
+        let first_time = *first_time.get_or_insert(time);
+
+        window.push_back((time, pwr as f64));
+        window_sum += pwr as f64;
+        while let Some(&(t, old_pwr)) = window.front() {
+            if (time - t).as_seconds_f64() < WINDOW_SECONDS {
+                break;
+            }
+            window.pop_front();
+            window_sum -= old_pwr;
+        }
+
+        // Don't start counting until we have a full window.
+        if (time - first_time).as_seconds_f64() >= WINDOW_SECONDS {
+            let rolling_avg = window_sum / window.len() as f64;
+            sum_of_4th_powers += rolling_avg.powi(4);
+            count += 1;
+        }
+    }
+
+    if count == 0 {
+        None
+    } else {
+        Some((sum_of_4th_powers / count as f64).powf(0.25))
+    }
+}
+
+/// Calculates the Intensity Factor, which is the Normalized Power as a
+/// fraction of the rider's Functional Threshold Power, the power they
+/// can sustain for an hour.
+fn intensity_factor(normalized_power: Option<f64>, ftp: f64) -> Option<f64> {
+    normalized_power.map(|np| np / ftp)
+}
+
+/// Calculates the Training Stress Score, which combines the intensity and
+/// the duration of a ride so that an hour at the Functional Threshold
+/// Power scores 100. TSS = seconds * NP * IF / (FTP * 3600) * 100.
+/// Returns None if there is no power or no positive duration.
+fn training_stress_score(
+    normalized_power: Option<f64>,
+    duration: Option<Duration>,
+    ftp: f64,
+) -> Option<f64> {
+    let np = normalized_power?;
+    let seconds = duration.map(|d| d.as_seconds_f64()).filter(|&s| s > 0.0)?;
+    let intensity_factor = np / ftp;
+    Some(seconds * np * intensity_factor / (ftp * 3600.0) * 100.0)
+}
+
+/// Finds the points with the min and max of 'value' over the stage, and
+/// the average. This is for values such as the air temperature, which
+/// come from the extensions and so may not be present on every point.
+fn find_min_max_avg<F>(
     gpx: &EnrichedGpx,
     start_idx: usize,
     end_idx: usize,
+    value: F,
 ) -> (
     Option<EnrichedTrackPoint>,
     Option<EnrichedTrackPoint>,
     Option<f64>,
-) {
+)
+where
+    F: Fn(&EnrichedTrackPoint) -> Option<f64>,
+{
     let mut sum: Option<f64> = None;
     let mut min: Option<EnrichedTrackPoint> = None;
     let mut max: Option<EnrichedTrackPoint> = None;
     let mut count = 0;
 
     for idx in start_idx..=end_idx {
-        if let Some(at) = gpx.points[idx].air_temp() {
+        if let Some(v) = value(&gpx.points[idx]) {
             count += 1;
-            sum = Some(sum.unwrap_or_default() + at);
+            sum = Some(sum.unwrap_or_default() + v);
 
-            if min.is_none() || min.as_ref().unwrap().air_temp().unwrap() > at {
+            if min.is_none() || value(min.as_ref().unwrap()).unwrap() > v {
                 min = Some(gpx.points[idx].clone());
             }
 
-            if max.is_none() || max.as_ref().unwrap().air_temp().unwrap() < at {
+            if max.is_none() || value(max.as_ref().unwrap()).unwrap() < v {
                 max = Some(gpx.points[idx].clone());
             }
         }
@@ -971,11 +2128,40 @@ fn distance_between_points_metres(p1: Point, p2: Point) -> f64 {
     p1.geodesic_distance(&p2)
 }
 
+/// Returns the length of the track through the points, in metres, as
+/// the sum of the geodesic distances between consecutive points. This
+/// does not need the points to be enriched. Returns 0 if there are
+/// fewer than 2 points.
+pub fn track_length_metres(points: &[TrackPoint]) -> f64 {
+    length_metres(points.iter().map(TrackPoint::as_geo_point))
+}
+
+/// As 'track_length_metres', for enriched points. This measures the
+/// points as they are now, so unlike 'running_metres' it reflects any
+/// points that have been removed since the track was enriched.
+pub fn enriched_track_length_metres(points: &[EnrichedTrackPoint]) -> f64 {
+    length_metres(points.iter().map(EnrichedTrackPoint::as_geo_point))
+}
+
+fn length_metres(points: impl Iterator<Item = Point>) -> f64 {
+    let mut prev: Option<Point> = None;
+    let mut metres = 0.0;
+    for p in points {
+        if let Some(prev) = prev {
+            metres += distance_between_points_metres(prev, p);
+        }
+        prev = Some(p);
+    }
+    metres
+}
+
 /// Try and figure out whether we are starting Moving or Stopped
-/// by looking at the average speed over the first 3 minutes.
-fn get_starting_stage_type(gpx: &EnrichedGpx, _params: &StageDetectionParameters) -> StageType {
-    // The first point has no start_time() since it does not have
-    // a delta time. We can safely skip it.
+/// by looking at the average speed over the first few minutes
+/// (as specified by 'initial_classification_window_seconds').
+fn get_starting_stage_type(gpx: &EnrichedGpx, params: &StageDetectionParameters) -> StageType {
+    // The first point has no interval of its own, its speed is just a copy
+    // of the second point's (see enrich_trackpoints), so start the window
+    // from the second point.
     let start = &gpx.points[1];
     let mut end_idx = 1;
 
@@ -987,19 +2173,24 @@ fn get_starting_stage_type(gpx: &EnrichedGpx, _params: &StageDetectionParameters
                 .start_time()
                 .expect("time exists due to check in detect_stages");
 
-        if duration.as_seconds_f64() >= 180.0 {
-            return classify_stage(start, &gpx.points[end_idx]);
+        if duration.as_seconds_f64() >= params.initial_classification_window_seconds {
+            return classify_stage(start, &gpx.points[end_idx], params);
         } else {
             end_idx += 1;
         }
     }
 
-    let end = &gpx.points[end_idx];
-    classify_stage(start, end)
+    // The whole track is shorter than the classification window.
+    let end = &gpx.points[gpx.last_valid_idx()];
+    classify_stage(start, end, params)
 }
 
 /// Classifies a stage, based on the average speed within that stage.
-fn classify_stage(start_point: &EnrichedTrackPoint, last_point: &EnrichedTrackPoint) -> StageType {
+fn classify_stage(
+    start_point: &EnrichedTrackPoint,
+    last_point: &EnrichedTrackPoint,
+    params: &StageDetectionParameters,
+) -> StageType {
     let distance_metres = last_point.running_metres - start_point.running_metres;
 
     let time = last_point
@@ -1011,10 +2202,895 @@ fn classify_stage(start_point: &EnrichedTrackPoint, last_point: &EnrichedTrackPo
 
     let speed = speed_kmh_from_duration(distance_metres, time);
 
-    if speed < 5.0 {
-        // Less than walking pace? Assume you're stopped.
+    if speed < params.initial_classification_speed_kmh {
         StageType::Control
     } else {
         StageType::Moving
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Extensions, FixInfo, GpxBuilder};
+    use crate::simplification::{write_gpx_to_bytes, WriteOptions};
+    use crate::test_helpers::{
+        enriched, make_enriched_gpx, read_gpx_str, ride, ride_points, track_point, xy_point,
+    };
+
+    #[test]
+    fn a_slow_start_is_classified_as_control() {
+        // Walking the bike out of the garage, then riding.
+        let gpx = ride(&[(3.0, 300), (20.0, 1200)]);
+        let params = StageDetectionParameters::default();
+        assert_eq!(get_starting_stage_type(&gpx, &params), StageType::Control);
+    }
+
+    #[test]
+    fn a_rolling_start_is_classified_as_moving() {
+        let gpx = ride(&[(20.0, 1200)]);
+        let params = StageDetectionParameters::default();
+        assert_eq!(get_starting_stage_type(&gpx, &params), StageType::Moving);
+    }
+
+    #[test]
+    fn the_initial_classification_speed_is_configurable() {
+        let gpx = ride(&[(3.0, 300), (20.0, 1200)]);
+        let params = StageDetectionParameters {
+            initial_classification_speed_kmh: 2.0,
+            ..Default::default()
+        };
+        assert_eq!(get_starting_stage_type(&gpx, &params), StageType::Moving);
+    }
+
+    #[test]
+    fn the_first_point_has_the_speed_of_the_first_interval() {
+        let gpx = ride(&[(20.0, 600), (0.0, 600), (20.0, 600)]);
+
+        let first_speed = gpx.points[0].speed_kmh.unwrap();
+        assert!((first_speed - 20.0).abs() < 0.5, "{first_speed}");
+        assert_eq!(gpx.points[0].speed_kmh, gpx.points[1].speed_kmh);
+
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages[0].stage_type, StageType::Moving);
+        assert_eq!(stages[0].start.index, 0);
+    }
+
+    #[test]
+    fn a_backward_time_is_an_error_not_a_panic() {
+        let mut points = ride_points(&[(20.0, 600)]);
+        points[25].time = points[23].time;
+
+        let mut gpx = make_enriched_gpx(points);
+        let err = enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap_err();
+
+        assert!(
+            matches!(err, GapixError::TimeNotIncreasing { index: 25, .. }),
+            "{err:?}"
+        );
+        let message = err.to_string();
+        assert!(message.contains("trackpoint 25 "), "{message}");
+    }
+
+    #[test]
+    fn despike_removes_a_teleport() {
+        // 2km at 20km/h, with one point 11km off to the north.
+        let mut points = ride_points(&[(20.0, 360)]);
+        points[18].lat += 0.1;
+        let spiked_metres = enriched(points.clone())
+            .points
+            .last()
+            .unwrap()
+            .running_metres;
+        assert!(spiked_metres > 20_000.0);
+
+        let mut gpx = make_enriched_gpx(points);
+        assert_eq!(despike(&mut gpx, 100.0), 1);
+        enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap();
+
+        let metres = gpx.points.last().unwrap().running_metres;
+        assert!((metres - 2000.0).abs() < 20.0, "{metres}");
+    }
+
+    #[test]
+    fn merging_short_stages_keeps_the_totals() {
+        // Two brief pauses, which a short min_duration_seconds picks up.
+        let gpx = ride(&[(20.0, 600), (0.0, 60), (20.0, 600), (0.0, 90), (20.0, 600)]);
+        let params = StageDetectionParameters {
+            min_duration_seconds: 30.0,
+            ..Default::default()
+        };
+        let mut stages = detect_stages(&gpx, params);
+        assert_eq!(stages.len(), 5);
+        let duration = stages.duration();
+        let end_metres = stages.last_point().unwrap().running_metres;
+
+        stages.merge_short_stages(&gpx, 300.0);
+
+        assert_eq!(stages.len(), 1);
+        assert_eq!(stages[0].stage_type, StageType::Moving);
+        assert_eq!(stages.duration(), duration);
+        assert_eq!(stages.last_point().unwrap().running_metres, end_metres);
+        assert_eq!(stages.distance_metres(), end_metres);
+    }
+
+    #[test]
+    fn merging_leaves_long_stages_alone() {
+        let gpx = ride(&[(20.0, 600), (0.0, 600), (20.0, 600)]);
+        let mut stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 3);
+
+        stages.merge_short_stages(&gpx, 300.0);
+        assert_eq!(stages.len(), 3);
+    }
+
+    #[test]
+    fn an_empty_stage_list_has_no_points_or_totals() {
+        let stages = StageList::default();
+
+        assert!(stages.first_point().is_none());
+        assert!(stages.last_point().is_none());
+        assert!(stages.start_time().is_none());
+        assert!(stages.end_time().is_none());
+        assert!(stages.duration().is_none());
+        assert!(stages.average_moving_speed().is_none());
+        assert!(stages.average_overall_speed().is_none());
+        assert!(stages.min_elevation().is_none());
+        assert!(stages.max_elevation().is_none());
+        assert!(stages.max_speed().is_none());
+        assert!(stages.max_heart_rate().is_none());
+        assert!(stages.min_temperature().is_none());
+        assert!(stages.max_temperature().is_none());
+        assert!(stages.max_grade().is_none());
+        assert!(stages.moving_ratio().is_none());
+        assert_eq!(stages.distance_km(), 0.0);
+    }
+
+    /// Makes an enriched track from (metres east, elevation) pairs.
+    fn profile(points: &[(f64, f64)]) -> EnrichedGpx {
+        enriched(
+            points
+                .iter()
+                .map(|&(x, ele)| TrackPoint {
+                    ele: Some(ele),
+                    ..xy_point(x, 0.0)
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn grades_are_zero_on_the_flat_positive_uphill_and_negative_downhill() {
+        let gpx = profile(&[(0.0, 100.0), (100.0, 100.0), (200.0, 105.0), (300.0, 97.0)]);
+        let grades: Vec<_> = gpx.points.iter().map(|p| p.grade_percent).collect();
+
+        assert_eq!(grades[0], None);
+        assert_eq!(grades[1], Some(0.0));
+        assert!((grades[2].unwrap() - 5.0).abs() < 0.1, "{grades:?}");
+        assert!((grades[3].unwrap() + 8.0).abs() < 0.1, "{grades:?}");
+    }
+
+    #[test]
+    fn there_is_no_grade_over_a_tiny_distance() {
+        // A 10cm step with a 1m jump in elevation would be a 1000% grade.
+        let gpx = profile(&[(0.0, 100.0), (100.0, 100.0), (100.1, 101.0)]);
+        assert_eq!(gpx.points[2].grade_percent, None);
+    }
+
+    /// Makes an enriched ride, as 'ride' does, giving each point the
+    /// extensions returned by 'extensions' for its index.
+    fn ride_with_extensions(
+        sections: &[(f64, i64)],
+        extensions: impl Fn(usize) -> Extensions,
+    ) -> EnrichedGpx {
+        let points = ride_points(sections)
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| TrackPoint {
+                extensions: Some(extensions(i)),
+                ..p
+            })
+            .collect();
+        enriched(points)
+    }
+
+    #[test]
+    fn stages_average_and_normalize_the_power() {
+        // Alternating 100W and 300W every 10 seconds.
+        let gpx = ride_with_extensions(&[(20.0, 600)], |i| Extensions {
+            power: Some(if i % 2 == 0 { 100 } else { 300 }),
+            ..Default::default()
+        });
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 1);
+
+        let avg = stages[0].avg_power.unwrap();
+        assert!((avg - 200.0).abs() < 5.0, "{avg}");
+
+        // The variation makes Normalized Power higher than the average.
+        let np = stages[0].normalized_power.unwrap();
+        assert!(np > avg && np < 300.0, "{np}");
+    }
+
+    #[test]
+    fn constant_power_has_a_normalized_power_equal_to_the_average() {
+        // An hour at a steady 200W.
+        let gpx = ride_with_extensions(&[(20.0, 3600)], |_| Extensions {
+            power: Some(200),
+            ..Default::default()
+        });
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 1);
+
+        let np = stages.normalized_power(&gpx).unwrap();
+        assert!((np - 200.0).abs() < 1e-9, "{np}");
+        assert!((np - stages[0].avg_power.unwrap()).abs() < 1e-9);
+
+        // IF = 200 / 250 = 0.8, and an hour at that is 100 * 0.8^2.
+        let intensity_factor = stages.intensity_factor(&gpx, 250.0).unwrap();
+        assert!((intensity_factor - 0.8).abs() < 1e-9, "{intensity_factor}");
+        let tss = stages.training_stress_score(&gpx, 250.0).unwrap();
+        assert!((tss - 64.0).abs() < 1e-9, "{tss}");
+        assert_eq!(stages[0].training_stress_score(250.0), Some(tss));
+    }
+
+    #[test]
+    fn there_are_no_power_metrics_without_power() {
+        let gpx = ride(&[(20.0, 600)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+
+        assert_eq!(stages.normalized_power(&gpx), None);
+        assert_eq!(stages.intensity_factor(&gpx, 250.0), None);
+        assert_eq!(stages.training_stress_score(&gpx, 250.0), None);
+        assert_eq!(stages[0].training_stress_score(250.0), None);
+    }
+
+    /// Makes a flat track 10km long whose elevations wobble by +/- 2m.
+    fn noisy_flat_points() -> Vec<TrackPoint> {
+        (0..1000)
+            .map(|i| TrackPoint {
+                ele: Some(if i % 2 == 0 { 48.0 } else { 52.0 }),
+                ..xy_point(i as f64 * 10.0, 0.0)
+            })
+            .collect()
+    }
+
+    fn total_ascent(gpx: &EnrichedGpx) -> f64 {
+        gpx.points.last().unwrap().running_ascent_metres.unwrap()
+    }
+
+    #[test]
+    fn smoothing_the_elevation_reduces_the_ascent_of_a_noisy_flat_track() {
+        let raw = enriched(noisy_flat_points());
+        let raw_ascent = total_ascent(&raw);
+        assert!(raw_ascent > 1900.0, "{raw_ascent}");
+
+        let mut smoothed = make_enriched_gpx(noisy_flat_points());
+        smooth_elevation(&mut smoothed, 5);
+        enrich_trackpoints(&mut smoothed, 0.0, None, None).unwrap();
+        let smoothed_ascent = total_ascent(&smoothed);
+        assert!(smoothed_ascent < raw_ascent / 4.0, "{smoothed_ascent}");
+    }
+
+    #[test]
+    fn the_ascent_threshold_ignores_small_wobbles() {
+        let mut gpx = make_enriched_gpx(noisy_flat_points());
+        enrich_trackpoints(&mut gpx, 5.0, None, None).unwrap();
+        assert_eq!(total_ascent(&gpx), 0.0);
+    }
+
+    #[test]
+    fn a_3m_threshold_removes_the_noise_from_a_climb() {
+        // A steady 500m climb with 2m of noise on every other point.
+        let points = || -> Vec<TrackPoint> {
+            (0..=1000)
+                .map(|i| TrackPoint {
+                    ele: Some(i as f64 * 0.5 + if i % 2 == 0 { 0.0 } else { 2.0 }),
+                    ..xy_point(i as f64 * 10.0, 0.0)
+                })
+                .collect()
+        };
+
+        let raw = enriched(points());
+        let raw_ascent = total_ascent(&raw);
+        assert!(raw_ascent > 1200.0, "{raw_ascent}");
+
+        let mut gpx = make_enriched_gpx(points());
+        enrich_trackpoints(&mut gpx, 3.0, None, None).unwrap();
+        let ascent = total_ascent(&gpx);
+        assert!((ascent - 500.0).abs() <= 3.0, "{ascent}");
+        let descent = gpx.points.last().unwrap().running_descent_metres.unwrap();
+        assert_eq!(descent, 0.0);
+    }
+
+    #[test]
+    fn smoothing_the_speed_flattens_a_glitch() {
+        // 20km/h, with one point 550m off to the north.
+        let mut points = ride_points(&[(20.0, 1200)]);
+        points[60].lat += 0.005;
+
+        let mut gpx = make_enriched_gpx(points);
+        enrich_trackpoints(&mut gpx, 0.0, Some(120.0), None).unwrap();
+
+        let max = |speeds: Vec<Option<f64>>| speeds.into_iter().flatten().fold(0.0, f64::max);
+        let raw_max = max(gpx.points.iter().map(|p| p.speed_kmh).collect());
+        let smoothed_max = max(gpx.points.iter().map(|p| p.smoothed_speed_kmh).collect());
+        assert!(raw_max > 150.0, "{raw_max}");
+        assert!(smoothed_max < raw_max / 2.0, "{smoothed_max}");
+
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        let stage_max = stages.max_speed().unwrap().effective_speed_kmh().unwrap();
+        assert_eq!(stage_max, smoothed_max);
+    }
+
+    #[test]
+    fn iterating_by_reference_and_by_value_visits_every_stage() {
+        let gpx = ride(&[(20.0, 600), (0.0, 600), (20.0, 600)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 3);
+
+        let mut by_ref = 0;
+        for _ in &stages {
+            by_ref += 1;
+        }
+        assert_eq!(by_ref, stages.len());
+        assert_eq!(stages.iter().count(), stages.len());
+
+        let len = stages.len();
+        let types: Vec<_> = stages.into_iter().map(|s| s.stage_type).collect();
+        assert_eq!(types.len(), len);
+        assert_eq!(
+            types,
+            [StageType::Moving, StageType::Control, StageType::Moving]
+        );
+    }
+
+    #[test]
+    fn the_max_temperature_is_the_hottest_point() {
+        // The first stage is 15-19C, the rest 5-25C, so the hottest point
+        // is not in the stage with the highest minimum.
+        let gpx = ride_with_extensions(&[(20.0, 600), (0.0, 600), (20.0, 600)], |i| Extensions {
+            air_temp: Some(if i < 60 {
+                15.0 + (i % 5) as f64
+            } else {
+                5.0 + (i % 21) as f64
+            }),
+            ..Default::default()
+        });
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 3);
+        assert!(stages[0].min_air_temp.as_ref().unwrap().air_temp() >= Some(15.0));
+
+        assert_eq!(stages.max_temperature().unwrap().air_temp(), Some(25.0));
+        assert_eq!(stages.min_temperature().unwrap().air_temp(), Some(5.0));
+    }
+
+    #[test]
+    fn a_track_without_times_is_split_by_distance() {
+        // 25km, with a point every 100m.
+        let gpx = enriched((0..=250).map(|i| xy_point(i as f64 * 100.0, 0.0)).collect());
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+
+        assert_eq!(stages.len(), 3);
+        assert!(stages.iter().all(|s| s.stage_type == StageType::Moving));
+        assert!(stages.iter().all(|s| s.duration().is_none()));
+        assert!(stages.iter().all(|s| s.average_speed_kmh().is_none()));
+
+        let distances: Vec<_> = stages.iter().map(|s| s.distance_km()).collect();
+        assert!((distances[0] - 10.0).abs() < 0.05, "{distances:?}");
+        assert!((distances[1] - 10.0).abs() < 0.05, "{distances:?}");
+        assert!((distances[2] - 4.8).abs() < 0.05, "{distances:?}");
+        assert_eq!(stages.last_point().unwrap().index, 250);
+
+        assert!(stages.duration().is_none());
+        assert!(stages.average_moving_speed().is_none());
+    }
+
+    #[test]
+    fn calories_at_a_constant_heart_rate() {
+        let gpx = ride_with_extensions(&[(20.0, 3600)], |_| Extensions {
+            heart_rate: Some(140),
+            ..Default::default()
+        });
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 1);
+
+        // A max HR of 190 gives an age of 25.7, so Keytel's formula is
+        // -55.0969 + 0.6309 * 140 + 0.1988 * 70 + 0.2017 * 25.7
+        // = 52.33 kJ/min = 12.51 kcal/min.
+        let profile = AthleteProfile {
+            weight_kg: 70.0,
+            max_hr: 190,
+            resting_hr: 50,
+            sex: Sex::Male,
+        };
+        let minutes = stages[0].duration().unwrap().as_seconds_f64() / 60.0;
+        let kcal = stages[0].estimated_calories(&profile).unwrap();
+        let expected = 12.51 * minutes;
+        assert!(
+            (kcal - expected).abs() < expected * 0.005,
+            "{kcal} vs {expected}"
+        );
+    }
+
+    #[test]
+    fn there_are_no_calories_without_heart_rate() {
+        let gpx = ride(&[(20.0, 600)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        let profile = AthleteProfile {
+            weight_kg: 70.0,
+            max_hr: 190,
+            resting_hr: 50,
+            sex: Sex::Female,
+        };
+        assert!(stages[0].estimated_calories(&profile).is_none());
+    }
+
+    #[test]
+    fn there_is_a_waypoint_at_each_stop() {
+        let gpx = ride(&[
+            (20.0, 1800),
+            (0.0, 1200),
+            (20.0, 1800),
+            (0.0, 600),
+            (20.0, 600),
+        ]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 5);
+
+        let waypoints = stages_to_waypoints(&stages);
+        let names: Vec<_> = waypoints
+            .iter()
+            .map(|w| w.name.as_deref().unwrap())
+            .collect();
+        assert_eq!(names, ["Stop 1 — 10.0km, 20min", "Stop 2 — 20.1km, 10min"]);
+        assert_eq!(waypoints[0].lat, stages[1].start.lat);
+        assert_eq!(waypoints[1].time, stages[3].start.time);
+    }
+
+    #[test]
+    fn a_stop_location_with_an_ampersand_is_written_as_valid_gpx() {
+        let mut gpx = ride(&[(20.0, 1800), (0.0, 1200), (20.0, 1800)]);
+        let stop = detect_stages(&gpx, StageDetectionParameters::default())[1]
+            .start
+            .index;
+        gpx.points[stop].location = Some("Smith & Sons, Tea Rooms".to_string());
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+
+        let waypoints = stages_to_waypoints(&stages);
+        assert_eq!(
+            waypoints[0].desc.as_deref(),
+            Some("Smith & Sons, Tea Rooms")
+        );
+
+        let bytes =
+            write_gpx_to_bytes(&gpx.to_gpx(), &waypoints, &WriteOptions::default()).unwrap();
+        let xml = String::from_utf8(bytes).unwrap();
+        assert!(
+            xml.contains("<desc>Smith &amp; Sons, Tea Rooms</desc>"),
+            "{xml}"
+        );
+        read_gpx_str(&xml);
+    }
+
+    fn elapsed(gpx: &EnrichedGpx) -> Duration {
+        gpx.points.last().unwrap().time.unwrap() - gpx.points[0].time.unwrap()
+    }
+
+    #[test]
+    fn trimming_the_ends_keeps_the_distance_but_not_the_waiting() {
+        let mut gpx = ride(&[(0.0, 900), (20.0, 1200), (0.0, 900)]);
+        let params = StageDetectionParameters::default();
+        let metres = gpx.points.last().unwrap().running_metres;
+        let duration = elapsed(&gpx);
+
+        assert!(gpx.trim_stationary_ends(&params) > 0);
+        enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap();
+
+        let trimmed_metres = gpx.points.last().unwrap().running_metres;
+        assert!(
+            (trimmed_metres - metres).abs() < 1.0,
+            "{trimmed_metres} vs {metres}"
+        );
+        let trimmed_duration = elapsed(&gpx);
+        assert!(
+            trimmed_duration < duration - Duration::minutes(25),
+            "{trimmed_duration}"
+        );
+        assert!(gpx.points.iter().enumerate().all(|(idx, p)| p.index == idx));
+    }
+
+    #[test]
+    fn trimming_a_track_that_starts_and_ends_moving_does_nothing() {
+        let mut gpx = ride(&[(20.0, 600), (0.0, 600), (20.0, 600)]);
+        assert_eq!(
+            gpx.trim_stationary_ends(&StageDetectionParameters::default()),
+            0
+        );
+    }
+
+    #[test]
+    fn the_time_in_each_zone_adds_up_to_the_duration() {
+        // Heart rates from 100 to 194, which covers all the zones.
+        let gpx = ride_with_extensions(&[(20.0, 1200), (0.0, 600), (20.0, 1200)], |i| Extensions {
+            heart_rate: Some(100 + (i * 7 % 95) as u16),
+            ..Default::default()
+        });
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 3);
+        let zones = HrZones::from_max_hr(200);
+
+        let total = stages.time_in_zones(&zones);
+        assert!(total.iter().all(|d| d.is_positive()), "{total:?}");
+        assert_eq!(total.iter().sum::<Duration>(), stages.duration().unwrap());
+
+        let stage_total: Duration = stages
+            .iter()
+            .flat_map(|stage| stage.time_in_zones(&zones))
+            .sum();
+        assert_eq!(stage_total, stages.duration().unwrap());
+    }
+
+    #[test]
+    fn the_ride_summary_agrees_with_the_stages() {
+        let points = ride_points(&[(20.0, 1200), (0.0, 600), (25.0, 1200)])
+            .into_iter()
+            .map(|p| TrackPoint {
+                ele: Some(100.0 + 20.0 * (p.lon * 500.0).sin()),
+                ..p
+            })
+            .collect();
+        let gpx = enriched(points);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 3);
+
+        let summary = gpx.summary();
+        let close = |a: f64, b: f64| (a - b).abs() <= b.abs() * 0.01;
+
+        assert_eq!(summary.duration, stages.duration());
+        assert!(close(summary.distance_km, stages.distance_km()));
+        assert!(close(
+            summary.avg_speed_kmh.unwrap(),
+            stages.average_overall_speed().unwrap()
+        ));
+        assert!(close(
+            summary.ascent_metres.unwrap(),
+            stages.total_ascent_metres().unwrap()
+        ));
+        assert!(close(
+            summary.descent_metres.unwrap(),
+            stages.total_descent_metres().unwrap()
+        ));
+        assert_eq!(
+            summary.max_speed_kmh,
+            stages.max_speed().unwrap().effective_speed_kmh()
+        );
+        assert_eq!(summary.avg_heart_rate, None);
+    }
+
+    #[test]
+    fn missing_elevations_are_interpolated_between_the_anchors() {
+        // Points every 100m. Known elevations at points 2 (100m), 6 (140m)
+        // and 8 (120m); the rest are missing.
+        let known = [(2, 100.0), (6, 140.0), (8, 120.0)];
+        let points = (0..10)
+            .map(|i| TrackPoint {
+                ele: known.iter().find(|(idx, _)| *idx == i).map(|(_, ele)| *ele),
+                ..xy_point(i as f64 * 100.0, 0.0)
+            })
+            .collect();
+        let mut gpx = make_enriched_gpx(points);
+
+        assert_eq!(fill_missing_elevation(&mut gpx), 7);
+
+        let elevations: Vec<_> = gpx.points.iter().map(|p| p.ele.unwrap()).collect();
+        let expected = [
+            100.0, 100.0, 100.0, 110.0, 120.0, 130.0, 140.0, 130.0, 120.0, 120.0,
+        ];
+        for (ele, expected) in elevations.iter().zip(expected) {
+            assert!((ele - expected).abs() < 0.01, "{elevations:?}");
+        }
+    }
+
+    #[test]
+    fn each_moving_stage_is_split_into_its_own_gpx() {
+        let gpx = ride(&[
+            (20.0, 600),
+            (0.0, 900),
+            (20.0, 600),
+            (0.0, 900),
+            (20.0, 600),
+        ]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 5);
+
+        let split = split_into_stage_gpx(&gpx, &stages);
+
+        let filenames: Vec<_> = split.iter().map(|g| g.filename.to_str().unwrap()).collect();
+        assert_eq!(
+            filenames,
+            ["test.stage01.gpx", "test.stage03.gpx", "test.stage05.gpx"]
+        );
+        for (stage_gpx, stage) in split.iter().zip(stages.iter().step_by(2)) {
+            let points = &stage_gpx.tracks[0].segments[0].points;
+            assert_eq!(points.len(), stage.points(&gpx).len());
+            assert_eq!(points.first().unwrap().time, stage.start.time);
+            assert_eq!(points.last().unwrap().time, stage.end.time);
+        }
+    }
+
+    #[test]
+    fn nearby_highlighted_points_collapse_to_the_more_significant() {
+        // A steady 20km/h with a heart rate of 120, except for a spike to
+        // 180 at point 60 and a burst of speed into point 61.
+        let mut points: Vec<_> = ride_points(&[(20.0, 1200)])
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| TrackPoint {
+                extensions: Some(Extensions {
+                    heart_rate: Some(if i == 60 { 180 } else { 120 }),
+                    ..Default::default()
+                }),
+                ..p
+            })
+            .collect();
+        points[61].lon += (points[62].lon - points[61].lon) / 2.0;
+        let gpx = enriched(points);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages[0].max_heart_rate.as_ref().unwrap().index, 60);
+        assert_eq!(stages[0].max_speed.as_ref().unwrap().index, 61);
+
+        let all = stages.highlighted_trackpoints();
+        assert!(all.contains(&60) && all.contains(&61), "{all:?}");
+
+        // The points are about 85m apart. Max speed ranks above max
+        // heart rate, so it is the one that is kept.
+        let spaced = stages.highlighted_trackpoints_spaced(200.0);
+        assert!(spaced.contains(&61) && !spaced.contains(&60), "{spaced:?}");
+        assert_eq!(spaced.len(), all.len() - 1);
+
+        let spaced = stages.highlighted_trackpoints_spaced(10.0);
+        assert_eq!(spaced, all);
+    }
+
+    fn stage_types(gpx: &EnrichedGpx, params: StageDetectionParameters) -> Vec<StageType> {
+        detect_stages(gpx, params)
+            .iter()
+            .map(|s| s.stage_type)
+            .collect()
+    }
+
+    fn with_cadence(rpm: u16) -> Extensions {
+        Extensions {
+            cadence: Some(rpm),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn a_coasting_descent_stays_moving_and_a_stop_becomes_control() {
+        let params = StageDetectionParameters {
+            stopped_cadence_rpm: Some(10),
+            ..Default::default()
+        };
+
+        // Freewheeling at 45km/h for 10 minutes in the middle.
+        let coasting = ride_with_extensions(&[(20.0, 600), (45.0, 600), (20.0, 600)], |i| {
+            with_cadence(if (61..=120).contains(&i) { 0 } else { 85 })
+        });
+        assert_eq!(stage_types(&coasting, params.clone()), [StageType::Moving]);
+
+        let stop = ride_with_extensions(&[(20.0, 600), (0.0, 600), (20.0, 600)], |i| {
+            with_cadence(if (61..=120).contains(&i) { 0 } else { 85 })
+        });
+        assert_eq!(
+            stage_types(&stop, params),
+            [StageType::Moving, StageType::Control, StageType::Moving]
+        );
+    }
+
+    #[test]
+    fn pedalling_while_the_speed_reads_zero_is_not_a_stop() {
+        // A GPS glitch: the position freezes but the cadence says we
+        // are still riding.
+        let gpx = ride_with_extensions(&[(20.0, 600), (0.0, 600), (20.0, 600)], |_| {
+            with_cadence(85)
+        });
+        let params = StageDetectionParameters {
+            stopped_cadence_rpm: Some(10),
+            ..Default::default()
+        };
+
+        assert_eq!(stage_types(&gpx, params), [StageType::Moving]);
+        assert_eq!(
+            stage_types(&gpx, StageDetectionParameters::default()),
+            [StageType::Moving, StageType::Control, StageType::Moving]
+        );
+    }
+
+    #[test]
+    fn the_cadence_threshold_does_nothing_without_cadence_data() {
+        let gpx = ride(&[(20.0, 600), (0.0, 600), (20.0, 600)]);
+        let params = StageDetectionParameters {
+            stopped_cadence_rpm: Some(10),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            stage_types(&gpx, params),
+            stage_types(&gpx, StageDetectionParameters::default())
+        );
+    }
+
+    #[test]
+    fn the_length_of_a_point_slice() {
+        // One degree of latitude north from the equator.
+        let points = [track_point(0.0, 0.0, None), track_point(1.0, 0.0, None)];
+        let metres = track_length_metres(&points);
+        assert!((metres - 110_574.4).abs() < 1.0, "{metres}");
+
+        assert_eq!(track_length_metres(&points[..1]), 0.0);
+        assert_eq!(track_length_metres(&[]), 0.0);
+
+        let gpx = ride(&[(20.0, 600)]);
+        let metres = enriched_track_length_metres(&gpx.points);
+        let running_metres = gpx.points.last().unwrap().running_metres;
+        assert!((metres - running_metres).abs() < 1e-6, "{metres}");
+        assert_eq!(enriched_track_length_metres(&[]), 0.0);
+    }
+
+    #[test]
+    fn an_empty_stage_list_has_no_first_or_last_point() {
+        let empty = StageList::default();
+        assert!(empty.is_empty());
+        assert_eq!(empty.len(), 0);
+        assert!(empty.first_point().is_none());
+        assert!(empty.last_point().is_none());
+
+        let gpx = ride(&[(20.0, 600)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert!(!stages.is_empty());
+        assert_eq!(stages.len(), 1);
+        assert_eq!(stages.first_point().unwrap().index, 0);
+        assert_eq!(stages.last_point().unwrap().index, gpx.points.len() - 1);
+    }
+
+    #[test]
+    fn a_ride_stopped_for_half_the_time_has_a_moving_ratio_of_a_half() {
+        let gpx = ride(&[(20.0, 900), (0.0, 1800), (20.0, 900)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+
+        let ratio = stages.moving_ratio().unwrap();
+        assert!((ratio - 0.5).abs() < 0.02, "{ratio}");
+        let controlling = stages.controlling_ratio().unwrap();
+        assert!((ratio + controlling - 1.0).abs() < 1e-9);
+
+        assert_eq!(StageList::default().moving_ratio(), None);
+    }
+
+    #[test]
+    fn the_points_of_a_stage_run_from_its_start_to_its_end() {
+        let gpx = ride(&[(20.0, 900), (0.0, 1800), (20.0, 900)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert!(stages.len() > 1);
+
+        for stage in &stages {
+            let points = stage.points(&gpx);
+            assert_eq!(points.len(), stage.end.index - stage.start.index + 1);
+            assert_eq!(points[0].index, stage.start.index);
+            assert_eq!(points.last().unwrap().index, stage.end.index);
+        }
+    }
+
+    #[test]
+    fn enrichment_reports_progress_for_every_point() {
+        let mut gpx = make_enriched_gpx(ride_points(&[(20.0, 600)]));
+        let num_points = gpx.points.len();
+        let updates = std::sync::Mutex::new(Vec::new());
+        let progress = |done: usize, total: usize| updates.lock().unwrap().push((done, total));
+
+        enrich_trackpoints(&mut gpx, 0.0, None, Some(&progress)).unwrap();
+
+        let expected: Vec<_> = (1..=num_points).map(|done| (done, num_points)).collect();
+        assert_eq!(updates.into_inner().unwrap(), expected);
+    }
+
+    #[test]
+    fn a_zero_duration_stage_has_no_average_speed() {
+        let gpx = ride(&[(20.0, 600)]);
+        let stage = make_stage(&gpx, StageType::Moving, 0, 0);
+        assert_eq!(stage.duration(), Some(Duration::ZERO));
+
+        assert_eq!(stage.average_speed_kmh(), None);
+        assert_eq!(stage.running_average_speed_kmh(), None);
+        assert_eq!(stage.average_speed_over_moving_points(&gpx), None);
+
+        let mut stages = StageList::default();
+        stages.push(stage);
+        assert_eq!(stages.average_moving_speed(), None);
+        assert_eq!(stages.average_overall_speed(), None);
+    }
+
+    #[test]
+    fn both_average_speeds_agree_for_a_normal_stage() {
+        let gpx = ride(&[(15.0, 600), (25.0, 600)]);
+        let stages = detect_stages(&gpx, StageDetectionParameters::default());
+        assert_eq!(stages.len(), 1);
+
+        let from_ends = stages[0].average_speed_kmh().unwrap();
+        let from_points = stages[0].average_speed_over_moving_points(&gpx).unwrap();
+        assert!((from_ends - 20.0).abs() < 0.5, "{from_ends}");
+        assert!(
+            (from_ends - from_points).abs() < 0.01,
+            "{from_ends} vs {from_points}"
+        );
+    }
+
+    #[test]
+    fn the_gap_between_segments_is_not_counted_as_distance() {
+        // Two 2km segments 5km apart, with 10 minutes of GPS loss.
+        let first = ride_points(&[(20.0, 360)]);
+        let second: Vec<_> = ride_points(&[(20.0, 360)])
+            .into_iter()
+            .map(|p| TrackPoint {
+                lat: p.lat + 0.045,
+                time: p.time.map(|t| t + Duration::minutes(16)),
+                ..p
+            })
+            .collect();
+        let mut builder = GpxBuilder::new("test.gpx");
+        for p in first {
+            builder.push_point(p);
+        }
+        builder.add_segment();
+        for p in second {
+            builder.push_point(p);
+        }
+        let mut gpx = EnrichedGpx::from(builder.build());
+
+        enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap();
+
+        let gap = &gpx.points[37];
+        assert!(gap.segment_start);
+        assert_eq!(gap.delta_metres, 0.0);
+        assert_eq!(gap.speed_kmh, Some(0.0));
+        let metres = gpx.points.last().unwrap().running_metres;
+        assert!((metres - 4000.0).abs() < 40.0, "{metres}");
+    }
+
+    #[test]
+    fn high_hdop_points_are_removed_before_the_distances_are_calculated() {
+        // Points 10 and 11 have jumped 500m north, and they and the first
+        // point have a poor fix.
+        let points = ride_points(&[(20.0, 600)])
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let bad = [0, 10, 11].contains(&i);
+                TrackPoint {
+                    lat: if i == 10 || i == 11 {
+                        p.lat + 0.0045
+                    } else {
+                        p.lat
+                    },
+                    fix_info: Some(FixInfo {
+                        hdop: Some(if bad { 30.0 } else { 1.0 }),
+                        ..Default::default()
+                    }),
+                    ..p
+                }
+            })
+            .collect();
+        let mut gpx = make_enriched_gpx(points);
+
+        assert_eq!(filter_by_hdop(&mut gpx, 20.0), 3);
+        enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap();
+
+        assert_eq!(gpx.points.len(), 58);
+        assert!(gpx.points.iter().enumerate().all(|(i, p)| p.index == i));
+        // 59 of the 60 10-second intervals at 20km/h, with no detour.
+        let metres = gpx.points.last().unwrap().running_metres;
+        let expected = 59.0 * 20.0 / 3.6 * 10.0;
+        assert!((metres - expected).abs() < 50.0, "{metres}");
+    }
+}
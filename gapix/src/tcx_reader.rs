@@ -0,0 +1,371 @@
+//! Contains the functionality relating to reading TCX (Training Center XML)
+//! files, as exported by Garmin Connect and many watches. The schema is at
+//! https://www8.garmin.com/xmlschemas/TrainingCenterDatabasev2.xsd
+//!
+//! TCX carries the same data that we use from GPX files but in a different
+//! shape. We map each Activity to a Track and each Lap within it to a
+//! TrackSegment, so the rest of the program does not need to know that
+//! the data came from a TCX file.
+
+/*
+<TrainingCenterDatabase>
+   <Activities>
+       <Activity Sport="Biking">                    -> Track
+           <Id>                                     -> Metadata time
+           <Lap>                                    -> TrackSegment
+               <Track>
+                   <Trackpoint>                     -> TrackPoint
+                       <Time>
+                       <Position>
+                           <LatitudeDegrees>
+                           <LongitudeDegrees>
+                       <AltitudeMeters>
+                       <HeartRateBpm><Value>        -> Extensions
+                       <Cadence>                    -> Extensions
+                       <Extensions><TPX><Speed>     -> Extensions
+                       <Extensions><TPX><Watts>     -> Extensions
+       <Creator><Name>                              -> GpxInfo creator
+*/
+
+use std::{fs::File, io::BufReader, path::Path};
+
+use log::info;
+use logging_timer::time;
+use quick_xml::{events::Event, Reader};
+use time::OffsetDateTime;
+
+use crate::{
+    error::GapixError,
+    gpx_reader::{parse_decl, parse_time, parse_value, xml_error},
+    model::{Extensions, Gpx, GpxBuilder, Metadata, TrackPoint},
+};
+
+/// Returns true if the root element of the file is a TCX
+/// 'TrainingCenterDatabase' element.
+pub fn is_tcx_file(input_file: &Path) -> Result<bool, GapixError> {
+    let mut reader = Reader::from_reader(BufReader::new(File::open(input_file)?));
+    let mut buf: Vec<u8> = Vec::with_capacity(512);
+
+    loop {
+        match reader
+            .read_event_into(&mut buf)
+            .map_err(|e| xml_error(&reader, e))?
+        {
+            Event::Start(e) | Event::Empty(e) => {
+                return Ok(e.local_name().as_ref() == b"TrainingCenterDatabase");
+            }
+            Event::Eof => return Ok(false),
+            _ => (),
+        }
+
+        buf.clear();
+    }
+}
+
+/// Reads a TCX file and converts it into a Gpx. Trackpoints without a
+/// position (which watches write when, for example, GPS is off) are skipped,
+/// as are trackpoints that are not later than the previous one (TCX files
+/// often repeat the last point of a lap as the first point of the next).
+#[time]
+pub fn read_tcx_file(input_file: &Path) -> Result<Gpx, GapixError> {
+    info!("Reading TCX file {:?}", input_file);
+    let mut reader = Reader::from_reader(BufReader::new(File::open(input_file)?));
+    let mut buf: Vec<u8> = Vec::with_capacity(512);
+
+    let mut builder = GpxBuilder::new(input_file);
+    let mut declaration = None;
+    let mut creator = None;
+    let mut activity_time = None;
+
+    let mut in_activity = false;
+    let mut point: Option<TcxPoint> = None;
+    let mut last_time = None;
+
+    // TCX re-uses some element names in different contexts, e.g. <Name>
+    // and <Value>, so we need to know where we are.
+    let mut in_creator = false;
+    let mut in_heart_rate = false;
+    let mut element = Vec::new();
+
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Decl(decl)) => {
+                declaration = Some(parse_decl(&decl)?);
+            }
+            Ok(Event::Start(e)) => {
+                match e.local_name().as_ref() {
+                    b"Activity" => {
+                        let sport = e
+                            .try_get_attribute("Sport")
+                            .map_err(|e| xml_error(&reader, e))?
+                            .map(|a| a.unescape_value())
+                            .transpose()
+                            .map_err(|e| xml_error(&reader, e))?
+                            .map(|v| v.to_string());
+
+                        builder.add_track(None).track_type(sport);
+                        in_activity = true;
+                        last_time = None;
+                    }
+                    b"Lap" => {
+                        builder.add_segment();
+                    }
+                    b"Trackpoint" => {
+                        point = Some(TcxPoint::default());
+                    }
+                    b"Creator" => in_creator = true,
+                    b"HeartRateBpm" => in_heart_rate = true,
+                    _ => (),
+                }
+                element = e.local_name().as_ref().to_vec();
+            }
+            Ok(Event::Text(e)) => {
+                let text = e.unescape().map_err(|e| xml_error(&reader, e))?;
+                let text = text.trim();
+                if text.is_empty() {
+                    buf.clear();
+                    continue;
+                }
+
+                match (element.as_slice(), point.as_mut()) {
+                    (b"Id", None) if in_activity && activity_time.is_none() => {
+                        activity_time = Some(parse_time(text)?);
+                    }
+                    (b"Name", None) if in_creator && creator.is_none() => {
+                        creator = Some(text.to_string());
+                    }
+                    (b"Time", Some(p)) => {
+                        p.time = Some(parse_time(text)?);
+                    }
+                    (b"LatitudeDegrees", Some(p)) => p.lat = Some(parse_value(text)?),
+                    (b"LongitudeDegrees", Some(p)) => p.lon = Some(parse_value(text)?),
+                    (b"AltitudeMeters", Some(p)) => p.ele = Some(parse_value(text)?),
+                    (b"Value", Some(p)) if in_heart_rate => p.heart_rate = Some(parse_value(text)?),
+                    (b"Cadence", Some(p)) => p.cadence = Some(parse_value(text)?),
+                    (b"Speed", Some(p)) => p.speed = Some(parse_value(text)?),
+                    (b"Watts", Some(p)) => p.power = Some(parse_value(text)?),
+                    _ => (),
+                }
+            }
+            Ok(Event::End(e)) => {
+                match e.local_name().as_ref() {
+                    b"Trackpoint" => {
+                        let p = point.take().unwrap();
+                        let is_later = match (p.time, last_time) {
+                            (Some(t1), Some(t2)) => t1 > t2,
+                            _ => true,
+                        };
+
+                        if let (Some(tp), true) = (p.into_trackpoint(), is_later) {
+                            last_time = tp.time.or(last_time);
+                            builder.push_point(tp);
+                        }
+                    }
+                    b"Activity" => in_activity = false,
+                    b"Creator" => in_creator = false,
+                    b"HeartRateBpm" => in_heart_rate = false,
+                    b"TrainingCenterDatabase" => {
+                        if let Some(creator) = creator.take() {
+                            builder.creator(creator);
+                        }
+                        builder.metadata(Metadata {
+                            time: activity_time,
+                            ..Default::default()
+                        });
+
+                        // The builder discards empty laps and activities.
+                        let mut gpx = builder.build();
+                        if gpx.tracks.is_empty() {
+                            return Err(GapixError::NoTrackPoints);
+                        }
+                        if let Some(declaration) = declaration.take() {
+                            gpx.declaration = declaration;
+                        }
+
+                        return Ok(gpx);
+                    }
+                    _ => (),
+                }
+                element.clear();
+            }
+            Ok(Event::Eof) => {
+                return Err(GapixError::UnexpectedEof("TrainingCenterDatabase"));
+            }
+            Err(e) => return Err(xml_error(&reader, e)),
+            _ => (),
+        }
+
+        buf.clear();
+    }
+}
+
+/// Accumulates the fields of a <Trackpoint> as we parse it.
+#[derive(Default)]
+struct TcxPoint {
+    lat: Option<f64>,
+    lon: Option<f64>,
+    ele: Option<f64>,
+    time: Option<OffsetDateTime>,
+    heart_rate: Option<u16>,
+    cadence: Option<u16>,
+    speed: Option<f64>,
+    power: Option<u16>,
+}
+
+impl TcxPoint {
+    /// Converts to a TrackPoint, or None if we did not get a position.
+    fn into_trackpoint(self) -> Option<TrackPoint> {
+        let extensions = if self.heart_rate.is_some()
+            || self.cadence.is_some()
+            || self.speed.is_some()
+            || self.power.is_some()
+        {
+            Some(Extensions {
+                air_temp: None,
+                water_temp: None,
+                depth: None,
+                heart_rate: self.heart_rate,
+                cadence: self.cadence,
+                speed: self.speed,
+                course: None,
+                power: self.power,
+                other: None,
+            })
+        } else {
+            None
+        };
+
+        Some(TrackPoint {
+            lat: self.lat?,
+            lon: self.lon?,
+            ele: self.ele,
+            time: self.time,
+            fix_info: None,
+            extensions,
+            raw_children: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        simplification::WriteOptions,
+        test_helpers::{read_gpx_str, temp_file, write_gpx_str},
+    };
+
+    /// Two laps, where the second repeats the last point of the first, plus
+    /// a point without a position.
+    const TCX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<TrainingCenterDatabase xmlns="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2">
+  <Activities>
+    <Activity Sport="Biking">
+      <Id>2024-06-01T08:00:00Z</Id>
+      <Lap StartTime="2024-06-01T08:00:00Z">
+        <Track>
+          <Trackpoint>
+            <Time>2024-06-01T08:00:00Z</Time>
+            <Position>
+              <LatitudeDegrees>51.5</LatitudeDegrees>
+              <LongitudeDegrees>-0.1</LongitudeDegrees>
+            </Position>
+            <AltitudeMeters>12.5</AltitudeMeters>
+            <HeartRateBpm><Value>101</Value></HeartRateBpm>
+            <Cadence>80</Cadence>
+          </Trackpoint>
+          <Trackpoint>
+            <Time>2024-06-01T08:00:10Z</Time>
+            <Position>
+              <LatitudeDegrees>51.501</LatitudeDegrees>
+              <LongitudeDegrees>-0.1</LongitudeDegrees>
+            </Position>
+            <AltitudeMeters>13.0</AltitudeMeters>
+            <HeartRateBpm><Value>110</Value></HeartRateBpm>
+          </Trackpoint>
+        </Track>
+      </Lap>
+      <Lap StartTime="2024-06-01T08:00:10Z">
+        <Track>
+          <Trackpoint>
+            <Time>2024-06-01T08:00:10Z</Time>
+            <Position>
+              <LatitudeDegrees>51.501</LatitudeDegrees>
+              <LongitudeDegrees>-0.1</LongitudeDegrees>
+            </Position>
+            <AltitudeMeters>13.0</AltitudeMeters>
+          </Trackpoint>
+          <Trackpoint>
+            <Time>2024-06-01T08:00:15Z</Time>
+            <HeartRateBpm><Value>112</Value></HeartRateBpm>
+          </Trackpoint>
+          <Trackpoint>
+            <Time>2024-06-01T08:00:20Z</Time>
+            <Position>
+              <LatitudeDegrees>51.502</LatitudeDegrees>
+              <LongitudeDegrees>-0.1</LongitudeDegrees>
+            </Position>
+            <AltitudeMeters>14.5</AltitudeMeters>
+            <HeartRateBpm><Value>120</Value></HeartRateBpm>
+          </Trackpoint>
+        </Track>
+      </Lap>
+      <Creator><Name>Edge 530</Name></Creator>
+    </Activity>
+  </Activities>
+</TrainingCenterDatabase>
+"#;
+
+    #[test]
+    fn reads_the_points_heart_rate_and_elevation() {
+        let path = temp_file("fixture.tcx", TCX);
+        assert!(is_tcx_file(&path).unwrap());
+
+        let gpx = read_tcx_file(&path).unwrap();
+        assert_eq!(gpx.tracks.len(), 1);
+        assert_eq!(gpx.tracks[0].r#type.as_deref(), Some("Biking"));
+        assert_eq!(gpx.tracks[0].segments.len(), 2);
+
+        let points: Vec<_> = gpx.tracks[0]
+            .segments
+            .iter()
+            .flat_map(|s| s.points.iter())
+            .collect();
+        assert_eq!(points.len(), 3);
+
+        let elevations: Vec<_> = points.iter().map(|p| p.ele).collect();
+        assert_eq!(elevations, [Some(12.5), Some(13.0), Some(14.5)]);
+
+        let heart_rates: Vec<_> = points
+            .iter()
+            .map(|p| p.extensions.as_ref().and_then(|e| e.heart_rate))
+            .collect();
+        assert_eq!(heart_rates, [Some(101), Some(110), Some(120)]);
+        assert_eq!(points[0].extensions.as_ref().unwrap().cadence, Some(80));
+    }
+
+    #[test]
+    fn an_ampersand_in_the_creator_name_is_written_as_valid_gpx() {
+        let tcx = TCX.replace("Edge 530", "Tom &amp; Jerry &lt;Edge&gt;");
+        let path = temp_file("ampersand.tcx", &tcx);
+
+        let gpx = read_tcx_file(&path).unwrap();
+        assert_eq!(gpx.info.creator, "Tom & Jerry <Edge>");
+
+        let xml = write_gpx_str(&gpx, &WriteOptions::default());
+        assert!(
+            xml.contains(r#"creator="Tom &amp; Jerry &lt;Edge&gt;""#),
+            "{xml}"
+        );
+        assert_eq!(read_gpx_str(&xml).info.creator, "Tom & Jerry <Edge>");
+    }
+
+    #[test]
+    fn a_gpx_file_is_not_tcx() {
+        let path = temp_file(
+            "not-tcx.gpx",
+            r#"<?xml version="1.0"?><gpx version="1.1" creator="test"></gpx>"#,
+        );
+        assert!(!is_tcx_file(&path).unwrap());
+    }
+}
@@ -0,0 +1,127 @@
+//! Helpers for building and round-tripping GPX files in the unit tests.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    gpx_reader::read_gpx_from_reader,
+    model::{EnrichedGpx, Gpx, GpxBuilder, TrackPoint},
+    simplification::{write_gpx_to_bytes, WriteOptions},
+    stage::enrich_trackpoints,
+};
+
+/// The latitude of the origin used by 'xy_point'.
+const ORIGIN_LAT: f64 = 51.5;
+
+/// The approximate length of a degree of latitude, and of a degree of
+/// longitude at the equator. Good enough for laying out test tracks.
+const METRES_PER_DEGREE: f64 = 111_195.0;
+
+/// Makes a TrackPoint with the given position and no time or extensions.
+pub fn track_point(lat: f64, lon: f64, ele: Option<f64>) -> TrackPoint {
+    TrackPoint {
+        lat,
+        lon,
+        ele,
+        time: None,
+        fix_info: None,
+        extensions: None,
+        raw_children: None,
+    }
+}
+
+/// Makes a TrackPoint 'x' metres east and 'y' metres north of a fixed
+/// origin. The distances are approximate but are consistent with each other.
+pub fn xy_point(x: f64, y: f64) -> TrackPoint {
+    let lat = ORIGIN_LAT + y / METRES_PER_DEGREE;
+    let lon = x / (METRES_PER_DEGREE * ORIGIN_LAT.to_radians().cos());
+    track_point(lat, lon, None)
+}
+
+/// The time of the first point made by 'ride_points', 2024-06-01T08:00:00Z.
+pub fn start_time() -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(1_717_228_800).unwrap()
+}
+
+/// The number of seconds between the points made by 'ride_points'.
+pub const RIDE_INTERVAL_SECONDS: i64 = 10;
+
+/// Makes the points of a ride heading east from a list of (speed in km/h,
+/// duration in seconds) sections, with a point every RIDE_INTERVAL_SECONDS.
+/// A speed of 0 is a stop.
+pub fn ride_points(sections: &[(f64, i64)]) -> Vec<TrackPoint> {
+    let mut x = 0.0;
+    let mut seconds = 0;
+    let mut points = vec![TrackPoint {
+        time: Some(start_time()),
+        ..xy_point(x, 0.0)
+    }];
+
+    for &(speed_kmh, duration) in sections {
+        for _ in 0..duration / RIDE_INTERVAL_SECONDS {
+            x += speed_kmh / 3.6 * RIDE_INTERVAL_SECONDS as f64;
+            seconds += RIDE_INTERVAL_SECONDS;
+            points.push(TrackPoint {
+                time: Some(start_time() + Duration::seconds(seconds)),
+                ..xy_point(x, 0.0)
+            });
+        }
+    }
+
+    points
+}
+
+/// Makes an enriched track from a list of (speed in km/h, duration in
+/// seconds) sections, see 'ride_points'.
+pub fn ride(sections: &[(f64, i64)]) -> EnrichedGpx {
+    enriched(ride_points(sections))
+}
+
+/// Makes an EnrichedGpx with a single track and enriches it, counting
+/// every change in elevation.
+pub fn enriched(points: Vec<TrackPoint>) -> EnrichedGpx {
+    let mut gpx = make_enriched_gpx(points);
+    enrich_trackpoints(&mut gpx, 0.0, None, None).unwrap();
+    gpx
+}
+
+/// Makes a Gpx with a single track containing a single segment.
+pub fn make_gpx(points: Vec<TrackPoint>) -> Gpx {
+    let mut builder = GpxBuilder::new("test.gpx");
+    for p in points {
+        builder.push_point(p);
+    }
+    builder.build()
+}
+
+/// Makes an EnrichedGpx with a single track. The points are not enriched.
+pub fn make_enriched_gpx(points: Vec<TrackPoint>) -> EnrichedGpx {
+    EnrichedGpx::from(make_gpx(points))
+}
+
+/// Parses a GPX document held in a string.
+pub fn read_gpx_str(xml: &str) -> Gpx {
+    read_gpx_from_reader(xml.as_bytes(), Path::new("test.gpx")).unwrap()
+}
+
+/// Writes the GPX to a string, using 'options'.
+pub fn write_gpx_str(gpx: &Gpx, options: &WriteOptions) -> String {
+    String::from_utf8(write_gpx_to_bytes(gpx, &[], options).unwrap()).unwrap()
+}
+
+/// Writes the GPX with the default options and reads it back in.
+pub fn round_trip(gpx: &Gpx) -> Gpx {
+    read_gpx_str(&write_gpx_str(gpx, &WriteOptions::default()))
+}
+
+/// Writes 'contents' to a file in the temp directory and returns its path.
+/// The name is made unique to this process so test runs don't collide.
+pub fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("gapix-{}-{name}", std::process::id()));
+    fs::write(&path, contents).unwrap();
+    path
+}
@@ -0,0 +1,68 @@
+//! Contains the functionality relating to time zones. The times in a GPX
+//! file are in UTC, but it is much more natural to see them in the local
+//! time of the place where the ride took place, which is not necessarily
+//! the time zone of the computer running this program.
+//!
+//! The 'tzf-rs' crate looks up the IANA time zone name of a lat-lon from
+//! the time zone boundary polygons (which are bundled in the crate, so no
+//! network access is needed) and the 'time-tz' crate then provides the
+//! rules (including DST) for the zone.
+//!
+//! A ride is shown entirely in the time zone of its first point, even if
+//! it crosses into another zone, so that the times of its stages are
+//! consistent with each other.
+
+use std::sync::OnceLock;
+
+use time_tz::{timezones, Tz};
+use tzf_rs::DefaultFinder;
+
+use crate::model::EnrichedTrackPoint;
+
+/// Returns the time zone at the lat-lon, found from the time zone
+/// boundary polygons. Points at sea are in one of the 'Etc/GMT' zones.
+pub fn timezone_for_location(lat: f64, lon: f64) -> Option<&'static Tz> {
+    static FINDER: OnceLock<DefaultFinder> = OnceLock::new();
+
+    let finder = FINDER.get_or_init(DefaultFinder::new);
+    timezones::get_by_name(finder.get_tz_name(lon, lat))
+}
+
+/// Returns the time zone of the place where the point was recorded.
+pub fn timezone_for_point(point: &EnrichedTrackPoint) -> Option<&'static Tz> {
+    timezone_for_location(point.lat, point.lon)
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{macros::datetime, UtcOffset};
+    use time_tz::TimeZone;
+
+    use super::*;
+    use crate::formatting::to_local_date;
+
+    #[test]
+    fn paris_changes_offset_across_the_dst_boundary() {
+        let paris = timezone_for_location(48.8566, 2.3522).unwrap();
+        assert_eq!(paris.name(), "Europe/Paris");
+
+        // The clocks went forward at 01:00 UTC on 2024-03-31.
+        let before = to_local_date(datetime!(2024-03-31 00:30 UTC), Some(paris));
+        assert_eq!(before.offset(), UtcOffset::from_hms(1, 0, 0).unwrap());
+        assert_eq!((before.hour(), before.minute()), (1, 30));
+
+        let after = to_local_date(datetime!(2024-03-31 01:30 UTC), Some(paris));
+        assert_eq!(after.offset(), UtcOffset::from_hms(2, 0, 0).unwrap());
+        assert_eq!((after.hour(), after.minute()), (3, 30));
+    }
+
+    #[test]
+    fn zones_are_found_from_the_boundaries_not_the_longitude() {
+        let name = |lat, lon| timezone_for_location(lat, lon).unwrap().name();
+
+        assert_eq!(name(51.5074, -0.1278), "Europe/London");
+        assert_eq!(name(43.1155, 131.8855), "Asia/Vladivostok");
+        assert_eq!(name(50.4452, -104.6189), "America/Regina");
+        assert_eq!(name(43.8256, 87.6168), "Asia/Shanghai");
+    }
+}
@@ -0,0 +1,463 @@
+//! Contains the functionality relating to checking that a Gpx conforms
+//! to the GPX 1.1 XSD, https://www.topografix.com/GPX/1/1/gpx.xsd
+//!
+//! The reader already rejects files which are not well-formed or which
+//! have elements in places we don't understand, so this checks the things
+//! the reader lets through: the values of attributes and elements which
+//! the XSD restricts, and the times of the points, which external tools
+//! assume are in order.
+//!
+//! It also contains 'EnrichedGpx::diagnostics', which looks for problems
+//! in the data itself (such as GPS glitches) which are valid according to
+//! the XSD but which make the analysis untrustworthy.
+
+use core::fmt;
+
+use geo::GeodesicDistance;
+use time::OffsetDateTime;
+
+use crate::{
+    formatting::format_utc_date,
+    model::{Bounds, EnrichedGpx, Gpx, Link, Metadata},
+    stage::speed_kmh_from_duration,
+};
+
+/// Consecutive points further apart than this are reported by 'diagnostics'.
+const POSITION_JUMP_METRES: f64 = 1000.0;
+
+/// Speeds faster than this are reported by 'diagnostics'. It is faster
+/// than anyone descends on a bike, so is almost certainly a GPS glitch.
+const MAX_PLAUSIBLE_SPEED_KMH: f64 = 150.0;
+
+/// A problem found by 'validate_gpx'.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationIssue {
+    /// Where the problem is, e.g. "track 1, segment 2, point 57".
+    /// Tracks, segments and points are numbered from 1.
+    pub location: String,
+    pub kind: IssueKind,
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+/// The types of problem found by 'validate_gpx'.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IssueKind {
+    /// The mandatory 'creator' attribute is empty.
+    MissingCreator,
+    /// The 'version' attribute is not "1.1".
+    UnsupportedVersion(String),
+    /// A latitude is outside -90..=90.
+    LatitudeOutOfRange(f64),
+    /// A longitude is outside -180..=180 (the XSD actually
+    /// requires -180 <= lon < 180, but many tools write 180).
+    LongitudeOutOfRange(f64),
+    /// A <bounds> element has a min greater than its max.
+    InvertedBounds,
+    /// A <copyright> element has an empty 'author' attribute.
+    MissingCopyrightAuthor,
+    /// A <link> element has an empty 'href' attribute.
+    MissingLinkHref,
+    /// A <magvar> is outside 0..360.
+    MagvarOutOfRange(f64),
+    /// A <dgpsid> is outside 0..=1023.
+    DgpsIdOutOfRange(u16),
+    /// The time of a point is earlier than the time of the previous point.
+    TimeNotIncreasing(OffsetDateTime),
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IssueKind::MissingCreator => write!(f, "the 'creator' attribute is empty"),
+            IssueKind::UnsupportedVersion(v) => {
+                write!(f, "the 'version' attribute is '{v}', expected '1.1'")
+            }
+            IssueKind::LatitudeOutOfRange(lat) => {
+                write!(f, "latitude {lat} is outside the range -90 to 90")
+            }
+            IssueKind::LongitudeOutOfRange(lon) => {
+                write!(f, "longitude {lon} is outside the range -180 to 180")
+            }
+            IssueKind::InvertedBounds => write!(f, "the minimum is greater than the maximum"),
+            IssueKind::MissingCopyrightAuthor => {
+                write!(f, "the copyright 'author' attribute is empty")
+            }
+            IssueKind::MissingLinkHref => write!(f, "the link 'href' attribute is empty"),
+            IssueKind::MagvarOutOfRange(magvar) => {
+                write!(f, "magvar {magvar} is outside the range 0 to 360")
+            }
+            IssueKind::DgpsIdOutOfRange(id) => {
+                write!(f, "dgpsid {id} is outside the range 0 to 1023")
+            }
+            IssueKind::TimeNotIncreasing(t) => {
+                write!(f, "time {t} is earlier than the previous point")
+            }
+        }
+    }
+}
+
+/// Checks 'gpx' against the rules of the XSD and returns all the problems
+/// found, rather than stopping at the first one. An empty list means that
+/// the file is valid (as far as we can tell).
+pub fn validate_gpx(gpx: &Gpx) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    let mut add = |location: String, kind: IssueKind| {
+        issues.push(ValidationIssue { location, kind });
+    };
+
+    if gpx.info.creator.trim().is_empty() {
+        add("gpx".to_string(), IssueKind::MissingCreator);
+    }
+    if gpx.info.version != "1.1" {
+        add(
+            "gpx".to_string(),
+            IssueKind::UnsupportedVersion(gpx.info.version.clone()),
+        );
+    }
+
+    validate_metadata(&gpx.metadata, &mut add);
+
+    for (track_idx, track) in gpx.tracks.iter().enumerate() {
+        for (segment_idx, segment) in track.segments.iter().enumerate() {
+            let mut prev_time = None;
+
+            for (point_idx, p) in segment.points.iter().enumerate() {
+                let location = || {
+                    format!(
+                        "track {}, segment {}, point {}",
+                        track_idx + 1,
+                        segment_idx + 1,
+                        point_idx + 1
+                    )
+                };
+
+                if !(-90.0..=90.0).contains(&p.lat) {
+                    add(location(), IssueKind::LatitudeOutOfRange(p.lat));
+                }
+                if !(-180.0..=180.0).contains(&p.lon) {
+                    add(location(), IssueKind::LongitudeOutOfRange(p.lon));
+                }
+
+                if let Some(fix_info) = &p.fix_info {
+                    if let Some(magvar) = fix_info.magvar.filter(|m| !(0.0..360.0).contains(m)) {
+                        add(location(), IssueKind::MagvarOutOfRange(magvar));
+                    }
+                    if let Some(id) = fix_info.dgps_id.filter(|&id| id > 1023) {
+                        add(location(), IssueKind::DgpsIdOutOfRange(id));
+                    }
+                }
+
+                if let Some(t) = p.time {
+                    if prev_time.is_some_and(|pt| t < pt) {
+                        add(location(), IssueKind::TimeNotIncreasing(t));
+                    }
+                    prev_time = Some(t);
+                }
+            }
+        }
+    }
+
+    issues
+}
+
+fn validate_metadata<F>(metadata: &Metadata, add: &mut F)
+where
+    F: FnMut(String, IssueKind),
+{
+    if let Some(copyright) = &metadata.copyright {
+        if copyright.author.trim().is_empty() {
+            add("metadata".to_string(), IssueKind::MissingCopyrightAuthor);
+        }
+    }
+
+    let author_link = metadata.author.as_ref().and_then(|a| a.link.as_ref());
+    for link in metadata.links.iter().chain(author_link) {
+        validate_link(link, add);
+    }
+
+    if let Some(bounds) = &metadata.bounds {
+        validate_bounds(bounds, add);
+    }
+}
+
+fn validate_link<F>(link: &Link, add: &mut F)
+where
+    F: FnMut(String, IssueKind),
+{
+    if link.href.trim().is_empty() {
+        add("metadata link".to_string(), IssueKind::MissingLinkHref);
+    }
+}
+
+fn validate_bounds<F>(bounds: &Bounds, add: &mut F)
+where
+    F: FnMut(String, IssueKind),
+{
+    let location = || "metadata bounds".to_string();
+
+    for lat in [bounds.min_lat, bounds.max_lat] {
+        if !(-90.0..=90.0).contains(&lat) {
+            add(location(), IssueKind::LatitudeOutOfRange(lat));
+        }
+    }
+    for lon in [bounds.min_lon, bounds.max_lon] {
+        if !(-180.0..=180.0).contains(&lon) {
+            add(location(), IssueKind::LongitudeOutOfRange(lon));
+        }
+    }
+    if bounds.min_lat > bounds.max_lat || bounds.min_lon > bounds.max_lon {
+        add(location(), IssueKind::InvertedBounds);
+    }
+}
+
+/// A problem with the data in a track found by 'EnrichedGpx::diagnostics'.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    /// The index of the point with the problem.
+    pub index: usize,
+    pub kind: AnomalyKind,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "point {}: {}", self.index, self.kind)
+    }
+}
+
+/// The types of problem found by 'EnrichedGpx::diagnostics'.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnomalyKind {
+    /// The time is not later than the time of the previous point.
+    /// Stage detection requires increasing times.
+    TimeNotIncreasing {
+        previous: OffsetDateTime,
+        time: OffsetDateTime,
+    },
+    /// The point is an exact copy of the previous point.
+    DuplicatePoint,
+    /// The speed from the previous point, in km/h, is implausibly high.
+    ImplausibleSpeed(f64),
+    /// The point is this many metres from the previous point.
+    PositionJump(f64),
+    /// This point and the 'count - 1' points after it have no elevation.
+    MissingElevation { count: usize },
+}
+
+impl fmt::Display for AnomalyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnomalyKind::TimeNotIncreasing { previous, time } => {
+                write!(
+                    f,
+                    "time {} is not later than the previous point's time {}",
+                    format_utc_date(time),
+                    format_utc_date(previous)
+                )
+            }
+            AnomalyKind::DuplicatePoint => write!(f, "duplicate of the previous point"),
+            AnomalyKind::ImplausibleSpeed(kmh) => {
+                write!(f, "speed of {kmh:.1} km/h from the previous point")
+            }
+            AnomalyKind::PositionJump(metres) => {
+                write!(f, "jumped {metres:.0}m from the previous point")
+            }
+            AnomalyKind::MissingElevation { count } => {
+                write!(f, "{count} points with no elevation")
+            }
+        }
+    }
+}
+
+impl EnrichedGpx {
+    /// Checks the points for problems which would make the analysis
+    /// untrustworthy, such as times that go backwards and GPS glitches,
+    /// and returns them in track order. This does not rely on the points
+    /// having been enriched, so it can be used on tracks which would
+    /// fail the checks in 'enrich_trackpoints'.
+    pub fn diagnostics(&self) -> Vec<Anomaly> {
+        let mut anomalies = Vec::new();
+        let mut add = |index: usize, kind: AnomalyKind| {
+            anomalies.push(Anomaly { index, kind });
+        };
+
+        for pair in self.points.windows(2) {
+            let (prev, p) = (&pair[0], &pair[1]);
+
+            if p.lat == prev.lat && p.lon == prev.lon && p.ele == prev.ele && p.time == prev.time {
+                add(p.index, AnomalyKind::DuplicatePoint);
+                continue;
+            }
+
+            let metres = prev.as_geo_point().geodesic_distance(&p.as_geo_point());
+            if metres > POSITION_JUMP_METRES {
+                add(p.index, AnomalyKind::PositionJump(metres));
+            }
+
+            if let (Some(previous), Some(time)) = (prev.time, p.time) {
+                if time <= previous {
+                    add(p.index, AnomalyKind::TimeNotIncreasing { previous, time });
+                } else {
+                    let speed = speed_kmh_from_duration(metres, time - previous);
+                    if speed > MAX_PLAUSIBLE_SPEED_KMH {
+                        add(p.index, AnomalyKind::ImplausibleSpeed(speed));
+                    }
+                }
+            }
+        }
+
+        // If no point has an elevation then the device just doesn't record
+        // it, which is not a problem with the data.
+        if self.points.iter().any(|p| p.ele.is_some()) {
+            let mut idx = 0;
+            while idx < self.points.len() {
+                if self.points[idx].ele.is_some() {
+                    idx += 1;
+                    continue;
+                }
+
+                let start = idx;
+                while idx < self.points.len() && self.points[idx].ele.is_none() {
+                    idx += 1;
+                }
+                add(
+                    self.points[start].index,
+                    AnomalyKind::MissingElevation { count: idx - start },
+                );
+            }
+        }
+
+        anomalies.sort_by_key(|a| a.index);
+        anomalies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::TrackPoint,
+        test_helpers::{make_enriched_gpx, make_gpx, ride_points, track_point},
+    };
+
+    #[test]
+    fn a_valid_file_has_no_issues() {
+        let gpx = make_gpx(vec![
+            track_point(51.5, -0.1, None),
+            track_point(51.6, -0.1, None),
+        ]);
+        assert_eq!(validate_gpx(&gpx), []);
+    }
+
+    #[test]
+    fn reports_an_out_of_range_latitude_and_a_missing_creator() {
+        let mut gpx = make_gpx(vec![
+            track_point(51.5, -0.1, None),
+            track_point(91.0, -0.1, None),
+        ]);
+        gpx.info.creator = String::new();
+
+        assert_eq!(
+            validate_gpx(&gpx),
+            [
+                ValidationIssue {
+                    location: "gpx".to_string(),
+                    kind: IssueKind::MissingCreator,
+                },
+                ValidationIssue {
+                    location: "track 1, segment 1, point 2".to_string(),
+                    kind: IssueKind::LatitudeOutOfRange(91.0),
+                },
+            ]
+        );
+    }
+
+    /// Returns the anomalies in a 10 minute ride at 20km/h, which has an
+    /// elevation on every point, after 'inject' has damaged it.
+    fn anomalies(inject: impl FnOnce(&mut Vec<TrackPoint>)) -> Vec<Anomaly> {
+        let mut points: Vec<_> = ride_points(&[(20.0, 600)])
+            .into_iter()
+            .map(|p| TrackPoint {
+                ele: Some(10.0),
+                ..p
+            })
+            .collect();
+        inject(&mut points);
+        make_enriched_gpx(points).diagnostics()
+    }
+
+    #[test]
+    fn a_clean_track_has_no_anomalies() {
+        assert_eq!(anomalies(|_| {}), []);
+    }
+
+    #[test]
+    fn reports_a_backward_time() {
+        let anomalies = anomalies(|points| points[20].time = points[18].time);
+
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].index, 20);
+        assert!(matches!(
+            anomalies[0].kind,
+            AnomalyKind::TimeNotIncreasing { .. }
+        ));
+    }
+
+    #[test]
+    fn reports_a_duplicate_point() {
+        let anomalies = anomalies(|points| points[10] = points[9].clone());
+
+        assert_eq!(
+            anomalies,
+            [Anomaly {
+                index: 10,
+                kind: AnomalyKind::DuplicatePoint
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_an_implausible_speed() {
+        // 600m north and back again, in 10 seconds each way.
+        let anomalies = anomalies(|points| points[30].lat += 600.0 / 111_000.0);
+
+        let indexes: Vec<_> = anomalies.iter().map(|a| a.index).collect();
+        assert_eq!(indexes, [30, 31]);
+        assert!(anomalies
+            .iter()
+            .all(|a| matches!(a.kind, AnomalyKind::ImplausibleSpeed(kmh) if kmh > 150.0)));
+    }
+
+    #[test]
+    fn reports_a_position_jump() {
+        let anomalies = anomalies(|points| points[40].lat += 0.02);
+
+        let jumps: Vec<_> = anomalies
+            .iter()
+            .filter(|a| matches!(a.kind, AnomalyKind::PositionJump(metres) if metres > 2000.0))
+            .map(|a| a.index)
+            .collect();
+        assert_eq!(jumps, [40, 41]);
+    }
+
+    #[test]
+    fn reports_a_run_of_missing_elevations() {
+        let anomalies = anomalies(|points| {
+            for p in &mut points[50..53] {
+                p.ele = None;
+            }
+        });
+
+        assert_eq!(
+            anomalies,
+            [Anomaly {
+                index: 50,
+                kind: AnomalyKind::MissingElevation { count: 3 }
+            }]
+        );
+    }
+}
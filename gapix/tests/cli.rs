@@ -0,0 +1,220 @@
+//! Tests which run the gapix executable. It processes the files in the
+//! directory that contains the executable, so each test copies the
+//! executable into a directory of its own along with its input files.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+/// Creates an empty directory for the test and copies gapix into it.
+fn install_gapix(test_name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("gapix-cli-{}-{test_name}", std::process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    fs::copy(env!("CARGO_BIN_EXE_gapix"), dir.join("gapix")).unwrap();
+    dir
+}
+
+/// Writes a GPX file of a 20 minute ride heading east at 20km/h with a
+/// point every 10 seconds, starting 'start_hour' hours after 08:00.
+fn write_ride(dir: &Path, filename: &str, start_hour: u32) {
+    let mut points = String::new();
+    for i in 0..=120 {
+        let secs = i * 10;
+        // 20km/h is 55.6m per 10 seconds, and at 51.5N a degree of
+        // longitude is about 69.2km.
+        let lon = -0.1 + secs as f64 / 10.0 * 55.6 / 69_200.0;
+        points.push_str(&format!(
+            r#"      <trkpt lat="51.500000" lon="{lon:.6}">
+        <ele>10.0</ele>
+        <time>2024-06-01T{:02}:{:02}:{:02}Z</time>
+      </trkpt>
+"#,
+            8 + start_hour + secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        ));
+    }
+
+    let gpx = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="test" version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+  <metadata>
+    <time>2024-06-01T{:02}:00:00Z</time>
+  </metadata>
+  <trk>
+    <name>{filename}</name>
+    <trkseg>
+{points}    </trkseg>
+  </trk>
+</gpx>
+"#,
+        8 + start_hour
+    );
+
+    fs::write(dir.join(filename), gpx).unwrap();
+}
+
+/// Runs the copy of gapix in 'dir'.
+fn run_gapix(dir: &Path, args: &[&str]) -> Output {
+    let output = Command::new(dir.join("gapix"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+/// Returns the names of the files in 'dir', sorted.
+fn files_in(dir: &Path) -> Vec<String> {
+    let mut names: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn dry_run_does_not_write_anything() {
+    let dir = install_gapix("dry-run");
+    write_ride(&dir, "ride.gpx", 0);
+    let before = files_in(&dir);
+
+    let output = run_gapix(&dir, &["--dry-run", "-d", "--json", "-m", "10"]);
+
+    assert_eq!(files_in(&dir), before);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Would write") && stdout.contains("ride.summary.xlsx"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("ride.analysis.json"), "{stdout}");
+    assert!(stdout.contains("ride.simplified.gpx"), "{stdout}");
+}
+
+#[test]
+fn dry_run_reports_the_outputs_of_the_join() {
+    let dir = install_gapix("dry-run-join");
+    // Joining by time puts b.gpx first, so the joined outputs are named after it.
+    write_ride(&dir, "a.gpx", 1);
+    write_ride(&dir, "b.gpx", 0);
+    let before = files_in(&dir);
+
+    let output = run_gapix(&dir, &["--dry-run", "--join", "-d", "--split-stages"]);
+
+    assert_eq!(files_in(&dir), before);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Would join 2 files"), "{stdout}");
+    assert!(stdout.contains("b.summary.xlsx"), "{stdout}");
+    assert!(stdout.contains("b.stageNN.gpx"), "{stdout}");
+    assert!(!stdout.contains("a.summary.xlsx"), "{stdout}");
+}
+
+#[test]
+fn analyse_individually_writes_a_summary_per_input_and_one_for_the_join() {
+    let dir = install_gapix("analyse-individually");
+    write_ride(&dir, "a.gpx", 0);
+    write_ride(&dir, "b.gpx", 1);
+    write_ride(&dir, "c.gpx", 2);
+
+    run_gapix(&dir, &["--join", "--analyse-individually", "-d"]);
+
+    let summaries: Vec<_> = files_in(&dir)
+        .into_iter()
+        .filter(|name| name.ends_with(".xlsx"))
+        .collect();
+    assert_eq!(summaries.len(), 4, "{summaries:?}");
+    for name in ["a.summary.xlsx", "b.summary.xlsx", "c.summary.xlsx"] {
+        assert!(summaries.contains(&name.to_string()), "{summaries:?}");
+    }
+}
+
+#[test]
+fn output_dir_puts_the_outputs_in_that_directory() {
+    let dir = install_gapix("output-dir");
+    write_ride(&dir, "ride.gpx", 0);
+    let before = files_in(&dir);
+
+    run_gapix(&dir, &["-d", "-m", "10", "--output-dir", "out"]);
+
+    let out = dir.join("out");
+    assert_eq!(files_in(&out), ["ride.simplified.gpx", "ride.summary.xlsx"]);
+    let mut after = before;
+    after.push("out".to_string());
+    after.sort();
+    assert_eq!(files_in(&dir), after);
+}
+
+#[test]
+fn stdin_is_simplified_to_stdout() {
+    let dir = install_gapix("stdin");
+    write_ride(&dir, "ride.gpx", 0);
+    let input = fs::read(dir.join("ride.gpx")).unwrap();
+    let before = files_in(&dir);
+
+    let mut child = Command::new(dir.join("gapix"))
+        .args(["--stdin", "-m", "10"])
+        .current_dir(&dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&input).unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("<?xml"), "{stdout}");
+    assert!(stdout.trim_end().ends_with("</gpx>"), "{stdout}");
+    // The ride is a straight line, so only its ends are kept.
+    assert_eq!(stdout.matches("<trkpt").count(), 2, "{stdout}");
+    assert_eq!(files_in(&dir), before);
+}
+
+#[test]
+fn epsilon_gives_the_same_output_as_the_equivalent_metres() {
+    let dir = install_gapix("epsilon");
+    write_ride(&dir, "ride.gpx", 0);
+    // Zigzag by about 11m every 5 points, so that there is something
+    // for RDP to keep.
+    let gpx = fs::read_to_string(dir.join("ride.gpx")).unwrap();
+    let gpx: Vec<_> = gpx
+        .split(r#"lat="51.500000""#)
+        .enumerate()
+        .map(|(i, part)| match i {
+            0 => part.to_string(),
+            _ if i / 5 % 2 == 0 => format!(r#"lat="51.500000"{part}"#),
+            _ => format!(r#"lat="51.500100"{part}"#),
+        })
+        .collect();
+    fs::write(dir.join("ride.gpx"), gpx.concat()).unwrap();
+    let simplified = dir.join("ride.simplified.gpx");
+
+    run_gapix(&dir, &["-m", "10"]);
+    let by_metres = fs::read_to_string(&simplified).unwrap();
+    fs::remove_file(&simplified).unwrap();
+
+    // This is what metres_to_epsilon(10) returns.
+    let epsilon = 10.0 / 111_111.0;
+    run_gapix(&dir, &["--epsilon", &epsilon.to_string()]);
+    let by_epsilon = fs::read_to_string(&simplified).unwrap();
+
+    assert!(by_metres.matches("<trkpt").count() > 10, "{by_metres}");
+    assert_eq!(by_epsilon, by_metres);
+}